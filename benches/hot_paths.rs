@@ -0,0 +1,158 @@
+use another_rust_load_balancer::{
+  acme::AcmeHandler,
+  backend_pool_matcher::BackendPoolMatcher,
+  health::{BackendHealth, HealthConfig, Healthiness},
+  http_client::StrategyNotifyHttpConnector,
+  load_balancing::{
+    ip_hash::IPHash, least_bandwidth::LeastBandwidth, least_connection::LeastConnection, random::Random,
+    round_robin::RoundRobin, sticky_cookie::StickyCookie, Context as LoadBalancingContext, LoadBalancingStrategy,
+  },
+  middleware::{backend_request, Context, MiddlewareChain, DEFAULT_MAX_BUFFER_BYTES},
+  server::{pool_by_req, BackendPoolBuilder, Scheme, SharedData},
+};
+use cookie::SameSite;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use hyper::{Body, Client, Request};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+const MATCHER: &str = "(Host('whoami.localhost') && PathRegexp('^/api/.*')) || (Host('admin.localhost') && Method('GET') && Query('debug', 'true'))";
+
+fn request() -> Request<Body> {
+  Request::builder()
+    .uri("/api/users?debug=true")
+    .header("host", "whoami.localhost")
+    .header(
+      "accept",
+      "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+    )
+    .header("accept-encoding", "gzip, deflate, br")
+    .header("accept-language", "en-US,en;q=0.5")
+    .header("cookie", "lb_cookie=127.0.0.1:8082; session=abc")
+    .header(
+      "user-agent",
+      "Mozilla/5.0 (X11; Linux x86_64; rv:86.0) Gecko/20100101 Firefox/86.0",
+    )
+    .header("x-forwarded-for", "10.0.0.1")
+    .body(Body::empty())
+    .unwrap()
+}
+
+fn bench_matcher(c: &mut Criterion) {
+  c.bench_function("matcher/parse", |b| {
+    b.iter(|| black_box(MATCHER).parse::<BackendPoolMatcher>())
+  });
+
+  let matcher: BackendPoolMatcher = MATCHER.parse().unwrap();
+  let request = request();
+  c.bench_function("matcher/matches", |b| b.iter(|| matcher.matches(black_box(&request))));
+}
+
+fn bench_pool_by_req(c: &mut Criterion) {
+  // the matching pool is the last of 20 pools to measure the worst case
+  let backend_pools = (0..20)
+    .map(|i| {
+      let matcher = if i == 19 {
+        MATCHER.parse().unwrap()
+      } else {
+        BackendPoolMatcher::Host(format!("pool{}.localhost", i))
+      };
+      let builder = BackendPoolBuilder::new(
+        format!("pool{}", i),
+        matcher,
+        vec![("127.0.0.1:8084".into(), BackendHealth::new(Healthiness::Healthy))],
+        HealthConfig::default(),
+        Box::new(Random::new()),
+        MiddlewareChain::Empty,
+        vec![Scheme::HTTP].into_iter().collect(),
+      );
+      Arc::new(builder.build())
+    })
+    .collect();
+  let shared_data = SharedData {
+    backend_pools,
+    default_pool: None,
+    http10_pools: HashMap::new(),
+    services: Vec::new(),
+    // without an index, every pool is a candidate
+    pool_index: Default::default(),
+    acme_handler: Arc::new(AcmeHandler::new()),
+  };
+  let request = request();
+  c.bench_function("pool_by_req", |b| {
+    b.iter(|| pool_by_req(&shared_data, black_box(&request), &Scheme::HTTP))
+  });
+}
+
+fn bench_strategies(c: &mut Criterion) {
+  let strategies: Vec<(&str, Box<dyn LoadBalancingStrategy>)> = vec![
+    ("random", Box::new(Random::new())),
+    ("round_robin", Box::new(RoundRobin::new())),
+    ("ip_hash", Box::new(IPHash::new())),
+    ("least_connection", Box::new(LeastConnection::new())),
+    ("least_bandwidth", Box::new(LeastBandwidth::default())),
+    (
+      "sticky_cookie",
+      Box::new(StickyCookie::new(
+        "lb_cookie".into(),
+        Box::new(RoundRobin::new()),
+        true,
+        false,
+        SameSite::Lax,
+      )),
+    ),
+  ];
+  let client_address: SocketAddr = "10.0.0.1:3000".parse().unwrap();
+  let backend_addresses = ["127.0.0.1:8081", "127.0.0.1:8082", "127.0.0.1:8083", "127.0.0.1:8084"];
+  let context = LoadBalancingContext {
+    client_address: &client_address,
+    backend_addresses: &backend_addresses,
+    pool_addresses: &backend_addresses,
+    backend_weights: &[],
+  };
+  let request = request();
+
+  let mut group = c.benchmark_group("select_backend");
+  for (name, strategy) in &strategies {
+    group.bench_function(*name, |b| {
+      b.iter(|| {
+        strategy
+          .select_backend(black_box(&request), &context)
+          .backend_address()
+          .len()
+      })
+    });
+  }
+  group.finish();
+}
+
+fn bench_backend_request(c: &mut Criterion) {
+  let strategy: Arc<Box<dyn LoadBalancingStrategy>> = Arc::new(Box::new(Random::new()));
+  let client = Client::builder().build(StrategyNotifyHttpConnector::new(strategy));
+  let client_address = "10.0.0.1:3000".parse().unwrap();
+  let context = Context {
+    client_scheme: &Scheme::HTTPS,
+    client_address: &client_address,
+    backend_uri: "http://127.0.0.1:8084/api/users?debug=true".parse().unwrap(),
+    client: &client,
+    max_buffer_bytes: DEFAULT_MAX_BUFFER_BYTES,
+    tls_fingerprint: None,
+    log_redaction: &Default::default(),
+    hedge: None,
+  };
+  c.bench_function("backend_request", |b| {
+    b.iter_batched(
+      request,
+      |request| backend_request(request, &context),
+      BatchSize::SmallInput,
+    )
+  });
+}
+
+criterion_group!(
+  benches,
+  bench_matcher,
+  bench_pool_by_req,
+  bench_strategies,
+  bench_backend_request
+);
+criterion_main!(benches);
@@ -1,23 +1,57 @@
-use crate::error_response::{bad_request, not_found};
+use crate::{
+  acme_account::{new_account_key, register_account, ExternalAccountBinding},
+  error_response::{bad_request, not_found},
+};
 use acme_lib::order::NewOrder;
-use acme_lib::persist::FilePersist;
+use acme_lib::persist::{FilePersist, Persist, PersistKey, PersistKind};
 use acme_lib::{create_rsa_key, Certificate, Directory, DirectoryUrl, Error};
+use chrono::{DateTime, Utc};
 use hyper::{Body, Request, Response, StatusCode};
+use log::warn;
+use serde_json::{json, Value};
 use std::{
-  path::Path,
+  collections::HashMap,
+  fs,
+  path::{Path, PathBuf},
   sync::{Arc, Mutex},
+  time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio_util::either::Either;
 use tokio_util::either::Either::{Left, Right};
 
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+pub const LETS_ENCRYPT_STAGING_DIRECTORY: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+/* The name acme-lib persists the account key under */
+const ACCOUNT_KEY_NAME: &str = "acme_account";
+/* Delay before the first retry of a failed order, doubled with every further failure */
+const ORDER_RETRY_DELAY: Duration = Duration::from_secs(60);
+const MAX_ORDER_RETRY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+/* CAs limit the orders per hour, e.g. Let's Encrypt allows 5 failed validations per domain and hour */
+const RATE_LIMITED_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// The account ordering certificates at an ACME server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcmeAccount {
+  pub directory_url: String,
+  pub email: String,
+  /// Keeps the account key and the certificates, so they are reused across restarts and renewals
+  pub persist_dir: PathBuf,
+  /// An existing account key in PEM, which replaces the persisted one
+  pub account_key: Option<Vec<u8>>,
+  pub external_account_binding: Option<ExternalAccountBinding>,
+}
+
 struct OpenChallenge {
   token: String,
   proof: String,
 }
 
+#[derive(Default)]
 pub struct AcmeHandler {
   challenges: Arc<Mutex<Vec<OpenChallenge>>>,
+  /* Serializes the orders per domain */
+  orders: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
 }
 
 type ChallengeSender = UnboundedSender<Either<(String, String), Result<Certificate, Error>>>;
@@ -26,6 +60,7 @@ impl AcmeHandler {
   pub fn new() -> AcmeHandler {
     AcmeHandler {
       challenges: Arc::new(Mutex::new(Vec::new())),
+      orders: Mutex::new(HashMap::new()),
     }
   }
 
@@ -83,31 +118,78 @@ impl AcmeHandler {
     });
   }
 
-  pub async fn initiate_challenge<P: AsRef<Path>>(
-    &self,
-    staging: bool,
-    persist_dir: P,
-    email: &str,
-    primary_name: &str,
-  ) -> Result<Certificate, Error> {
-    std::fs::create_dir_all(&persist_dir).map_err(|e| Error::Other(e.to_string()))?;
-    let persist = FilePersist::new(&persist_dir);
-    let dir_url = if staging {
-      DirectoryUrl::LetsEncryptStaging
-    } else {
-      DirectoryUrl::LetsEncrypt
-    };
-    let dir = Directory::from_url(persist, dir_url)?;
-    let acc = dir.account(email)?;
+  /// Returns the persisted certificate of the domain or orders a new one. Failed orders are retried with an
+  /// exponential backoff, which is persisted, so restarts and reloads don't run into the rate limits of the CA.
+  pub async fn initiate_challenge(&self, account: &AcmeAccount, primary_name: &str) -> Result<Certificate, Error> {
+    // concurrent orders of the same domain, e.g. of overlapping reloads, wait for each other, so the later ones find
+    // the certificate of the first one instead of ordering it again
+    let order_lock = self.order_lock(primary_name);
+    let _order_lock = order_lock.lock().await;
 
-    let existing_cert = acc.certificate(primary_name)?;
-    if let Some(cert) = existing_cert {
-      if cert.valid_days_left() > 0 {
-        return Ok(cert);
+    std::fs::create_dir_all(&account.persist_dir).map_err(|e| Error::Other(e.to_string()))?;
+    let failures = OrderFailures::load(&account.persist_dir, primary_name);
+    if let Some(failures) = &failures {
+      let retry_at = failures.retry_at();
+      if SystemTime::now() < retry_at {
+        return Err(Error::Other(format!(
+          "Not ordering a certificate for '{}' before {} after {} failed orders",
+          primary_name,
+          DateTime::<Utc>::from(retry_at).to_rfc3339(),
+          failures.count
+        )));
       }
     }
 
-    let ord_new = acc.new_order(primary_name, &[])?;
+    let result = self.order_certificate(account, primary_name).await;
+    match &result {
+      Ok(_) if failures.is_some() => OrderFailures::clear(&account.persist_dir, primary_name),
+      Ok(_) => {}
+      Err(e) => {
+        let failures = failures.unwrap_or_default().record(e, SystemTime::now());
+        warn!(
+          "Ordering a certificate for '{}' failed {} times, retrying in {} seconds at the earliest",
+          primary_name,
+          failures.count,
+          failures.retry_delay().as_secs()
+        );
+        failures.save(&account.persist_dir, primary_name);
+      }
+    }
+    result
+  }
+
+  fn order_lock(&self, primary_name: &str) -> Arc<tokio::sync::Mutex<()>> {
+    let mut orders = self.orders.lock().unwrap();
+    orders.entry(primary_name.to_string()).or_default().clone()
+  }
+
+  async fn order_certificate(&self, account: &AcmeAccount, primary_name: &str) -> Result<Certificate, Error> {
+    let persist = FilePersist::new(&account.persist_dir);
+    prepare_account_key(&persist, account).await?;
+    let directory_url = account.directory_url.clone();
+    let email = account.email.clone();
+    let name = primary_name.to_string();
+    // acme-lib blocks while it talks to the ACME server, which would stall the other orders
+    let order = tokio::task::spawn_blocking(move || {
+      let dir = Directory::from_url(persist, DirectoryUrl::Other(&directory_url))?;
+      let acc = dir.account(&email)?;
+
+      let existing_cert = acc.certificate(&name)?;
+      if let Some(cert) = existing_cert {
+        if cert.valid_days_left() > 0 {
+          return Ok(Left(cert));
+        }
+      }
+
+      acc.new_order(&name, &[]).map(Right)
+    })
+    .await
+    .map_err(|e| Error::Other(e.to_string()))??;
+    let ord_new = match order {
+      Left(cert) => return Ok(cert),
+      Right(ord_new) => ord_new,
+    };
+
     let (cs, mut cr) = unbounded_channel();
     AcmeHandler::start_challenge_handler(ord_new, cs);
 
@@ -147,6 +229,86 @@ impl AcmeHandler {
   }
 }
 
+/* Consecutive failed orders of a domain, persisted next to the certificates */
+#[derive(Debug, Default, Clone, PartialEq)]
+struct OrderFailures {
+  count: u32,
+  last_failure: u64,
+  rate_limited: bool,
+}
+
+impl OrderFailures {
+  fn path(persist_dir: &Path, primary_name: &str) -> PathBuf {
+    persist_dir.join(format!("order_failures_{}.json", primary_name))
+  }
+
+  fn load(persist_dir: &Path, primary_name: &str) -> Option<OrderFailures> {
+    let json: Value = serde_json::from_slice(&fs::read(OrderFailures::path(persist_dir, primary_name)).ok()?).ok()?;
+    Some(OrderFailures {
+      count: json["count"].as_u64()? as u32,
+      last_failure: json["last_failure"].as_u64()?,
+      rate_limited: json["rate_limited"].as_bool().unwrap_or(false),
+    })
+  }
+
+  fn save(&self, persist_dir: &Path, primary_name: &str) {
+    let json = json!({
+      "count": self.count,
+      "last_failure": self.last_failure,
+      "rate_limited": self.rate_limited,
+    });
+    if let Err(e) = fs::write(OrderFailures::path(persist_dir, primary_name), json.to_string()) {
+      warn!("Could not persist failed order of '{}' due to: {}", primary_name, e);
+    }
+  }
+
+  fn clear(persist_dir: &Path, primary_name: &str) {
+    let _ = fs::remove_file(OrderFailures::path(persist_dir, primary_name));
+  }
+
+  fn record(self, error: &Error, now: SystemTime) -> OrderFailures {
+    OrderFailures {
+      count: self.count.saturating_add(1),
+      last_failure: now.duration_since(UNIX_EPOCH).map_or(0, |it| it.as_secs()),
+      // the type of the problem document, e.g. `urn:ietf:params:acme:error:rateLimited`, is part of the message
+      rate_limited: error.to_string().contains("rateLimited"),
+    }
+  }
+
+  fn retry_delay(&self) -> Duration {
+    let factor = 2u32.saturating_pow(self.count.saturating_sub(1));
+    let delay = ORDER_RETRY_DELAY
+      .checked_mul(factor)
+      .map_or(MAX_ORDER_RETRY_DELAY, |it| it.min(MAX_ORDER_RETRY_DELAY));
+    if self.rate_limited {
+      delay.max(RATE_LIMITED_RETRY_DELAY)
+    } else {
+      delay
+    }
+  }
+
+  fn retry_at(&self) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(self.last_failure) + self.retry_delay()
+  }
+}
+
+/* Persists the configured account key, so acme-lib uses it instead of creating one, and registers new account keys
+with the external account binding. Keys are only registered once, since providers may accept a binding only once. */
+async fn prepare_account_key(persist: &FilePersist, account: &AcmeAccount) -> Result<(), Error> {
+  let key_name = PersistKey::new(&account.email, PersistKind::AccountPrivateKey, ACCOUNT_KEY_NAME);
+  let account_key = match (&account.account_key, persist.get(&key_name)?) {
+    (Some(configured), Some(persisted)) if *configured == persisted => return Ok(()),
+    (Some(configured), _) => configured.clone(),
+    (None, None) if account.external_account_binding.is_some() => new_account_key()?,
+    // acme-lib keeps using the persisted key or creates a new one itself
+    (None, _) => return Ok(()),
+  };
+  if let Some(binding) = &account.external_account_binding {
+    register_account(&account.directory_url, &account_key, &account.email, binding).await?;
+  }
+  persist.put(&key_name, &account_key)
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -215,4 +377,68 @@ mod tests {
     assert_eq!(status, StatusCode::OK);
     assert_eq!(body, "abc");
   }
+
+  #[tokio::test]
+  async fn prepare_account_key_persists_configured_key() {
+    // given:
+    let persist_dir = std::env::temp_dir().join(format!("arlb-acme-{}", std::process::id()));
+    std::fs::create_dir_all(&persist_dir).unwrap();
+    let persist = FilePersist::new(&persist_dir);
+    let account_key = new_account_key().unwrap();
+    let account = AcmeAccount {
+      directory_url: LETS_ENCRYPT_STAGING_DIRECTORY.into(),
+      email: "me@example.com".into(),
+      persist_dir: persist_dir.clone(),
+      account_key: Some(account_key.clone()),
+      external_account_binding: None,
+    };
+
+    // when:
+    prepare_account_key(&persist, &account).await.unwrap();
+
+    // then:
+    let key_name = PersistKey::new("me@example.com", PersistKind::AccountPrivateKey, ACCOUNT_KEY_NAME);
+    assert_eq!(persist.get(&key_name).unwrap(), Some(account_key));
+    std::fs::remove_dir_all(persist_dir).unwrap();
+  }
+
+  #[test]
+  fn order_retry_delay_grows_exponentially_up_to_a_day() {
+    let failures = |count: u32, rate_limited: bool| OrderFailures {
+      count,
+      last_failure: 0,
+      rate_limited,
+    };
+
+    assert_eq!(failures(1, false).retry_delay(), Duration::from_secs(60));
+    assert_eq!(failures(3, false).retry_delay(), Duration::from_secs(240));
+    assert_eq!(failures(40, false).retry_delay(), MAX_ORDER_RETRY_DELAY);
+    assert_eq!(failures(1, true).retry_delay(), RATE_LIMITED_RETRY_DELAY);
+  }
+
+  #[test]
+  fn order_failures_are_persisted_until_cleared() {
+    // given:
+    let persist_dir = std::env::temp_dir().join(format!("arlb-acme-failures-{}", std::process::id()));
+    fs::create_dir_all(&persist_dir).unwrap();
+    let error = Error::Other("urn:ietf:params:acme:error:rateLimited: too many failed authorizations".into());
+    let failures = OrderFailures::default().record(&error, UNIX_EPOCH + Duration::from_secs(1000));
+
+    // when:
+    failures.save(&persist_dir, "whoami.localhost");
+    let loaded = OrderFailures::load(&persist_dir, "whoami.localhost");
+    OrderFailures::clear(&persist_dir, "whoami.localhost");
+
+    // then:
+    assert_eq!(
+      loaded,
+      Some(OrderFailures {
+        count: 1,
+        last_failure: 1000,
+        rate_limited: true,
+      })
+    );
+    assert_eq!(OrderFailures::load(&persist_dir, "whoami.localhost"), None);
+    fs::remove_dir_all(persist_dir).unwrap();
+  }
 }
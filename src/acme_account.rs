@@ -0,0 +1,286 @@
+use acme_lib::Error;
+use hyper::{body, header::CONTENT_TYPE, Body, Client, Method, Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use log::info;
+use openssl::{
+  base64,
+  bn::{BigNum, BigNumContext, BigNumRef},
+  ec::{EcGroup, EcKey},
+  ecdsa::EcdsaSig,
+  nid::Nid,
+  pkey::{PKey, Private},
+  sha::sha256,
+};
+use ring::hmac;
+use serde_json::{json, Value};
+use std::fmt::Display;
+
+/* Length of the coordinates and signature components of P-256 keys */
+const P256_FIELD_BYTES: usize = 32;
+
+/// Credentials of an account at the certificate authority, which some ACME providers, e.g. ZeroSSL or Google Trust
+/// Services, require to create an ACME account, see RFC 8555, section 7.3.4.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternalAccountBinding {
+  pub key_id: String,
+  pub hmac_key: Vec<u8>,
+}
+
+impl ExternalAccountBinding {
+  /// Creates the binding from the HMAC key as handed out by the providers, encoded in base64url.
+  pub fn new(key_id: String, encoded_hmac_key: &str) -> Result<ExternalAccountBinding, String> {
+    match decode_base64url(encoded_hmac_key.trim()) {
+      Some(hmac_key) if !hmac_key.is_empty() => Ok(ExternalAccountBinding { key_id, hmac_key }),
+      _ => Err(format!(
+        "invalid HMAC key for the external account binding '{}'",
+        key_id
+      )),
+    }
+  }
+}
+
+/// Creates an account key in the format persisted by acme-lib, a P-256 key in PKCS#8 PEM.
+pub fn new_account_key() -> Result<Vec<u8>, Error> {
+  let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(other)?;
+  let key = EcKey::generate(&group).map_err(other)?;
+  PKey::from_ec_key(key)
+    .and_then(|key| key.private_key_to_pem_pkcs8())
+    .map_err(other)
+}
+
+/// Registers the account key at the ACME server with an external account binding. acme-lib can't send the binding,
+/// but once the key is registered, it finds the existing account of the key.
+pub async fn register_account(
+  directory_url: &str,
+  account_key: &[u8],
+  email: &str,
+  binding: &ExternalAccountBinding,
+) -> Result<(), Error> {
+  let key = PKey::private_key_from_pem(account_key)
+    .and_then(|key| key.ec_key())
+    .map_err(other)?;
+  let client = Client::builder().build::<_, Body>(HttpsConnector::with_native_roots());
+
+  let directory = client.get(directory_url.parse().map_err(other)?).await.map_err(other)?;
+  let directory: Value =
+    serde_json::from_slice(&body::to_bytes(directory.into_body()).await.map_err(other)?).map_err(other)?;
+  let (new_nonce, new_account) = match (directory["newNonce"].as_str(), directory["newAccount"].as_str()) {
+    (Some(new_nonce), Some(new_account)) => (new_nonce, new_account),
+    _ => return Err(other(format!("Invalid ACME directory at {}", directory_url))),
+  };
+  let nonce = client
+    .request(Request::head(new_nonce).body(Body::empty()).map_err(other)?)
+    .await
+    .map_err(other)?
+    .headers()
+    .get("replay-nonce")
+    .and_then(|it| it.to_str().ok())
+    .map(str::to_string)
+    .ok_or_else(|| other(format!("No nonce received from {}", new_nonce)))?;
+
+  let body = new_account_request(&key, &nonce, new_account, email, binding)?;
+  let request = Request::builder()
+    .method(Method::POST)
+    .uri(new_account)
+    .header(CONTENT_TYPE, "application/jose+json")
+    .body(Body::from(body.to_string()))
+    .map_err(other)?;
+  let response = client.request(request).await.map_err(other)?;
+  let status = response.status();
+  let problem = body::to_bytes(response.into_body()).await.map_err(other)?;
+  match status {
+    StatusCode::OK | StatusCode::CREATED => {
+      info!(
+        "Registered ACME account at {} with external account binding '{}'",
+        new_account, binding.key_id
+      );
+      Ok(())
+    }
+    _ => Err(other(format!(
+      "Could not register ACME account at {}: {} {}",
+      new_account,
+      status,
+      String::from_utf8_lossy(&problem)
+    ))),
+  }
+}
+
+/* The signed newAccount request, with the public key of the account signed by the HMAC key of the binding */
+fn new_account_request(
+  key: &EcKey<Private>,
+  nonce: &str,
+  new_account: &str,
+  email: &str,
+  binding: &ExternalAccountBinding,
+) -> Result<Value, Error> {
+  let jwk = jwk(key)?;
+  let binding = jws(
+    &json!({ "alg": "HS256", "kid": binding.key_id, "url": new_account }),
+    &jwk,
+    |data| {
+      Ok(
+        hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &binding.hmac_key), data)
+          .as_ref()
+          .to_vec(),
+      )
+    },
+  )?;
+  let payload = json!({
+    "termsOfServiceAgreed": true,
+    "contact": [format!("mailto:{}", email)],
+    "externalAccountBinding": binding,
+  });
+  jws(
+    &json!({ "alg": "ES256", "jwk": jwk, "nonce": nonce, "url": new_account }),
+    &payload,
+    |data| es256(key, data),
+  )
+}
+
+/* A JSON web signature in the flattened JSON serialization required by ACME */
+fn jws<F>(protected: &Value, payload: &Value, sign: F) -> Result<Value, Error>
+where
+  F: FnOnce(&[u8]) -> Result<Vec<u8>, Error>,
+{
+  let protected = base64url(protected.to_string().as_bytes());
+  let payload = base64url(payload.to_string().as_bytes());
+  let signature = sign(format!("{}.{}", protected, payload).as_bytes())?;
+  Ok(json!({
+    "protected": protected,
+    "payload": payload,
+    "signature": base64url(&signature),
+  }))
+}
+
+/* ECDSA signatures are encoded as the concatenation of r and s in JSON web signatures, see RFC 7518, section 3.4 */
+fn es256(key: &EcKey<Private>, data: &[u8]) -> Result<Vec<u8>, Error> {
+  let signature = EcdsaSig::sign(&sha256(data), key).map_err(other)?;
+  let mut raw = padded(signature.r());
+  raw.extend(padded(signature.s()));
+  Ok(raw)
+}
+
+/* Writes a number big-endian with leading zeros up to the field length of P-256 */
+fn padded(number: &BigNumRef) -> Vec<u8> {
+  let bytes = number.to_vec();
+  let mut padded = vec![0; P256_FIELD_BYTES.saturating_sub(bytes.len())];
+  padded.extend(bytes);
+  padded
+}
+
+fn jwk(key: &EcKey<Private>) -> Result<Value, Error> {
+  let mut context = BigNumContext::new().map_err(other)?;
+  let mut x = BigNum::new().map_err(other)?;
+  let mut y = BigNum::new().map_err(other)?;
+  key
+    .public_key()
+    .affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut context)
+    .map_err(other)?;
+  Ok(json!({
+    "crv": "P-256",
+    "kty": "EC",
+    "x": base64url(&padded(&x)),
+    "y": base64url(&padded(&y)),
+  }))
+}
+
+fn base64url(data: &[u8]) -> String {
+  base64::encode_block(data)
+    .trim_end_matches('=')
+    .replace('+', "-")
+    .replace('/', "_")
+}
+
+fn decode_base64url(encoded: &str) -> Option<Vec<u8>> {
+  let mut standard = encoded.replace('-', "+").replace('_', "/");
+  while standard.len() % 4 != 0 {
+    standard.push('=');
+  }
+  base64::decode_block(&standard).ok()
+}
+
+fn other<E: Display>(error: E) -> Error {
+  Error::Other(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn decode(encoded: &Value) -> Vec<u8> {
+    decode_base64url(encoded.as_str().unwrap()).unwrap()
+  }
+
+  #[test]
+  fn decodes_base64url_hmac_keys() {
+    let binding = ExternalAccountBinding::new("kid-1".into(), "_-8").unwrap();
+
+    assert_eq!(binding.hmac_key, vec![0xff, 0xef]);
+    assert!(ExternalAccountBinding::new("kid-1".into(), "").is_err());
+    assert!(ExternalAccountBinding::new("kid-1".into(), "not base64!").is_err());
+  }
+
+  #[test]
+  fn pads_numbers_to_the_field_length() {
+    let number = BigNum::from_u32(0x0102).unwrap();
+
+    let actual = padded(&number);
+
+    assert_eq!(actual.len(), P256_FIELD_BYTES);
+    assert_eq!(actual[P256_FIELD_BYTES - 2..], [1, 2]);
+    assert!(actual[..P256_FIELD_BYTES - 2].iter().all(|it| *it == 0));
+  }
+
+  #[test]
+  fn encodes_base64url_without_padding() {
+    assert_eq!(base64url(&[0xff, 0xef]), "_-8");
+    assert_eq!(base64url(b"arlb"), "YXJsYg");
+  }
+
+  #[test]
+  fn signs_new_account_request_with_account_key_and_binding() {
+    // given:
+    let key = PKey::private_key_from_pem(&new_account_key().unwrap())
+      .unwrap()
+      .ec_key()
+      .unwrap();
+    let binding = ExternalAccountBinding {
+      key_id: "kid-1".into(),
+      hmac_key: b"secret".to_vec(),
+    };
+
+    // when:
+    let request = new_account_request(
+      &key,
+      "nonce",
+      "https://acme.test/new-account",
+      "me@example.com",
+      &binding,
+    )
+    .unwrap();
+
+    // then:
+    let signed = format!(
+      "{}.{}",
+      request["protected"].as_str().unwrap(),
+      request["payload"].as_str().unwrap()
+    );
+    let signature = decode(&request["signature"]);
+    let r = BigNum::from_slice(&signature[..32]).unwrap();
+    let s = BigNum::from_slice(&signature[32..]).unwrap();
+    let signature = EcdsaSig::from_private_components(r, s).unwrap();
+    assert!(signature.verify(&sha256(signed.as_bytes()), &key).unwrap());
+
+    let payload: Value = serde_json::from_slice(&decode(&request["payload"])).unwrap();
+    let eab = &payload["externalAccountBinding"];
+    let eab_signed = format!(
+      "{}.{}",
+      eab["protected"].as_str().unwrap(),
+      eab["payload"].as_str().unwrap()
+    );
+    let eab_key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+    assert!(hmac::verify(&eab_key, eab_signed.as_bytes(), &decode(&eab["signature"])).is_ok());
+    let eab_payload: Value = serde_json::from_slice(&decode(&eab["payload"])).unwrap();
+    assert_eq!(eab_payload, jwk(&key).unwrap());
+  }
+}
@@ -0,0 +1,584 @@
+use crate::{
+  certificate_expiry,
+  configuration::{reload_config, RuntimeConfig},
+  debug_headers::type_name,
+  error_response::{method_not_allowed, not_found, text_response, UpstreamFailure},
+  health::check_pool_health_now,
+  listeners::{plain_acceptor, tls_handshake_failures},
+  process_stats::ProcessStats,
+  server::BackendPool,
+};
+use arc_swap::ArcSwap;
+use chrono::SecondsFormat;
+use futures::TryFutureExt;
+use hyper::{
+  header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+  service::{make_service_fn, service_fn},
+  Body, Method, Request, Response, Server, StatusCode,
+};
+use log::info;
+use ring::{
+  constant_time::verify_slices_are_equal,
+  digest::{digest, SHA256},
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+  collections::{BTreeMap, HashMap},
+  convert::Infallible,
+  io,
+  net::SocketAddr,
+  path::PathBuf,
+  sync::Arc,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+/* Single page application showing the status and triggering the operations of the admin API */
+const DASHBOARD: &str = include_str!("admin_dashboard.html");
+
+/// Serves the admin API, which must only be reachable by operators.
+pub async fn serve(listener: TcpListener, admin_api: AdminApi) -> Result<(), io::Error> {
+  info!("Started listening for admin API requests on {}", listener.local_addr()?);
+
+  let admin_api = Arc::new(admin_api);
+  let service = make_service_fn(move |stream: &TcpStream| {
+    let admin_api = admin_api.clone();
+    let client_address = stream.peer_addr().ok();
+    async move {
+      Ok::<_, io::Error>(service_fn(move |mut request: Request<Body>| {
+        let admin_api = admin_api.clone();
+        if let Some(client_address) = client_address {
+          request.extensions_mut().insert(client_address);
+        }
+        async move { Ok::<_, Infallible>(admin_api.handle(request).await) }
+      }))
+    }
+  });
+  Server::builder(plain_acceptor(listener))
+    .serve(service)
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to listen admin API: {}", e)))
+    .await
+}
+
+/// Permissions of an admin API token. Read-only tokens may only query the status, admin tokens may also change the
+/// state of the load balancer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, JsonSchema)]
+pub enum AdminRole {
+  ReadOnly,
+  Admin,
+}
+
+/// A static bearer token for the admin API. The name identifies the caller in the audit log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdminToken {
+  pub name: String,
+  pub token: String,
+  pub role: AdminRole,
+}
+
+/* Returns the token presented as bearer token in the `Authorization` header, if it is one of the `tokens` */
+fn authenticate<'a>(tokens: &'a [AdminToken], request: &Request<Body>) -> Option<&'a AdminToken> {
+  let presented = request
+    .headers()
+    .get(AUTHORIZATION)?
+    .to_str()
+    .ok()?
+    .strip_prefix("Bearer ")?
+    .trim();
+  // compare digests of equal length in constant time, so neither the tokens nor their lengths can be guessed
+  let presented = digest(&SHA256, presented.as_bytes());
+  tokens
+    .iter()
+    .filter(|it| verify_slices_are_equal(digest(&SHA256, it.token.as_bytes()).as_ref(), presented.as_ref()).is_ok())
+    .last()
+}
+
+pub struct AdminApi {
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  config_path: PathBuf,
+}
+
+impl AdminApi {
+  pub fn new(config: Arc<ArcSwap<RuntimeConfig>>, config_path: PathBuf) -> AdminApi {
+    AdminApi { config, config_path }
+  }
+
+  /* Authenticates the caller and writes an audit log entry for every call changing the state of the load balancer */
+  async fn handle(&self, request: Request<Body>) -> Response<Body> {
+    let required_role = match *request.method() {
+      Method::GET | Method::HEAD => AdminRole::ReadOnly,
+      _ => AdminRole::Admin,
+    };
+    let call = format!("{} {}", request.method(), request.uri().path());
+    let client_address = match request.extensions().get::<SocketAddr>() {
+      Some(client_address) => client_address.to_string(),
+      None => "unknown address".to_string(),
+    };
+    // the dashboard page holds no data, it asks for a token once it queries the admin API
+    let is_dashboard = *request.method() == Method::GET && request.uri().path() == "/";
+
+    let tokens = self.config.load().admin_tokens.clone();
+    let (caller, response) = if tokens.is_empty() || is_dashboard {
+      ("anonymous".to_string(), self.route(request).await)
+    } else {
+      match authenticate(&tokens, &request) {
+        Some(token) if token.role >= required_role => (format!("'{}'", token.name), self.route(request).await),
+        Some(token) => (format!("'{}'", token.name), forbidden()),
+        None => ("unauthenticated caller".to_string(), unauthorized()),
+      }
+    };
+    if required_role == AdminRole::Admin {
+      info!(
+        target: "audit",
+        "{} by {} from {} answered with {}",
+        call,
+        caller,
+        client_address,
+        response.status()
+      );
+    }
+    response
+  }
+
+  async fn route(&self, request: Request<Body>) -> Response<Body> {
+    match (request.method(), request.uri().path()) {
+      (&Method::GET, "/") if self.config.load().admin_dashboard => html_response(DASHBOARD),
+      (&Method::POST, "/reload") => self.reload().await,
+      (_, "/reload") => method_not_allowed(),
+      (&Method::GET, "/status") => self.status(),
+      (_, "/status") => method_not_allowed(),
+      (method, path) => match path.strip_prefix("/pools/").and_then(|it| it.split_once('/')) {
+        Some((name, operation)) => self.pool_operation(method, name, operation).await,
+        None => not_found(),
+      },
+    }
+  }
+
+  /* Pauses, resumes or health checks a single backend pool */
+  async fn pool_operation(&self, method: &Method, name: &str, operation: &str) -> Response<Body> {
+    if !matches!(operation, "pause" | "resume" | "health-check") {
+      return not_found();
+    }
+    if *method != Method::POST {
+      return method_not_allowed();
+    }
+    let pool = match self
+      .config
+      .load()
+      .shared_data
+      .backend_pools
+      .iter()
+      .find(|pool| pool.name == name)
+    {
+      Some(pool) => pool.clone(),
+      None => return text_response(StatusCode::NOT_FOUND, format!("Unknown backend pool '{}'\n", name)),
+    };
+    match operation {
+      "pause" => {
+        pool.pause();
+        info!("Paused backend pool '{}'", pool.name);
+        text_response(StatusCode::OK, format!("Paused backend pool '{}'\n", pool.name))
+      }
+      "resume" => {
+        pool.resume();
+        info!("Resumed backend pool '{}'", pool.name);
+        text_response(StatusCode::OK, format!("Resumed backend pool '{}'\n", pool.name))
+      }
+      _ => {
+        let events = self.config.load().health_interval.events.clone();
+        check_pool_health_now(&pool, events.as_deref()).await;
+        json_response(StatusCode::OK, json!({ "backends": backends(&pool, None) }))
+      }
+    }
+  }
+
+  /* Re-reads the configuration file, keeping the old configuration if the new one is invalid */
+  async fn reload(&self) -> Response<Body> {
+    match reload_config(&self.config_path, &self.config).await {
+      Ok(()) => text_response(StatusCode::OK, "Reloaded configuration\n".to_string()),
+      Err(e) => text_response(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        format!(
+          "Could not reload configuration due to: {}\nKeeping old configuration\n",
+          e
+        ),
+      ),
+    }
+  }
+
+  fn status(&self) -> Response<Body> {
+    let config = self.config.load();
+    let backend_pools = config
+      .shared_data
+      .backend_pools
+      .iter()
+      .enumerate()
+      .map(|(index, pool)| {
+        let connections_in_use = pool.backend_limit.as_ref().map(|limit| limit.connections_in_use());
+        let backends = backends(pool, connections_in_use.as_ref());
+        let (request_bytes, response_bytes) = pool.traffic.total();
+        let (responses, server_errors) = pool.traffic.total_responses();
+        let recent_errors = pool
+          .traffic
+          .recent_errors()
+          .into_iter()
+          .map(|error| {
+            json!({
+              "timestamp": error.at.to_rfc3339_opts(SecondsFormat::Millis, true),
+              "address": error.address,
+              "status": error.status.as_u16(),
+              "upstream_error": error.upstream_failure.map(UpstreamFailure::code),
+            })
+          })
+          .collect::<Vec<_>>();
+        json!({
+          "index": index,
+          "name": pool.name,
+          "paused": pool.is_paused(),
+          "matcher": format!("{:?}", pool.matcher),
+          "strategy": type_name(&pool.strategy),
+          "strategy_metrics": pool.strategy.metrics().into_iter().collect::<BTreeMap<_, _>>(),
+          "backends": backends,
+          "request_bytes": request_bytes,
+          "response_bytes": response_bytes,
+          "responses": responses,
+          "server_errors": server_errors,
+          "recent_errors": recent_errors,
+          "shadow_strategy": pool.shadow_strategy.as_ref().map(|shadow| json!({
+            "strategy": shadow.name(),
+            "agreements": shadow.agreements(),
+            "divergences": shadow.divergences(),
+            "selections": shadow.selections(),
+          })),
+        })
+      })
+      .collect::<Vec<_>>();
+    json_response(
+      StatusCode::OK,
+      json!({
+        "process": ProcessStats::collect().to_json(),
+        "backend_pools": backend_pools,
+        "pending_certificates": config.pending_certificates(),
+        "certificates": certificates(&config),
+        "tls_handshake_failures": tls_handshake_failures(),
+      }),
+    )
+  }
+}
+
+fn backends(pool: &BackendPool, connections_in_use: Option<&HashMap<String, usize>>) -> Vec<Value> {
+  pool
+    .addresses
+    .iter()
+    .map(|(address, health)| {
+      let mut backend = json!({ "address": address, "healthiness": health.load().to_string() });
+      let resolved_ips = health.resolved_ips();
+      if !resolved_ips.is_empty() {
+        backend["resolved_ips"] = json!(resolved_ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>());
+      }
+      if let Some(traffic) = pool.traffic.backend(address) {
+        backend["request_bytes"] = json!(traffic.request_bytes());
+        backend["response_bytes"] = json!(traffic.response_bytes());
+        backend["responses"] = json!(traffic.responses());
+        backend["server_errors"] = json!(traffic.server_errors());
+        backend["upstream_failures"] = json!(traffic.upstream_failures());
+        backend["client_aborts"] = json!(traffic.client_aborts());
+      }
+      if let Some(cooldown) = &pool.cooldown {
+        let remaining = cooldown.remaining(address).unwrap_or_default();
+        backend["cooldown_ms"] = json!(remaining.as_millis() as u64);
+      }
+      if let Some(connections_in_use) = connections_in_use {
+        backend["connections"] = json!(connections_in_use.get(address).copied().unwrap_or(0));
+      }
+      backend
+    })
+    .collect()
+}
+
+fn certificates(config: &RuntimeConfig) -> BTreeMap<String, Value> {
+  certificate_expiry::validities(&config.certificates.load())
+    .into_iter()
+    .map(|(name, validity)| {
+      let validity = validity.map(|validity| {
+        json!({
+          "not_after": validity.not_after,
+          "days_left": validity.days_left(),
+        })
+      });
+      (name, json!(validity))
+    })
+    .collect()
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header(CONTENT_TYPE, "application/json")
+    .body(Body::from(body.to_string()))
+    .unwrap()
+}
+
+fn unauthorized() -> Response<Body> {
+  let mut response = text_response(
+    StatusCode::UNAUTHORIZED,
+    "Missing or unknown bearer token\n".to_string(),
+  );
+  response
+    .headers_mut()
+    .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer realm=\"admin\""));
+  response
+}
+
+fn forbidden() -> Response<Body> {
+  text_response(
+    StatusCode::FORBIDDEN,
+    "The token is not allowed to change the load balancer\n".to_string(),
+  )
+}
+
+fn html_response(body: &'static str) -> Response<Body> {
+  Response::builder()
+    .header(CONTENT_TYPE, "text/html; charset=utf-8")
+    .body(Body::from(body))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::configuration::read_initial_config;
+  use hyper::body;
+  use std::fs;
+
+  const CONFIG: &str = r#"
+    [[backend_pools]]
+    name = "whoami"
+    matcher = "Host('whoami.localhost')"
+    addresses = ["127.0.0.1:8080"]
+    schemes = ["HTTP"]
+    strategy = { RoundRobin = {} }
+  "#;
+
+  async fn admin_api(name: &str) -> (AdminApi, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("arlb-admin-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config_path = dir.join("config.toml");
+    fs::write(&config_path, CONFIG).unwrap();
+    let config = read_initial_config(&config_path, None).await.unwrap();
+    (AdminApi::new(config, config_path.clone()), config_path)
+  }
+
+  #[test]
+  fn authenticates_only_complete_tokens() {
+    let tokens = vec![AdminToken {
+      name: "deploy".into(),
+      token: "admin-token".into(),
+      role: AdminRole::Admin,
+    }];
+    let request = |token: &str| {
+      Request::builder()
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .body(Body::empty())
+        .unwrap()
+    };
+
+    assert_eq!(authenticate(&tokens, &request("admin-token")), Some(&tokens[0]));
+    assert_eq!(authenticate(&tokens, &request("admin")), None);
+    assert_eq!(authenticate(&tokens, &request("admin-token2")), None);
+  }
+
+  fn reload_request(method: Method) -> Request<Body> {
+    Request::builder()
+      .method(method)
+      .uri("/reload")
+      .body(Body::empty())
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn reload_applies_new_config() {
+    // given:
+    let (admin_api, config_path) = admin_api("reload").await;
+    fs::write(&config_path, CONFIG.replace("8080", "8081")).unwrap();
+
+    // when:
+    let response = admin_api.handle(reload_request(Method::POST)).await;
+
+    // then:
+    assert_eq!(response.status(), StatusCode::OK);
+    let config = admin_api.config.load();
+    assert_eq!(config.shared_data.backend_pools[0].addresses[0].0, "127.0.0.1:8081");
+  }
+
+  #[tokio::test]
+  async fn reload_keeps_old_config_if_new_one_is_invalid() {
+    // given:
+    let (admin_api, config_path) = admin_api("invalid").await;
+    fs::write(&config_path, CONFIG.replace("Host(", "Hos(")).unwrap();
+
+    // when:
+    let response = admin_api.handle(reload_request(Method::POST)).await;
+
+    // then:
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = body::to_bytes(response.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("invalid matcher"));
+    let config = admin_api.config.load();
+    assert_eq!(config.shared_data.backend_pools[0].addresses[0].0, "127.0.0.1:8080");
+  }
+
+  #[tokio::test]
+  async fn reload_rejects_duplicate_pool_names() {
+    // given:
+    let (admin_api, config_path) = admin_api("duplicate").await;
+    fs::write(&config_path, format!("{}{}", CONFIG, CONFIG)).unwrap();
+
+    // when:
+    let response = admin_api.handle(reload_request(Method::POST)).await;
+
+    // then:
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = body::to_bytes(response.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("backend pool name 'whoami' is not unique"));
+  }
+
+  #[tokio::test]
+  async fn status_lists_backend_pools() {
+    // given:
+    let (admin_api, _) = admin_api("status").await;
+
+    // when:
+    let response = admin_api
+      .handle(Request::get("/status").body(Body::empty()).unwrap())
+      .await;
+
+    // then:
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body::to_bytes(response.into_body()).await.unwrap();
+    let status: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["backend_pools"][0]["name"], "whoami");
+    assert_eq!(status["backend_pools"][0]["strategy"], "RoundRobin");
+    assert_eq!(status["backend_pools"][0]["backends"][0]["address"], "127.0.0.1:8080");
+    assert_eq!(status["backend_pools"][0]["backends"][0]["healthiness"], "Healthy");
+    assert_eq!(status["backend_pools"][0]["backends"][0]["request_bytes"], 0);
+    assert_eq!(status["backend_pools"][0]["response_bytes"], 0);
+    assert_eq!(status["backend_pools"][0]["responses"], 0);
+    assert_eq!(status["backend_pools"][0]["recent_errors"], json!([]));
+    assert_eq!(status["pending_certificates"], json!([]));
+    assert_eq!(status["certificates"], json!({}));
+    assert!(status["tls_handshake_failures"]["unknown_server_name"].is_u64());
+  }
+
+  #[tokio::test]
+  async fn serves_dashboard_only_if_enabled() {
+    // given:
+    let (admin_api, config_path) = admin_api("dashboard").await;
+    let dashboard_request = || Request::get("/").body(Body::empty()).unwrap();
+
+    // when:
+    let disabled = admin_api.handle(dashboard_request()).await;
+    fs::write(&config_path, format!("admin_dashboard = true\n{}", CONFIG)).unwrap();
+    admin_api.handle(reload_request(Method::POST)).await;
+    let enabled = admin_api.handle(dashboard_request()).await;
+
+    // then:
+    assert_eq!(disabled.status(), StatusCode::NOT_FOUND);
+    assert_eq!(enabled.status(), StatusCode::OK);
+    assert_eq!(enabled.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+  }
+
+  #[tokio::test]
+  async fn requires_token_with_sufficient_role() {
+    // given:
+    let (admin_api, config_path) = admin_api("tokens").await;
+    let tokens = r#"
+      admin_tokens = [
+        { name = "monitoring", token = "read-token", role = "ReadOnly" },
+        { name = "deploy", token = "admin-token", role = "Admin" },
+      ]
+    "#;
+    fs::write(&config_path, format!("{}{}", tokens, CONFIG)).unwrap();
+    admin_api.handle(reload_request(Method::POST)).await;
+    let request = |method: Method, path: &str, token: Option<&str>| {
+      let mut request = Request::builder().method(method).uri(path);
+      if let Some(token) = token {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+      }
+      request.body(Body::empty()).unwrap()
+    };
+
+    // when:
+    let anonymous = admin_api.handle(request(Method::GET, "/status", None)).await;
+    let unknown = admin_api.handle(request(Method::GET, "/status", Some("guess"))).await;
+    let status = admin_api
+      .handle(request(Method::GET, "/status", Some("read-token")))
+      .await;
+    let read_only_pause = admin_api
+      .handle(request(Method::POST, "/pools/whoami/pause", Some("read-token")))
+      .await;
+    let is_paused = admin_api.config.load().shared_data.backend_pools[0].is_paused();
+    let admin_pause = admin_api
+      .handle(request(Method::POST, "/pools/whoami/pause", Some("admin-token")))
+      .await;
+
+    // then:
+    assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(anonymous.headers()[WWW_AUTHENTICATE], "Bearer realm=\"admin\"");
+    assert_eq!(unknown.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(status.status(), StatusCode::OK);
+    assert_eq!(read_only_pause.status(), StatusCode::FORBIDDEN);
+    assert!(!is_paused);
+    assert_eq!(admin_pause.status(), StatusCode::OK);
+    assert!(admin_api.config.load().shared_data.backend_pools[0].is_paused());
+  }
+
+  #[tokio::test]
+  async fn reload_requires_post() {
+    // given:
+    let (admin_api, _) = admin_api("method").await;
+
+    // when:
+    let response = admin_api.handle(reload_request(Method::GET)).await;
+
+    // then:
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+  }
+
+  #[tokio::test]
+  async fn pause_and_resume_backend_pool() {
+    // given:
+    let (admin_api, _) = admin_api("pause").await;
+    let pool_request = |operation: &str| {
+      Request::post(format!("/pools/whoami/{}", operation))
+        .body(Body::empty())
+        .unwrap()
+    };
+
+    // when:
+    let paused = admin_api.handle(pool_request("pause")).await;
+    let is_paused = admin_api.config.load().shared_data.backend_pools[0].is_paused();
+    let resumed = admin_api.handle(pool_request("resume")).await;
+
+    // then:
+    assert_eq!(paused.status(), StatusCode::OK);
+    assert!(is_paused);
+    assert_eq!(resumed.status(), StatusCode::OK);
+    assert!(!admin_api.config.load().shared_data.backend_pools[0].is_paused());
+  }
+
+  #[tokio::test]
+  async fn pool_operations_require_known_pool() {
+    // given:
+    let (admin_api, _) = admin_api("unknown-pool").await;
+
+    // when:
+    let response = admin_api
+      .handle(Request::post("/pools/unknown/pause").body(Body::empty()).unwrap())
+      .await;
+
+    // then:
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+  }
+}
@@ -0,0 +1,168 @@
+use hyper::http::uri::Authority;
+use std::{
+  fmt::{self, Display},
+  net::{IpAddr, SocketAddr},
+  str::FromStr,
+};
+
+/// The address of a backend server, an IP address or host name with an optional port. Addresses are parsed when the
+/// configuration is loaded, so invalid addresses are reported right away instead of failing requests or health checks.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BackendAddress {
+  /* IPv6 addresses are enclosed in brackets */
+  host: String,
+  port: Option<u16>,
+}
+
+impl BackendAddress {
+  pub fn authority(&self) -> Authority {
+    Authority::from_maybe_shared(self.to_string()).expect("BackendAddress is a valid authority")
+  }
+
+  /// Returns `true` if the host has to be resolved via DNS before connecting to it.
+  pub fn is_host_name(&self) -> bool {
+    !self.host.starts_with('[') && self.host.parse::<IpAddr>().is_err()
+  }
+
+  /// Returns the address of the same host with another `port`.
+  pub fn with_port(&self, port: u16) -> BackendAddress {
+    BackendAddress {
+      host: self.host.clone(),
+      port: Some(port),
+    }
+  }
+
+  /// Host and port to resolve, the port defaults to 80 like for all requests to the backend servers.
+  pub fn lookup_target(&self) -> (&str, u16) {
+    (&self.host, self.port.unwrap_or(80))
+  }
+}
+
+impl FromStr for BackendAddress {
+  type Err = String;
+
+  fn from_str(address: &str) -> Result<Self, Self::Err> {
+    // IP addresses are written in their canonical form, e.g. `[2001:DB8:0::1]:80` as `[2001:db8::1]:80`, so they
+    // compare equal to the addresses reported by the connections to the backend servers
+    if let Ok(socket_address) = address.parse::<SocketAddr>() {
+      return check_port(socket_address.port()).map(|port| BackendAddress {
+        host: host_of_ip(socket_address.ip()),
+        port: Some(port),
+      });
+    }
+    if let Ok(ip) = address.parse::<IpAddr>() {
+      return match ip {
+        IpAddr::V4(_) => Ok(BackendAddress {
+          host: host_of_ip(ip),
+          port: None,
+        }),
+        IpAddr::V6(_) => Err("IPv6 addresses must be enclosed in brackets, e.g. [::1]:8080".to_string()),
+      };
+    }
+    let (host, port) = match address.strip_prefix('[').and_then(|rest| rest.find(']')) {
+      Some(index) => address.split_at(index + 2),
+      None => address.split_at(address.find(':').unwrap_or(address.len())),
+    };
+    let port = match port {
+      "" => None,
+      port => {
+        let port = port.strip_prefix(':').unwrap_or(port);
+        let port = port
+          .parse::<u16>()
+          .map_err(|_| format!("has an invalid port '{}'", port))?;
+        Some(check_port(port)?)
+      }
+    };
+    let is_valid_host = !host.is_empty()
+      && !host.contains('@')
+      && host
+        .parse::<Authority>()
+        .map_or(false, |it| it.as_str() == host && it.port().is_none());
+    if !is_valid_host {
+      return Err("must be a host name or IP address with an optional port, e.g. 127.0.0.1:8080".to_string());
+    }
+    // host names are case insensitive, so `App1.internal` and `app1.internal` are the same backend server
+    let host = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+      Ok(ip) => host_of_ip(ip),
+      Err(_) => host.to_ascii_lowercase(),
+    };
+    Ok(BackendAddress { host, port })
+  }
+}
+
+fn check_port(port: u16) -> Result<u16, String> {
+  if port == 0 {
+    Err("port must not be 0".to_string())
+  } else {
+    Ok(port)
+  }
+}
+
+fn host_of_ip(ip: IpAddr) -> String {
+  match ip {
+    IpAddr::V4(ip) => ip.to_string(),
+    IpAddr::V6(ip) => format!("[{}]", ip),
+  }
+}
+
+impl Display for BackendAddress {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.port {
+      Some(port) => write!(f, "{}:{}", self.host, port),
+      None => write!(f, "{}", self.host),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(address: &str) -> Result<String, String> {
+    address.parse::<BackendAddress>().map(|it| it.to_string())
+  }
+
+  #[test]
+  fn writes_ip_addresses_canonically() {
+    assert_eq!(parse("[2001:DB8:0::1]:80"), Ok("[2001:db8::1]:80".to_string()));
+    assert_eq!(parse("[2001:DB8:0::1]"), Ok("[2001:db8::1]".to_string()));
+    assert_eq!(parse("127.0.0.1:8080"), Ok("127.0.0.1:8080".to_string()));
+    assert_eq!(parse("whoami:80"), Ok("whoami:80".to_string()));
+    assert_eq!(parse("whoami"), Ok("whoami".to_string()));
+  }
+
+  #[test]
+  fn writes_host_names_in_lower_case() {
+    assert_eq!(parse("App1.Internal:8080"), Ok("app1.internal:8080".to_string()));
+  }
+
+  #[test]
+  fn only_host_names_need_to_be_resolved() {
+    let host_name = "app1.internal".parse::<BackendAddress>().unwrap();
+
+    assert!(host_name.is_host_name());
+    assert_eq!(host_name.lookup_target(), ("app1.internal", 80));
+    assert!(!"127.0.0.1:8080".parse::<BackendAddress>().unwrap().is_host_name());
+    assert!(!"[::1]:8080".parse::<BackendAddress>().unwrap().is_host_name());
+  }
+
+  #[test]
+  fn rejects_invalid_addresses() {
+    assert!(parse("2001:db8::1:80").unwrap_err().contains("brackets"));
+    assert!(parse("user@whoami:80").is_err());
+    assert!(parse("whoami:0").is_err());
+    assert!(parse("whoami:").is_err());
+    assert!(parse("whoami:65536").is_err());
+    assert!(parse("http://whoami:80").is_err());
+    assert!(parse("whoami:80/path").is_err());
+    assert!(parse("").is_err());
+  }
+
+  #[test]
+  fn converts_to_authority() {
+    let address = "[::1]:8080".parse::<BackendAddress>().unwrap();
+
+    assert_eq!(address.authority().host(), "[::1]");
+    assert_eq!(address.authority().port_u16(), Some(8080));
+  }
+}
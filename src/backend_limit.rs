@@ -0,0 +1,228 @@
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+use tokio::{
+  sync::Notify,
+  time::{timeout_at, Instant},
+};
+
+/// Limits the number of connections each backend server of a [`BackendPool`](crate::server::BackendPool) handles
+/// concurrently. A connection is in use while a request is forwarded over it and its response body is streamed.
+/// Backend servers at their limit are excluded from the load balancing; if all of them are, requests wait up to
+/// `max_wait` for a connection to be released. With a queue, at most `max_waiting` requests wait at the same time.
+#[derive(Debug)]
+pub struct BackendLimit {
+  max_connections: usize,
+  max_wait: Duration,
+  max_waiting: Option<usize>,
+  waiting: AtomicUsize,
+  connections: Arc<Connections>,
+}
+
+/* Shared with the connections, which outlive the request handling while their response body is streamed */
+#[derive(Debug, Default)]
+struct Connections {
+  in_use: Mutex<HashMap<String, usize>>,
+  released: Notify,
+}
+
+/// A connection to a backend server, released once dropped.
+#[derive(Debug)]
+pub struct BackendConnection {
+  connections: Arc<Connections>,
+  address: String,
+}
+
+impl Drop for BackendConnection {
+  fn drop(&mut self) {
+    let mut in_use = self.connections.in_use.lock().unwrap();
+    if let Some(count) = in_use.get_mut(&self.address) {
+      *count -= 1;
+      if *count == 0 {
+        in_use.remove(&self.address);
+      }
+    }
+    drop(in_use);
+    self.connections.released.notify_one();
+  }
+}
+
+/// Decrements the number of waiting requests, even if the waiting request is cancelled.
+struct WaitingGuard<'l>(&'l AtomicUsize);
+
+impl Drop for WaitingGuard<'_> {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+impl BackendLimit {
+  pub fn new(max_connections: usize, max_wait: Duration) -> BackendLimit {
+    BackendLimit {
+      max_connections,
+      max_wait,
+      max_waiting: None,
+      waiting: AtomicUsize::new(0),
+      connections: Arc::new(Connections::default()),
+    }
+  }
+
+  /// Limits the number of requests waiting for a connection to `max_waiting`.
+  pub fn queue(max_connections: usize, max_waiting: usize, max_wait: Duration) -> BackendLimit {
+    BackendLimit {
+      max_waiting: Some(max_waiting),
+      ..BackendLimit::new(max_connections, max_wait)
+    }
+  }
+
+  pub fn max_wait(&self) -> Duration {
+    self.max_wait
+  }
+
+  /// Waits until at least one of the `addresses` is below its limit and returns all such addresses.
+  /// Returns `None` if none became available before the `deadline` or the queue is full.
+  pub async fn available<'a>(&self, addresses: &[&'a str], deadline: Instant) -> Option<Vec<&'a str>> {
+    let available = self.available_now(addresses);
+    if !available.is_empty() {
+      return Some(available);
+    }
+
+    let waiting = self.waiting.fetch_add(1, Ordering::SeqCst);
+    let _guard = WaitingGuard(&self.waiting);
+    if matches!(self.max_waiting, Some(max_waiting) if waiting >= max_waiting) {
+      return None;
+    }
+    loop {
+      timeout_at(deadline, self.connections.released.notified()).await.ok()?;
+      let available = self.available_now(addresses);
+      if !available.is_empty() {
+        return Some(available);
+      }
+    }
+  }
+
+  fn available_now<'a>(&self, addresses: &[&'a str]) -> Vec<&'a str> {
+    let in_use = self.connections.in_use.lock().unwrap();
+    addresses
+      .iter()
+      .filter(|address| in_use.get(**address).copied().unwrap_or(0) < self.max_connections)
+      .copied()
+      .collect()
+  }
+
+  /// Takes a connection to the backend server at `address`, unless it is at its limit.
+  pub fn try_acquire(&self, address: &str) -> Option<BackendConnection> {
+    let mut in_use = self.connections.in_use.lock().unwrap();
+    let count = in_use.entry(address.to_string()).or_insert(0);
+    if *count >= self.max_connections {
+      return None;
+    }
+    *count += 1;
+    Some(BackendConnection {
+      connections: self.connections.clone(),
+      address: address.to_string(),
+    })
+  }
+
+  /// Returns the number of connections in use per backend server.
+  pub fn connections_in_use(&self) -> HashMap<String, usize> {
+    self.connections.in_use.lock().unwrap().clone()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn capped_backend_is_not_available() {
+    // given:
+    let limit = BackendLimit::new(1, Duration::from_millis(10));
+    let _connection = limit.try_acquire("127.0.0.1:8080").unwrap();
+
+    // when:
+    let available = limit.available_now(&["127.0.0.1:8080", "127.0.0.1:8081"]);
+    let second = limit.try_acquire("127.0.0.1:8080");
+
+    // then:
+    assert_eq!(available, vec!["127.0.0.1:8081"]);
+    assert!(second.is_none());
+  }
+
+  #[test]
+  fn dropped_connection_is_released() {
+    // given:
+    let limit = BackendLimit::new(1, Duration::from_millis(10));
+    let connection = limit.try_acquire("127.0.0.1:8080").unwrap();
+
+    // when:
+    drop(connection);
+
+    // then:
+    assert!(limit.connections_in_use().is_empty());
+    assert!(limit.try_acquire("127.0.0.1:8080").is_some());
+  }
+
+  #[tokio::test]
+  async fn waits_until_connection_is_released() {
+    // given:
+    let limit = Arc::new(BackendLimit::new(1, Duration::from_secs(5)));
+    let waiting_limit = limit.clone();
+    let connection = limit.try_acquire("127.0.0.1:8080");
+    let waiting = tokio::spawn(async move {
+      let deadline = Instant::now() + waiting_limit.max_wait();
+      waiting_limit.available(&["127.0.0.1:8080"], deadline).await
+    });
+    tokio::task::yield_now().await;
+
+    // when:
+    drop(connection);
+
+    // then:
+    assert_eq!(waiting.await.unwrap(), Some(vec!["127.0.0.1:8080"]));
+  }
+
+  #[tokio::test]
+  async fn waiting_times_out() {
+    // given:
+    let limit = BackendLimit::new(1, Duration::from_millis(10));
+    let _connection = limit.try_acquire("127.0.0.1:8080").unwrap();
+
+    // when:
+    let available = limit
+      .available(&["127.0.0.1:8080"], Instant::now() + limit.max_wait())
+      .await;
+
+    // then:
+    assert!(available.is_none());
+  }
+
+  #[tokio::test]
+  async fn full_queue_rejects_waiting_request() {
+    // given:
+    let limit = Arc::new(BackendLimit::queue(1, 1, Duration::from_secs(5)));
+    let waiting_limit = limit.clone();
+    let connection = limit.try_acquire("127.0.0.1:8080");
+    let waiting = tokio::spawn(async move {
+      let deadline = Instant::now() + waiting_limit.max_wait();
+      waiting_limit.available(&["127.0.0.1:8080"], deadline).await
+    });
+    tokio::task::yield_now().await;
+
+    // when:
+    let overflowing = limit
+      .available(&["127.0.0.1:8080"], Instant::now() + limit.max_wait())
+      .await;
+    drop(connection);
+
+    // then:
+    assert!(overflowing.is_none());
+    assert_eq!(waiting.await.unwrap(), Some(vec!["127.0.0.1:8080"]));
+    assert_eq!(limit.waiting.load(Ordering::SeqCst), 0);
+  }
+}
@@ -1,11 +1,12 @@
-use std::{collections::HashMap, iter::FromIterator, ops::Deref, str::FromStr};
+use std::{collections::HashMap, error::Error, fmt::Display, iter::FromIterator, ops::Deref, str::FromStr};
 
+use crate::{tls_fingerprint::TlsFingerprint, utils::find_cookie};
 use hyper::{header::HOST, Body, Method, Request};
 use pom::parser::*;
 use regex::Regex;
 
 /// A newtype for Regex, which makes it comparable by its string value
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ComparableRegex(Regex);
 
 /// neat trick for making all functions of the internal type available
@@ -29,7 +30,7 @@ impl ComparableRegex {
   }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum BackendPoolMatcher {
   Host(String),
   HostRegexp(ComparableRegex),
@@ -37,18 +38,91 @@ pub enum BackendPoolMatcher {
   Path(String),
   PathRegexp(ComparableRegex),
   Query(String, String),
+  Cookie(String, String),
+  /// The JA3 fingerprint of the client's TLS connection, never matches plain HTTP requests
+  Ja3(String),
   And(Box<BackendPoolMatcher>, Box<BackendPoolMatcher>),
   Or(Box<BackendPoolMatcher>, Box<BackendPoolMatcher>),
 }
 
-impl From<String> for BackendPoolMatcher {
-  fn from(str: String) -> Self {
+impl FromStr for BackendPoolMatcher {
+  type Err = MatcherParseError;
+
+  fn from_str(str: &str) -> Result<Self, Self::Err> {
     let chars: Vec<char> = str.chars().collect();
-    let result = parser().parse(&chars).unwrap();
+    let result = parser()
+      .parse(&chars)
+      .map_err(|error| MatcherParseError::new(str, error));
     result
   }
 }
 
+/// The error returned when parsing an invalid matcher, pointing at the position where parsing failed.
+#[derive(Debug, PartialEq)]
+pub struct MatcherParseError {
+  matcher: String,
+  position: Option<usize>,
+  message: String,
+}
+
+impl MatcherParseError {
+  fn new(matcher: &str, error: pom::Error) -> MatcherParseError {
+    let (position, message) = match error {
+      pom::Error::Incomplete => (None, "unexpected end of input".to_string()),
+      pom::Error::Mismatch { message, position }
+      | pom::Error::Conversion { message, position }
+      | pom::Error::Expect { message, position, .. }
+      | pom::Error::Custom { message, position, .. } => (Some(position), message),
+    };
+    MatcherParseError {
+      matcher: matcher.to_string(),
+      position,
+      message,
+    }
+  }
+}
+
+impl Display for MatcherParseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.position {
+      Some(position) => write!(
+        f,
+        "{} at position {}\n  {}\n  {:>width$}",
+        self.message,
+        position,
+        self.matcher,
+        "^",
+        width = position + 1
+      ),
+      None => write!(f, "{}\n  {}", self.message, self.matcher),
+    }
+  }
+}
+
+impl Error for MatcherParseError {}
+
+/// The compiled matchers of a configuration keyed by their source, so matchers which did not change are not parsed
+/// again when the configuration is reloaded.
+#[derive(Debug, Default)]
+pub struct MatcherCache(HashMap<String, BackendPoolMatcher>);
+
+impl MatcherCache {
+  /// Returns the matcher for `source` from the `previous` cache or parses it if it is new. Either way the matcher is
+  /// added to this cache.
+  pub fn get_or_parse(
+    &mut self,
+    source: &str,
+    previous: &MatcherCache,
+  ) -> Result<BackendPoolMatcher, MatcherParseError> {
+    let matcher = match previous.0.get(source) {
+      Some(matcher) => matcher.clone(),
+      None => source.parse()?,
+    };
+    self.0.insert(source.to_string(), matcher.clone());
+    Ok(matcher)
+  }
+}
+
 impl BackendPoolMatcher {
   /// Returns true if the BackendPoolMatcher is statisfied by the given request
   ///
@@ -59,7 +133,9 @@ impl BackendPoolMatcher {
   /// # Examples
   ///
   /// ```
-  /// let request = Request::builder().uri("https://google.de").body(Body::empty());
+  /// # use another_rust_load_balancer::backend_pool_matcher::BackendPoolMatcher;
+  /// # use hyper::{Body, Request};
+  /// let request = Request::builder().header("host", "google.de").body(Body::empty()).unwrap();
   /// let matcher = BackendPoolMatcher::Host("google.de".into());
   ///
   /// assert_eq!(matcher.matches(&request), true);
@@ -87,23 +163,101 @@ impl BackendPoolMatcher {
           .map(|sent_value| sent_value == value)
           .unwrap_or(false)
       }
+      BackendPoolMatcher::Cookie(name, value) => find_cookie(request.headers(), name)
+        .map(|cookie| cookie.value() == value)
+        .unwrap_or(false),
+      BackendPoolMatcher::Ja3(fingerprint) => request
+        .extensions()
+        .get::<TlsFingerprint>()
+        .map(|it| it.as_str().eq_ignore_ascii_case(fingerprint))
+        .unwrap_or(false),
       BackendPoolMatcher::And(left, right) => left.matches(request) && right.matches(request),
       BackendPoolMatcher::Or(left, right) => left.matches(request) || right.matches(request),
     }
   }
+
+  /// Same as [`matches`](BackendPoolMatcher::matches), but also writes the result of every node of the expression tree
+  /// to `trace`, one line per node. Both sides of `&&` and `||` are always evaluated to make the trace complete.
+  pub fn trace(&self, request: &Request<Body>, trace: &mut String) -> bool {
+    self.trace_node(request, 0, trace)
+  }
+
+  fn trace_node(&self, request: &Request<Body>, depth: usize, trace: &mut String) -> bool {
+    let line = trace.len();
+    let (label, result) = match self {
+      BackendPoolMatcher::And(left, right) => {
+        trace.push('\n');
+        let left = left.trace_node(request, depth + 1, trace);
+        let right = right.trace_node(request, depth + 1, trace);
+        ("&&".to_string(), left && right)
+      }
+      BackendPoolMatcher::Or(left, right) => {
+        trace.push('\n');
+        let left = left.trace_node(request, depth + 1, trace);
+        let right = right.trace_node(request, depth + 1, trace);
+        ("||".to_string(), left || right)
+      }
+      _ => (format!("{:?}", self), self.matches(request)),
+    };
+    let result_label = if result { "match" } else { "no match" };
+    let node = format!("{}{} => {}", "  ".repeat(depth), label, result_label);
+    if line == trace.len() {
+      trace.push_str(&node);
+      trace.push('\n');
+    } else {
+      // the children were already written, so insert the parent above them
+      trace.insert_str(line, &node);
+    }
+    result
+  }
+
+  /// Returns true if every request satisfying `other` provably satisfies this matcher as well, e.g. `Host('a')` covers
+  /// `Host('a') && Path('/admin')`. If that can not be decided, e.g. for two different regular expressions, the result
+  /// is false, so a backend pool is never reported as unreachable by mistake.
+  pub fn covers(&self, other: &BackendPoolMatcher) -> bool {
+    if self == other {
+      return true;
+    }
+    match (self, other) {
+      (BackendPoolMatcher::And(left, right), _) => left.covers(other) && right.covers(other),
+      (_, BackendPoolMatcher::Or(left, right)) => self.covers(left) && self.covers(right),
+      (BackendPoolMatcher::Or(left, right), _) if left.covers(other) || right.covers(other) => true,
+      (_, BackendPoolMatcher::And(left, right)) => self.covers(left) || self.covers(right),
+      (BackendPoolMatcher::HostRegexp(regex), BackendPoolMatcher::Host(host)) => regex.is_match(host),
+      (BackendPoolMatcher::PathRegexp(regex), BackendPoolMatcher::Path(path)) => regex.is_match(path),
+      _ => false,
+    }
+  }
+
+  /// Returns the hosts of which a request must have one to satisfy this matcher, or `None` if requests for any host
+  /// may satisfy it, e.g. `Host('a') || Path('/admin')`. Regular expressions are not resolved to hosts.
+  pub fn required_hosts(&self) -> Option<Vec<&str>> {
+    match self {
+      BackendPoolMatcher::Host(host) => Some(vec![host]),
+      BackendPoolMatcher::And(left, right) => left.required_hosts().or_else(|| right.required_hosts()),
+      BackendPoolMatcher::Or(left, right) => {
+        let mut hosts = left.required_hosts()?;
+        hosts.extend(right.required_hosts()?);
+        Some(hosts)
+      }
+      _ => None,
+    }
+  }
 }
 
 /// A PEG parser for generating BackendPoolMatcher rules
 ///
 /// # Examples:
 ///
-/// ```
+/// ```text
 /// "Host('google.de')"
 /// "HostRegexp('^(www\.)?google.de$')"
 /// "Host('google.de') && Path('/admin')"
 /// "Host('google.de') || Path('/admin')"
 /// "Host('google.de') && Query('admin', 'true')"
 /// "Host('google.de') && Method('GET')"
+/// "Host('google.de') && Cookie('ab_variant', 'b')"
+/// "Ja3('e7d705a3286e19ea42f587b344ee6865')"
 /// "Host('google.de') && ( Path('/admin') || Path('/moderator') )"
 /// ```
 fn parser<'a>() -> Parser<'a, char, BackendPoolMatcher> {
@@ -145,6 +299,14 @@ fn query<'a>() -> Parser<'a, char, (String, String)> {
   tag("Query(") * string() - space() - sym(',') - space() + string() - sym(')')
 }
 
+fn cookie<'a>() -> Parser<'a, char, (String, String)> {
+  tag("Cookie(") * string() - space() - sym(',') - space() + string() - sym(')')
+}
+
+fn ja3<'a>() -> Parser<'a, char, String> {
+  tag("Ja3(") * string() - sym(')')
+}
+
 fn and<'a>() -> Parser<'a, char, (BackendPoolMatcher, BackendPoolMatcher)> {
   call(value) - space() - tag("&&") - space() + call(value)
 }
@@ -164,6 +326,8 @@ fn value<'a>() -> Parser<'a, char, BackendPoolMatcher> {
     | path().map(BackendPoolMatcher::Path)
     | path_regexp().map(BackendPoolMatcher::PathRegexp)
     | query().map(|(key, value)| BackendPoolMatcher::Query(key, value))
+    | cookie().map(|(name, value)| BackendPoolMatcher::Cookie(name, value))
+    | ja3().map(BackendPoolMatcher::Ja3)
     | (sym('(') * space() * (chained_expression() | call(value)) - space() - sym(')'))
 }
 
@@ -179,11 +343,34 @@ fn top_level_expression<'a>() -> Parser<'a, char, BackendPoolMatcher> {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use hyper::header::COOKIE;
 
   fn to_char_vec(str: &'static str) -> Vec<char> {
     str.to_string().chars().collect()
   }
 
+  #[test]
+  fn trace_nested_expression() {
+    let matcher: BackendPoolMatcher = "Host('whoami.localhost') && (Path('/admin') || Method('GET'))"
+      .parse()
+      .unwrap();
+    let request = Request::builder()
+      .header(HOST, "whoami.localhost")
+      .uri("/")
+      .body(Body::empty())
+      .unwrap();
+    let mut trace = String::new();
+
+    let result = matcher.trace(&request, &mut trace);
+
+    assert_eq!(result, true);
+    assert_eq!(
+      trace,
+      "&& => match\n  Host(\"whoami.localhost\") => match\n  || => match\n    Path(\"/admin\") => no match\n    \
+       Method(GET) => match\n"
+    );
+  }
+
   #[test]
   fn parse_host() {
     let input = to_char_vec("Host('whatisup.localhost')");
@@ -355,6 +542,59 @@ mod tests {
     assert_eq!(matcher.matches(&request_2), false);
   }
 
+  #[test]
+  fn parse_cookie() {
+    let input = to_char_vec("Cookie('ab_variant', 'b')");
+
+    assert_eq!(
+      parser().parse(&input),
+      Ok(BackendPoolMatcher::Cookie("ab_variant".into(), "b".into()))
+    );
+  }
+
+  #[test]
+  fn matches_cookie() {
+    let request_1 = Request::builder()
+      .header(COOKIE, "session=123; ab_variant=b")
+      .body(Body::empty())
+      .unwrap();
+    let request_2 = Request::builder()
+      .header(COOKIE, "ab_variant=a")
+      .body(Body::empty())
+      .unwrap();
+    let request_3 = Request::builder().body(Body::empty()).unwrap();
+
+    let matcher = BackendPoolMatcher::Cookie("ab_variant".into(), "b".into());
+
+    assert_eq!(matcher.matches(&request_1), true);
+    assert_eq!(matcher.matches(&request_2), false);
+    assert_eq!(matcher.matches(&request_3), false);
+  }
+
+  #[test]
+  fn parse_ja3() {
+    let input = to_char_vec("Ja3('e7d705a3286e19ea42f587b344ee6865')");
+
+    assert_eq!(
+      parser().parse(&input),
+      Ok(BackendPoolMatcher::Ja3("e7d705a3286e19ea42f587b344ee6865".into()))
+    );
+  }
+
+  #[test]
+  fn matches_ja3() {
+    let mut request_1 = Request::builder().body(Body::empty()).unwrap();
+    request_1
+      .extensions_mut()
+      .insert(TlsFingerprint("e7d705a3286e19ea42f587b344ee6865".into()));
+    let request_2 = Request::builder().body(Body::empty()).unwrap();
+
+    let matcher = BackendPoolMatcher::Ja3("E7D705A3286E19EA42F587B344EE6865".into());
+
+    assert_eq!(matcher.matches(&request_1), true);
+    assert_eq!(matcher.matches(&request_2), false);
+  }
+
   #[test]
   fn matches_and() {
     let request_1 = Request::builder()
@@ -398,4 +638,95 @@ mod tests {
     assert_eq!(matcher.matches(&request_1), true);
     assert_eq!(matcher.matches(&request_2), true);
   }
+
+  #[test]
+  fn parse_error_position() {
+    let error = "Host('whoami.localhost') && Path('/admin'"
+      .parse::<BackendPoolMatcher>()
+      .unwrap_err();
+
+    assert_eq!(error.position, Some(24));
+    assert_eq!(
+      error.to_string(),
+      format!(
+        "{} at position 24\n  Host('whoami.localhost') && Path('/admin'\n  {}^",
+        error.message,
+        " ".repeat(24)
+      )
+    );
+  }
+
+  #[test]
+  fn matcher_cache_reuses_previous_matcher() {
+    let mut previous = MatcherCache::default();
+    // a different matcher than the source to prove the source is not parsed again
+    previous
+      .0
+      .insert("Host('whoami.localhost')".into(), BackendPoolMatcher::Path("/".into()));
+    let mut cache = MatcherCache::default();
+
+    let cached = cache.get_or_parse("Host('whoami.localhost')", &previous);
+    let parsed = cache.get_or_parse("Path('/admin')", &previous);
+
+    assert_eq!(cached, Ok(BackendPoolMatcher::Path("/".into())));
+    assert_eq!(parsed, Ok(BackendPoolMatcher::Path("/admin".into())));
+    assert_eq!(cache.0.len(), 2);
+  }
+
+  fn covers(matcher: &str, other: &str) -> bool {
+    let matcher = matcher.parse::<BackendPoolMatcher>().unwrap();
+    matcher.covers(&other.parse().unwrap())
+  }
+
+  #[test]
+  fn covers_identical_and_narrower_matchers() {
+    assert!(covers("Host('whoami.localhost')", "(Host('whoami.localhost'))"));
+    assert!(covers(
+      "Host('whoami.localhost')",
+      "Host('whoami.localhost') && Path('/admin')"
+    ));
+    assert!(covers(
+      "Host('a.localhost') || Host('b.localhost')",
+      "Host('b.localhost')"
+    ));
+    assert!(covers(
+      "Host('a.localhost') || Host('b.localhost')",
+      "Host('b.localhost') || Host('a.localhost')"
+    ));
+    assert!(covers("HostRegexp('localhost$')", "Host('whoami.localhost')"));
+    assert!(covers("PathRegexp('^/api/')", "Path('/api/users') && Method('GET')"));
+  }
+
+  #[test]
+  fn does_not_cover_wider_or_unrelated_matchers() {
+    assert!(!covers(
+      "Host('whoami.localhost') && Path('/admin')",
+      "Host('whoami.localhost')"
+    ));
+    assert!(!covers(
+      "Host('a.localhost')",
+      "Host('a.localhost') || Host('b.localhost')"
+    ));
+    assert!(!covers("Host('a.localhost')", "Host('b.localhost')"));
+    assert!(!covers("HostRegexp('^a')", "HostRegexp('^ab')"));
+  }
+
+  #[test]
+  fn required_hosts_of_matchers() {
+    let required_hosts = |matcher: &str| {
+      let matcher: BackendPoolMatcher = matcher.parse().unwrap();
+      matcher
+        .required_hosts()
+        .map(|hosts| hosts.into_iter().map(String::from).collect::<Vec<_>>())
+    };
+
+    assert_eq!(required_hosts("Host('a') && Path('/admin')"), Some(vec!["a".into()]));
+    assert_eq!(required_hosts("Path('/admin') && Host('a')"), Some(vec!["a".into()]));
+    assert_eq!(
+      required_hosts("Host('a') || (Host('b') && Method('GET'))"),
+      Some(vec!["a".into(), "b".into()])
+    );
+    assert_eq!(required_hosts("Host('a') || Path('/admin')"), None);
+    assert_eq!(required_hosts("HostRegexp('^a')"), None);
+  }
 }
@@ -0,0 +1,147 @@
+use linked_hash_map::LinkedHashMap;
+use log::info;
+use std::{net::IpAddr, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+/// Temporarily bans clients after `after` violations within `duration`, e.g. exceeding a rate limit. Banned clients
+/// are rejected for `duration`.
+#[derive(Debug)]
+pub struct Bans {
+  after: u64,
+  duration: Duration,
+  max_clients: usize,
+  /// The clients are kept in the order of `since`, so expired entries can be evicted from the front.
+  clients: Mutex<LinkedHashMap<IpAddr, BanState>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BanState {
+  violations: u64,
+  since: Instant,
+  banned: bool,
+}
+
+impl Bans {
+  pub fn new(after: u64, duration: Duration, max_clients: usize) -> Bans {
+    Bans {
+      after: after.max(1),
+      duration,
+      max_clients: max_clients.max(1),
+      clients: Mutex::new(LinkedHashMap::new()),
+    }
+  }
+
+  fn evict_expired(&self, clients: &mut LinkedHashMap<IpAddr, BanState>, now: Instant) {
+    while let Some((_, state)) = clients.front() {
+      if now.saturating_duration_since(state.since) < self.duration {
+        break;
+      }
+      clients.pop_front();
+    }
+  }
+
+  /// Returns how long the client is still banned.
+  pub fn remaining_ban(&self, client_ip: &IpAddr, now: Instant) -> Option<Duration> {
+    let mut clients = self.clients.lock().unwrap();
+    self.evict_expired(&mut clients, now);
+    clients
+      .get(client_ip)
+      .filter(|state| state.banned)
+      .map(|state| self.duration.saturating_sub(now.saturating_duration_since(state.since)))
+  }
+
+  /// Records a violation and returns the duration of the ban, if the client is banned because of it.
+  pub fn record_violation(&self, client_ip: IpAddr, now: Instant) -> Option<Duration> {
+    let mut clients = self.clients.lock().unwrap();
+    self.evict_expired(&mut clients, now);
+
+    let violations = match clients.get_mut(&client_ip) {
+      Some(state) => {
+        state.violations += 1;
+        state.violations
+      }
+      None => {
+        if clients.len() >= self.max_clients {
+          clients.pop_front();
+        }
+        let state = BanState {
+          violations: 1,
+          since: now,
+          banned: false,
+        };
+        clients.insert(client_ip, state);
+        1
+      }
+    };
+    if violations < self.after {
+      return None;
+    }
+
+    info!("Banning client {} for {}s", client_ip, self.duration.as_secs());
+    // reinsert at the end to keep the order of `since`
+    clients.remove(&client_ip);
+    let state = BanState {
+      violations,
+      since: now,
+      banned: true,
+    };
+    clients.insert(client_ip, state);
+    Some(self.duration)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_bans_after_repeated_violations() {
+    // given:
+    let bans = Bans::new(2, Duration::from_secs(60), 10);
+    let client = "127.0.0.1".parse().unwrap();
+    let now = Instant::now();
+
+    // when:
+    let first = bans.record_violation(client, now);
+    let second = bans.record_violation(client, now + Duration::from_secs(10));
+
+    // then:
+    assert_eq!(first, None);
+    assert_eq!(second, Some(Duration::from_secs(60)));
+    assert_eq!(
+      bans.remaining_ban(&client, now + Duration::from_secs(30)),
+      Some(Duration::from_secs(40))
+    );
+    assert_eq!(bans.remaining_ban(&client, now + Duration::from_secs(70)), None);
+  }
+
+  #[test]
+  fn test_violations_expire() {
+    // given:
+    let bans = Bans::new(2, Duration::from_secs(60), 10);
+    let client = "127.0.0.1".parse().unwrap();
+    let now = Instant::now();
+    bans.record_violation(client, now);
+
+    // when:
+    let actual = bans.record_violation(client, now + Duration::from_secs(60));
+
+    // then:
+    assert_eq!(actual, None);
+  }
+
+  #[test]
+  fn test_remaining_ban_tolerates_ban_started_later() {
+    // given:
+    let bans = Bans::new(1, Duration::from_secs(60), 10);
+    let client = "127.0.0.1".parse().unwrap();
+    let now = Instant::now() + Duration::from_secs(1);
+    bans.record_violation(client, now);
+
+    // when:
+    let actual = bans.remaining_ban(&client, now - Duration::from_secs(1));
+
+    // then:
+    assert_eq!(actual, Some(Duration::from_secs(60)));
+  }
+}
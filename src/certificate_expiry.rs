@@ -0,0 +1,196 @@
+use crate::tls::Certificates;
+use arc_swap::{access::Access, ArcSwap};
+use log::{debug, error, warn};
+use openssl::{asn1::Asn1Time, x509::X509};
+use std::{
+  collections::{BTreeMap, HashMap},
+  io::{self, ErrorKind::InvalidData},
+  ops::Deref,
+  time::Duration,
+};
+use tokio_rustls::{rustls::sign::CertifiedKey, webpki::DNSName};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertificateExpiryConfig {
+  /* Warn about certificates expiring within this duration */
+  pub warn_before: Duration,
+  pub check_every: Duration,
+  /* Refuse to load local certificates which already expired */
+  pub refuse_expired: bool,
+}
+
+impl Default for CertificateExpiryConfig {
+  fn default() -> Self {
+    CertificateExpiryConfig {
+      warn_before: Duration::from_secs(14 * 24 * 60 * 60),
+      check_every: Duration::from_secs(60 * 60),
+      refuse_expired: false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Validity {
+  pub not_after: String,
+  /* Negative if the certificate already expired */
+  pub seconds_left: i64,
+}
+
+impl Validity {
+  pub fn of(certified_key: &CertifiedKey) -> io::Result<Validity> {
+    let end_entity = certified_key
+      .end_entity_cert()
+      .map_err(|_| io::Error::new(InvalidData, "Missing end entity certificate"))?;
+    Validity::of_der(&end_entity.0)
+  }
+
+  fn of_der(der: &[u8]) -> io::Result<Validity> {
+    let certificate = X509::from_der(der).map_err(|e| io::Error::new(InvalidData, e))?;
+    let now = Asn1Time::days_from_now(0).map_err(|e| io::Error::new(InvalidData, e))?;
+    let diff = now
+      .diff(certificate.not_after())
+      .map_err(|e| io::Error::new(InvalidData, e))?;
+    Ok(Validity {
+      not_after: certificate.not_after().to_string(),
+      seconds_left: i64::from(diff.days) * SECONDS_PER_DAY + i64::from(diff.secs),
+    })
+  }
+
+  pub fn is_expired(&self) -> bool {
+    self.seconds_left <= 0
+  }
+
+  pub fn days_left(&self) -> i64 {
+    self.seconds_left / SECONDS_PER_DAY
+  }
+
+  fn expires_within(&self, duration: Duration) -> bool {
+    self.seconds_left < duration.as_secs() as i64
+  }
+}
+
+pub async fn watch_certificate_expiry<A, G, C, J>(certificates: A, config: C)
+where
+  A: Access<Certificates, Guard = G>,
+  G: Deref<Target = Certificates>,
+  C: Access<CertificateExpiryConfig, Guard = J>,
+  J: Deref<Target = CertificateExpiryConfig>,
+{
+  loop {
+    let config = config.load().deref().clone();
+    check_certificates_once(&ArcSwap::load(&*certificates.load()), &config);
+    tokio::time::sleep(config.check_every).await;
+  }
+}
+
+/// Returns the validity of the certificate of each server name, `None` if the certificate can not be read.
+pub fn validities(certificates: &HashMap<DNSName, CertifiedKey>) -> BTreeMap<String, Option<Validity>> {
+  certificates
+    .iter()
+    .map(|(name, certified_key)| {
+      let name: &str = name.as_ref().into();
+      (name.to_string(), Validity::of(certified_key).ok())
+    })
+    .collect()
+}
+
+fn check_certificates_once(certificates: &HashMap<DNSName, CertifiedKey>, config: &CertificateExpiryConfig) {
+  for (name, certified_key) in certificates {
+    let name: &str = name.as_ref().into();
+    match Validity::of(certified_key) {
+      Ok(validity) if validity.is_expired() => {
+        error!("Certificate for '{}' expired on {}", name, validity.not_after)
+      }
+      Ok(validity) if validity.expires_within(config.warn_before) => warn!(
+        "Certificate for '{}' expires in {} days on {}",
+        name,
+        validity.days_left(),
+        validity.not_after
+      ),
+      Ok(validity) => debug!(
+        "Certificate for '{}' expires in {} days on {}",
+        name,
+        validity.days_left(),
+        validity.not_after
+      ),
+      Err(e) => warn!(
+        "Could not check the expiry of the certificate for '{}' due to: {}",
+        name, e
+      ),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use openssl::{
+    asn1::Asn1Integer,
+    bn::BigNum,
+    hash::MessageDigest,
+    pkey::PKey,
+    rsa::Rsa,
+    x509::{X509Builder, X509NameBuilder},
+  };
+
+  fn certificate_der(not_before: &Asn1Time, not_after: &Asn1Time) -> Vec<u8> {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "whoami.localhost").unwrap();
+    let name = name.build();
+
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    let serial = Asn1Integer::from_bn(&BigNum::from_u32(1).unwrap()).unwrap();
+    builder.set_serial_number(&serial).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(not_before).unwrap();
+    builder.set_not_after(not_after).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    builder.build().to_der().unwrap()
+  }
+
+  #[test]
+  fn validity_of_valid_certificate() {
+    // given:
+    let der = certificate_der(
+      &Asn1Time::days_from_now(0).unwrap(),
+      &Asn1Time::days_from_now(30).unwrap(),
+    );
+
+    // when:
+    let validity = Validity::of_der(&der).unwrap();
+
+    // then:
+    assert!(!validity.is_expired());
+    assert!(validity.days_left() >= 29 && validity.days_left() <= 30);
+    assert!(validity.expires_within(Duration::from_secs(31 * 24 * 60 * 60)));
+    assert!(!validity.expires_within(Duration::from_secs(14 * 24 * 60 * 60)));
+  }
+
+  #[test]
+  fn validity_of_expired_certificate() {
+    // given:
+    let der = certificate_der(
+      &Asn1Time::from_unix(0).unwrap(),
+      &Asn1Time::from_unix(24 * 60 * 60).unwrap(),
+    );
+
+    // when:
+    let validity = Validity::of_der(&der).unwrap();
+
+    // then:
+    assert!(validity.is_expired());
+    assert!(validity.days_left() < 0);
+    assert_eq!(validity.not_after, "Jan  2 00:00:00 1970 GMT");
+  }
+
+  #[test]
+  fn validity_of_invalid_certificate() {
+    assert!(Validity::of_der(b"not a certificate").is_err());
+  }
+}
@@ -1,92 +1,325 @@
 use crate::{
-  acme::AcmeHandler,
-  health::{HealthConfig, Healthiness},
+  acme::{AcmeAccount, AcmeHandler, LETS_ENCRYPT_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY},
+  acme_account::ExternalAccountBinding,
+  admin::{AdminRole, AdminToken},
+  backend_address::BackendAddress,
+  backend_limit::BackendLimit,
+  backend_pool_matcher::{BackendPoolMatcher, MatcherCache},
+  certificate_expiry::{CertificateExpiryConfig, Validity},
+  connection_limit::{ConnectionLimit, ConnectionLimitSettings},
+  cooldown::Cooldown,
+  debug_headers::{self, DebugHeaders},
+  error_response::{self, ErrorPages},
+  fallback::{ErrorBudget, Fallback},
+  health::{self, BackendHealth, HealthConfig, HealthInterval, Healthiness, WarmUpConfig},
+  health_coordination::{self, HealthCoordination},
+  health_endpoint::HealthEndpointConfig,
+  health_events::{HealthEventSink, HealthEvents},
+  hedging::Hedging,
+  internal_endpoints::{self, InternalEndpoints},
+  listeners::BindRetry,
   load_balancing::{
-    ip_hash::IPHash, least_connection::LeastConnection, random::Random, round_robin::RoundRobin,
-    sticky_cookie::StickyCookie, LoadBalancingStrategy,
+    fallback::WithFallback,
+    ip_hash::IPHash,
+    least_bandwidth::{self, LeastBandwidth},
+    least_connection::LeastConnection,
+    random::Random,
+    round_robin::RoundRobin,
+    sticky_cookie::{validate_cookie_prefix, AffinityFailover, StickyCookie},
+    sticky_ip::{self, StickyIP},
+    LoadBalancingStrategy, SlowWeights,
   },
+  log_redaction::{self, LogRedaction},
   middleware::{
-    authentication::Authentication, compression::Compression, custom_error_pages::CustomErrorPages,
-    https_redirector::HttpsRedirector, maxbodysize::MaxBodySize, rate_limiter::RateLimiter, Middleware,
-    MiddlewareChain,
+    ab_test::ABTest, allowed_methods::AllowedMethods, authentication::Authentication, body_checksum::BodyChecksum,
+    canonical_host::CanonicalHost, compression::Compression, conditional_requests::ConditionalRequests,
+    custom_error_pages::CustomErrorPages, host_rewriter::HostRewriter, html_injection::HtmlInjection,
+    https_redirector::HttpsRedirector, maxbodysize::MaxBodySize, range_requests::RangeRequests,
+    rate_limiter::RateLimiter, response_digest::ResponseDigest, Middleware, MiddlewareChain,
   },
+  no_healthy_backends::{NoHealthyBackends, NoHealthyBackendsResponse},
+  pool_index::PoolIndex,
+  pool_status::PoolStatus,
   server::{BackendPool, BackendPoolBuilder, Scheme, SharedData},
-  tls::{certified_key_from_acme_certificate, load_certified_key},
+  service::{Service, WeightedPool},
+  shadow_strategy::ShadowStrategy,
+  tls::{
+    certificate_dns_names, certified_key_from_acme_certificate, load_certified_key, Certificates, TlsSessionSettings,
+    MIN_TICKET_SECRET_LEN,
+  },
 };
 use arc_swap::ArcSwap;
-use log::{info, trace, warn};
+use futures::{future, stream, StreamExt};
+use hyper::{
+  header::{HeaderName, HeaderValue},
+  StatusCode, Uri,
+};
+use log::{debug, info, trace, warn};
 use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{
+  de::{MapAccess, Visitor},
+  Deserialize, Deserializer,
+};
 use std::{
   collections::{HashMap, HashSet},
   convert::{TryFrom, TryInto},
   error::Error,
-  fmt::Debug,
-  fs, io,
-  net::SocketAddr,
+  fmt::{self, Debug},
+  fs, io, iter,
+  net::{IpAddr, SocketAddr},
   ops::Deref,
-  path::Path,
+  path::{Path, PathBuf},
   sync::{mpsc::channel, Arc},
   thread::spawn,
   time::Duration,
 };
-use tokio::sync::watch;
+use tokio::{
+  select,
+  sync::{watch, Notify},
+};
 use tokio_rustls::{
   rustls::sign::CertifiedKey,
   webpki::{DNSName, DNSNameRef},
 };
 use toml::{value::Table, Value};
 
-pub async fn read_initial_config<P: AsRef<Path>>(path: P) -> Result<Arc<ArcSwap<RuntimeConfig>>, io::Error> {
+pub mod schema;
+
+/// Reads the configuration at `path`. Relative paths in the configuration are resolved against `config_dir`, if given,
+/// instead of the directory of the configuration file.
+pub async fn read_initial_config<P: AsRef<Path>>(
+  path: P,
+  config_dir: Option<PathBuf>,
+) -> Result<Arc<ArcSwap<RuntimeConfig>>, io::Error> {
   let acme_handler = Arc::new(AcmeHandler::new());
-  // Don't initialize ACME certificates on startup, because the HTTP listener is not running yet
-  let init_acme = false;
-  let config = read_runtime_config(&path, acme_handler, init_acme)
+  // ACME certificates are issued once the HTTP listener is running, see `issue_acme_certificates`
+  let config = read_runtime_config(&path, config_dir, acme_handler, &MatcherCache::default())
     .await
     .map_err(|e| io::Error::new(e.kind(), format!("Could not load configuration due to: {}", e)))?;
   Ok(Arc::new(ArcSwap::from_pointee(config)))
 }
 
-pub async fn watch_config<P>(path: P, config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error>
+/// Reloads the configuration whenever the file is modified or `reload_requests` is notified.
+///
+/// The parent directory is watched instead of the file itself, so files replaced via a symlink swap are noticed too,
+/// e.g. Kubernetes ConfigMaps. The file is only reloaded if its content changed.
+pub async fn watch_config<P>(
+  path: P,
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  reload_requests: Arc<Notify>,
+) -> Result<(), io::Error>
 where
   P: AsRef<Path> + Send + 'static,
 {
-  let mut receiver = start_config_watcher(path);
+  let config_path = path.as_ref().to_path_buf();
+  let mut receiver = start_config_watcher(watched_directory(&config_path));
+  let mut loaded_content = None;
   loop {
-    let old_config = config.load();
-    let acme_handler = old_config.shared_data.acme_handler.clone();
-    match receiver.borrow().deref() {
-      DebouncedEvent::Write(path) => match read_runtime_config(&path, acme_handler, true).await {
-        Ok(new_config) => {
-          warn_about_ineffectual_config_changes(&old_config, &new_config);
-          config.store(Arc::new(new_config));
-          info!("Reloaded configuration");
+    let may_be_modified = match receiver.borrow().deref() {
+      DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => false,
+      DebouncedEvent::Error(e, _) => {
+        warn!("Error while watching '{}': {}", config_path.display(), e);
+        false
+      }
+      e => {
+        trace!("{:?}", e);
+        true
+      }
+    };
+    if may_be_modified {
+      reload_config_if_modified(&config_path, &config, &mut loaded_content).await;
+    }
+    loop {
+      select! {
+        changed = receiver.changed() => {
+          changed.map_err(broken_pipe)?;
+          break;
         }
-        Err(e) => {
-          warn!("Could not reload configuration due to: {}", e);
-          warn!("Keeping old configuration")
+        _ = reload_requests.notified() => {
+          info!("Reloading configuration on request");
+          let _ = reload_config(&config_path, &config).await;
         }
-      },
-      DebouncedEvent::Remove(path) => warn!("'{}' was deleted", path.display()),
-      e => trace!("{:?}", e),
+      }
+    }
+  }
+}
+
+fn watched_directory(config_path: &Path) -> PathBuf {
+  match config_path.parent() {
+    Some(parent) if parent.as_os_str().is_empty() => PathBuf::from("."),
+    Some(parent) => parent.to_path_buf(),
+    None => config_path.to_path_buf(),
+  }
+}
+
+async fn reload_config_if_modified(
+  path: &Path,
+  config: &Arc<ArcSwap<RuntimeConfig>>,
+  loaded_content: &mut Option<Vec<u8>>,
+) {
+  match fs::read(path) {
+    Ok(content) if loaded_content.as_ref() != Some(&content) => {
+      *loaded_content = Some(content);
+      let _ = reload_config(path, config).await;
+    }
+    Ok(_) => trace!("'{}' was not modified", path.display()),
+    Err(e) => warn!("Could not read '{}' due to: {}", path.display(), e),
+  }
+}
+
+/// Reads the configuration at `path` and replaces `config` with it. If the new configuration is invalid, the old one is
+/// kept and the error is returned. New ACME certificates don't delay the reload, they are issued in the background.
+pub async fn reload_config(path: &Path, config: &Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
+  let old_config = config.load();
+  let acme_handler = old_config.shared_data.acme_handler.clone();
+  let config_dir_override = old_config.config_dir_override.clone();
+  match read_runtime_config(path, config_dir_override, acme_handler, &old_config.matchers).await {
+    Ok(mut new_config) => {
+      warn_about_ineffectual_config_changes(&old_config, &new_config);
+      // the listeners keep using the connection limit and TLS session settings they were started with
+      new_config.connection_limit = old_config.connection_limit.clone();
+      new_config.tls_sessions = old_config.tls_sessions.clone();
+      keep_issued_acme_certificates(&old_config, &mut new_config);
+      keep_backend_health(&old_config, &new_config);
+      // the backend servers of the new pools are assumed to be healthy until they are checked
+      if new_config.health_interval.initial_check {
+        health::check_health_initially(&new_config.shared_data.backend_pools, &new_config.health_interval).await;
+      }
+      // paused backend pools stay paused until they are resumed explicitly
+      for pool in new_config.shared_data.backend_pools.iter() {
+        let was_paused = old_config
+          .shared_data
+          .backend_pools
+          .iter()
+          .any(|old_pool| old_pool.name == pool.name && old_pool.is_paused());
+        if was_paused {
+          pool.pause();
+        }
+      }
+      config.store(Arc::new(new_config));
+      old_config.replacement.notify();
+      info!("Reloaded configuration");
+      tokio::spawn(issue_acme_certificates(config.clone()));
+      Ok(())
+    }
+    Err(e) => {
+      warn!("Could not reload configuration due to: {}", e);
+      warn!("Keeping old configuration");
+      Err(e)
+    }
+  }
+}
+
+/* Certificates issued for an unchanged ACME configuration are served until they are issued again */
+fn keep_issued_acme_certificates(old: &RuntimeConfig, new: &mut RuntimeConfig) {
+  let old_certificates = old.certificates.load();
+  let mut certificates = (**new.certificates.load()).clone();
+  for order in new.acme_orders.iter().filter(|order| old.acme_orders.contains(order)) {
+    if let Some(certificate) = old_certificates.get(&order.dns_name) {
+      certificates.insert(order.dns_name.clone(), certificate.clone());
     }
-    receiver.changed().await.map_err(broken_pipe)?;
   }
+  new.certificates = ArcSwap::from_pointee(certificates);
+}
+
+/* Backend servers which were already part of a backend pool of the same name keep their healthiness, only added
+servers start in their initial state */
+fn keep_backend_health(old: &RuntimeConfig, new: &RuntimeConfig) {
+  for pool in new.shared_data.backend_pools.iter() {
+    let old_pool = match old.shared_data.backend_pools.iter().find(|it| it.name == pool.name) {
+      Some(old_pool) => old_pool,
+      None => continue,
+    };
+    for (address, health) in pool.addresses.iter() {
+      if let Some((_, old_health)) = old_pool.addresses.iter().find(|(it, _)| it == address) {
+        health.inherit(old_health);
+      }
+    }
+    pool.refresh_working_addresses();
+  }
+}
+
+/// Issues the ACME certificates of the current configuration in the background and swaps each one in once it is
+/// issued. Until then, its name is pending and TLS handshakes for it fail. Certificates of a configuration which was
+/// replaced in the meantime are only swapped in, if the new configuration still orders them the same way.
+pub async fn issue_acme_certificates(config: Arc<ArcSwap<RuntimeConfig>>) {
+  let current = config.load_full();
+  let pending = current.pending_certificates();
+  if !pending.is_empty() {
+    info!("Issuing ACME certificates for {} in the background", pending.join(", "));
+  }
+  let acme_handler = &current.shared_data.acme_handler;
+  // ACME certificates are ordered concurrently, but only a few at once to stay within the rate limits of the CA
+  stream::iter(current.acme_orders.iter().cloned())
+    .map(|order| async move {
+      let result = issue_acme_certificate(acme_handler, &order).await;
+      (order, result)
+    })
+    .buffer_unordered(current.max_concurrent_acme_orders)
+    .for_each(|(order, result)| {
+      match result {
+        Ok(certificate) => install_acme_certificate(&config.load(), &order, certificate),
+        Err(e) => warn!(
+          "Could not issue ACME certificate for '{}' due to: {}",
+          Into::<&str>::into(order.dns_name.as_ref()),
+          e
+        ),
+      }
+      future::ready(())
+    })
+    .await;
+}
+
+fn install_acme_certificate(config: &RuntimeConfig, order: &AcmeOrder, certificate: CertifiedKey) {
+  let name: &str = order.dns_name.as_ref().into();
+  if !config.acme_orders.contains(order) {
+    debug!(
+      "Discarding ACME certificate for '{}', since its configuration changed",
+      name
+    );
+    return;
+  }
+  config.certificates.rcu(|certificates| {
+    let mut certificates = (**certificates).clone();
+    certificates.insert(order.dns_name.clone(), certificate.clone());
+    certificates
+  });
+  info!("Installed ACME certificate for '{}'", name);
 }
 
 fn warn_about_ineffectual_config_changes(old: &RuntimeConfig, new: &RuntimeConfig) {
   if old.http_address != new.http_address {
     warn!(
       "A restart is required for the new http_address '{}' to take effect",
-      new.http_address
+      display_listen_address(new.http_address)
     );
   }
   if old.https_address != new.https_address {
     warn!(
       "A restart is required for the new https_address '{}' to take effect",
-      new.https_address
+      display_listen_address(new.https_address)
     );
   }
+  if old.admin_address != new.admin_address {
+    warn!(
+      "A restart is required for the new admin_address '{}' to take effect",
+      display_listen_address(new.admin_address)
+    );
+  }
+  let health_endpoint_address = |config: &RuntimeConfig| config.health_endpoint.as_ref().map(|it| it.address);
+  if health_endpoint_address(old) != health_endpoint_address(new) {
+    warn!(
+      "A restart is required for the new health_endpoint address '{}' to take effect",
+      display_listen_address(health_endpoint_address(new))
+    );
+  }
+  if old.connection_limit.as_ref().map(|it| it.settings()) != new.connection_limit.as_ref().map(|it| it.settings()) {
+    warn!("A restart is required for the new connection_limit to take effect");
+  }
+  if old.tls_sessions != new.tls_sessions {
+    warn!("A restart is required for the new tls_sessions to take effect");
+  }
 }
 
 fn start_config_watcher<P>(path: P) -> watch::Receiver<DebouncedEvent>
@@ -115,102 +348,454 @@ fn watch_config_blocking<P: AsRef<Path>>(
 
 async fn read_runtime_config<P>(
   path: P,
+  config_dir_override: Option<PathBuf>,
   acme_handler: Arc<AcmeHandler>,
-  init_acme: bool,
+  previous_matchers: &MatcherCache,
 ) -> Result<RuntimeConfig, io::Error>
 where
   P: AsRef<Path>,
 {
-  let config = TomlConfig::read(&path)?;
+  let mut config = TomlConfig::read(&path)?;
   let canonical_path = path.as_ref().canonicalize()?;
-  let config_dir = canonical_path
+  let config_file_dir = canonical_path
     .parent()
     .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Config path does not have a parrent"))?;
-  runtime_config_from_toml_config(config_dir, config, acme_handler, init_acme).await
+  let config_dir = config_dir(
+    config_file_dir,
+    config.config_dir.take().as_deref(),
+    config_dir_override.as_deref(),
+  )?;
+  let mut runtime_config = runtime_config_from_toml_config(config_dir, config, acme_handler, previous_matchers)?;
+  runtime_config.config_dir_override = config_dir_override;
+  Ok(runtime_config)
 }
 
-async fn runtime_config_from_toml_config<P: AsRef<Path>>(
+/// Returns the directory relative paths in the configuration are resolved against: the directory given on the command
+/// line, the `config_dir` of the configuration file relative to the configuration file, or the directory containing
+/// the configuration file, in this order.
+fn config_dir(config_file_dir: &Path, configured: Option<&str>, override_dir: Option<&Path>) -> io::Result<PathBuf> {
+  let dir = match (override_dir, configured) {
+    (Some(dir), _) => dir.to_path_buf(),
+    (None, Some(dir)) => config_file_dir.join(dir),
+    (None, None) => return Ok(config_file_dir.to_path_buf()),
+  };
+  dir
+    .canonicalize()
+    .map_err(|e| invalid_data(format!("invalid config_dir '{}': {}", dir.display(), e)))
+}
+
+fn runtime_config_from_toml_config<P: AsRef<Path>>(
   config_dir: P,
   other: TomlConfig,
   acme_handler: Arc<AcmeHandler>,
-  init_acme: bool,
+  previous_matchers: &MatcherCache,
 ) -> Result<RuntimeConfig, io::Error> {
-  let http_address = other.http_address.parse().map_err(invalid_data)?;
-  let https_address = other.https_address.parse().map_err(invalid_data)?;
+  let http_address = parse_listen_address(&other.http_address)?;
+  let https_address = parse_listen_address(&other.https_address)?;
+  let admin_address = other
+    .admin_address
+    .map(|it| it.parse())
+    .transpose()
+    .map_err(invalid_data)?;
+  let admin_tokens = other
+    .admin_tokens
+    .into_iter()
+    .map(|it| admin_token(&config_dir, it))
+    .collect::<io::Result<Vec<_>>>()?;
+  let health_endpoint = match other.health_endpoint {
+    Some(health_endpoint) => Some(HealthEndpointConfig {
+      address: health_endpoint.address.parse().map_err(invalid_data)?,
+      liveness_path: health_endpoint.liveness_path,
+      readiness_path: health_endpoint.readiness_path,
+      pool_path_prefix: health_endpoint.pool_path_prefix,
+    }),
+    None => None,
+  };
+  let bind_retry = other.bind_retry.map_or_else(BindRetry::default, |it| BindRetry {
+    attempts: it.attempts,
+    initial_delay: Duration::from_millis(it.initial_delay_ms),
+    max_delay: Duration::from_millis(it.max_delay_ms),
+  });
+
+  let log_redaction = LogRedaction {
+    headers: match other.log_redaction.headers {
+      Some(headers) => headers
+        .iter()
+        .map(|it| HeaderName::from_bytes(it.as_bytes()))
+        .collect::<Result<_, _>>()
+        .map_err(invalid_data)?,
+      None => log_redaction::default_headers(),
+    },
+    query_parameters: other
+      .log_redaction
+      .query_parameters
+      .unwrap_or_else(log_redaction::default_query_parameters),
+  };
 
-  let backend_pools = other.backend_pools.into_iter().map(|it| Arc::new(it.into())).collect();
+  let trace_matchers = other.trace_matchers;
+  let mut matchers = MatcherCache::default();
+  let pool_indices = other
+    .backend_pools
+    .iter()
+    .enumerate()
+    .map(|(index, pool)| (pool.name.clone(), index))
+    .collect::<HashMap<_, _>>();
+  let services = other
+    .services
+    .into_iter()
+    .map(|service| {
+      let matcher = matchers
+        .get_or_parse(&service.matcher, previous_matchers)
+        .map_err(|e| invalid_data(format!("service '{}' has an invalid matcher: {}", service.name, e)))?;
+      Ok(Service {
+        name: service.name,
+        matcher,
+        pools: service
+          .pools
+          .into_iter()
+          .map(|it| WeightedPool {
+            pool: pool_indices[&it.pool],
+            weight: it.weight,
+          })
+          .collect(),
+        failover: service.failover.iter().map(|it| pool_indices[it]).collect(),
+      })
+    })
+    .collect::<Result<_, io::Error>>()?;
+  let backend_pools: Vec<Arc<BackendPool>> = other
+    .backend_pools
+    .into_iter()
+    .map(|mut it| {
+      let matcher = matchers
+        .get_or_parse(&it.matcher, previous_matchers)
+        .map_err(|e| invalid_data(format!("backend pool '{}' has an invalid matcher: {}", it.name, e)))?;
+      it.trace_matcher |= trace_matchers;
+      Ok(Arc::new(it.into_backend_pool(
+        matcher,
+        config_dir.as_ref(),
+        &pool_indices,
+        &log_redaction,
+      )?))
+    })
+    .collect::<Result<_, io::Error>>()?;
+  let pool_index = PoolIndex::new(&backend_pools);
+
+  let certificate_expiry = CertificateExpiryConfig {
+    warn_before: Duration::from_secs(other.certificate_expiry.warn_days * 24 * 60 * 60),
+    check_every: Duration::from_secs(other.certificate_expiry.check_every_sec),
+    refuse_expired: other.certificate_expiry.refuse_expired,
+  };
 
   let mut certificates = HashMap::new();
+  let mut declared_names = Vec::new();
+  let mut subject_alt_names = Vec::new();
+  let mut acme_orders = Vec::new();
   for (sni_name, certificate_config) in other.certificates {
     let dns_name = DNSNameRef::try_from_ascii_str(&sni_name)
       .map_err(invalid_data)?
       .to_owned();
-    if init_acme || !matches!(certificate_config, CertificateConfig::ACME { .. }) {
-      let certificate = create_certified_key(&config_dir, certificate_config, dns_name.as_ref(), &acme_handler).await?;
-      certificates.insert(dns_name, certificate);
+    let (names, certificate) = match certificate_config {
+      CertificateConfig::Local {
+        certificate_path,
+        private_key_path,
+        names,
+      } => {
+        let certificate = load_local_certificate(
+          &config_dir,
+          &certificate_path,
+          &private_key_path,
+          dns_name.as_ref(),
+          &certificate_expiry,
+        )?;
+        (names, certificate)
+      }
+      CertificateConfig::ACME {
+        staging,
+        directory_url,
+        email,
+        persist_dir,
+        account_key_path,
+        external_account_binding,
+      } => {
+        let account = AcmeAccount {
+          directory_url: directory_url.unwrap_or_else(|| {
+            let directory_url = if staging {
+              LETS_ENCRYPT_STAGING_DIRECTORY
+            } else {
+              LETS_ENCRYPT_DIRECTORY
+            };
+            directory_url.to_string()
+          }),
+          email,
+          persist_dir: config_dir.as_ref().join(persist_dir),
+          account_key: account_key_path
+            .map(|path| read_file(config_dir.as_ref().join(path)))
+            .transpose()?,
+          external_account_binding: external_account_binding
+            .map(|it| external_account_binding_of(&config_dir, it))
+            .transpose()?,
+        };
+        // ACME certificates are issued in the background, see `issue_acme_certificates`
+        acme_orders.push(AcmeOrder { dns_name, account });
+        continue;
+      }
+    };
+    for name in names {
+      let name = DNSNameRef::try_from_ascii_str(&name).map_err(invalid_data)?;
+      certificate.cross_check_end_entity_cert(Some(name)).map_err(|e| {
+        invalid_data(format!(
+          "Certificate of '{}' is not valid for '{}': {}",
+          sni_name,
+          Into::<&str>::into(name),
+          e
+        ))
+      })?;
+      declared_names.push((name.to_owned(), certificate.clone()));
     }
+    for name in certificate_dns_names(&certificate) {
+      subject_alt_names.push((name, certificate.clone()));
+    }
+    certificates.insert(dns_name, certificate);
+  }
+  // Names configured explicitly take precedence over the names found in the certificates
+  for (name, certificate) in declared_names.into_iter().chain(subject_alt_names) {
+    certificates.entry(name).or_insert(certificate);
   }
 
   let health_interval_config: HealthIntervalConfig = other.health_interval;
-  let health_interval = Duration::from_secs(health_interval_config.check_every);
+  let health_interval = HealthInterval {
+    check_every: Duration::from_secs(health_interval_config.check_every),
+    stagger: health_interval_config.stagger,
+    max_concurrent_checks: health_interval_config.max_concurrent_checks,
+    initial_check: health_interval_config.initial_check,
+    coordination: match &health_interval_config.redis_url {
+      Some(redis_url) => Some(Arc::new(
+        HealthCoordination::new(redis_url, health_interval_config.redis_key_prefix).map_err(invalid_data)?,
+      )),
+      None => None,
+    },
+    events: other
+      .health_events
+      .map(|it| health_event_sink(&config_dir, it).map(|sink| Arc::new(HealthEvents::new(sink))))
+      .transpose()?,
+  };
+
+  let debug_headers = DebugHeaders {
+    always: other.debug_headers.always,
+    trigger_header: other
+      .debug_headers
+      .trigger_header
+      .map(|it| HeaderName::from_bytes(it.as_bytes()))
+      .transpose()
+      .map_err(invalid_data)?,
+    allowed_ips: other.debug_headers.allowed_ips,
+  };
+
+  let error_pages = match other.error_pages {
+    Some(error_pages) => {
+      let location = error_pages.location.map(|it| config_dir.as_ref().join(it));
+      let content_type = HeaderValue::from_str(&error_pages.content_type).map_err(invalid_data)?;
+      let error_pages = ErrorPages::load(location.as_deref(), content_type, error_pages.json)
+        .map_err(|e| io::Error::new(e.kind(), format!("Could not load error pages due to: {}", e)))?;
+      Some(Arc::new(error_pages))
+    }
+    None => None,
+  };
+
+  let connection_limit = other.connection_limit.map(|it| {
+    Arc::new(ConnectionLimit::new(ConnectionLimitSettings {
+      max_per_client: it.max_per_client,
+      ban_after: it.ban_after,
+      ban_duration: Duration::from_secs(it.ban_sec),
+    }))
+  });
+
+  let tls_sessions = match other.tls_sessions {
+    Some(tls_sessions) => tls_session_settings(&config_dir, tls_sessions)?,
+    None => TlsSessionSettings::default(),
+  };
 
   Ok(RuntimeConfig {
+    config_dir_override: None,
     http_address,
     https_address,
+    bind_retry,
+    admin_address,
+    admin_dashboard: other.admin_dashboard,
+    admin_tokens,
+    replacement: Replacement::default(),
+    health_endpoint,
     shared_data: SharedData {
       backend_pools,
+      default_pool: other.default_pool.map(|it| pool_indices[&it]),
+      http10_pools: other
+        .http10_pools
+        .into_iter()
+        .map(|(scheme, pool)| (scheme, pool_indices[&pool]))
+        .collect(),
+      services,
+      pool_index,
       acme_handler,
     },
-    certificates,
+    certificates: ArcSwap::from_pointee(certificates),
+    acme_orders,
+    max_concurrent_acme_orders: other.max_concurrent_acme_orders,
+    certificate_expiry,
     health_interval,
+    normalize_paths: other.normalize_paths,
+    strict_sni: other.strict_sni,
+    internal_endpoints: InternalEndpoints {
+      reserved_prefix: other.reserved_path_prefix,
+    },
+    slow_request_threshold: other.slow_request_threshold_ms.map(Duration::from_millis),
+    process_stats_interval: other.process_stats_interval_sec.map(Duration::from_secs),
+    debug_headers,
+    log_redaction,
+    matchers,
+    error_pages,
+    connection_limit,
+    tls_sessions,
+  })
+}
+
+fn tls_session_settings<P: AsRef<Path>>(config_dir: P, config: TlsSessionsConfig) -> io::Result<TlsSessionSettings> {
+  if config.ticket_rotation_sec == 0 {
+    return Err(invalid_data("tls_sessions.ticket_rotation_sec must be greater than 0"));
+  }
+  let ticket_secret = match config.ticket_key_file {
+    Some(ticket_key_file) => {
+      let path = config_dir.as_ref().join(ticket_key_file);
+      let secret = fs::read(&path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Could not read '{}' due to: {}", path.display(), e)))?;
+      if secret.len() < MIN_TICKET_SECRET_LEN {
+        return Err(invalid_data(format!(
+          "'{}' must contain at least {} bytes",
+          path.display(),
+          MIN_TICKET_SECRET_LEN
+        )));
+      }
+      Some(secret)
+    }
+    None => None,
+  };
+  Ok(TlsSessionSettings {
+    cache_size: config.cache_size,
+    tickets: config.tickets,
+    ticket_rotation: Duration::from_secs(config.ticket_rotation_sec),
+    ticket_secret,
+  })
+}
+
+fn admin_token<P: AsRef<Path>>(config_dir: P, config: AdminTokenConfig) -> io::Result<AdminToken> {
+  let token = match (config.token, config.token_file) {
+    (Some(token), None) => token,
+    (None, Some(token_file)) => {
+      let path = config_dir.as_ref().join(token_file);
+      let token = fs::read_to_string(&path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Could not read '{}' due to: {}", path.display(), e)))?;
+      token.trim().to_string()
+    }
+    _ => {
+      return Err(invalid_data(format!(
+        "admin token '{}' requires either token or token_file",
+        config.name
+      )))
+    }
+  };
+  if token.is_empty() {
+    return Err(invalid_data(format!("admin token '{}' is empty", config.name)));
+  }
+  Ok(AdminToken {
+    name: config.name,
+    token,
+    role: config.role,
   })
 }
 
-async fn create_certified_key<P: AsRef<Path>>(
+fn parse_listen_address(address: &str) -> Result<Option<SocketAddr>, io::Error> {
+  if address == DISABLED_ADDRESS {
+    Ok(None)
+  } else {
+    address.parse().map(Some).map_err(invalid_data)
+  }
+}
+
+fn display_listen_address(address: Option<SocketAddr>) -> String {
+  address.map_or_else(|| DISABLED_ADDRESS.to_string(), |it| it.to_string())
+}
+
+fn load_local_certificate<P: AsRef<Path>>(
   config_dir: P,
-  config: CertificateConfig,
+  certificate_path: &str,
+  private_key_path: &str,
   sni_name: DNSNameRef<'_>,
-  acme_handler: &AcmeHandler,
+  certificate_expiry: &CertificateExpiryConfig,
 ) -> Result<CertifiedKey, io::Error> {
-  let certified_key = match config {
-    CertificateConfig::Local {
-      certificate_path,
-      private_key_path,
-    } => {
-      let certificate_path = config_dir.as_ref().join(certificate_path);
-      let private_key_path = config_dir.as_ref().join(private_key_path);
-      load_certified_key(certificate_path, private_key_path)?
-    }
-    CertificateConfig::ACME {
-      staging,
-      email,
-      persist_dir,
-    } => {
-      let persist_dir = config_dir.as_ref().join(persist_dir);
-      // TODO refresh certificates once they expire?
-      let certificate = acme_handler
-        .initiate_challenge(staging, &persist_dir, &email, sni_name.into())
-        .await
-        .map_err(other)?;
-
-      certified_key_from_acme_certificate(certificate).map_err(|e| {
-        io::Error::new(
-          e.kind(),
-          format!(
-            "Could not load ACME certificate for '{}' due to: {}",
-            Into::<&str>::into(sni_name),
-            e
-          ),
-        )
-      })?
+  let certificate_path = config_dir.as_ref().join(certificate_path);
+  let private_key_path = config_dir.as_ref().join(private_key_path);
+  let certified_key = load_certified_key(&certificate_path, private_key_path)?;
+  if certificate_expiry.refuse_expired {
+    let validity = Validity::of(&certified_key)?;
+    if validity.is_expired() {
+      return Err(invalid_data(format!(
+        "Certificate '{}' expired on {}",
+        certificate_path.display(),
+        validity.not_after
+      )));
     }
-  };
+  }
+  certified_key
+    .cross_check_end_entity_cert(Some(sni_name))
+    .map_err(invalid_data)?;
+  Ok(certified_key)
+}
+
+async fn issue_acme_certificate(acme_handler: &AcmeHandler, order: &AcmeOrder) -> Result<CertifiedKey, io::Error> {
+  let sni_name = order.dns_name.as_ref();
+  // TODO refresh certificates once they expire?
+  let certificate = acme_handler
+    .initiate_challenge(&order.account, sni_name.into())
+    .await
+    .map_err(other)?;
+  let certified_key = certified_key_from_acme_certificate(certificate).map_err(|e| {
+    io::Error::new(
+      e.kind(),
+      format!(
+        "Could not load ACME certificate for '{}' due to: {}",
+        Into::<&str>::into(sni_name),
+        e
+      ),
+    )
+  })?;
   certified_key
     .cross_check_end_entity_cert(Some(sni_name))
     .map_err(invalid_data)?;
   Ok(certified_key)
 }
 
+fn external_account_binding_of<P: AsRef<Path>>(
+  config_dir: P,
+  config: ExternalAccountBindingConfig,
+) -> io::Result<ExternalAccountBinding> {
+  let hmac_key = match (config.hmac_key, config.hmac_key_file) {
+    (Some(hmac_key), None) => hmac_key,
+    (None, Some(hmac_key_file)) => {
+      String::from_utf8_lossy(&read_file(config_dir.as_ref().join(hmac_key_file))?).into_owned()
+    }
+    _ => {
+      return Err(invalid_data(format!(
+        "external account binding '{}' requires either hmac_key or hmac_key_file",
+        config.key_id
+      )))
+    }
+  };
+  ExternalAccountBinding::new(config.key_id, &hmac_key).map_err(invalid_data)
+}
+
+fn read_file(path: PathBuf) -> io::Result<Vec<u8>> {
+  fs::read(&path).map_err(|e| io::Error::new(e.kind(), format!("Could not read '{}' due to: {}", path.display(), e)))
+}
+
 fn map_notify_error(error: notify::Error) -> io::Error {
   match error {
     notify::Error::Generic(e) => other(e),
@@ -248,28 +833,283 @@ where
   io::Error::new(io::ErrorKind::Other, error)
 }
 
+/// Signals that a configuration was replaced by a reloaded one, e.g. to close client connections using backend servers
+/// which were removed.
+#[derive(Debug)]
+pub struct Replacement {
+  sender: watch::Sender<bool>,
+  receiver: watch::Receiver<bool>,
+}
+
+impl Default for Replacement {
+  fn default() -> Self {
+    let (sender, receiver) = watch::channel(false);
+    Replacement { sender, receiver }
+  }
+}
+
+impl Replacement {
+  fn notify(&self) {
+    let _ = self.sender.send(true);
+  }
+
+  /// Returns a receiver which is notified once the configuration is replaced, even if that already happened.
+  pub fn subscribe(&self) -> watch::Receiver<bool> {
+    self.receiver.clone()
+  }
+}
+
+/// An ACME certificate to issue for a SNI name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcmeOrder {
+  pub dns_name: DNSName,
+  pub account: AcmeAccount,
+}
+
 pub struct RuntimeConfig {
-  pub http_address: SocketAddr,
-  pub https_address: SocketAddr,
+  /* The directory given on the command line, kept for reloads */
+  pub config_dir_override: Option<PathBuf>,
+  /* `None` if the listener is disabled */
+  pub http_address: Option<SocketAddr>,
+  pub https_address: Option<SocketAddr>,
+  pub bind_retry: BindRetry,
+  /* `None` if the admin API is disabled */
+  pub admin_address: Option<SocketAddr>,
+  pub admin_dashboard: bool,
+  /* The admin API requires one of the tokens, if any */
+  pub admin_tokens: Vec<AdminToken>,
+  pub replacement: Replacement,
+  pub health_endpoint: Option<HealthEndpointConfig>,
   pub shared_data: SharedData,
-  pub certificates: HashMap<DNSName, CertifiedKey>,
-  pub health_interval: Duration,
+  pub certificates: Certificates,
+  /* ACME certificates are issued in the background, so they are missing from `certificates` until then */
+  pub acme_orders: Vec<AcmeOrder>,
+  pub max_concurrent_acme_orders: usize,
+  pub certificate_expiry: CertificateExpiryConfig,
+  pub health_interval: HealthInterval,
+  pub normalize_paths: bool,
+  pub strict_sni: bool,
+  pub internal_endpoints: InternalEndpoints,
+  pub slow_request_threshold: Option<Duration>,
+  /* `None` if the process stats are not logged periodically */
+  pub process_stats_interval: Option<Duration>,
+  pub debug_headers: DebugHeaders,
+  pub log_redaction: LogRedaction,
+  pub matchers: MatcherCache,
+  pub error_pages: Option<Arc<ErrorPages>>,
+  pub connection_limit: Option<Arc<ConnectionLimit>>,
+  pub tls_sessions: TlsSessionSettings,
 }
 
-#[derive(Debug, Deserialize)]
+impl RuntimeConfig {
+  /// Returns the names of the ACME certificates which are not issued yet.
+  pub fn pending_certificates(&self) -> Vec<&str> {
+    let certificates = self.certificates.load();
+    self
+      .acme_orders
+      .iter()
+      .filter(|order| !certificates.contains_key(&order.dns_name))
+      .map(|order| order.dns_name.as_ref().into())
+      .collect()
+  }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct TomlConfig {
+  config_dir: Option<String>,
   #[serde(default = "default_http_address")]
   http_address: String,
   #[serde(default = "default_https_address")]
   https_address: String,
+  bind_retry: Option<BindRetryConfig>,
+  admin_address: Option<String>,
+  #[serde(default)]
+  admin_dashboard: bool,
+  #[serde(default)]
+  admin_tokens: Vec<AdminTokenConfig>,
+  health_endpoint: Option<HealthEndpointTomlConfig>,
   #[serde(default)]
   backend_pools: Vec<BackendPoolConfig>,
   #[serde(default)]
-  certificates: HashMap<String, CertificateConfig>,
+  services: Vec<ServiceConfig>,
+  default_pool: Option<String>,
+  #[serde(default)]
+  http10_pools: HashMap<Scheme, String>,
+  #[serde(default, deserialize_with = "certificates_in_order")]
+  #[schemars(with = "HashMap<String, CertificateConfig>")]
+  certificates: Vec<(String, CertificateConfig)>,
+  #[serde(default = "default_max_concurrent_acme_orders")]
+  max_concurrent_acme_orders: usize,
+  #[serde(default)]
+  certificate_expiry: CertificateExpiryTomlConfig,
   #[serde(default = "default_health_interval_config")]
   health_interval: HealthIntervalConfig,
+  health_events: Option<HealthEventsConfig>,
+  #[serde(default)]
+  normalize_paths: bool,
+  #[serde(default)]
+  strict_sni: bool,
+  #[serde(default = "default_reserved_path_prefix")]
+  reserved_path_prefix: String,
+  slow_request_threshold_ms: Option<u64>,
+  process_stats_interval_sec: Option<u64>,
+  #[serde(default)]
+  debug_headers: DebugHeadersConfig,
+  #[serde(default)]
+  log_redaction: LogRedactionConfig,
+  #[serde(default)]
+  trace_matchers: bool,
+  error_pages: Option<ErrorPagesConfig>,
+  connection_limit: Option<ConnectionLimitConfig>,
+  tls_sessions: Option<TlsSessionsConfig>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct TlsSessionsConfig {
+  #[serde(default = "default_session_cache_size")]
+  cache_size: usize,
+  #[serde(default)]
+  tickets: bool,
+  #[serde(default = "default_ticket_rotation_sec")]
+  ticket_rotation_sec: u64,
+  ticket_key_file: Option<String>,
+}
+
+fn default_max_concurrent_acme_orders() -> usize {
+  2
+}
+
+fn default_reserved_path_prefix() -> String {
+  internal_endpoints::DEFAULT_RESERVED_PREFIX.to_string()
+}
+
+fn default_session_cache_size() -> usize {
+  256
+}
+
+fn default_ticket_rotation_sec() -> u64 {
+  6 * 60 * 60
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct AdminTokenConfig {
+  name: String,
+  token: Option<String>,
+  token_file: Option<String>,
+  role: AdminRole,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct HealthEndpointTomlConfig {
+  address: String,
+  #[serde(default = "default_liveness_path")]
+  liveness_path: String,
+  #[serde(default = "default_readiness_path")]
+  readiness_path: String,
+  #[serde(default = "default_pool_path_prefix")]
+  pool_path_prefix: String,
+}
+
+fn default_liveness_path() -> String {
+  "/healthz".to_string()
+}
+
+fn default_readiness_path() -> String {
+  "/readyz".to_string()
+}
+
+fn default_pool_path_prefix() -> String {
+  "/pools/".to_string()
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct BindRetryConfig {
+  attempts: u32,
+  #[serde(default = "default_bind_retry_initial_delay_ms")]
+  initial_delay_ms: u64,
+  #[serde(default = "default_bind_retry_max_delay_ms")]
+  max_delay_ms: u64,
+}
+
+fn default_bind_retry_initial_delay_ms() -> u64 {
+  500
+}
+
+fn default_bind_retry_max_delay_ms() -> u64 {
+  10000
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct CertificateExpiryTomlConfig {
+  #[serde(default = "default_warn_days")]
+  warn_days: u64,
+  #[serde(default = "default_certificate_check_every_sec")]
+  check_every_sec: u64,
+  #[serde(default)]
+  refuse_expired: bool,
+}
+
+impl Default for CertificateExpiryTomlConfig {
+  fn default() -> Self {
+    CertificateExpiryTomlConfig {
+      warn_days: default_warn_days(),
+      check_every_sec: default_certificate_check_every_sec(),
+      refuse_expired: false,
+    }
+  }
+}
+
+fn default_warn_days() -> u64 {
+  14
+}
+
+fn default_certificate_check_every_sec() -> u64 {
+  3600
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ConnectionLimitConfig {
+  max_per_client: usize,
+  ban_after: Option<u64>,
+  #[serde(default = "default_ban_sec")]
+  ban_sec: u64,
+}
+
+fn default_ban_sec() -> u64 {
+  600
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ErrorPagesConfig {
+  location: Option<String>,
+  #[serde(default = "default_error_pages_content_type")]
+  content_type: String,
+  #[serde(default)]
+  json: bool,
+}
+
+fn default_error_pages_content_type() -> String {
+  error_response::TEXT_HTML.to_string()
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+struct DebugHeadersConfig {
+  #[serde(default)]
+  always: bool,
+  trigger_header: Option<String>,
+  #[serde(default)]
+  allowed_ips: Vec<IpAddr>,
 }
 
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+struct LogRedactionConfig {
+  /* `None` keeps the default lists */
+  headers: Option<Vec<String>>,
+  query_parameters: Option<Vec<String>>,
+}
+
+const DISABLED_ADDRESS: &str = "disabled";
+
 // Dual Stack if /proc/sys/net/ipv6/bindv6only has default value 0
 // rf https://man7.org/linux/man-pages/man7/ipv6.7.html
 fn default_http_address() -> String {
@@ -281,7 +1121,14 @@ fn default_https_address() -> String {
 }
 
 fn default_health_interval_config() -> HealthIntervalConfig {
-  HealthIntervalConfig { check_every: 10 }
+  HealthIntervalConfig {
+    check_every: default_check_every(),
+    stagger: false,
+    max_concurrent_checks: None,
+    initial_check: false,
+    redis_url: None,
+    redis_key_prefix: default_health_key_prefix(),
+  }
 }
 
 impl TomlConfig {
@@ -308,39 +1155,531 @@ impl TomlConfig {
       )
     })?;
     config.print_warnings();
+    config.validate()?;
     Ok(config)
   }
 
-  fn print_warnings(&self) {
-    if self.backend_pools.is_empty() {
-      warn!("No backend pool found.");
+  fn validate(&self) -> io::Result<()> {
+    if self.http_address == DISABLED_ADDRESS && self.https_address == DISABLED_ADDRESS {
+      return Err(invalid_data("http_address and https_address can not both be disabled"));
     }
-    for (index, pool) in self.backend_pools.iter().enumerate() {
-      if pool.schemes.is_empty() {
-        warn!("backend pool at index {} is unreachable, since no schemes are registered. Consider adding `HTTP` or `HTTPS` to the schemes array.", index);
+    if let Some(default_pool) = self.default_pool.as_ref().filter(|it| !self.has_pool(it)) {
+      return Err(invalid_data(format!(
+        "default_pool references the unknown backend pool '{}'",
+        default_pool
+      )));
+    }
+    if let Some((scheme, pool)) = self.http10_pools.iter().find(|(_, it)| !self.has_pool(it)) {
+      return Err(invalid_data(format!(
+        "the HTTP/1.0 pool for {} references the unknown backend pool '{}'",
+        scheme, pool
+      )));
+    }
+    if let Some(health_endpoint) = &self.health_endpoint {
+      let prefix = &health_endpoint.pool_path_prefix;
+      if !prefix.starts_with('/') || !prefix.ends_with('/') {
+        return Err(invalid_data(format!(
+          "pool_path_prefix '{}' of the health endpoint must start and end with '/'",
+          prefix
+        )));
       }
-
-      if pool.addresses.is_empty() {
-        warn!(
-          "backend pool at index {} does not contain any addresses. It will always result in bad gateway errors.",
+      if health_endpoint.liveness_path.starts_with(prefix) || health_endpoint.readiness_path.starts_with(prefix) {
+        return Err(invalid_data(format!(
+          "pool_path_prefix '{}' of the health endpoint overlaps with its liveness or readiness path",
+          prefix
+        )));
+      }
+    }
+    if !self.reserved_path_prefix.starts_with('/') || !self.reserved_path_prefix.ends_with('/') {
+      return Err(invalid_data(format!(
+        "reserved_path_prefix '{}' must start and end with '/'",
+        self.reserved_path_prefix
+      )));
+    }
+    if self.max_concurrent_acme_orders == 0 {
+      return Err(invalid_data("max_concurrent_acme_orders must be greater than 0"));
+    }
+    if self.process_stats_interval_sec == Some(0) {
+      return Err(invalid_data("process_stats_interval_sec must be greater than 0"));
+    }
+    if self.certificate_expiry.check_every_sec == 0 {
+      return Err(invalid_data(
+        "check_every_sec of certificate_expiry must be greater than 0",
+      ));
+    }
+    if self.health_interval.max_concurrent_checks == Some(0) {
+      return Err(invalid_data("max_concurrent_checks must be greater than 0"));
+    }
+    if matches!(&self.connection_limit, Some(limit) if limit.max_per_client == 0) {
+      return Err(invalid_data(
+        "max_per_client of connection_limit must be greater than 0",
+      ));
+    }
+    for (name, certificate) in &self.certificates {
+      if let CertificateConfig::ACME {
+        staging: true,
+        directory_url: Some(_),
+        ..
+      } = certificate
+      {
+        return Err(invalid_data(format!(
+          "ACME certificate '{}' can not set both staging and directory_url",
+          name
+        )));
+      }
+    }
+    let mut token_names = HashSet::new();
+    for token in &self.admin_tokens {
+      if !token_names.insert(token.name.as_str()) {
+        return Err(invalid_data(format!("admin token name '{}' is not unique", token.name)));
+      }
+    }
+    let mut names = HashSet::new();
+    for (index, pool) in self.backend_pools.iter().enumerate() {
+      if pool.name.trim().is_empty() {
+        return Err(invalid_data(format!(
+          "backend pool at index {} has an empty name",
           index
-        );
+        )));
+      }
+      if !names.insert(pool.name.as_str()) {
+        return Err(invalid_data(format!("backend pool name '{}' is not unique", pool.name)));
+      }
+      if let Some(status) = pool.status.as_ref().filter(|it| !it.path.starts_with('/')) {
+        return Err(invalid_data(format!(
+          "status path '{}' of backend pool '{}' must start with '/'",
+          status.path, pool.name
+        )));
+      }
+      if let Some(status) = &pool.status {
+        if status.path.starts_with(self.reserved_path_prefix.as_str()) {
+          return Err(invalid_data(format!(
+            "status path '{}' of backend pool '{}' is reserved, the status is served on '{}status/{}' anyway",
+            status.path, pool.name, self.reserved_path_prefix, pool.name
+          )));
+        }
+      }
+      for (address_index, address) in pool.addresses.iter().enumerate() {
+        if let Err(e) = address.parse::<BackendAddress>() {
+          return Err(invalid_data(format!(
+            "backend pool '{}' has an invalid address '{}' at index {}: {}",
+            pool.name, address, address_index, e
+          )));
+        }
+      }
+      for (address, probe_address) in &pool.health_config.addresses {
+        if let Err(e) = probe_address.parse::<BackendAddress>() {
+          return Err(invalid_data(format!(
+            "backend pool '{}' has an invalid health check address '{}' for '{}': {}",
+            pool.name, probe_address, address, e
+          )));
+        }
+      }
+      if pool.https_only && !pool.schemes.is_empty() && pool.schemes != pool.schemes() {
+        return Err(invalid_data(format!(
+          "backend pool '{}' is https_only, which requires the schemes HTTP and HTTPS",
+          pool.name
+        )));
+      }
+      if pool.health_config.port == Some(0) {
+        return Err(invalid_data(format!(
+          "backend pool '{}' has an invalid health check port 0",
+          pool.name
+        )));
+      }
+      let check_every = pool
+        .health_config
+        .check_every
+        .unwrap_or(self.health_interval.check_every);
+      if pool.health_config.initial_state == InitialState::Unknown && check_every == 0 {
+        return Err(invalid_data(format!(
+          "backend pool '{}' starts in the unknown state, which requires health checks",
+          pool.name
+        )));
+      }
+      if let Some(fallback) = &pool.fallback {
+        if fallback.pool == pool.name || !self.has_pool(&fallback.pool) {
+          return Err(invalid_data(format!(
+            "backend pool '{}' has an invalid fallback pool '{}'",
+            pool.name, fallback.pool
+          )));
+        }
+        if let Some(max_error_rate) = fallback.max_error_rate {
+          if !(0.0..=1.0).contains(&max_error_rate) {
+            return Err(invalid_data(format!(
+              "max_error_rate {} of backend pool '{}' must be between 0.0 and 1.0",
+              max_error_rate, pool.name
+            )));
+          }
+        }
+      }
+      if matches!(&pool.queue, Some(queue) if queue.max_requests_per_backend == 0) {
+        return Err(invalid_data(format!(
+          "max_requests_per_backend of backend pool '{}' must be greater than 0",
+          pool.name
+        )));
+      }
+      if let Some(range) = pool
+        .slow_weights
+        .iter()
+        .find(|range| range.weight.is_nan() || range.weight < 0.0)
+      {
+        return Err(invalid_data(format!(
+          "backend pool '{}' has an invalid slow weight {}",
+          pool.name, range.weight
+        )));
+      }
+      if !pool.slow_weights.is_empty() && !pool.strategy.uses_weights() {
+        return Err(invalid_data(format!(
+          "backend pool '{}' has slow_weights, but its strategy ignores weights",
+          pool.name
+        )));
+      }
+      if let Err(e) = pool.strategy.validate() {
+        return Err(invalid_data(format!(
+          "backend pool '{}' has an invalid strategy: {}",
+          pool.name, e
+        )));
+      }
+      if let Some(Err(e)) = pool.shadow_strategy.as_ref().map(LoadBalancingStrategyConfig::validate) {
+        return Err(invalid_data(format!(
+          "backend pool '{}' has an invalid shadow strategy: {}",
+          pool.name, e
+        )));
+      }
+      if matches!(&pool.max_connections, Some(max_connections) if max_connections.per_address == 0) {
+        return Err(invalid_data(format!(
+          "backend pool '{}' must allow at least one connection per address",
+          pool.name
+        )));
+      }
+    }
+    let mut service_names = HashSet::new();
+    for service in &self.services {
+      if !service_names.insert(service.name.as_str()) {
+        return Err(invalid_data(format!("service name '{}' is not unique", service.name)));
+      }
+      if service.pools.is_empty() {
+        return Err(invalid_data(format!("service '{}' has no backend pools", service.name)));
+      }
+      let pool_names = service.pools.iter().map(|it| &it.pool).chain(service.failover.iter());
+      for pool_name in pool_names {
+        if !names.contains(pool_name.as_str()) {
+          return Err(invalid_data(format!(
+            "service '{}' references the unknown backend pool '{}'",
+            service.name, pool_name
+          )));
+        }
       }
+      if let Some(pool) = service
+        .pools
+        .iter()
+        .find(|it| !it.weight.is_finite() || it.weight < 0.0)
+      {
+        return Err(invalid_data(format!(
+          "service '{}' has an invalid weight {} for backend pool '{}'",
+          service.name, pool.weight, pool.pool
+        )));
+      }
+    }
+    // a pool which is only used through a service, a fallback or as default pool may repeat the matcher of another pool
+    if let Some(shadowed) = self
+      .shadowed_pools()
+      .into_iter()
+      .find(|it| it.identical && !self.is_referenced(it.pool))
+    {
+      return Err(invalid_data(format!(
+        "backend pool '{}' is unreachable, since backend pool '{}' comes first and has the same matcher",
+        self.backend_pools[shadowed.pool].name, self.backend_pools[shadowed.by].name
+      )));
     }
+    Ok(())
   }
-}
 
-#[derive(Debug, Deserialize)]
+  /* Finds the backend pools which never receive a request through their matcher, since an earlier pool supporting the
+  same schemes provably matches all of their requests */
+  fn shadowed_pools(&self) -> Vec<ShadowedPool> {
+    // invalid matchers are reported when they are parsed
+    let matchers = self
+      .backend_pools
+      .iter()
+      .map(|pool| pool.matcher.parse::<BackendPoolMatcher>().ok())
+      .collect::<Vec<_>>();
+    let mut shadowed_pools = Vec::new();
+    let schemes = self.backend_pools.iter().map(|pool| pool.schemes()).collect::<Vec<_>>();
+    for index in 0..self.backend_pools.len() {
+      let matcher = match &matchers[index] {
+        Some(matcher) if !schemes[index].is_empty() => matcher,
+        _ => continue,
+      };
+      let covering_pools = schemes[index]
+        .iter()
+        .map(|scheme| {
+          (0..index).find(|&earlier| {
+            schemes[earlier].contains(scheme)
+              && matches!(&matchers[earlier], Some(earlier_matcher) if earlier_matcher.covers(matcher))
+          })
+        })
+        .collect::<Option<Vec<_>>>();
+      if let Some(by) = covering_pools.and_then(|it| it.into_iter().min()) {
+        shadowed_pools.push(ShadowedPool {
+          pool: index,
+          by,
+          identical: matchers[by].as_ref() == Some(matcher),
+        });
+      }
+    }
+    shadowed_pools
+  }
+
+  fn has_pool(&self, name: &str) -> bool {
+    self.backend_pools.iter().any(|pool| pool.name == name)
+  }
+
+  /* Returns true if requests are routed to the pool at `index` without evaluating its matcher */
+  fn is_referenced(&self, index: usize) -> bool {
+    let name = self.backend_pools[index].name.as_str();
+    self.default_pool.as_deref() == Some(name)
+      || self.http10_pools.values().any(|it| it == name)
+      || self
+        .backend_pools
+        .iter()
+        .any(|pool| matches!(&pool.fallback, Some(fallback) if fallback.pool == name))
+      || self
+        .services
+        .iter()
+        .any(|service| service.pools.iter().any(|it| it.pool == name) || service.failover.iter().any(|it| it == name))
+  }
+
+  fn print_warnings(&self) {
+    let has_acme_certificates = self
+      .certificates
+      .iter()
+      .any(|(_, it)| matches!(it, CertificateConfig::ACME { .. }));
+    if self.http_address == DISABLED_ADDRESS && has_acme_certificates {
+      warn!("ACME certificates can not be issued, since the HTTP listener is disabled.");
+    }
+    if self.backend_pools.is_empty() {
+      warn!("No backend pool found.");
+    }
+    for pool in &self.backend_pools {
+      if pool.schemes().is_empty() {
+        warn!("backend pool '{}' is unreachable, since no schemes are registered. Consider adding `HTTP` or `HTTPS` to the schemes array.", pool.name);
+      }
+
+      if pool.addresses.is_empty() {
+        warn!(
+          "backend pool '{}' does not contain any addresses. It will always result in bad gateway errors.",
+          pool.name
+        );
+      }
+    }
+    for shadowed in self.shadowed_pools() {
+      // unreferenced pools with the same matcher are rejected by `validate`
+      if !shadowed.identical || self.is_referenced(shadowed.pool) {
+        warn!(
+          "backend pool '{}' is unreachable through its matcher, since backend pool '{}' comes first and matches all of its requests.",
+          self.backend_pools[shadowed.pool].name, self.backend_pools[shadowed.by].name
+        );
+      }
+    }
+  }
+}
+
+/* A backend pool whose requests are all matched by the earlier pool `by` */
+#[derive(Debug, PartialEq)]
+struct ShadowedPool {
+  pool: usize,
+  by: usize,
+  identical: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct BackendPoolConfig {
+  name: String,
   matcher: String,
   addresses: Vec<String>,
+  #[serde(default)]
   schemes: HashSet<Scheme>,
+  #[serde(default)]
+  https_only: bool,
   client: Option<ClientConfig>,
   #[serde(default = "default_health_config")]
   health_config: HealthTomlConfig,
   strategy: LoadBalancingStrategyConfig,
+  shadow_strategy: Option<LoadBalancingStrategyConfig>,
+  /* The options of the middlewares are parsed by the middlewares themselves */
   #[serde(default)]
+  #[schemars(with = "HashMap<String, serde_json::Map<String, serde_json::Value>>")]
   middlewares: Table,
+  fallback: Option<FallbackConfig>,
+  #[serde(default)]
+  trace_matcher: bool,
+  queue: Option<QueueConfig>,
+  max_connections: Option<MaxConnectionsConfig>,
+  no_healthy_backends: Option<NoHealthyBackendsConfig>,
+  #[serde(default)]
+  slow_weights: Vec<SlowWeightConfig>,
+  max_buffer_bytes: Option<usize>,
+  retry_after: Option<RetryAfterConfig>,
+  status: Option<PoolStatusConfig>,
+  hedging: Option<HedgingConfig>,
+  #[serde(default)]
+  streaming: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct PoolStatusConfig {
+  #[serde(default = "default_pool_status_path")]
+  path: String,
+  allowed_ips: Vec<IpAddr>,
+}
+
+fn default_pool_status_path() -> String {
+  "/_arlb/status".to_string()
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct RetryAfterConfig {
+  #[serde(default = "default_max_cooldown_sec")]
+  max_cooldown_sec: u64,
+}
+
+fn default_max_cooldown_sec() -> u64 {
+  300
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ServiceConfig {
+  name: String,
+  matcher: String,
+  pools: Vec<ServicePoolConfig>,
+  #[serde(default)]
+  failover: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ServicePoolConfig {
+  pool: String,
+  #[serde(default = "default_service_pool_weight")]
+  weight: f64,
+}
+
+fn default_service_pool_weight() -> f64 {
+  1.0
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct SlowWeightConfig {
+  above_ms: i64,
+  weight: f64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct QueueConfig {
+  max_requests_per_backend: usize,
+  #[serde(default = "default_queue_max_depth")]
+  max_depth: usize,
+  #[serde(default = "default_queue_max_wait_ms")]
+  max_wait_ms: u64,
+}
+
+fn default_queue_max_depth() -> usize {
+  100
+}
+
+fn default_queue_max_wait_ms() -> u64 {
+  1000
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct NoHealthyBackendsConfig {
+  #[serde(default = "default_use_slow_backends")]
+  use_slow_backends: bool,
+  #[serde(default = "default_no_healthy_backends_response")]
+  response: NoHealthyBackendsResponseConfig,
+}
+
+fn default_use_slow_backends() -> bool {
+  true
+}
+
+fn default_no_healthy_backends_response() -> NoHealthyBackendsResponseConfig {
+  NoHealthyBackendsResponseConfig::BadGateway
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+enum NoHealthyBackendsResponseConfig {
+  BadGateway,
+  StaticPage {
+    file: String,
+    #[serde(default = "default_static_page_status")]
+    status: u16,
+    #[serde(default = "default_error_pages_content_type")]
+    content_type: String,
+  },
+  Redirect {
+    location: String,
+  },
+  FailOpen,
+}
+
+fn default_static_page_status() -> u16 {
+  503
+}
+
+impl NoHealthyBackendsConfig {
+  fn load<P: AsRef<Path>>(self, config_dir: P) -> io::Result<NoHealthyBackends> {
+    let response = match self.response {
+      NoHealthyBackendsResponseConfig::BadGateway => NoHealthyBackendsResponse::BadGateway,
+      NoHealthyBackendsResponseConfig::StaticPage {
+        file,
+        status,
+        content_type,
+      } => NoHealthyBackendsResponse::StaticPage {
+        status: StatusCode::from_u16(status).map_err(invalid_data)?,
+        content_type: HeaderValue::from_str(&content_type).map_err(invalid_data)?,
+        body: fs::read_to_string(config_dir.as_ref().join(&file)).map_err(|e| {
+          io::Error::new(
+            e.kind(),
+            format!("Could not read the page for no healthy backends {}: {}", file, e),
+          )
+        })?,
+      },
+      NoHealthyBackendsResponseConfig::Redirect { location } => NoHealthyBackendsResponse::Redirect {
+        location: HeaderValue::from_str(&location).map_err(invalid_data)?,
+      },
+      NoHealthyBackendsResponseConfig::FailOpen => NoHealthyBackendsResponse::FailOpen,
+    };
+    Ok(NoHealthyBackends {
+      use_slow_backends: self.use_slow_backends,
+      response,
+    })
+  }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct HedgingConfig {
+  delay_ms: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct MaxConnectionsConfig {
+  per_address: usize,
+  #[serde(default)]
+  max_wait_ms: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct FallbackConfig {
+  pool: String,
+  max_error_rate: Option<f64>,
+  #[serde(default = "default_error_rate_window_sec")]
+  window_sec: u64,
+}
+
+fn default_error_rate_window_sec() -> u64 {
+  10
 }
 
 fn default_health_config() -> HealthTomlConfig {
@@ -348,31 +1687,127 @@ fn default_health_config() -> HealthTomlConfig {
     slow_threshold: default_slow_threshold(),
     timeout: default_timeout(),
     path: default_path(),
+    rise: default_rise(),
+    fall: default_fall(),
+    check_every: None,
+    port: None,
+    addresses: HashMap::new(),
+    warm_up: None,
+    initial_state: InitialState::default(),
+  }
+}
+
+/// The health checks of a backend pool configured with the defaults of the configuration file.
+impl Default for HealthConfig {
+  fn default() -> Self {
+    HealthConfig {
+      slow_threshold: default_slow_threshold(),
+      timeout: default_timeout(),
+      path: default_path(),
+      rise: default_rise(),
+      fall: default_fall(),
+      interval: None,
+      port: None,
+      addresses: HashMap::new(),
+      warm_up: None,
+    }
   }
 }
 
-impl From<BackendPoolConfig> for BackendPool {
-  fn from(other: BackendPoolConfig) -> Self {
-    // TODO: This conversion can fail, should we use TryFrom or wrap this in some kind of error?
-    let matcher = other.matcher.into();
-    let addresses = other
+/// Writes IP addresses in their canonical form, e.g. `[2001:DB8:0::1]:80` as `[2001:db8::1]:80`, so they compare
+/// equal to the addresses reported by the connections to the backend servers. Invalid addresses are rejected by
+/// [`TomlConfig::validate`] beforehand.
+fn normalize_address(address: String) -> String {
+  match address.parse::<BackendAddress>() {
+    Ok(backend_address) => backend_address.to_string(),
+    Err(_) => address,
+  }
+}
+
+impl BackendPoolConfig {
+  /* A pool which is `https_only` serves HTTP, to redirect the requests, and HTTPS */
+  fn schemes(&self) -> HashSet<Scheme> {
+    if self.https_only {
+      vec![Scheme::HTTP, Scheme::HTTPS].into_iter().collect()
+    } else {
+      self.schemes.clone()
+    }
+  }
+
+  fn into_backend_pool(
+    self,
+    matcher: BackendPoolMatcher,
+    config_dir: &Path,
+    pool_indices: &HashMap<String, usize>,
+    log_redaction: &LogRedaction,
+  ) -> io::Result<BackendPool> {
+    // before any field is moved out
+    let schemes = self.schemes();
+    let initial_healthiness = Healthiness::from(self.health_config.initial_state);
+    let addresses = self
       .addresses
       .into_iter()
-      .map(|address| (address, ArcSwap::from_pointee(Healthiness::Healthy)))
+      .map(|address| {
+        (
+          normalize_address(address),
+          BackendHealth::new(initial_healthiness.clone()),
+        )
+      })
       .collect();
-    let health_toml_config = other.health_config;
-    let strategy = other.strategy.into();
-    let chain = other.middlewares.into();
-    let schemes = other.schemes;
+    let health_toml_config = self.health_config;
+    let strategy = self.strategy.into();
+    let middlewares = if self.https_only {
+      // redirect before any other middleware handles the request
+      let redirector = ("HttpsRedirector".to_string(), Value::Table(Table::new()));
+      iter::once(redirector)
+        .chain(
+          self
+            .middlewares
+            .into_iter()
+            .filter(|(name, _)| name != "HttpsRedirector"),
+        )
+        .collect()
+    } else {
+      self.middlewares
+    };
+    let name = &self.name;
+    let mut chain = MiddlewareChain::try_from((middlewares, config_dir))
+      .map_err(|e| invalid_data(format!("backend pool '{}': {}", name, e)))?;
+    if self.streaming {
+      let (streaming_chain, removed) = chain.without_buffering();
+      if !removed.is_empty() {
+        let removed: Vec<_> = removed.iter().map(debug_headers::type_name).collect();
+        warn!(
+          "Backend pool '{}' is streaming, ignoring its buffering middlewares {}",
+          name,
+          removed.join(", ")
+        );
+      }
+      chain = streaming_chain;
+      /* a streamed response may never end, so a hedged request would keep a second backend server busy */
+      if self.hedging.is_some() {
+        warn!("Backend pool '{}' is streaming, ignoring hedging", name);
+      }
+    }
 
     let health_config = HealthConfig {
       slow_threshold: health_toml_config.slow_threshold,
       timeout: health_toml_config.timeout,
       path: health_toml_config.path,
+      rise: health_toml_config.rise,
+      fall: health_toml_config.fall,
+      interval: health_toml_config.check_every.map(Duration::from_secs),
+      port: health_toml_config.port,
+      addresses: health_toml_config
+        .addresses
+        .into_iter()
+        .map(|(address, probe_address)| (normalize_address(address), probe_address))
+        .collect(),
+      warm_up: health_toml_config.warm_up,
     };
 
-    let mut builder = BackendPoolBuilder::new(matcher, addresses, health_config, strategy, chain, schemes);
-    if let Some(client) = other.client {
+    let mut builder = BackendPoolBuilder::new(self.name, matcher, addresses, health_config, strategy, chain, schemes);
+    if let Some(client) = self.client {
       if let Some(pool_idle_timeout) = client.pool_idle_timeout {
         builder.pool_idle_timeout(pool_idle_timeout);
       }
@@ -382,17 +1817,88 @@ impl From<BackendPoolConfig> for BackendPool {
       }
     }
 
-    builder.build()
+    if let Some(fallback) = self.fallback {
+      let window = Duration::from_secs(fallback.window_sec);
+      builder.fallback(Fallback {
+        pool: pool_indices[&fallback.pool],
+        error_budget: fallback
+          .max_error_rate
+          .map(|max_error_rate| ErrorBudget::new(max_error_rate, window)),
+      });
+    }
+
+    if self.trace_matcher {
+      builder.trace_matcher();
+    }
+    builder.log_redaction(log_redaction.clone());
+
+    if let Some(max_buffer_bytes) = self.max_buffer_bytes {
+      builder.max_buffer_bytes(max_buffer_bytes);
+    }
+
+    if let Some(queue) = self.queue {
+      builder.admission_queue(BackendLimit::queue(
+        queue.max_requests_per_backend,
+        queue.max_depth,
+        Duration::from_millis(queue.max_wait_ms),
+      ));
+    }
+
+    if let Some(max_connections) = self.max_connections {
+      builder.backend_limit(BackendLimit::new(
+        max_connections.per_address,
+        Duration::from_millis(max_connections.max_wait_ms),
+      ));
+    }
+
+    if !self.slow_weights.is_empty() {
+      builder.slow_weights(SlowWeights::new(
+        self
+          .slow_weights
+          .iter()
+          .map(|range| (range.above_ms, range.weight))
+          .collect(),
+      ));
+    }
+
+    if let Some(retry_after) = self.retry_after {
+      builder.cooldown(Cooldown::new(Duration::from_secs(retry_after.max_cooldown_sec)));
+    }
+
+    if self.streaming {
+      builder.streaming();
+    } else if let Some(hedging) = self.hedging {
+      builder.hedging(Hedging {
+        delay: Duration::from_millis(hedging.delay_ms),
+      });
+    }
+
+    if let Some(no_healthy_backends) = self.no_healthy_backends {
+      builder.no_healthy_backends(no_healthy_backends.load(config_dir)?);
+    }
+
+    if let Some(shadow_strategy) = self.shadow_strategy {
+      builder.shadow_strategy(ShadowStrategy::new(shadow_strategy.into()));
+    }
+
+    if let Some(status) = self.status {
+      builder.status(PoolStatus {
+        path: status.path,
+        allowed_ips: status.allowed_ips,
+      });
+    }
+
+    Ok(builder.build())
   }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct ClientConfig {
   pool_idle_timeout: Option<Duration>,
   pool_max_idle_per_host: Option<usize>,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, PartialEq)]
 enum LoadBalancingStrategyConfig {
   StickyCookie {
     cookie_name: String,
@@ -400,13 +1906,71 @@ enum LoadBalancingStrategyConfig {
     secure: bool,
     same_site: StickyCookieSameSite,
     inner: Box<LoadBalancingStrategyConfig>,
+    max_age: Option<u64>,
+    path: Option<String>,
+    domain: Option<String>,
+    on_unhealthy_backend: Option<AffinityFailoverConfig>,
+  },
+  StickyIP {
+    inner: Box<LoadBalancingStrategyConfig>,
+    idle_timeout_sec: Option<u64>,
+    max_clients: Option<usize>,
+  },
+  WithFallback {
+    primary: Box<LoadBalancingStrategyConfig>,
+    fallback: Box<LoadBalancingStrategyConfig>,
   },
   Random,
   IPHash,
   LeastConnection,
+  LeastBandwidth {
+    window_sec: Option<u64>,
+  },
   RoundRobin,
 }
 
+impl LoadBalancingStrategyConfig {
+  /* Only some strategies take the weights of slow backend servers into account, see `SlowWeights` */
+  fn uses_weights(&self) -> bool {
+    match self {
+      LoadBalancingStrategyConfig::StickyCookie { inner, .. } | LoadBalancingStrategyConfig::StickyIP { inner, .. } => {
+        inner.uses_weights()
+      }
+      LoadBalancingStrategyConfig::WithFallback { primary, fallback } => {
+        primary.uses_weights() || fallback.uses_weights()
+      }
+      LoadBalancingStrategyConfig::Random | LoadBalancingStrategyConfig::LeastBandwidth { .. } => true,
+      LoadBalancingStrategyConfig::IPHash
+      | LoadBalancingStrategyConfig::LeastConnection
+      | LoadBalancingStrategyConfig::RoundRobin => false,
+    }
+  }
+
+  fn validate(&self) -> Result<(), String> {
+    match self {
+      LoadBalancingStrategyConfig::StickyCookie {
+        cookie_name,
+        inner,
+        path,
+        domain,
+        ..
+      } => {
+        validate_cookie_prefix(cookie_name, path.as_deref(), domain.as_deref())?;
+        inner.validate()
+      }
+      LoadBalancingStrategyConfig::StickyIP { inner, .. } => inner.validate(),
+      LoadBalancingStrategyConfig::WithFallback { primary, fallback } => {
+        primary.validate()?;
+        fallback.validate()
+      }
+      LoadBalancingStrategyConfig::LeastBandwidth { window_sec: Some(0) } => {
+        Err("window_sec of LeastBandwidth must be greater than 0".to_string())
+      }
+      _ => Ok(()),
+    }
+  }
+}
+
 impl From<LoadBalancingStrategyConfig> for Box<dyn LoadBalancingStrategy> {
   fn from(other: LoadBalancingStrategyConfig) -> Self {
     match other {
@@ -416,31 +1980,68 @@ impl From<LoadBalancingStrategyConfig> for Box<dyn LoadBalancingStrategy> {
         secure,
         same_site,
         inner,
+        max_age,
+        path,
+        domain,
+        on_unhealthy_backend,
       } => {
         let inner = (*inner).into();
-        Box::new(StickyCookie::new(
-          cookie_name,
-          inner,
-          http_only,
-          secure,
-          same_site.into(),
-        ))
+        let mut sticky_cookie = StickyCookie::new(cookie_name, inner, http_only, secure, same_site.into());
+        sticky_cookie.max_age = max_age.map(Duration::from_secs);
+        sticky_cookie.path = path;
+        sticky_cookie.domain = domain;
+        if let Some(on_unhealthy_backend) = on_unhealthy_backend {
+          sticky_cookie.on_unhealthy_backend = on_unhealthy_backend.into();
+        }
+        Box::new(sticky_cookie)
+      }
+      LoadBalancingStrategyConfig::StickyIP {
+        inner,
+        idle_timeout_sec,
+        max_clients,
+      } => Box::new(StickyIP::new(
+        (*inner).into(),
+        idle_timeout_sec.map_or(sticky_ip::DEFAULT_IDLE_TIMEOUT, Duration::from_secs),
+        max_clients.unwrap_or(sticky_ip::DEFAULT_MAX_CLIENTS),
+      )),
+      LoadBalancingStrategyConfig::WithFallback { primary, fallback } => {
+        Box::new(WithFallback::new((*primary).into(), (*fallback).into()))
       }
       LoadBalancingStrategyConfig::Random => Box::new(Random::new()),
       LoadBalancingStrategyConfig::IPHash => Box::new(IPHash::new()),
       LoadBalancingStrategyConfig::RoundRobin => Box::new(RoundRobin::new()),
       LoadBalancingStrategyConfig::LeastConnection => Box::new(LeastConnection::new()),
+      LoadBalancingStrategyConfig::LeastBandwidth { window_sec } => Box::new(LeastBandwidth::new(
+        window_sec.map_or(least_bandwidth::DEFAULT_WINDOW, Duration::from_secs),
+      )),
     }
   }
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, PartialEq)]
 pub enum StickyCookieSameSite {
   Strict,
   Lax,
   None,
 }
 
+#[derive(Debug, Deserialize, JsonSchema, PartialEq)]
+pub enum AffinityFailoverConfig {
+  Reassign,
+  Reject,
+  Keep,
+}
+
+impl From<AffinityFailoverConfig> for AffinityFailover {
+  fn from(other: AffinityFailoverConfig) -> Self {
+    match other {
+      AffinityFailoverConfig::Reassign => AffinityFailover::Reassign,
+      AffinityFailoverConfig::Reject => AffinityFailover::Reject,
+      AffinityFailoverConfig::Keep => AffinityFailover::Keep,
+    }
+  }
+}
+
 impl From<StickyCookieSameSite> for cookie::SameSite {
   fn from(other: StickyCookieSameSite) -> Self {
     match other {
@@ -451,72 +2052,181 @@ impl From<StickyCookieSameSite> for cookie::SameSite {
   }
 }
 
-impl From<Table> for MiddlewareChain {
-  fn from(other: Table) -> Self {
+/// Builds the middlewares of a backend pool, resolving relative paths against the `config_dir`. The optional
+/// `matcher` of a middleware restricts it to the requests satisfying the matcher.
+impl TryFrom<(Table, &Path)> for MiddlewareChain {
+  type Error = io::Error;
+
+  fn try_from((other, config_dir): (Table, &Path)) -> Result<Self, Self::Error> {
     let mut chain = MiddlewareChain::Empty;
-    for middleware in other.into_iter().rev() {
-      if let Ok(middleware) = middleware.try_into() {
+    for (name, mut payload) in other.into_iter().rev() {
+      let matcher = match payload.as_table_mut().and_then(|it| it.remove("matcher")) {
+        Some(Value::String(matcher)) => Some(
+          matcher
+            .parse::<BackendPoolMatcher>()
+            .map_err(|e| invalid_data(format!("middleware '{}' has an invalid matcher: {}", name, e)))?,
+        ),
+        Some(_) => {
+          return Err(invalid_data(format!(
+            "the matcher of middleware '{}' must be a string",
+            name
+          )))
+        }
+        None => None,
+      };
+      if let Ok(middleware) = (name, payload, config_dir).try_into() {
         chain = MiddlewareChain::Entry {
           middleware,
+          matcher,
           chain: Box::new(chain),
         };
       }
     }
-    chain
+    Ok(chain)
   }
 }
 
-impl TryFrom<(String, Value)> for Box<dyn Middleware> {
+impl TryFrom<(String, Value, &Path)> for Box<dyn Middleware> {
   type Error = ();
 
-  fn try_from((name, payload): (String, Value)) -> Result<Self, Self::Error> {
+  fn try_from((name, payload, config_dir): (String, Value, &Path)) -> Result<Self, Self::Error> {
     match (name.as_str(), payload) {
-      ("RateLimiter", Value::Table(t)) => Ok(Box::new(RateLimiter::new(
-        t.get("limit")
-          .and_then(Value::as_integer)
-          .and_then(|it| it.try_into().ok())
-          .ok_or(())?,
-        t.get("window_sec")
-          .and_then(Value::as_integer)
-          .and_then(|it| it.try_into().ok())
-          .ok_or(())?,
-      ))),
+      ("RateLimiter", Value::Table(t)) => Ok(Box::new(RateLimiter::try_from(t)?)),
       ("Authentication", Value::Table(t)) => Ok(Box::new(Authentication {
         ldap_address: t.get("ldap_address").and_then(Value::as_str).ok_or(())?.to_string(),
         user_directory: t.get("user_directory").and_then(Value::as_str).ok_or(())?.to_string(),
         rdn_identifier: t.get("rdn_identifier").and_then(Value::as_str).ok_or(())?.to_string(),
         recursive: t.get("recursive").and_then(Value::as_bool).ok_or(())?,
       })),
-      ("Compression", _) => Ok(Box::new(Compression)),
+      ("Compression", Value::Table(t)) => Ok(Box::new(Compression::try_from(t)?)),
+      ("Compression", _) => Ok(Box::new(Compression::default())),
+      ("ConditionalRequests", Value::Table(t)) => Ok(Box::new(ConditionalRequests::try_from(t)?)),
       ("HttpsRedirector", _) => Ok(Box::new(HttpsRedirector)),
-      ("MaxBodySize", Value::Table(t)) => Ok(Box::new(MaxBodySize {
-        limit: t.get("limit").and_then(Value::as_integer).ok_or(())?,
-      })),
-      ("CustomErrorPages", Value::Table(t)) => Ok(Box::new(CustomErrorPages::try_from(t)?)),
+      ("CanonicalHost", Value::Table(t)) => Ok(Box::new(CanonicalHost::try_from(t)?)),
+      ("RangeRequests", Value::Table(t)) => Ok(Box::new(RangeRequests::try_from(t)?)),
+      ("MaxBodySize", Value::Table(t)) => Ok(Box::new(MaxBodySize::try_from(t)?)),
+      ("BodyChecksum", Value::Table(t)) => Ok(Box::new(BodyChecksum::try_from((t, config_dir))?)),
+      ("CustomErrorPages", Value::Table(t)) => Ok(Box::new(CustomErrorPages::try_from(t)?.relative_to(config_dir))),
+      ("ABTest", Value::Table(t)) => Ok(Box::new(ABTest::try_from(t)?)),
+      ("AllowedMethods", Value::Table(t)) => Ok(Box::new(AllowedMethods::try_from(t)?)),
+      ("ResponseDigest", Value::Table(t)) => Ok(Box::new(ResponseDigest::try_from(t)?)),
+      ("ResponseDigest", _) => Ok(Box::new(ResponseDigest::default())),
+      ("HtmlInjection", Value::Table(t)) => Ok(Box::new(HtmlInjection::try_from(t)?)),
+      ("HostRewriter", Value::Table(t)) => Ok(Box::new(HostRewriter::try_from(t)?)),
       _ => Err(()),
     }
   }
 }
 
-#[derive(Debug, Deserialize)]
+/* Keeps the certificates in the order of the configuration file, which decides between certificates sharing a name */
+fn certificates_in_order<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> Result<Vec<(String, CertificateConfig)>, D::Error> {
+  struct InOrder;
+
+  impl<'de> Visitor<'de> for InOrder {
+    type Value = Vec<(String, CertificateConfig)>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+      formatter.write_str("a table of certificates by name")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<Self::Value, M::Error> {
+      let mut certificates = Vec::new();
+      while let Some(entry) = map.next_entry()? {
+        certificates.push(entry);
+      }
+      Ok(certificates)
+    }
+  }
+
+  deserializer.deserialize_map(InOrder)
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 pub enum CertificateConfig {
   Local {
     certificate_path: String,
     private_key_path: String,
+    #[serde(default)]
+    names: Vec<String>,
   },
   ACME {
+    #[serde(default)]
     staging: bool,
+    /* Overrides `staging`, defaults to Let's Encrypt */
+    directory_url: Option<String>,
     email: String,
     persist_dir: String,
+    account_key_path: Option<String>,
+    external_account_binding: Option<ExternalAccountBindingConfig>,
   },
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ExternalAccountBindingConfig {
+  key_id: String,
+  hmac_key: Option<String>,
+  hmac_key_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Default)]
 pub struct HealthIntervalConfig {
+  #[serde(default = "default_check_every")]
   pub check_every: u64,
+  #[serde(default)]
+  pub stagger: bool,
+  pub max_concurrent_checks: Option<usize>,
+  #[serde(default)]
+  pub initial_check: bool,
+  pub redis_url: Option<String>,
+  #[serde(default = "default_health_key_prefix")]
+  pub redis_key_prefix: String,
+}
+
+fn default_check_every() -> u64 {
+  10
+}
+
+fn default_health_key_prefix() -> String {
+  health_coordination::DEFAULT_KEY_PREFIX.to_string()
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq, Default)]
+#[derive(Debug, Deserialize, JsonSchema)]
+enum HealthEventsConfig {
+  File {
+    path: String,
+  },
+  Udp {
+    address: String,
+    #[serde(default)]
+    syslog: bool,
+  },
+  Http {
+    url: String,
+  },
+}
+
+fn health_event_sink<P: AsRef<Path>>(config_dir: P, config: HealthEventsConfig) -> io::Result<HealthEventSink> {
+  match config {
+    HealthEventsConfig::File { path } => Ok(HealthEventSink::File(config_dir.as_ref().join(path))),
+    HealthEventsConfig::Udp { address, syslog } => {
+      let address = address
+        .parse()
+        .map_err(|e| invalid_data(format!("invalid health events address '{}' due to: {}", address, e)))?;
+      Ok(HealthEventSink::Udp { address, syslog })
+    }
+    HealthEventsConfig::Http { url } => match url.parse::<Uri>() {
+      Ok(uri) if uri.scheme_str() == Some("http") && uri.host().is_some() => Ok(HealthEventSink::Http(uri)),
+      _ => Err(invalid_data(format!(
+        "invalid health events url '{}', expected an absolute http url",
+        url
+      ))),
+    },
+  }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, PartialEq, Eq, Default)]
 pub struct HealthTomlConfig {
   #[serde(default = "default_slow_threshold")]
   pub slow_threshold: i64,
@@ -524,6 +2234,40 @@ pub struct HealthTomlConfig {
   pub timeout: u64,
   #[serde(default = "default_path")]
   pub path: String,
+  #[serde(default = "default_rise")]
+  pub rise: usize,
+  #[serde(default = "default_fall")]
+  pub fall: usize,
+  pub check_every: Option<u64>,
+  pub port: Option<u16>,
+  #[serde(default)]
+  pub addresses: HashMap<String, String>,
+  pub warm_up: Option<WarmUpConfig>,
+  #[serde(default)]
+  pub initial_state: InitialState,
+}
+
+/* The healthiness of backend servers until their first health check */
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InitialState {
+  Healthy,
+  Unknown,
+}
+
+impl Default for InitialState {
+  fn default() -> Self {
+    InitialState::Healthy
+  }
+}
+
+impl From<InitialState> for Healthiness {
+  fn from(other: InitialState) -> Self {
+    match other {
+      InitialState::Healthy => Healthiness::Healthy,
+      InitialState::Unknown => Healthiness::Unknown,
+    }
+  }
 }
 
 fn default_slow_threshold() -> i64 {
@@ -537,3 +2281,745 @@ fn default_timeout() -> u64 {
 fn default_path() -> String {
   "/".to_string()
 }
+
+fn default_rise() -> usize {
+  1
+}
+
+fn default_fall() -> usize {
+  1
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn toml_config(addresses: &str) -> TomlConfig {
+    toml::from_str(&format!(
+      r#"
+        [[backend_pools]]
+        name = "whoami"
+        matcher = "Host('whoami.localhost')"
+        addresses = {}
+        schemes = ["HTTP"]
+        strategy = {{ RoundRobin = {{}} }}
+      "#,
+      addresses
+    ))
+    .unwrap()
+  }
+
+  #[test]
+  fn validate_rejects_pool_path_prefix_overlapping_probe_paths() {
+    let mut config = toml_config(r#"["127.0.0.1:8080"]"#);
+    config.health_endpoint = Some(HealthEndpointTomlConfig {
+      address: "127.0.0.1:8082".to_string(),
+      liveness_path: default_liveness_path(),
+      readiness_path: default_readiness_path(),
+      pool_path_prefix: default_pool_path_prefix(),
+    });
+    assert!(config.validate().is_ok());
+
+    config.health_endpoint.as_mut().unwrap().pool_path_prefix = "/".to_string();
+    assert!(config.validate().is_err());
+  }
+
+  #[test]
+  fn validate_rejects_max_error_rate_outside_of_0_and_1() {
+    let mut config = toml_config(r#"["127.0.0.1:8080"]"#);
+    config
+      .backend_pools
+      .push(toml_config(r#"["127.0.0.1:8081"]"#).backend_pools.remove(0));
+    config.backend_pools[1].name = "fallback".to_string();
+    config.backend_pools[1].matcher = "Host('fallback.localhost')".to_string();
+    let fallback = |max_error_rate| FallbackConfig {
+      pool: "fallback".to_string(),
+      max_error_rate: Some(max_error_rate),
+      window_sec: default_error_rate_window_sec(),
+    };
+
+    config.backend_pools[0].fallback = Some(fallback(0.5));
+    assert!(config.validate().is_ok());
+    config.backend_pools[0].fallback = Some(fallback(1.5));
+    assert!(config.validate().is_err());
+    config.backend_pools[0].fallback = Some(fallback(f64::NAN));
+    assert!(config.validate().is_err());
+  }
+
+  #[test]
+  fn validate_rejects_references_to_unknown_pools() {
+    let mut default_pool = toml_config(r#"["127.0.0.1:8080"]"#);
+    default_pool.default_pool = Some("other".to_string());
+    let mut http10_pool = toml_config(r#"["127.0.0.1:8080"]"#);
+    http10_pool.http10_pools.insert(Scheme::HTTP, "other".to_string());
+    let mut fallback = toml_config(r#"["127.0.0.1:8080"]"#);
+    fallback.backend_pools[0].fallback = Some(FallbackConfig {
+      pool: "whoami".to_string(),
+      max_error_rate: None,
+      window_sec: default_error_rate_window_sec(),
+    });
+
+    assert_eq!(
+      default_pool.validate().unwrap_err().to_string(),
+      "default_pool references the unknown backend pool 'other'"
+    );
+    assert_eq!(
+      http10_pool.validate().unwrap_err().to_string(),
+      "the HTTP/1.0 pool for http references the unknown backend pool 'other'"
+    );
+    assert_eq!(
+      fallback.validate().unwrap_err().to_string(),
+      "backend pool 'whoami' has an invalid fallback pool 'whoami'"
+    );
+  }
+
+  #[test]
+  fn pool_references_are_resolved_to_indices() {
+    let mut config = pools_config(&[
+      ("whoami", "Host('whoami.localhost')", r#"["HTTP"]"#),
+      ("maintenance", "Host('maintenance.localhost')", r#"["HTTP"]"#),
+    ]);
+    config.default_pool = Some("maintenance".to_string());
+    config.http10_pools.insert(Scheme::HTTP, "maintenance".to_string());
+    config.backend_pools[0].fallback = Some(FallbackConfig {
+      pool: "maintenance".to_string(),
+      max_error_rate: None,
+      window_sec: default_error_rate_window_sec(),
+    });
+    assert!(config.validate().is_ok());
+
+    let config =
+      runtime_config_from_toml_config(".", config, Arc::new(AcmeHandler::new()), &MatcherCache::default()).unwrap();
+
+    assert_eq!(config.shared_data.default_pool, Some(1));
+    assert_eq!(config.shared_data.http10_pools[&Scheme::HTTP], 1);
+    assert_eq!(config.shared_data.backend_pools[0].fallback.as_ref().unwrap().pool, 1);
+  }
+
+  #[test]
+  fn validate_rejects_queue_without_requests_per_backend() {
+    let mut config = toml_config(r#"["127.0.0.1:8080"]"#);
+    config.backend_pools[0].queue = Some(QueueConfig {
+      max_requests_per_backend: 0,
+      max_depth: default_queue_max_depth(),
+      max_wait_ms: default_queue_max_wait_ms(),
+    });
+
+    assert!(config.validate().is_err());
+  }
+
+  #[test]
+  fn validate_accepts_ipv6_backend_addresses() {
+    let config = toml_config(r#"["[2001:db8::1]:80", "[::1]:8080", "127.0.0.1:8080", "whoami:80"]"#);
+
+    assert!(config.validate().is_ok());
+  }
+
+  #[test]
+  fn validate_rejects_ipv6_backend_addresses_without_brackets() {
+    let config = toml_config(r#"["2001:db8::1:80"]"#);
+
+    assert!(config.validate().is_err());
+  }
+
+  #[test]
+  fn validate_names_pool_and_index_of_invalid_addresses() {
+    let config = toml_config(r#"["127.0.0.1:8080", "whoami:http"]"#);
+
+    let error = config.validate().unwrap_err();
+
+    assert_eq!(
+      error.to_string(),
+      "backend pool 'whoami' has an invalid address 'whoami:http' at index 1: has an invalid port 'http'"
+    );
+  }
+
+  fn pools_config(pools: &[(&str, &str, &str)]) -> TomlConfig {
+    let pools = pools
+      .iter()
+      .map(|(name, matcher, schemes)| {
+        format!(
+          r#"
+            [[backend_pools]]
+            name = "{}"
+            matcher = "{}"
+            addresses = ["127.0.0.1:8080"]
+            schemes = {}
+            strategy = {{ RoundRobin = {{}} }}
+          "#,
+          name, matcher, schemes
+        )
+      })
+      .collect::<String>();
+    toml::from_str(&pools).unwrap()
+  }
+
+  #[test]
+  fn validate_rejects_pools_with_the_same_matcher() {
+    let config = pools_config(&[
+      ("whoami", "Host('whoami.localhost')", r#"["HTTP", "HTTPS"]"#),
+      ("whoami-copy", "Host('whoami.localhost')", r#"["HTTPS"]"#),
+    ]);
+
+    let error = config.validate().unwrap_err();
+
+    assert_eq!(
+      error.to_string(),
+      "backend pool 'whoami-copy' is unreachable, since backend pool 'whoami' comes first and has the same matcher"
+    );
+  }
+
+  #[test]
+  fn validate_accepts_the_same_matcher_for_other_schemes_or_referenced_pools() {
+    let other_schemes = pools_config(&[
+      ("whoami", "Host('whoami.localhost')", r#"["HTTP"]"#),
+      ("whoami-tls", "Host('whoami.localhost')", r#"["HTTPS"]"#),
+    ]);
+    let mut default_pool = pools_config(&[
+      ("whoami", "Host('whoami.localhost')", r#"["HTTP"]"#),
+      ("whoami-default", "Host('whoami.localhost')", r#"["HTTP"]"#),
+    ]);
+    default_pool.default_pool = Some("whoami-default".to_string());
+
+    assert!(other_schemes.validate().is_ok());
+    assert!(default_pool.validate().is_ok());
+  }
+
+  #[test]
+  fn finds_pools_shadowed_by_wider_matchers() {
+    let config = pools_config(&[
+      ("admin", "Host('whoami.localhost') && Path('/admin')", r#"["HTTP"]"#),
+      ("whoami", "Host('whoami.localhost')", r#"["HTTP"]"#),
+      ("whoami-api", "Host('whoami.localhost') && Path('/api')", r#"["HTTP"]"#),
+      (
+        "whoami-tls",
+        "Host('whoami.localhost') && Path('/api')",
+        r#"["HTTP", "HTTPS"]"#,
+      ),
+    ]);
+
+    assert_eq!(
+      config.shadowed_pools(),
+      vec![ShadowedPool {
+        pool: 2,
+        by: 1,
+        identical: false,
+      }]
+    );
+    assert!(config.validate().is_ok());
+  }
+
+  fn https_only_config(schemes: &str) -> TomlConfig {
+    toml::from_str(&format!(
+      r#"
+        [[backend_pools]]
+        name = "whoami"
+        matcher = "Host('whoami.localhost')"
+        addresses = ["127.0.0.1:8080"]
+        https_only = true
+        {}
+        strategy = {{ RoundRobin = {{}} }}
+
+        [backend_pools.middlewares.Compression]
+      "#,
+      schemes
+    ))
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn https_only_pools_serve_both_schemes_and_redirect_first() {
+    let mut config = https_only_config("");
+    assert!(config.validate().is_ok());
+
+    let pool_config = config.backend_pools.remove(0);
+    let pool = pool_config
+      .into_backend_pool(
+        "Host('whoami.localhost')".parse().unwrap(),
+        Path::new("."),
+        &HashMap::new(),
+        &LogRedaction::default(),
+      )
+      .unwrap();
+
+    assert_eq!(pool.schemes, vec![Scheme::HTTP, Scheme::HTTPS].into_iter().collect());
+    assert!(
+      format!("{:?}", pool.chain).starts_with("Entry { middleware: HttpsRedirector"),
+      "{:?}",
+      pool.chain
+    );
+  }
+
+  #[tokio::test]
+  async fn builds_pools_hedging_requests() {
+    let mut config: TomlConfig = toml::from_str(
+      r#"
+        [[backend_pools]]
+        name = "whoami"
+        matcher = "Host('whoami.localhost')"
+        addresses = ["127.0.0.1:8080", "127.0.0.1:8081"]
+        schemes = ["HTTP"]
+        strategy = { RoundRobin = {} }
+        hedging = { delay_ms = 50 }
+      "#,
+    )
+    .unwrap();
+
+    let pool = config
+      .backend_pools
+      .remove(0)
+      .into_backend_pool(
+        "Host('whoami.localhost')".parse().unwrap(),
+        Path::new("."),
+        &HashMap::new(),
+        &LogRedaction::default(),
+      )
+      .unwrap();
+
+    assert_eq!(
+      pool.hedging,
+      Some(Hedging {
+        delay: Duration::from_millis(50)
+      })
+    );
+  }
+
+  #[tokio::test]
+  async fn streaming_pools_drop_buffering_middlewares_and_hedging() {
+    // given:
+    let mut config: TomlConfig = toml::from_str(
+      r#"
+        [[backend_pools]]
+        name = "events"
+        matcher = "Host('events.localhost')"
+        addresses = ["127.0.0.1:8080", "127.0.0.1:8081"]
+        schemes = ["HTTP"]
+        strategy = { RoundRobin = {} }
+        streaming = true
+        hedging = { delay_ms = 50 }
+
+        [backend_pools.middlewares.Compression]
+        [backend_pools.middlewares.MaxBodySize]
+        limit = 1024
+      "#,
+    )
+    .unwrap();
+
+    // when:
+    let pool = config
+      .backend_pools
+      .remove(0)
+      .into_backend_pool(
+        "Host('events.localhost')".parse().unwrap(),
+        Path::new("."),
+        &HashMap::new(),
+        &LogRedaction::default(),
+      )
+      .unwrap();
+
+    // then:
+    assert!(pool.streaming);
+    assert_eq!(pool.hedging, None);
+    let chain = format!("{:?}", pool.chain);
+    assert!(chain.starts_with("Entry { middleware: MaxBodySize"), "{}", chain);
+    assert!(!chain.contains("Compression"), "{}", chain);
+  }
+
+  #[test]
+  fn builds_conditional_middlewares() {
+    let middlewares: Table = toml::from_str(
+      r#"
+        [Compression]
+        matcher = "PathRegexp('^/static/')"
+
+        [AllowedMethods]
+        methods = ["GET"]
+      "#,
+    )
+    .unwrap();
+
+    let chain = MiddlewareChain::try_from((middlewares, Path::new("."))).unwrap();
+
+    match chain {
+      MiddlewareChain::Entry { matcher, chain, .. } => {
+        assert!(matcher.is_some());
+        assert!(matches!(*chain, MiddlewareChain::Entry { matcher: None, .. }));
+      }
+      MiddlewareChain::Empty => panic!("expected a middleware"),
+    }
+  }
+
+  #[test]
+  fn rejects_invalid_middleware_matchers() {
+    let middlewares: Table = toml::from_str(
+      r#"
+        [Compression]
+        matcher = "Path('/static'"
+      "#,
+    )
+    .unwrap();
+
+    let error = MiddlewareChain::try_from((middlewares, Path::new("."))).unwrap_err();
+
+    assert!(error
+      .to_string()
+      .starts_with("middleware 'Compression' has an invalid matcher"));
+  }
+
+  #[tokio::test]
+  async fn builds_shadow_strategy_of_pools() {
+    let mut config: TomlConfig = toml::from_str(
+      r#"
+        [[backend_pools]]
+        name = "whoami"
+        matcher = "Host('whoami.localhost')"
+        addresses = ["127.0.0.1:8080", "127.0.0.1:8081"]
+        strategy = { RoundRobin = {} }
+        shadow_strategy = { IPHash = {} }
+      "#,
+    )
+    .unwrap();
+    assert!(config.validate().is_ok());
+
+    let pool = config
+      .backend_pools
+      .remove(0)
+      .into_backend_pool(
+        "Host('whoami.localhost')".parse().unwrap(),
+        Path::new("."),
+        &HashMap::new(),
+        &LogRedaction::default(),
+      )
+      .unwrap();
+
+    assert_eq!(pool.shadow_strategy.map(|it| it.name()), Some("IPHash".to_string()));
+  }
+
+  #[test]
+  fn validate_rejects_slow_weights_of_strategies_ignoring_weights() {
+    let mut config = toml_config(r#"["127.0.0.1:8080"]"#);
+    config.backend_pools[0].slow_weights = vec![SlowWeightConfig {
+      above_ms: 200,
+      weight: 0.5,
+    }];
+
+    let round_robin = config.validate().unwrap_err();
+    config.backend_pools[0].strategy = LoadBalancingStrategyConfig::StickyIP {
+      inner: Box::new(LoadBalancingStrategyConfig::Random),
+      idle_timeout_sec: None,
+      max_clients: None,
+    };
+
+    assert_eq!(
+      round_robin.to_string(),
+      "backend pool 'whoami' has slow_weights, but its strategy ignores weights"
+    );
+    assert!(config.validate().is_ok());
+  }
+
+  #[test]
+  fn validate_rejects_least_bandwidth_without_window() {
+    let mut config = toml_config(r#"["127.0.0.1:8080"]"#);
+    config.backend_pools[0].strategy = LoadBalancingStrategyConfig::WithFallback {
+      primary: Box::new(LoadBalancingStrategyConfig::LeastBandwidth { window_sec: Some(0) }),
+      fallback: Box::new(LoadBalancingStrategyConfig::RoundRobin),
+    };
+
+    let error = config.validate().unwrap_err();
+
+    assert_eq!(
+      error.to_string(),
+      "backend pool 'whoami' has an invalid strategy: window_sec of LeastBandwidth must be greater than 0"
+    );
+  }
+
+  #[test]
+  fn validate_rejects_zero_certificate_check_interval() {
+    let mut config = toml_config(r#"["127.0.0.1:8080"]"#);
+    config.certificate_expiry.check_every_sec = 0;
+
+    let error = config.validate().unwrap_err();
+
+    assert_eq!(
+      error.to_string(),
+      "check_every_sec of certificate_expiry must be greater than 0"
+    );
+  }
+
+  #[test]
+  fn validate_rejects_zero_concurrent_health_checks() {
+    let mut config = toml_config(r#"["127.0.0.1:8080"]"#);
+    config.health_interval.max_concurrent_checks = Some(0);
+
+    let error = config.validate().unwrap_err();
+
+    assert_eq!(error.to_string(), "max_concurrent_checks must be greater than 0");
+  }
+
+  #[test]
+  fn validate_rejects_zero_connections_per_client() {
+    let mut config = toml_config(r#"["127.0.0.1:8080"]"#);
+    config.connection_limit = Some(ConnectionLimitConfig {
+      max_per_client: 0,
+      ban_after: None,
+      ban_sec: default_ban_sec(),
+    });
+
+    let error = config.validate().unwrap_err();
+
+    assert_eq!(
+      error.to_string(),
+      "max_per_client of connection_limit must be greater than 0"
+    );
+  }
+
+  #[test]
+  fn validate_rejects_https_only_pools_with_other_schemes() {
+    assert!(https_only_config(r#"schemes = ["HTTP", "HTTPS"]"#).validate().is_ok());
+    assert!(https_only_config(r#"schemes = ["HTTPS"]"#).validate().is_err());
+  }
+
+  #[test]
+  fn certificates_keep_the_order_of_the_configuration() {
+    let config: TomlConfig = toml::from_str(
+      r#"
+        [certificates]
+        "www.localhost" = { Local = { certificate_path = "www.cer", private_key_path = "www.key" } }
+        "api.localhost" = { Local = { certificate_path = "api.cer", private_key_path = "api.key" } }
+        "app.localhost" = { Local = { certificate_path = "app.cer", private_key_path = "app.key" } }
+      "#,
+    )
+    .unwrap();
+
+    let names: Vec<&str> = config.certificates.iter().map(|(name, _)| name.as_str()).collect();
+
+    assert_eq!(names, vec!["www.localhost", "api.localhost", "app.localhost"]);
+  }
+
+  #[test]
+  fn validate_rejects_acme_certificates_with_staging_and_directory_url() {
+    let config: TomlConfig = toml::from_str(
+      r#"
+        [certificates]
+        "zerossl.localhost" = { ACME = { email = "me@example.com", persist_dir = "./certificates", directory_url = "https://acme.zerossl.com/v2/DV90" } }
+        "staging.localhost" = { ACME = { email = "me@example.com", persist_dir = "./certificates", staging = true, directory_url = "https://acme.zerossl.com/v2/DV90" } }
+      "#,
+    )
+    .unwrap();
+
+    let error = config.validate().unwrap_err();
+
+    assert_eq!(
+      error.to_string(),
+      "ACME certificate 'staging.localhost' can not set both staging and directory_url"
+    );
+  }
+
+  #[test]
+  fn external_account_binding_requires_one_hmac_key() {
+    let binding = |hmac_key: Option<&str>, hmac_key_file: Option<&str>| ExternalAccountBindingConfig {
+      key_id: "kid-1".into(),
+      hmac_key: hmac_key.map(str::to_string),
+      hmac_key_file: hmac_key_file.map(str::to_string),
+    };
+
+    assert_eq!(
+      external_account_binding_of(".", binding(Some("_-8"), None))
+        .unwrap()
+        .hmac_key,
+      vec![0xff, 0xef]
+    );
+    assert!(external_account_binding_of(".", binding(None, None)).is_err());
+    assert!(external_account_binding_of(".", binding(Some("_-8"), Some("hmac.key"))).is_err());
+  }
+
+  #[test]
+  fn validate_rejects_services_with_unknown_pools() {
+    let mut config = toml_config(r#"["127.0.0.1:8080"]"#);
+    config.services = toml::from_str::<TomlConfig>(
+      r#"
+        [[services]]
+        name = "whoami"
+        matcher = "Host('whoami.localhost')"
+        pools = [{ pool = "whoami", weight = 3 }, { pool = "whoami-us" }]
+      "#,
+    )
+    .unwrap()
+    .services;
+
+    let error = config.validate().unwrap_err();
+
+    assert_eq!(
+      error.to_string(),
+      "service 'whoami' references the unknown backend pool 'whoami-us'"
+    );
+  }
+
+  #[test]
+  fn config_dir_prefers_command_line_over_configuration_file() {
+    // given:
+    let dir = std::env::temp_dir().join(format!("arlb-config-dir-{}", std::process::id()));
+    fs::create_dir_all(dir.join("etc/errorpages")).unwrap();
+    let config_file_dir = dir.join("etc").canonicalize().unwrap();
+
+    // when:
+    let default = config_dir(&config_file_dir, None, None).unwrap();
+    let configured = config_dir(&config_file_dir, Some("errorpages"), None).unwrap();
+    let overridden = config_dir(&config_file_dir, Some("errorpages"), Some(&dir)).unwrap();
+    let missing = config_dir(&config_file_dir, Some("missing"), None);
+
+    // then:
+    assert_eq!(default, config_file_dir);
+    assert_eq!(configured, config_file_dir.join("errorpages"));
+    assert_eq!(overridden, dir.canonicalize().unwrap());
+    assert!(missing.is_err());
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn health_event_sink_resolves_file_relative_to_config_dir() {
+    let config = toml::from_str::<TomlConfig>(
+      r#"
+        health_events = { File = { path = "health.jsonl" } }
+      "#,
+    )
+    .unwrap();
+
+    let sink = health_event_sink("/etc/arlb", config.health_events.unwrap()).unwrap();
+
+    assert_eq!(sink, HealthEventSink::File("/etc/arlb/health.jsonl".into()));
+  }
+
+  #[test]
+  fn health_event_sink_rejects_relative_url() {
+    let config = HealthEventsConfig::Http { url: "/events".into() };
+
+    let error = health_event_sink("/etc/arlb", config).unwrap_err();
+
+    assert_eq!(
+      error.to_string(),
+      "invalid health events url '/events', expected an absolute http url"
+    );
+  }
+
+  #[test]
+  fn normalize_address_writes_ip_addresses_canonically() {
+    assert_eq!(normalize_address("[2001:DB8:0::1]:80".into()), "[2001:db8::1]:80");
+    assert_eq!(normalize_address("127.0.0.1:8080".into()), "127.0.0.1:8080");
+    assert_eq!(normalize_address("whoami:80".into()), "whoami:80");
+  }
+
+  #[test]
+  fn log_redaction_replaces_the_default_lists() {
+    let config: TomlConfig = toml::from_str(
+      r#"
+        [log_redaction]
+        headers = ["X-Api-Key"]
+      "#,
+    )
+    .unwrap();
+
+    let config =
+      runtime_config_from_toml_config(".", config, Arc::new(AcmeHandler::new()), &MatcherCache::default()).unwrap();
+
+    assert_eq!(config.log_redaction.headers, vec![HeaderName::from_static("x-api-key")]);
+    assert_eq!(
+      config.log_redaction.query_parameters,
+      log_redaction::default_query_parameters()
+    );
+  }
+
+  fn acme_config() -> RuntimeConfig {
+    let config: TomlConfig = toml::from_str(
+      r#"
+        [certificates]
+        "acme.localhost" = { ACME = { email = "me@example.com", persist_dir = "./certificates", staging = true } }
+      "#,
+    )
+    .unwrap();
+    runtime_config_from_toml_config(".", config, Arc::new(AcmeHandler::new()), &MatcherCache::default()).unwrap()
+  }
+
+  fn certified_key() -> CertifiedKey {
+    let key = openssl::rsa::Rsa::generate(2048).unwrap().private_key_to_der().unwrap();
+    let key = tokio_rustls::rustls::sign::any_supported_type(&tokio_rustls::rustls::PrivateKey(key)).unwrap();
+    CertifiedKey::new(Vec::new(), Arc::new(key))
+  }
+
+  #[test]
+  fn acme_certificates_are_pending_until_issued() {
+    // given:
+    let config = acme_config();
+    assert_eq!(config.pending_certificates(), vec!["acme.localhost"]);
+
+    // when:
+    install_acme_certificate(&config, &config.acme_orders[0], certified_key());
+
+    // then:
+    assert!(config.pending_certificates().is_empty());
+    assert_eq!(config.certificates.load().len(), 1);
+  }
+
+  #[test]
+  fn acme_certificates_of_changed_orders_are_discarded() {
+    // given:
+    let config = acme_config();
+    let mut order = config.acme_orders[0].clone();
+    order.account.email = "someone-else@example.com".into();
+
+    // when:
+    install_acme_certificate(&config, &order, certified_key());
+
+    // then:
+    assert_eq!(config.pending_certificates(), vec!["acme.localhost"]);
+  }
+
+  #[test]
+  fn reload_keeps_issued_acme_certificates() {
+    // given:
+    let old_config = acme_config();
+    install_acme_certificate(&old_config, &old_config.acme_orders[0], certified_key());
+    let mut new_config = acme_config();
+
+    // when:
+    keep_issued_acme_certificates(&old_config, &mut new_config);
+
+    // then:
+    assert!(new_config.pending_certificates().is_empty());
+  }
+
+  fn pool_config(addresses: &str) -> RuntimeConfig {
+    let config: TomlConfig = toml::from_str(&format!(
+      r#"
+        [[backend_pools]]
+        name = "whoami"
+        matcher = "Host('whoami.localhost')"
+        addresses = {}
+        schemes = ["HTTP"]
+        strategy = {{ RoundRobin = {{}} }}
+        health_config = {{ initial_state = "unknown" }}
+      "#,
+      addresses
+    ))
+    .unwrap();
+    runtime_config_from_toml_config(".", config, Arc::new(AcmeHandler::new()), &MatcherCache::default()).unwrap()
+  }
+
+  #[test]
+  fn reload_keeps_backend_health_of_known_servers() {
+    // given:
+    let old_config = pool_config(r#"["127.0.0.1:8080"]"#);
+    old_config.shared_data.backend_pools[0].addresses[0]
+      .1
+      .inherit(&BackendHealth::new(Healthiness::Slow(400)));
+    let new_config = pool_config(r#"["127.0.0.1:8080", "127.0.0.1:8081"]"#);
+
+    // when:
+    keep_backend_health(&old_config, &new_config);
+
+    // then:
+    let addresses = &new_config.shared_data.backend_pools[0].addresses;
+    assert_eq!(*addresses[0].1.load().as_ref(), Healthiness::Slow(400));
+    assert_eq!(*addresses[1].1.load().as_ref(), Healthiness::Unknown);
+  }
+}
@@ -0,0 +1,143 @@
+use super::TomlConfig;
+use clap::{App, SubCommand};
+use schemars::{
+  gen::SchemaSettings,
+  schema::{InstanceType, ObjectValidation, RootSchema, Schema, SchemaObject, SingleOrVec},
+  visit::{self, Visitor},
+};
+use std::io;
+
+pub const NAME: &str = "config-schema";
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name(NAME)
+    .about("Prints the JSON Schema of the configuration, so editors and CI pipelines can validate configuration files.")
+}
+
+pub fn run() -> Result<(), io::Error> {
+  println!("{}", serde_json::to_string_pretty(&schema())?);
+  Ok(())
+}
+
+/// The JSON Schema (draft-07) of the TOML configuration, derived from the types it is deserialized into. Unknown keys
+/// are rejected, so typos are caught before the configuration is loaded.
+pub fn schema() -> RootSchema {
+  SchemaSettings::draft07()
+    .with_visitor(TomlConventions)
+    .into_generator()
+    .into_root_schema_for::<TomlConfig>()
+}
+
+/* Adapts the schema serde would describe for JSON to the way the configuration is written in TOML */
+#[derive(Debug, Clone)]
+struct TomlConventions;
+
+impl Visitor for TomlConventions {
+  fn visit_schema_object(&mut self, schema: &mut SchemaObject) {
+    visit::visit_schema_object(self, schema);
+
+    // unknown keys are most likely typos of optional settings
+    if let Some(object) = &mut schema.object {
+      if !object.properties.is_empty() && object.additional_properties.is_none() {
+        object.additional_properties = Some(Box::new(Schema::Bool(false)));
+      }
+    }
+
+    // serde accepts unit variants as empty tables as well, e.g. `strategy = { RoundRobin = {} }`
+    if let Some(variants) = unit_variants(schema) {
+      let names = SchemaObject {
+        instance_type: schema.instance_type.take(),
+        enum_values: schema.enum_values.take(),
+        ..Default::default()
+      };
+      let tables = variants.into_iter().map(|variant| {
+        let mut table = ObjectValidation::default();
+        table.properties.insert(variant.clone(), empty_table());
+        table.required.insert(variant);
+        table.additional_properties = Some(Box::new(Schema::Bool(false)));
+        SchemaObject {
+          instance_type: Some(InstanceType::Object.into()),
+          object: Some(Box::new(table)),
+          ..Default::default()
+        }
+        .into()
+      });
+      schema.subschemas().one_of = Some(std::iter::once(names.into()).chain(tables).collect());
+    }
+  }
+}
+
+/* The names of the variants if the schema describes an enum without fields */
+fn unit_variants(schema: &SchemaObject) -> Option<Vec<String>> {
+  if schema.instance_type != Some(SingleOrVec::Single(Box::new(InstanceType::String))) {
+    return None;
+  }
+  schema
+    .enum_values
+    .as_ref()?
+    .iter()
+    .map(|value| value.as_str().map(str::to_string))
+    .collect()
+}
+
+fn empty_table() -> Schema {
+  SchemaObject {
+    instance_type: Some(InstanceType::Object.into()),
+    object: Some(Box::new(ObjectValidation {
+      additional_properties: Some(Box::new(Schema::Bool(false))),
+      ..Default::default()
+    })),
+    ..Default::default()
+  }
+  .into()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::Value as Json;
+
+  fn json_schema() -> Json {
+    serde_json::to_value(schema()).unwrap()
+  }
+
+  #[test]
+  fn schema_rejects_unknown_keys() {
+    let schema = json_schema();
+
+    assert_eq!(schema["additionalProperties"], false);
+    assert_eq!(
+      schema["definitions"]["BackendPoolConfig"]["additionalProperties"],
+      false
+    );
+  }
+
+  #[test]
+  fn schema_accepts_unit_variants_as_tables() {
+    let schema = json_schema();
+
+    let variants = schema["definitions"]["LoadBalancingStrategyConfig"]["oneOf"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .flat_map(|variant| variant["oneOf"].as_array().cloned().unwrap_or_default())
+      .collect::<Vec<_>>();
+    let mut names = variants
+      .iter()
+      .filter_map(|variant| variant["enum"].as_array())
+      .flatten();
+    assert!(names.any(|name| name == "RoundRobin"));
+    assert!(variants.iter().any(|variant| variant["required"][0] == "RoundRobin"));
+  }
+
+  #[test]
+  fn schema_contains_defaults() {
+    let schema = json_schema();
+
+    assert_eq!(schema["properties"]["http_address"]["default"], "[::]:80");
+    assert_eq!(
+      schema["definitions"]["QueueConfig"]["properties"]["max_depth"]["default"],
+      100
+    );
+  }
+}
@@ -0,0 +1,213 @@
+use crate::{
+  bans::Bans,
+  listeners::{ClientFingerprint, RemoteAddress, TlsServerName},
+  tls_fingerprint::TlsFingerprint,
+};
+use log::debug;
+use pin_project::pin_project;
+use std::{
+  collections::HashMap,
+  io,
+  net::{IpAddr, SocketAddr},
+  pin::Pin,
+  sync::{Arc, Mutex},
+  task::{Context, Poll},
+  time::Duration,
+};
+use tokio::{
+  io::{AsyncRead, AsyncWrite, ReadBuf},
+  time::Instant,
+};
+
+/// Upper bound of clients tracked for bans, so a flood of source addresses can't exhaust the memory.
+const MAX_BANNED_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionLimitSettings {
+  pub max_per_client: usize,
+  /// Ban clients exceeding the limit this many times within `ban_duration`.
+  pub ban_after: Option<u64>,
+  pub ban_duration: Duration,
+}
+
+/// Limits the number of concurrent connections per client IP address across all listeners.
+#[derive(Debug)]
+pub struct ConnectionLimit {
+  settings: ConnectionLimitSettings,
+  connections: Mutex<HashMap<IpAddr, usize>>,
+  bans: Option<Bans>,
+}
+
+impl ConnectionLimit {
+  pub fn new(settings: ConnectionLimitSettings) -> ConnectionLimit {
+    let bans = settings
+      .ban_after
+      .map(|after| Bans::new(after, settings.ban_duration, MAX_BANNED_CLIENTS));
+    ConnectionLimit {
+      settings,
+      connections: Mutex::new(HashMap::new()),
+      bans,
+    }
+  }
+
+  pub fn settings(&self) -> &ConnectionLimitSettings {
+    &self.settings
+  }
+
+  /// Reserves a connection for the client, which is released once the returned [`ConnectionPermit`] is dropped.
+  /// Returns `None` if the client has too many open connections or is banned.
+  pub fn acquire(self: &Arc<Self>, client_ip: IpAddr) -> Option<ConnectionPermit> {
+    let now = Instant::now();
+    if let Some(bans) = &self.bans {
+      if bans.remaining_ban(&client_ip, now).is_some() {
+        return None;
+      }
+    }
+
+    let mut connections = self.connections.lock().unwrap();
+    let count = connections.entry(client_ip).or_insert(0);
+    if *count >= self.settings.max_per_client {
+      drop(connections);
+      debug!(
+        "Rejecting connection of {}, it has too many open connections",
+        client_ip
+      );
+      if let Some(bans) = &self.bans {
+        bans.record_violation(client_ip, now);
+      }
+      return None;
+    }
+    *count += 1;
+    Some(ConnectionPermit {
+      limit: self.clone(),
+      client_ip,
+    })
+  }
+
+  fn release(&self, client_ip: &IpAddr) {
+    let mut connections = self.connections.lock().unwrap();
+    if let Some(count) = connections.get_mut(client_ip) {
+      *count -= 1;
+      if *count == 0 {
+        connections.remove(client_ip);
+      }
+    }
+  }
+}
+
+/// A connection counted by a [`ConnectionLimit`].
+#[derive(Debug)]
+pub struct ConnectionPermit {
+  limit: Arc<ConnectionLimit>,
+  client_ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+  fn drop(&mut self) {
+    self.limit.release(&self.client_ip);
+  }
+}
+
+/// A wrapper around an accepted stream, holding its [`ConnectionPermit`] until the stream is closed.
+#[pin_project]
+pub struct LimitedStream<T> {
+  #[pin]
+  inner: T,
+  _permit: Option<ConnectionPermit>,
+}
+
+impl<T> LimitedStream<T> {
+  pub fn new(inner: T, permit: Option<ConnectionPermit>) -> LimitedStream<T> {
+    LimitedStream { inner, _permit: permit }
+  }
+}
+
+impl<T: AsyncRead> AsyncRead for LimitedStream<T> {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    self.project().inner.poll_read(cx, buf)
+  }
+}
+
+impl<T: AsyncWrite> AsyncWrite for LimitedStream<T> {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+    self.project().inner.poll_write(cx, buf)
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    self.project().inner.poll_flush(cx)
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    self.project().inner.poll_shutdown(cx)
+  }
+}
+
+impl<T: RemoteAddress> RemoteAddress for LimitedStream<T> {
+  fn remote_addr(&self) -> io::Result<SocketAddr> {
+    self.inner.remote_addr()
+  }
+}
+
+impl<T: TlsServerName> TlsServerName for LimitedStream<T> {
+  fn server_name(&self) -> Option<String> {
+    self.inner.server_name()
+  }
+}
+
+impl<T: ClientFingerprint> ClientFingerprint for LimitedStream<T> {
+  fn tls_fingerprint(&self) -> Option<TlsFingerprint> {
+    self.inner.tls_fingerprint()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn connection_limit(ban_after: Option<u64>) -> Arc<ConnectionLimit> {
+    Arc::new(ConnectionLimit::new(ConnectionLimitSettings {
+      max_per_client: 2,
+      ban_after,
+      ban_duration: Duration::from_secs(60),
+    }))
+  }
+
+  #[test]
+  fn test_acquire_limits_connections_per_client() {
+    // given:
+    let limit = connection_limit(None);
+    let client = "127.0.0.1".parse().unwrap();
+    let other_client = "127.0.0.2".parse().unwrap();
+    let _first = limit.acquire(client).unwrap();
+    let second = limit.acquire(client).unwrap();
+
+    // when:
+    let rejected = limit.acquire(client);
+    let other = limit.acquire(other_client);
+    drop(second);
+    let after_release = limit.acquire(client);
+
+    // then:
+    assert!(rejected.is_none());
+    assert!(other.is_some());
+    assert!(after_release.is_some());
+  }
+
+  #[test]
+  fn test_acquire_bans_repeatedly_exceeding_client() {
+    // given:
+    let limit = connection_limit(Some(2));
+    let client = "127.0.0.1".parse().unwrap();
+    let first = limit.acquire(client).unwrap();
+    let second = limit.acquire(client).unwrap();
+    assert!(limit.acquire(client).is_none());
+    assert!(limit.acquire(client).is_none());
+
+    // when:
+    drop((first, second));
+    let actual = limit.acquire(client);
+
+    // then:
+    assert!(actual.is_none());
+  }
+}
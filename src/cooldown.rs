@@ -0,0 +1,133 @@
+use chrono::{DateTime, Utc};
+use hyper::{
+  header::{HeaderValue, RETRY_AFTER},
+  Body, Response, StatusCode,
+};
+use log::debug;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+/// Takes backend servers out of rotation while they ask clients to come back later. A `429 Too Many Requests` or
+/// `503 Service Unavailable` response with a `Retry-After` header cools the backend server down for the advertised
+/// duration, at most `max_cooldown`, so the load balancer doesn't keep hammering an overloaded backend server.
+#[derive(Debug)]
+pub struct Cooldown {
+  max_cooldown: Duration,
+  until: Mutex<HashMap<String, Instant>>,
+}
+
+impl Cooldown {
+  pub fn new(max_cooldown: Duration) -> Cooldown {
+    Cooldown {
+      max_cooldown,
+      until: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Cools the backend server at `address` down if its `response` asks to retry later.
+  pub fn record(&self, address: &str, response: &Response<Body>) {
+    if !matches!(
+      response.status(),
+      StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+      return;
+    }
+    let retry_after = match response
+      .headers()
+      .get(RETRY_AFTER)
+      .and_then(|it| parse_retry_after(it, Utc::now()))
+    {
+      Some(retry_after) => retry_after.min(self.max_cooldown),
+      None => return,
+    };
+    debug!(
+      "Backend server {} answered with {}, cooling it down for {}s",
+      address,
+      response.status(),
+      retry_after.as_secs()
+    );
+    let until = Instant::now() + retry_after;
+    let mut cooldowns = self.until.lock().unwrap();
+    let entry = cooldowns.entry(address.to_string()).or_insert(until);
+    *entry = (*entry).max(until);
+  }
+
+  /// Returns the remaining time the backend server at `address` is cooling down, if it is.
+  pub fn remaining(&self, address: &str) -> Option<Duration> {
+    let now = Instant::now();
+    let mut cooldowns = self.until.lock().unwrap();
+    match cooldowns.get(address) {
+      Some(until) if *until > now => Some(until.saturating_duration_since(now)),
+      Some(_) => {
+        cooldowns.remove(address);
+        None
+      }
+      None => None,
+    }
+  }
+
+  pub fn is_cooling_down(&self, address: &str) -> bool {
+    self.remaining(address).is_some()
+  }
+}
+
+/// Parses the delay in seconds or the HTTP date of a `Retry-After` header, see
+/// https://tools.ietf.org/html/rfc7231#section-7.1.3
+fn parse_retry_after(value: &HeaderValue, now: DateTime<Utc>) -> Option<Duration> {
+  let value = value.to_str().ok()?.trim();
+  if let Ok(seconds) = value.parse::<u64>() {
+    return Some(Duration::from_secs(seconds));
+  }
+  let date = DateTime::parse_from_rfc2822(value).ok()?;
+  Some(date.signed_duration_since(now).to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::TimeZone;
+
+  fn response(status: StatusCode, retry_after: &'static str) -> Response<Body> {
+    Response::builder()
+      .status(status)
+      .header(RETRY_AFTER, retry_after)
+      .body(Body::empty())
+      .unwrap()
+  }
+
+  #[test]
+  fn parses_delay_and_date() {
+    let now = Utc.ymd(2015, 10, 21).and_hms(7, 28, 0);
+
+    assert_eq!(
+      parse_retry_after(&HeaderValue::from_static("120"), now),
+      Some(Duration::from_secs(120))
+    );
+    assert_eq!(
+      parse_retry_after(&HeaderValue::from_static("Wed, 21 Oct 2015 07:29:00 GMT"), now),
+      Some(Duration::from_secs(60))
+    );
+    assert_eq!(
+      parse_retry_after(&HeaderValue::from_static("Wed, 21 Oct 2015 07:00:00 GMT"), now),
+      Some(Duration::ZERO)
+    );
+    assert_eq!(parse_retry_after(&HeaderValue::from_static("soon"), now), None);
+  }
+
+  #[test]
+  fn cools_down_backend_asking_to_retry_later() {
+    // given:
+    let cooldown = Cooldown::new(Duration::from_secs(60));
+
+    // when:
+    cooldown.record("127.0.0.1:1", &response(StatusCode::TOO_MANY_REQUESTS, "3600"));
+    cooldown.record("127.0.0.1:2", &response(StatusCode::OK, "3600"));
+    cooldown.record("127.0.0.1:3", &response(StatusCode::SERVICE_UNAVAILABLE, "0"));
+
+    // then:
+    let remaining = cooldown.remaining("127.0.0.1:1").unwrap();
+    assert!(remaining <= Duration::from_secs(60) && remaining > Duration::from_secs(59));
+    assert!(!cooldown.is_cooling_down("127.0.0.1:2"));
+    assert!(!cooldown.is_cooling_down("127.0.0.1:3"));
+  }
+}
@@ -0,0 +1,173 @@
+use crate::{middleware::MiddlewareChain, server::BackendPool, timing::RequestTiming};
+use hyper::{
+  header::{HeaderName, HeaderValue},
+  Body, HeaderMap, Request, Response,
+};
+use std::{fmt::Debug, net::IpAddr};
+
+/// Configures when responses are annotated with headers describing the routing decisions of the load balancer.
+#[derive(Debug, Default, PartialEq)]
+pub struct DebugHeaders {
+  /// Annotate all responses
+  pub always: bool,
+  /// Annotate responses to requests containing this header, but only if sent from one of the `allowed_ips`
+  pub trigger_header: Option<HeaderName>,
+  pub allowed_ips: Vec<IpAddr>,
+}
+
+impl DebugHeaders {
+  pub fn is_requested(&self, request: &Request<Body>, client_ip: &IpAddr) -> bool {
+    let triggered = self
+      .trigger_header
+      .as_ref()
+      .map_or(false, |header| request.headers().contains_key(header));
+    self.always || (triggered && self.allowed_ips.contains(client_ip))
+  }
+}
+
+/// How the backend pool handling a request was selected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Route<'l> {
+  /// The matcher of the backend pool matches the request
+  Matcher,
+  /// The backend pool handles HTTP/1.0 requests without `Host` header
+  Http10,
+  /// The service with this name selected the backend pool
+  Service(&'l str),
+  /// No backend pool matches the request
+  DefaultPool,
+  /// The backend pool with this name is not available
+  Fallback(&'l str),
+}
+
+impl Route<'_> {
+  /// Names the backend `pool` together with the way it was selected, e.g. `maintenance (fallback of 'api')`.
+  pub fn describe(&self, pool: &str) -> String {
+    match self {
+      Route::Matcher => pool.to_string(),
+      Route::Http10 => format!("{} (HTTP/1.0)", pool),
+      Route::Service(service) => format!("{} (service '{}')", pool, service),
+      Route::DefaultPool => format!("{} (default)", pool),
+      Route::Fallback(primary) => format!("{} (fallback of '{}')", pool, primary),
+    }
+  }
+}
+
+/// The routing decisions for a single request.
+pub struct RoutingDecision<'l> {
+  /// The backend pool as described by [Route::describe]
+  pub route: &'l str,
+  pub pool: &'l BackendPool,
+  pub backend_address: &'l str,
+  pub timing: &'l RequestTiming,
+}
+
+impl RoutingDecision<'_> {
+  pub fn annotate(&self, response: &mut Response<Body>) {
+    let headers = response.headers_mut();
+    insert(headers, "x-arlb-pool", self.route);
+    insert(headers, "x-arlb-matcher", &format!("{:?}", self.pool.matcher));
+    insert(headers, "x-arlb-backend", self.backend_address);
+    insert(headers, "x-arlb-strategy", &type_name(&self.pool.strategy));
+    insert(
+      headers,
+      "x-arlb-middlewares",
+      &middleware_names(&self.pool.chain).join(", "),
+    );
+    let answered_by = if self.timing.time_to_first_byte.is_some() {
+      "backend"
+    } else {
+      "middleware"
+    };
+    insert(headers, "x-arlb-answered-by", answered_by);
+    insert(headers, "x-arlb-timing", &self.timing.to_string());
+  }
+}
+
+fn insert(headers: &mut HeaderMap, name: &'static str, value: &str) {
+  if let Ok(value) = HeaderValue::from_str(value) {
+    headers.insert(name, value);
+  }
+}
+
+pub fn middleware_names(chain: &MiddlewareChain) -> Vec<String> {
+  let mut names = Vec::new();
+  let mut chain = chain;
+  while let MiddlewareChain::Entry {
+    middleware,
+    chain: tail,
+    ..
+  } = chain
+  {
+    names.push(type_name(middleware));
+    chain = tail;
+  }
+  names
+}
+
+/// Returns the name of the struct by stripping the fields from its debug representation.
+pub fn type_name<T: Debug + ?Sized>(value: &T) -> String {
+  let debug = format!("{:?}", value);
+  debug
+    .split(|c: char| !c.is_alphanumeric() && c != '_')
+    .next()
+    .unwrap_or_default()
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::middleware::{compression::Compression, https_redirector::HttpsRedirector};
+
+  #[test]
+  fn is_requested_only_from_allowed_ips() {
+    // given:
+    let debug_headers = DebugHeaders {
+      always: false,
+      trigger_header: Some(HeaderName::from_static("x-arlb-debug")),
+      allowed_ips: vec!["10.0.0.1".parse().unwrap()],
+    };
+    let request = Request::builder()
+      .header("x-arlb-debug", "1")
+      .body(Body::empty())
+      .unwrap();
+
+    // then:
+    assert!(debug_headers.is_requested(&request, &"10.0.0.1".parse().unwrap()));
+    assert!(!debug_headers.is_requested(&request, &"10.0.0.2".parse().unwrap()));
+    assert!(!debug_headers.is_requested(&Request::new(Body::empty()), &"10.0.0.1".parse().unwrap()));
+  }
+
+  #[test]
+  fn route_describes_how_the_pool_was_selected() {
+    assert_eq!(Route::Matcher.describe("api"), "api");
+    assert_eq!(Route::Http10.describe("legacy"), "legacy (HTTP/1.0)");
+    assert_eq!(Route::Service("shop").describe("canary"), "canary (service 'shop')");
+    assert_eq!(Route::DefaultPool.describe("catch-all"), "catch-all (default)");
+    assert_eq!(
+      Route::Fallback("api").describe("maintenance"),
+      "maintenance (fallback of 'api')"
+    );
+  }
+
+  #[test]
+  fn middleware_names_in_order() {
+    // given:
+    let chain = MiddlewareChain::Entry {
+      middleware: Box::new(Compression::default()),
+      matcher: None,
+      chain: Box::new(MiddlewareChain::Entry {
+        middleware: Box::new(HttpsRedirector),
+        matcher: None,
+        chain: Box::new(MiddlewareChain::Empty),
+      }),
+    };
+
+    // when:
+    let actual = middleware_names(&chain);
+
+    // then:
+    assert_eq!(actual, vec!["Compression", "HttpsRedirector"]);
+  }
+}
@@ -1,34 +1,166 @@
-use hyper::{Body, Response, StatusCode};
+use crate::utils::append_vary;
+use hyper::{
+  body::Bytes,
+  header::{
+    HeaderValue, ACCEPT, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING, X_CONTENT_TYPE_OPTIONS,
+  },
+  http::response::Parts,
+  Body, Response, StatusCode,
+};
 use log::error;
-use std::error::Error;
+use std::{collections::HashMap, error::Error, fs, io, path::Path};
+
+/// The header naming the [`UpstreamFailure`] of error responses, e.g. `x-arlb-upstream-error: connection_refused`.
+pub const UPSTREAM_ERROR_HEADER: &str = "x-arlb-upstream-error";
+
+/// Marks responses created by the load balancer itself, which can be replaced by [`ErrorPages`].
+#[derive(Debug, Clone, Copy)]
+pub struct BuiltInErrorResponse;
+
+pub const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+pub const TEXT_HTML: &str = "text/html; charset=utf-8";
+
+fn error_response<B: Into<Bytes>>(status: StatusCode, body: B) -> Response<Body> {
+  let (parts, _) = Response::builder().status(status).body(()).unwrap().into_parts();
+  let mut response = replace_body(parts, HeaderValue::from_static(TEXT_PLAIN), body);
+  response.extensions_mut().insert(BuiltInErrorResponse);
+  response
+}
+
+/// Creates an error response with the `body`, replacing the body the `parts` belonged to. As the body is generated by
+/// the load balancer, its `Content-Type` and `Content-Length` are set explicitly and browsers are told not to sniff
+/// another content type from it, e.g. from an error page reflecting parts of the request.
+pub fn replace_body<B: Into<Bytes>>(mut parts: Parts, content_type: HeaderValue, body: B) -> Response<Body> {
+  let body = body.into();
+  parts.headers.remove(CONTENT_ENCODING);
+  parts.headers.remove(TRANSFER_ENCODING);
+  parts.headers.insert(CONTENT_TYPE, content_type);
+  parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+  parts
+    .headers
+    .insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+  Response::from_parts(parts, Body::from(body))
+}
+
+/// Creates a response with the `body` as is, e.g. the answers of the admin API and the health endpoint.
+pub fn text_response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+  Response::builder().status(status).body(body.into()).unwrap()
+}
 
 pub fn not_found() -> Response<Body> {
-  Response::builder()
-    .status(StatusCode::NOT_FOUND)
-    .body(Body::from("404 - page not found"))
-    .unwrap()
+  error_response(StatusCode::NOT_FOUND, "404 - page not found")
 }
 
-pub fn handle_bad_gateway<E: Error>(error: E) -> Response<Body> {
+/// Answers a request whose backend server could not be reached or failed to respond, with a status code depending on
+/// the [`UpstreamFailure`]. The failure is named by the [`UPSTREAM_ERROR_HEADER`] and attached to the extensions of
+/// the response.
+pub fn handle_upstream_error(error: hyper::Error) -> Response<Body> {
+  let failure = UpstreamFailure::of(&error);
   log_error(error);
-  bad_gateway()
+  let mut response = error_response(failure.status(), Bytes::new());
+  response
+    .headers_mut()
+    .insert(UPSTREAM_ERROR_HEADER, HeaderValue::from_static(failure.code()));
+  response.extensions_mut().insert(failure);
+  response
+}
+
+/// Why a request to a backend server failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamFailure {
+  /* Nothing listens on the address of the backend server */
+  ConnectionRefused,
+  /* The host of the backend server could not be resolved */
+  Dns,
+  /* Connecting or waiting for the response timed out */
+  Timeout,
+  /* The connection was closed or reset before the response was complete */
+  Reset,
+  Other,
+}
+
+impl UpstreamFailure {
+  pub const ALL: [UpstreamFailure; 5] = [
+    UpstreamFailure::ConnectionRefused,
+    UpstreamFailure::Dns,
+    UpstreamFailure::Timeout,
+    UpstreamFailure::Reset,
+    UpstreamFailure::Other,
+  ];
+
+  /// Classifies the error of a request to a backend server. The reason of connect errors is only available from the
+  /// chain of sources, as the connect error of hyper is private.
+  pub fn of(error: &hyper::Error) -> UpstreamFailure {
+    let mut source = error.source();
+    while let Some(cause) = source {
+      if error.is_connect() && cause.to_string().starts_with("dns error") {
+        return UpstreamFailure::Dns;
+      }
+      if let Some(io_error) = cause.downcast_ref::<io::Error>() {
+        match io_error.kind() {
+          io::ErrorKind::ConnectionRefused => return UpstreamFailure::ConnectionRefused,
+          io::ErrorKind::TimedOut => return UpstreamFailure::Timeout,
+          io::ErrorKind::ConnectionReset
+          | io::ErrorKind::ConnectionAborted
+          | io::ErrorKind::BrokenPipe
+          | io::ErrorKind::UnexpectedEof => return UpstreamFailure::Reset,
+          _ => {}
+        }
+      }
+      // a request which is canceled because the backend server closed the connection wraps the cause
+      if let Some(hyper_error) = cause.downcast_ref::<hyper::Error>() {
+        if hyper_error.is_incomplete_message() || hyper_error.is_closed() {
+          return UpstreamFailure::Reset;
+        }
+      }
+      source = cause.source();
+    }
+    if error.is_incomplete_message() || error.is_closed() {
+      UpstreamFailure::Reset
+    } else {
+      UpstreamFailure::Other
+    }
+  }
+
+  /// A refused connection means the backend server is down, so the request may be retried later, while a timeout
+  /// is reported as such. Any other failure is a bad gateway.
+  pub fn status(self) -> StatusCode {
+    match self {
+      UpstreamFailure::ConnectionRefused => StatusCode::SERVICE_UNAVAILABLE,
+      UpstreamFailure::Timeout => StatusCode::GATEWAY_TIMEOUT,
+      UpstreamFailure::Dns | UpstreamFailure::Reset | UpstreamFailure::Other => StatusCode::BAD_GATEWAY,
+    }
+  }
+
+  pub fn code(self) -> &'static str {
+    match self {
+      UpstreamFailure::ConnectionRefused => "connection_refused",
+      UpstreamFailure::Dns => "dns_failure",
+      UpstreamFailure::Timeout => "timeout",
+      UpstreamFailure::Reset => "connection_reset",
+      UpstreamFailure::Other => "upstream_error",
+    }
+  }
 }
 
 pub fn bad_gateway() -> Response<Body> {
-  Response::builder()
-    .status(StatusCode::BAD_GATEWAY)
-    .body(Body::empty())
-    .unwrap()
+  error_response(StatusCode::BAD_GATEWAY, Bytes::new())
+}
+
+pub fn bad_request<B: Into<Bytes>>(message: B) -> Response<Body> {
+  error_response(StatusCode::BAD_REQUEST, message)
+}
+
+pub fn forbidden() -> Response<Body> {
+  error_response(StatusCode::FORBIDDEN, "403 - Forbidden")
+}
+
+pub fn misdirected_request() -> Response<Body> {
+  error_response(StatusCode::MISDIRECTED_REQUEST, "421 - Misdirected Request")
 }
 
-pub fn bad_request<B>(message: B) -> Response<Body>
-where
-  Body: From<B>,
-{
-  Response::builder()
-    .status(StatusCode::BAD_REQUEST)
-    .body(Body::from(message))
-    .unwrap()
+pub fn service_unavailable() -> Response<Body> {
+  error_response(StatusCode::SERVICE_UNAVAILABLE, "503 - Service Unavailable")
 }
 
 pub fn handle_internal_server_error<E: Error>(error: E) -> Response<Body> {
@@ -37,10 +169,7 @@ pub fn handle_internal_server_error<E: Error>(error: E) -> Response<Body> {
 }
 
 pub fn internal_server_error() -> Response<Body> {
-  Response::builder()
-    .status(StatusCode::INTERNAL_SERVER_ERROR)
-    .body(Body::empty())
-    .unwrap()
+  error_response(StatusCode::INTERNAL_SERVER_ERROR, Bytes::new())
 }
 
 pub fn log_error<E: Error>(error: E) {
@@ -48,15 +177,256 @@ pub fn log_error<E: Error>(error: E) {
 }
 
 pub fn request_entity_to_large() -> Response<Body> {
-  Response::builder()
-    .status(StatusCode::PAYLOAD_TOO_LARGE)
-    .body(Body::empty())
-    .unwrap()
+  error_response(StatusCode::PAYLOAD_TOO_LARGE, Bytes::new())
+}
+
+pub fn unsupported_media_type() -> Response<Body> {
+  error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, Bytes::new())
+}
+
+pub fn method_not_allowed() -> Response<Body> {
+  error_response(StatusCode::METHOD_NOT_ALLOWED, "405 - Method Not Allowed")
+}
+
+/// Globally configured replacements for the built-in error responses.
+///
+/// Pages are templates in which `{{status}}` and `{{reason}}` are replaced by the status code and its canonical
+/// reason.
+#[derive(Debug)]
+pub struct ErrorPages {
+  pages: HashMap<StatusCode, String>,
+  default_page: Option<String>,
+  content_type: HeaderValue,
+  /// Answer clients preferring `application/json` with a JSON object instead of a page.
+  json: bool,
 }
 
-pub fn too_many_requests() -> Response<Body> {
-  Response::builder()
-    .status(StatusCode::TOO_MANY_REQUESTS)
-    .body(Body::from("429 - Too Many Requests"))
-    .unwrap()
+impl ErrorPages {
+  /// Loads the pages `{STATUS_CODE}.html` and the fallback page `default.html` from `location` if present.
+  pub fn load(location: Option<&Path>, content_type: HeaderValue, json: bool) -> io::Result<ErrorPages> {
+    let mut pages = HashMap::new();
+    let mut default_page = None;
+    if let Some(location) = location {
+      for entry in fs::read_dir(location)? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |it| it != "html") {
+          continue;
+        }
+        let stem = path.file_stem().and_then(|it| it.to_str()).unwrap_or_default();
+        if stem == "default" {
+          default_page = Some(fs::read_to_string(&path)?);
+        } else if let Some(status) = stem.parse().ok().and_then(|it| StatusCode::from_u16(it).ok()) {
+          pages.insert(status, fs::read_to_string(&path)?);
+        }
+      }
+    }
+    Ok(ErrorPages {
+      pages,
+      default_page,
+      content_type,
+      json,
+    })
+  }
+
+  /// Replaces the body of built-in error responses depending on the `accept` header of the request.
+  pub fn apply(&self, response: Response<Body>, accept: Option<&HeaderValue>) -> Response<Body> {
+    if response.extensions().get::<BuiltInErrorResponse>().is_none() {
+      return response;
+    }
+    let status = response.status();
+    let reason = status.canonical_reason().unwrap_or("");
+    let (body, content_type) = if self.json && prefers_json(accept) {
+      let body = format!("{{\"status\":{},\"error\":\"{}\"}}", status.as_u16(), reason);
+      (body, HeaderValue::from_static("application/json"))
+    } else {
+      match self.pages.get(&status).or_else(|| self.default_page.as_ref()) {
+        Some(page) => {
+          let body = page
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", reason);
+          (body, self.content_type.clone())
+        }
+        None => return response,
+      }
+    };
+
+    let (mut parts, _) = response.into_parts();
+    if self.json {
+      append_vary(&mut parts.headers, &ACCEPT);
+    }
+    replace_body(parts, content_type, body)
+  }
+}
+
+/// Returns true if the `accept` header ranks `application/json` at least as high as `text/html`.
+fn prefers_json(accept: Option<&HeaderValue>) -> bool {
+  let accept = match accept.and_then(|it| it.to_str().ok()) {
+    Some(accept) => accept,
+    None => return false,
+  };
+  let mut json = 0.0;
+  let mut html = 0.0;
+  for media_range in accept.split(',') {
+    let mut params = media_range.split(';').map(str::trim);
+    let media_type = params.next().unwrap_or_default();
+    let quality = params
+      .find_map(|it| it.strip_prefix("q="))
+      .and_then(|it| it.parse::<f32>().ok())
+      .unwrap_or(1.0);
+    if media_type.eq_ignore_ascii_case("application/json") {
+      json = quality;
+    } else if media_type.eq_ignore_ascii_case("text/html") {
+      html = quality;
+    }
+  }
+  json > 0.0 && json >= html
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::{body, header::VARY, Client};
+  use tokio::net::TcpListener;
+
+  fn error_pages() -> ErrorPages {
+    let mut pages = HashMap::new();
+    pages.insert(StatusCode::NOT_FOUND, "<h1>{{status}} - {{reason}}</h1>".to_string());
+    ErrorPages {
+      pages,
+      default_page: None,
+      content_type: HeaderValue::from_static("text/html"),
+      json: true,
+    }
+  }
+
+  #[tokio::test]
+  async fn test_upstream_error_of_refused_connection() {
+    // given:
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    drop(listener);
+
+    // when:
+    let error = Client::new()
+      .get(format!("http://{}/", address).parse().unwrap())
+      .await
+      .unwrap_err();
+    let response = handle_upstream_error(error);
+
+    // then:
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[UPSTREAM_ERROR_HEADER], "connection_refused");
+    assert_eq!(
+      response.extensions().get::<UpstreamFailure>(),
+      Some(&UpstreamFailure::ConnectionRefused)
+    );
+  }
+
+  #[tokio::test]
+  async fn test_upstream_error_of_closed_connection() {
+    // given:
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      let (connection, _) = listener.accept().await.unwrap();
+      drop(connection);
+    });
+
+    // when:
+    let error = Client::new()
+      .get(format!("http://{}/", address).parse().unwrap())
+      .await
+      .unwrap_err();
+
+    // then:
+    assert_eq!(UpstreamFailure::of(&error), UpstreamFailure::Reset);
+    assert_eq!(UpstreamFailure::Reset.status(), StatusCode::BAD_GATEWAY);
+  }
+
+  #[test]
+  fn test_built_in_error_responses_declare_their_content() {
+    let response = not_found();
+
+    assert_eq!(response.headers()[CONTENT_TYPE], TEXT_PLAIN);
+    assert_eq!(response.headers()[CONTENT_LENGTH], "20");
+    assert_eq!(response.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+  }
+
+  #[test]
+  fn test_replace_body_drops_framing_of_replaced_body() {
+    // given:
+    let response = Response::builder()
+      .status(StatusCode::NOT_FOUND)
+      .header(CONTENT_ENCODING, "gzip")
+      .header(TRANSFER_ENCODING, "chunked")
+      .header(CONTENT_TYPE, "image/png")
+      .body(Body::empty())
+      .unwrap();
+    let (parts, _) = response.into_parts();
+
+    // when:
+    let response = replace_body(parts, HeaderValue::from_static(TEXT_HTML), "<h1>404</h1>");
+
+    // then:
+    assert_eq!(response.headers()[CONTENT_TYPE], TEXT_HTML);
+    assert_eq!(response.headers()[CONTENT_LENGTH], "12");
+    assert!(response.headers().get(CONTENT_ENCODING).is_none());
+    assert!(response.headers().get(TRANSFER_ENCODING).is_none());
+  }
+
+  #[test]
+  fn test_prefers_json() {
+    assert!(prefers_json(Some(&HeaderValue::from_static("application/json"))));
+    assert!(prefers_json(Some(&HeaderValue::from_static(
+      "text/html;q=0.5, application/json"
+    ))));
+    assert!(!prefers_json(Some(&HeaderValue::from_static(
+      "text/html,application/xhtml+xml,*/*;q=0.8"
+    ))));
+    assert!(!prefers_json(Some(&HeaderValue::from_static("application/json;q=0"))));
+    assert!(!prefers_json(None));
+  }
+
+  #[tokio::test]
+  async fn test_apply_renders_page_template() {
+    // when:
+    let response = error_pages().apply(not_found(), None);
+
+    // then:
+    assert_eq!(response.headers()[CONTENT_TYPE], "text/html");
+    assert_eq!(response.headers()[CONTENT_LENGTH], "24");
+    assert_eq!(response.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+    let body = body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "<h1>404 - Not Found</h1>");
+  }
+
+  #[tokio::test]
+  async fn test_apply_json() {
+    // given:
+    let accept = HeaderValue::from_static("application/json");
+
+    // when:
+    let response = error_pages().apply(bad_gateway(), Some(&accept));
+
+    // then:
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    assert_eq!(response.headers()[VARY], "accept");
+    let body = body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "{\"status\":502,\"error\":\"Bad Gateway\"}");
+  }
+
+  #[test]
+  fn test_apply_ignores_responses_of_backend_servers() {
+    // given:
+    let response = Response::builder()
+      .status(StatusCode::NOT_FOUND)
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = error_pages().apply(response, None);
+
+    // then:
+    assert!(response.headers().get(CONTENT_TYPE).is_none());
+  }
 }
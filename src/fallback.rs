@@ -0,0 +1,155 @@
+use std::{
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+/// Minimum number of requests in a window before its error rate is considered representative.
+const MIN_REQUESTS: u64 = 10;
+
+/// Routes the requests of a [`BackendPool`](crate::server::BackendPool) to another pool once none of its
+/// backend servers are working or its [`ErrorBudget`] is exhausted.
+#[derive(Debug)]
+pub struct Fallback {
+  /// The index of the fallback pool in the configured backend pools.
+  pub pool: usize,
+  pub error_budget: Option<ErrorBudget>,
+}
+
+/// Tracks the share of server error responses of a pool in consecutive windows of a fixed duration.
+#[derive(Debug)]
+pub struct ErrorBudget {
+  max_error_rate: f64,
+  window: Duration,
+  state: Mutex<ErrorBudgetState>,
+}
+
+#[derive(Debug)]
+struct ErrorBudgetState {
+  window_start: Instant,
+  requests: u64,
+  errors: u64,
+  previous_error_rate: Option<f64>,
+}
+
+impl ErrorBudget {
+  pub fn new(max_error_rate: f64, window: Duration) -> ErrorBudget {
+    ErrorBudget {
+      max_error_rate,
+      window,
+      state: Mutex::new(ErrorBudgetState {
+        window_start: Instant::now(),
+        requests: 0,
+        errors: 0,
+        previous_error_rate: None,
+      }),
+    }
+  }
+
+  pub fn record(&self, is_error: bool) {
+    self.record_at(Instant::now(), is_error)
+  }
+
+  /// Returns true if the error rate of the current window (or the previous window, if the current one has too few
+  /// requests) exceeds the maximum error rate.
+  pub fn is_exhausted(&self) -> bool {
+    self.is_exhausted_at(Instant::now())
+  }
+
+  fn record_at(&self, now: Instant, is_error: bool) {
+    let mut state = self.state.lock().unwrap();
+    self.roll_window(&mut state, now);
+    state.requests += 1;
+    if is_error {
+      state.errors += 1;
+    }
+  }
+
+  fn is_exhausted_at(&self, now: Instant) -> bool {
+    let mut state = self.state.lock().unwrap();
+    self.roll_window(&mut state, now);
+    let error_rate = if state.requests >= MIN_REQUESTS {
+      Some(state.errors as f64 / state.requests as f64)
+    } else {
+      state.previous_error_rate
+    };
+    error_rate.map_or(false, |error_rate| error_rate > self.max_error_rate)
+  }
+
+  /// Starts a new window once the current one is over. The error rate of the finished window is only remembered if
+  /// the new window starts right after it, so a pool which did not receive any requests is tried again.
+  fn roll_window(&self, state: &mut ErrorBudgetState, now: Instant) {
+    let elapsed = now.saturating_duration_since(state.window_start);
+    if elapsed < self.window {
+      return;
+    }
+    state.previous_error_rate = if elapsed < self.window * 2 && state.requests >= MIN_REQUESTS {
+      Some(state.errors as f64 / state.requests as f64)
+    } else {
+      None
+    };
+    state.window_start = now;
+    state.requests = 0;
+    state.errors = 0;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn record_many(budget: &ErrorBudget, now: Instant, successes: u64, errors: u64) {
+    for _ in 0..successes {
+      budget.record_at(now, false);
+    }
+    for _ in 0..errors {
+      budget.record_at(now, true);
+    }
+  }
+
+  #[test]
+  fn error_budget_is_not_exhausted_without_requests() {
+    let budget = ErrorBudget::new(0.5, Duration::from_secs(10));
+
+    assert!(!budget.is_exhausted());
+  }
+
+  #[test]
+  fn error_budget_ignores_too_few_requests() {
+    let budget = ErrorBudget::new(0.5, Duration::from_secs(10));
+    let now = Instant::now();
+
+    record_many(&budget, now, 0, MIN_REQUESTS - 1);
+
+    assert!(!budget.is_exhausted_at(now));
+  }
+
+  #[test]
+  fn error_budget_is_exhausted_above_max_error_rate() {
+    let budget = ErrorBudget::new(0.5, Duration::from_secs(10));
+    let now = Instant::now();
+
+    record_many(&budget, now, 4, 6);
+
+    assert!(budget.is_exhausted_at(now));
+  }
+
+  #[test]
+  fn error_budget_remembers_previous_window() {
+    let budget = ErrorBudget::new(0.5, Duration::from_secs(10));
+    let now = Instant::now();
+
+    record_many(&budget, now, 4, 6);
+
+    assert!(budget.is_exhausted_at(now + Duration::from_secs(11)));
+  }
+
+  #[test]
+  fn error_budget_recovers_after_idle_window() {
+    let budget = ErrorBudget::new(0.5, Duration::from_secs(10));
+    let now = Instant::now();
+
+    record_many(&budget, now, 4, 6);
+
+    assert!(!budget.is_exhausted_at(now + Duration::from_secs(21)));
+  }
+}
@@ -1,25 +1,79 @@
-use crate::server::BackendPool;
-use arc_swap::{access::Access, ArcSwap};
-use futures::future::join_all;
-use hyper::{
-  client::HttpConnector,
-  http::uri::{self, Authority},
-  Client, StatusCode, Uri,
+use crate::{
+  backend_address::BackendAddress,
+  health_coordination::HealthCoordination,
+  health_events::{HealthEvent, HealthEvents},
+  server::BackendPool,
 };
+use arc_swap::{access::Access, ArcSwap, Guard};
+use futures::future::join_all;
+use hyper::{client::HttpConnector, http::uri, Client, StatusCode, Uri};
 use hyper_timeout::TimeoutConnector;
-use log::info;
+use log::{debug, info, warn};
+use rand::{thread_rng, Rng};
+use schemars::JsonSchema;
 use serde::Deserialize;
-use std::time::Duration;
-use std::time::SystemTime;
-use std::{convert::TryFrom, ops::Deref};
-use std::{fmt, sync::Arc};
-use tokio::time::interval;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use std::{
+  collections::{HashMap, VecDeque},
+  convert::TryFrom,
+  ops::Deref,
+};
+use std::{
+  fmt,
+  sync::{Arc, Mutex},
+};
+use tokio::{net::lookup_host, sync::Semaphore};
 /* Contains the user preferences regarding health checks */
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct HealthConfig {
   pub slow_threshold: i64,
   pub timeout: u64,
   pub path: String,
+  pub rise: usize,
+  pub fall: usize,
+  /* Overrides the global health check interval for this pool */
+  pub interval: Option<Duration>,
+  /* Probes all servers on this port instead of the port handling client requests */
+  pub port: Option<u16>,
+  /* Probes individual servers on a different address, keyed by the address handling client requests */
+  pub addresses: HashMap<String, String>,
+  pub warm_up: Option<WarmUpConfig>,
+}
+/* Requests which are sent to a recovered server before it is used for client requests again */
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct WarmUpConfig {
+  pub path: String,
+  pub requests: usize,
+}
+
+impl HealthConfig {
+  /* Returns the address that is probed to determine the healthiness of the server at `address` */
+  fn probe_address(&self, address: &str) -> String {
+    if let Some(probe_address) = self.addresses.get(address) {
+      return probe_address.clone();
+    }
+    // the addresses are validated when the configuration is loaded
+    match (self.port, address.parse::<BackendAddress>()) {
+      (Some(port), Ok(address)) => address.with_port(port).to_string(),
+      _ => address.to_string(),
+    }
+  }
+}
+/* Contains the global user preferences regarding the scheduling of health checks */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthInterval {
+  pub check_every: Duration,
+  /* Spread the checks of a pool across its interval instead of checking all servers at once */
+  pub stagger: bool,
+  /* Maximum number of health checks running at the same time across all pools */
+  pub max_concurrent_checks: Option<usize>,
+  /* Reports changes of the effective healthiness of all servers */
+  pub events: Option<Arc<HealthEvents>>,
+  /* Checks all servers once before a configuration serves requests, at startup and on reloads */
+  pub initial_check: bool,
+  /* Shares the results of the health checks with other instances of the load balancer */
+  pub coordination: Option<Arc<HealthCoordination>>,
 }
 /* Healthiness of a backend server */
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -27,6 +81,8 @@ pub enum Healthiness {
   Healthy,
   Slow(i64),
   Unresponsive(Option<StatusCode>),
+  /* Not checked yet, the server is not used until it passes its first health check */
+  Unknown,
 }
 
 impl fmt::Display for Healthiness {
@@ -36,83 +92,429 @@ impl fmt::Display for Healthiness {
       Healthiness::Slow(response_time) => write!(f, "Slow {}", response_time),
       Healthiness::Unresponsive(Some(status_code)) => write!(f, "Unresponsive, status: {}", status_code),
       Healthiness::Unresponsive(None) => write!(f, "Unresponsive"),
+      Healthiness::Unknown => write!(f, "Unknown"),
+    }
+  }
+}
+impl Healthiness {
+  /* Healthy and slow servers are both able to handle client requests */
+  pub fn is_up(&self) -> bool {
+    !matches!(self, Healthiness::Unresponsive(_) | Healthiness::Unknown)
+  }
+}
+/* Minimum number of health check results that are kept per backend server */
+const HEALTH_HISTORY_LENGTH: usize = 10;
+/* Effective healthiness of a backend server together with the results of its most recent health checks.
+The effective healthiness only switches between up (healthy or slow) and down (unresponsive)
+after `rise` consecutive successful or `fall` consecutive failed checks, to dampen flapping servers. */
+#[derive(Debug)]
+pub struct BackendHealth {
+  healthiness: ArcSwap<Healthiness>,
+  history: Mutex<VecDeque<Healthiness>>,
+  /* IP addresses the host name of the server resolved to during the last health check, empty for IP addresses */
+  resolved_ips: Mutex<Vec<IpAddr>>,
+}
+
+impl BackendHealth {
+  pub fn new(healthiness: Healthiness) -> BackendHealth {
+    BackendHealth {
+      healthiness: ArcSwap::from_pointee(healthiness),
+      history: Mutex::new(VecDeque::new()),
+      resolved_ips: Mutex::new(Vec::new()),
+    }
+  }
+
+  pub fn resolved_ips(&self) -> Vec<IpAddr> {
+    self.resolved_ips.lock().unwrap().clone()
+  }
+
+  pub fn load(&self) -> Guard<Arc<Healthiness>> {
+    self.healthiness.load()
+  }
+
+  fn store(&self, healthiness: Healthiness) {
+    self.healthiness.store(Arc::new(healthiness));
+  }
+
+  /// Takes over the healthiness and the recent health check results of the same server in a previous configuration,
+  /// so a reload neither resets the dampening nor the initial state of a known server.
+  pub fn inherit(&self, previous: &BackendHealth) {
+    self.store(previous.load().as_ref().clone());
+    *self.history.lock().unwrap() = previous.history.lock().unwrap().clone();
+    *self.resolved_ips.lock().unwrap() = previous.resolved_ips();
+  }
+
+  /* A server which was never checked has no results to dampen */
+  fn was_checked(&self) -> bool {
+    !self.history.lock().unwrap().is_empty()
+  }
+
+  /* Records a health check result and returns the new effective healthiness if it differs from the current one.
+  The new effective healthiness is not stored, so the caller can prepare the server first. */
+  fn record(&self, result: Healthiness, rise: usize, fall: usize) -> Option<Healthiness> {
+    let mut history = self.history.lock().unwrap();
+    history.push_back(result);
+    while history.len() > HEALTH_HISTORY_LENGTH.max(rise).max(fall) {
+      history.pop_front();
+    }
+
+    let previous_healthiness = self.healthiness.load();
+    let healthiness = effective_healthiness(&previous_healthiness, &history, rise, fall);
+    if previous_healthiness.as_ref() != &healthiness {
+      Some(healthiness)
+    } else {
+      None
+    }
+  }
+}
+/* Determines the effective healthiness based on the current one and the recorded results, newest last */
+fn effective_healthiness(
+  current: &Healthiness,
+  history: &VecDeque<Healthiness>,
+  rise: usize,
+  fall: usize,
+) -> Healthiness {
+  let latest = match history.back() {
+    Some(latest) => latest,
+    None => return current.clone(),
+  };
+  // the first result of a server in an unknown state is all that is known about it
+  if *current == Healthiness::Unknown {
+    return latest.clone();
+  }
+  let consecutive = |count: usize, up: bool| {
+    let count = count.max(1);
+    history.len() >= count && history.iter().rev().take(count).all(|it| it.is_up() == up)
+  };
+  match (current.is_up(), latest.is_up()) {
+    (true, false) if !consecutive(fall, false) => current.clone(),
+    (false, true) if !consecutive(rise, true) => current.clone(),
+    _ => latest.clone(),
+  }
+}
+/* How often watch_health looks for pools that are due for a health check */
+const SCHEDULER_RESOLUTION: Duration = Duration::from_millis(100);
+/* Keeps track of when the backends of a pool were last checked */
+#[derive(Debug, Default)]
+pub struct HealthSchedule {
+  last_check: Mutex<Option<Instant>>,
+}
+
+impl HealthSchedule {
+  /* Returns true and marks the pool as checked at `now` if the last check is at least `interval` ago.
+  An interval of zero deactivates health checks. */
+  fn start_check_if_due(&self, now: Instant, interval: Duration) -> bool {
+    if interval == Duration::from_secs(0) {
+      return false;
+    }
+    let mut last_check = self.last_check.lock().unwrap();
+    match *last_check {
+      Some(last_check) if now.saturating_duration_since(last_check) < interval => false,
+      _ => {
+        *last_check = Some(now);
+        true
+      }
     }
   }
 }
 /* Start loop to regularly contact backend to investigate the healthiness of each server.
+Each pool is checked in its own interval, which defaults to the global interval.
 The healthiness is noted in the backend_pool vector  */
-pub async fn watch_health<A, G, H, J>(backend_pools: A, interval_duration: H)
+pub async fn watch_health<A, G, H, J>(backend_pools: A, health_interval: H)
 where
   A: Access<Vec<Arc<BackendPool>>, Guard = G> + Send + Sync + 'static,
   G: Deref<Target = Vec<Arc<BackendPool>>> + Send + Sync,
-  H: Access<Duration, Guard = J>,
-  J: Deref<Target = Duration>,
+  H: Access<HealthInterval, Guard = J>,
+  J: Deref<Target = HealthInterval>,
 {
+  let mut limit: Option<(usize, Arc<Semaphore>)> = None;
   loop {
-    // set and start interval timer
-    let interval_duration = *interval_duration.load().deref();
-    if interval_duration == Duration::from_secs(0) {
-      tokio::time::sleep(Duration::from_secs(5)).await;
-      continue;
-    }
-    let mut interval_timer = interval(interval_duration);
-    interval_timer.tick().await;
-    // create and perform server checks
-    let loaded_pools = backend_pools.load();
-    let mut checks = Vec::new();
-    for pool in loaded_pools.iter() {
-      for (server_address, healthiness) in &pool.addresses {
-        let future = check_server_health_once(server_address.clone(), healthiness, &pool.health_config);
-        checks.push(future);
+    let health_interval = health_interval.load().deref().clone();
+    limit = match (limit, health_interval.max_concurrent_checks) {
+      (Some((current, semaphore)), Some(max)) if current == max => Some((current, semaphore)),
+      (_, Some(max)) => Some((max, Arc::new(Semaphore::new(max)))),
+      (_, None) => None,
+    };
+    let now = Instant::now();
+    for pool in backend_pools.load().iter() {
+      let interval = pool.health_config.interval.unwrap_or(health_interval.check_every);
+      if pool.health_schedule.start_check_if_due(now, interval) {
+        let pool = pool.clone();
+        let stagger = if health_interval.stagger { Some(interval) } else { None };
+        let semaphore = limit.as_ref().map(|(_, semaphore)| semaphore.clone());
+        let events = health_interval.events.clone();
+        let coordination = health_interval.coordination.clone();
+        tokio::spawn(async move {
+          match coordination {
+            Some(coordination) => {
+              check_pool_health_coordinated(&pool, interval, &coordination, stagger, semaphore, events.as_deref()).await
+            }
+            None => check_pool_health_once(&pool, stagger, semaphore, events.as_deref(), true).await,
+          }
+        });
       }
     }
-    join_all(checks).await;
-    /*  Yes tick is called twice in one loop on purpose.
-    Since we are recreating interval_timer on every loop,
-    the first tick, marks the starting point, resuming immediately.
-    */
-    interval_timer.tick().await;
+    tokio::time::sleep(SCHEDULER_RESOLUTION).await;
+  }
+}
+/// Checks all backend servers of the pool immediately, regardless of its health check interval, and resets the
+/// interval.
+pub async fn check_pool_health_now(pool: &BackendPool, events: Option<&HealthEvents>) {
+  *pool.health_schedule.last_check.lock().unwrap() = Some(Instant::now());
+  check_pool_health_once(pool, None, None, events, true).await;
+}
+/// Checks all backend servers of all pools once and resets their health check intervals, so dead servers are not
+/// used for the first requests of a configuration. Pools whose health checks are deactivated are skipped. The result
+/// of this check is not dampened by `rise` and `fall` for servers which were never checked, since their initial
+/// healthiness is only assumed.
+pub async fn check_health_initially(backend_pools: &[Arc<BackendPool>], health_interval: &HealthInterval) {
+  let semaphore = health_interval
+    .max_concurrent_checks
+    .map(|max| Arc::new(Semaphore::new(max)));
+  let now = Instant::now();
+  let checks = backend_pools
+    .iter()
+    .filter(|pool| pool.health_config.interval.unwrap_or(health_interval.check_every) != Duration::from_secs(0))
+    .map(|pool| {
+      *pool.health_schedule.last_check.lock().unwrap() = Some(now);
+      check_pool_health_once(pool, None, semaphore.clone(), health_interval.events.as_deref(), false)
+    });
+  join_all(checks).await;
+}
+/* Checks all servers of a pool concurrently, optionally spread across the `stagger` interval
+and limited by the permits of `semaphore`. Without `dampened`, the results of servers which were never checked take
+effect immediately. */
+async fn check_pool_health_once(
+  pool: &BackendPool,
+  stagger: Option<Duration>,
+  semaphore: Option<Arc<Semaphore>>,
+  events: Option<&HealthEvents>,
+  dampened: bool,
+) {
+  let count = pool.addresses.len();
+  let checks = pool
+    .addresses
+    .iter()
+    .enumerate()
+    .map(|(index, (server_address, health))| {
+      let delay = stagger.map(|interval| stagger_delay(interval, index, count));
+      let semaphore = semaphore.clone();
+      async move {
+        if let Some(delay) = delay {
+          tokio::time::sleep(delay).await;
+        }
+        let _permit = match semaphore {
+          Some(semaphore) => semaphore.acquire_owned().await.ok(),
+          None => None,
+        };
+        check_server_health_once(server_address.clone(), health, pool, events, dampened).await
+      }
+    });
+  join_all(checks).await;
+}
+/* Checks the servers of a pool if this instance leads the health checks of the pool and publishes the results.
+Otherwise the results of the leader are taken over. If Redis is not available or the leader did not publish results
+for all servers, the servers are checked by this instance, as without coordination. */
+async fn check_pool_health_coordinated(
+  pool: &BackendPool,
+  interval: Duration,
+  coordination: &HealthCoordination,
+  stagger: Option<Duration>,
+  semaphore: Option<Arc<Semaphore>>,
+  events: Option<&HealthEvents>,
+) {
+  let leads = coordination.lead(&pool.name, interval).await.unwrap_or_else(|e| {
+    warn!(
+      "Could not coordinate the health checks of backend pool '{}' due to: {}",
+      pool.name, e
+    );
+    true
+  });
+  if !leads {
+    let addresses = pool
+      .addresses
+      .iter()
+      .map(|(address, _)| address.as_str())
+      .collect::<Vec<_>>();
+    match coordination.fetch(&pool.name, &addresses).await {
+      Ok(results) if results.iter().all(Option::is_some) => {
+        for ((server_address, health), healthiness) in pool.addresses.iter().zip(results.into_iter().flatten()) {
+          if health.load().as_ref() != &healthiness {
+            switch_healthiness(server_address, health, pool, events, healthiness);
+          }
+        }
+        return;
+      }
+      Ok(_) => debug!(
+        "The leader did not publish the health of backend pool '{}' yet",
+        pool.name
+      ),
+      Err(e) => warn!(
+        "Could not fetch the health of backend pool '{}' due to: {}",
+        pool.name, e
+      ),
+    }
+  }
+
+  check_pool_health_once(pool, stagger, semaphore, events, true).await;
+  if leads {
+    let servers = pool
+      .addresses
+      .iter()
+      .map(|(address, health)| (address.as_str(), health.load().as_ref().clone()))
+      .collect::<Vec<_>>();
+    if let Err(e) = coordination.publish(&pool.name, interval, &servers).await {
+      warn!(
+        "Could not publish the health of backend pool '{}' due to: {}",
+        pool.name, e
+      );
+    }
   }
 }
-/* Contacts one server and sets health value if changed */
+/* Divides the interval into one slot per server and returns a random point in time within the slot of the server */
+fn stagger_delay(interval: Duration, index: usize, count: usize) -> Duration {
+  let slot = interval / count.max(1) as u32;
+  let jitter = match slot.as_millis() as u64 {
+    0 => 0,
+    slot_millis => thread_rng().gen_range(0..slot_millis),
+  };
+  slot * index as u32 + Duration::from_millis(jitter)
+}
+/* Contacts one server and records the result, which might change its effective health value */
 async fn check_server_health_once(
   server_address: String,
-  healthiness: &ArcSwap<Healthiness>,
-  health_config: &HealthConfig,
+  health: &BackendHealth,
+  pool: &BackendPool,
+  events: Option<&HealthEvents>,
+  dampened: bool,
 ) {
-  let uri = uri::Uri::builder()
-    .scheme("http")
-    .path_and_query(&health_config.path)
-    .authority(Authority::from_maybe_shared(server_address.clone()).unwrap())
-    .build()
-    .unwrap();
+  let health_config = &pool.health_config;
+  resolve_server(&server_address, health, pool).await;
+  let client = health_client(pool.connector.connector(), health_config.timeout);
+  // the addresses are validated when the configuration is loaded, but the path may still be invalid
+  let probe_address = health_config.probe_address(&server_address);
+  let uri = probe_address.parse::<BackendAddress>().and_then(|address| {
+    uri::Uri::builder()
+      .scheme("http")
+      .path_and_query(health_config.path.as_str())
+      .authority(address.authority())
+      .build()
+      .map_err(|e| e.to_string())
+  });
+  let uri = match uri {
+    Ok(uri) => uri,
+    Err(e) => {
+      warn!("Can not check the health of {} due to: {}", probe_address, e);
+      return;
+    }
+  };
 
-  let previous_healthiness = healthiness.load();
-  let result = contact_server(uri, health_config.slow_threshold, health_config.timeout).await;
+  let result = contact_server(&client, uri, health_config.slow_threshold).await;
 
-  if previous_healthiness.as_ref() != &result {
-    info!("new healthiness for {}: {}", &server_address, &result);
-    healthiness.store(Arc::new(result));
+  let (rise, fall) = if dampened || health.was_checked() {
+    (health_config.rise, health_config.fall)
+  } else {
+    (1, 1)
+  };
+  if let Some(healthiness) = health.record(result, rise, fall) {
+    let recovered = !health.load().is_up() && healthiness.is_up();
+    if let (true, Some(warm_up)) = (recovered, &health_config.warm_up) {
+      warm_up_server(&client, &server_address, warm_up).await;
+    }
+    switch_healthiness(&server_address, health, pool, events, healthiness);
   }
 }
-/* Returns the healthiness of the given server by performing a network request  */
-async fn contact_server(server_address: Uri, slow_threshold: i64, timeout: u64) -> Healthiness {
-  let http_connector = HttpConnector::new();
+/* Stores the new effective healthiness of a server and reports the change */
+fn switch_healthiness(
+  server_address: &str,
+  health: &BackendHealth,
+  pool: &BackendPool,
+  events: Option<&HealthEvents>,
+  healthiness: Healthiness,
+) {
+  info!(
+    "new healthiness for {} of backend pool '{}': {}",
+    server_address, pool.name, &healthiness
+  );
+  if let Some(events) = events {
+    let previous = health.load().as_ref().clone();
+    let event = HealthEvent::new(&pool.name, server_address, previous, healthiness.clone());
+    events.emit(event);
+  }
+  health.store(healthiness);
+  pool.refresh_working_addresses();
+}
+/* Resolves the host name of a server again, so changes of its DNS records show up in the logs and the admin API.
+The connector resolves host names on every new connection by itself, this only makes the current addresses visible. */
+async fn resolve_server(server_address: &str, health: &BackendHealth, pool: &BackendPool) {
+  let address = match server_address.parse::<BackendAddress>() {
+    Ok(address) if address.is_host_name() => address,
+    _ => return,
+  };
+  let mut ips = match lookup_host(address.lookup_target()).await {
+    Ok(resolved) => resolved.map(|it| it.ip()).collect::<Vec<_>>(),
+    Err(e) => {
+      warn!(
+        "Could not resolve {} of backend pool '{}' due to: {}",
+        server_address, pool.name, e
+      );
+      return;
+    }
+  };
+  ips.sort();
+  ips.dedup();
+  let mut resolved_ips = health.resolved_ips.lock().unwrap();
+  if *resolved_ips != ips {
+    info!(
+      "{} of backend pool '{}' resolves to {:?}",
+      server_address, pool.name, ips
+    );
+    *resolved_ips = ips;
+  }
+}
+/* Sends the configured warm-up requests to a server, ignoring their responses */
+async fn warm_up_server(client: &HealthClient, server_address: &str, warm_up: &WarmUpConfig) {
+  let uri = match uri::Uri::builder()
+    .scheme("http")
+    .path_and_query(warm_up.path.as_str())
+    .authority(server_address)
+    .build()
+  {
+    Ok(uri) => uri,
+    Err(e) => {
+      warn!("Could not warm up {} due to: {}", server_address, e);
+      return;
+    }
+  };
+  info!("warming up {} with {} requests", server_address, warm_up.requests);
+  for _ in 0..warm_up.requests {
+    if let Err(e) = client.get(uri.clone()).await {
+      debug!("warm-up request to {} failed: {}", server_address, e);
+    }
+  }
+}
+
+type HealthClient = Client<TimeoutConnector<HttpConnector>>;
+
+/* Builds a client using the same connector settings as the requests forwarded to the pool, so health checks probe
+the backend servers the same way as real traffic */
+fn health_client(http_connector: HttpConnector, timeout: u64) -> HealthClient {
   let mut connector = TimeoutConnector::new(http_connector);
   connector.set_connect_timeout(Some(Duration::from_millis(timeout)));
   connector.set_read_timeout(Some(Duration::from_millis(timeout)));
   connector.set_write_timeout(Some(Duration::from_millis(timeout)));
-  let client = Client::builder().build::<_, hyper::Body>(connector);
-
-  let before_request = SystemTime::now();
+  Client::builder().build::<_, hyper::Body>(connector)
+}
+/* Returns the healthiness of the given server by performing a network request  */
+async fn contact_server(client: &HealthClient, server_address: Uri, slow_threshold: i64) -> Healthiness {
+  // measured with the monotonic clock, so adjustments of the system time don't distort the response time
+  let before_request = Instant::now();
   // Await the response...
   if let Ok(response) = client.get(server_address).await {
     if response.status().is_success() {
-      // elapsed() only fails when system time is later than "self"
-      let time_to_respond = before_request.elapsed().unwrap().as_millis();
-      let response_time = i64::try_from(time_to_respond);
-      if response_time.unwrap() > slow_threshold {
-        Healthiness::Slow(response_time.unwrap())
+      let response_time = i64::try_from(before_request.elapsed().as_millis()).unwrap_or(i64::MAX);
+      if response_time > slow_threshold {
+        Healthiness::Slow(response_time)
       } else {
         Healthiness::Healthy
       }
@@ -123,3 +525,323 @@ async fn contact_server(server_address: Uri, slow_threshold: i64, timeout: u64)
     Healthiness::Unresponsive(None)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    backend_pool_matcher::BackendPoolMatcher,
+    listeners::plain_acceptor,
+    load_balancing::round_robin::RoundRobin,
+    middleware::MiddlewareChain,
+    server::{test_pool_builder, BackendPoolBuilder, Scheme},
+  };
+  use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+  };
+  use std::{collections::HashSet, convert::Infallible, iter::FromIterator};
+  use tokio::net::{TcpListener, TcpStream};
+
+  fn history(results: Vec<Healthiness>) -> VecDeque<Healthiness> {
+    results.into_iter().collect()
+  }
+
+  #[test]
+  fn effective_healthiness_switches_immediately_without_dampening() {
+    let results = history(vec![Healthiness::Unresponsive(None)]);
+
+    let actual = effective_healthiness(&Healthiness::Healthy, &results, 1, 1);
+
+    assert_eq!(actual, Healthiness::Unresponsive(None));
+  }
+
+  #[test]
+  fn effective_healthiness_requires_fall_consecutive_failures() {
+    let results = history(vec![
+      Healthiness::Unresponsive(None),
+      Healthiness::Healthy,
+      Healthiness::Unresponsive(None),
+      Healthiness::Unresponsive(None),
+    ]);
+
+    assert_eq!(
+      effective_healthiness(&Healthiness::Healthy, &results, 1, 3),
+      Healthiness::Healthy
+    );
+    assert_eq!(
+      effective_healthiness(&Healthiness::Healthy, &results, 1, 2),
+      Healthiness::Unresponsive(None)
+    );
+  }
+
+  #[test]
+  fn effective_healthiness_requires_rise_consecutive_successes() {
+    let results = history(vec![
+      Healthiness::Unresponsive(None),
+      Healthiness::Slow(400),
+      Healthiness::Healthy,
+    ]);
+
+    assert_eq!(
+      effective_healthiness(&Healthiness::Unresponsive(None), &results, 3, 1),
+      Healthiness::Unresponsive(None)
+    );
+    assert_eq!(
+      effective_healthiness(&Healthiness::Unresponsive(None), &results, 2, 1),
+      Healthiness::Healthy
+    );
+  }
+
+  #[test]
+  fn effective_healthiness_leaves_unknown_state_with_first_result() {
+    let up = history(vec![Healthiness::Healthy]);
+    let down = history(vec![Healthiness::Unresponsive(None)]);
+
+    assert_eq!(
+      effective_healthiness(&Healthiness::Unknown, &up, 3, 3),
+      Healthiness::Healthy
+    );
+    assert_eq!(
+      effective_healthiness(&Healthiness::Unknown, &down, 3, 3),
+      Healthiness::Unresponsive(None)
+    );
+    assert_eq!(
+      effective_healthiness(&Healthiness::Unknown, &VecDeque::new(), 3, 3),
+      Healthiness::Unknown
+    );
+  }
+
+  #[test]
+  fn effective_healthiness_switches_between_healthy_and_slow_immediately() {
+    let results = history(vec![Healthiness::Healthy, Healthiness::Slow(400)]);
+
+    let actual = effective_healthiness(&Healthiness::Healthy, &results, 3, 3);
+
+    assert_eq!(actual, Healthiness::Slow(400));
+  }
+
+  #[test]
+  fn health_schedule_is_due_once_per_interval() {
+    let schedule = HealthSchedule::default();
+    let interval = Duration::from_secs(10);
+    let start = Instant::now();
+
+    assert!(schedule.start_check_if_due(start, interval));
+    assert!(!schedule.start_check_if_due(start + Duration::from_secs(9), interval));
+    assert!(schedule.start_check_if_due(start + Duration::from_secs(10), interval));
+  }
+
+  #[test]
+  fn health_schedule_is_not_due_if_last_check_is_later() {
+    // e.g. a forced check started after the scheduler took the current time
+    let schedule = HealthSchedule::default();
+    let interval = Duration::from_secs(10);
+    let start = Instant::now();
+    schedule.start_check_if_due(start + Duration::from_secs(1), interval);
+
+    assert!(!schedule.start_check_if_due(start, interval));
+  }
+
+  #[test]
+  fn health_schedule_zero_interval_is_never_due() {
+    let schedule = HealthSchedule::default();
+
+    assert!(!schedule.start_check_if_due(Instant::now(), Duration::from_secs(0)));
+  }
+
+  #[test]
+  fn stagger_delay_stays_within_slot_of_server() {
+    let interval = Duration::from_secs(10);
+
+    for index in 0..5 {
+      let delay = stagger_delay(interval, index, 5);
+
+      assert!(delay >= Duration::from_secs(2 * index as u64));
+      assert!(delay < Duration::from_secs(2 * (index as u64 + 1)));
+    }
+  }
+
+  #[test]
+  fn stagger_delay_without_jitter_for_tiny_intervals() {
+    assert_eq!(stagger_delay(Duration::from_nanos(1), 3, 5), Duration::from_secs(0));
+  }
+
+  fn health_config(port: Option<u16>, addresses: Vec<(&str, &str)>) -> HealthConfig {
+    HealthConfig {
+      port,
+      addresses: addresses
+        .into_iter()
+        .map(|(address, probe_address)| (address.to_string(), probe_address.to_string()))
+        .collect(),
+      ..HealthConfig::default()
+    }
+  }
+
+  #[test]
+  fn probe_address_defaults_to_server_address() {
+    let config = health_config(None, vec![]);
+
+    assert_eq!(config.probe_address("127.0.0.1:8080"), "127.0.0.1:8080");
+  }
+
+  #[test]
+  fn probe_address_replaces_port() {
+    let config = health_config(Some(9090), vec![]);
+
+    assert_eq!(config.probe_address("127.0.0.1:8080"), "127.0.0.1:9090");
+    assert_eq!(config.probe_address("[::1]:8080"), "[::1]:9090");
+    assert_eq!(config.probe_address("app.internal:8080"), "app.internal:9090");
+    assert_eq!(config.probe_address("[::1]"), "[::1]:9090");
+    assert_eq!(config.probe_address("app.internal"), "app.internal:9090");
+  }
+
+  #[test]
+  fn probe_address_prefers_explicit_address() {
+    let config = health_config(Some(9090), vec![("127.0.0.1:8080", "10.0.0.1:8000")]);
+
+    assert_eq!(config.probe_address("127.0.0.1:8080"), "10.0.0.1:8000");
+    assert_eq!(config.probe_address("127.0.0.1:8081"), "127.0.0.1:9090");
+  }
+
+  #[test]
+  fn backend_health_record_keeps_bounded_history() {
+    let health = BackendHealth::new(Healthiness::Healthy);
+
+    for _ in 0..HEALTH_HISTORY_LENGTH + 5 {
+      health.record(Healthiness::Healthy, 1, 1);
+    }
+
+    assert_eq!(health.history.lock().unwrap().len(), HEALTH_HISTORY_LENGTH);
+  }
+
+  #[test]
+  fn backend_health_inherits_previous_results() {
+    // given:
+    let previous = BackendHealth::new(Healthiness::Healthy);
+    previous.record(Healthiness::Unresponsive(None), 1, 2);
+    let health = BackendHealth::new(Healthiness::Unknown);
+
+    // when:
+    health.inherit(&previous);
+
+    // then:
+    assert_eq!(*health.load().as_ref(), Healthiness::Healthy);
+    assert_eq!(
+      health.record(Healthiness::Unresponsive(None), 1, 2),
+      Some(Healthiness::Unresponsive(None))
+    );
+  }
+
+  #[tokio::test]
+  async fn resolves_host_names_of_backend_servers() {
+    // given:
+    let pool = test_pool_builder(
+      "localhost",
+      BackendPoolMatcher::Host("localhost".into()),
+      vec![
+        ("localhost:8084".into(), BackendHealth::new(Healthiness::Healthy)),
+        ("127.0.0.1:8084".into(), BackendHealth::new(Healthiness::Healthy)),
+      ],
+      Box::new(RoundRobin::new()),
+    )
+    .build();
+
+    // when:
+    for (address, health) in pool.addresses.iter() {
+      resolve_server(address, health, &pool).await;
+    }
+
+    // then:
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+    assert!(pool.addresses[0].1.resolved_ips().contains(&ip));
+    assert!(pool.addresses[1].1.resolved_ips().is_empty());
+  }
+
+  #[tokio::test]
+  async fn initial_check_is_not_dampened() {
+    // given:
+    let pool = |interval: Option<Duration>| {
+      Arc::new(
+        BackendPoolBuilder::new(
+          "localhost".into(),
+          BackendPoolMatcher::Host("localhost".into()),
+          vec![("127.0.0.1:1".into(), BackendHealth::new(Healthiness::Healthy))],
+          HealthConfig {
+            fall: 3,
+            interval,
+            ..HealthConfig::default()
+          },
+          Box::new(RoundRobin::new()),
+          MiddlewareChain::Empty,
+          HashSet::from_iter(vec![Scheme::HTTP]),
+        )
+        .build(),
+      )
+    };
+    let pools = vec![pool(None), pool(Some(Duration::from_secs(0)))];
+    let health_interval = HealthInterval {
+      check_every: Duration::from_secs(10),
+      stagger: false,
+      max_concurrent_checks: None,
+      events: None,
+      initial_check: true,
+      coordination: None,
+    };
+
+    // when:
+    check_health_initially(&pools, &health_interval).await;
+
+    // then:
+    assert_eq!(
+      *pools[0].addresses[0].1.load().as_ref(),
+      Healthiness::Unresponsive(None)
+    );
+    assert_eq!(*pools[1].addresses[0].1.load().as_ref(), Healthiness::Healthy);
+  }
+
+  #[tokio::test]
+  async fn warms_up_recovered_servers_before_using_them_again() {
+    // given: a backend server recording the paths of all requests
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let paths = Arc::new(Mutex::new(Vec::new()));
+    let recorded = paths.clone();
+    tokio::spawn(
+      Server::builder(plain_acceptor(listener)).serve(make_service_fn(move |_: &TcpStream| {
+        let recorded = recorded.clone();
+        async move {
+          Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+            recorded.lock().unwrap().push(request.uri().path().to_string());
+            async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+          }))
+        }
+      })),
+    );
+    let pool = BackendPoolBuilder::new(
+      "localhost".into(),
+      BackendPoolMatcher::Host("localhost".into()),
+      vec![(address.clone(), BackendHealth::new(Healthiness::Unresponsive(None)))],
+      HealthConfig {
+        warm_up: Some(WarmUpConfig {
+          path: "/warm-up".into(),
+          requests: 2,
+        }),
+        ..HealthConfig::default()
+      },
+      Box::new(RoundRobin::new()),
+      MiddlewareChain::Empty,
+      HashSet::from_iter(vec![Scheme::HTTP]),
+    )
+    .build();
+    let health = &pool.addresses[0].1;
+
+    // when:
+    check_server_health_once(address.clone(), health, &pool, None, false).await;
+    check_server_health_once(address.clone(), health, &pool, None, false).await;
+
+    // then: only the recovery triggers the warm-up requests, which are sent before the server is healthy again
+    assert_eq!(*health.load().as_ref(), Healthiness::Healthy);
+    assert_eq!(*paths.lock().unwrap(), vec!["/", "/warm-up", "/warm-up", "/"]);
+  }
+}
@@ -0,0 +1,160 @@
+use crate::{health::Healthiness, redis_connection::RedisConnection, utils::split_once};
+use gethostname::gethostname;
+use hyper::StatusCode;
+use redis::RedisResult;
+use std::{io, process, time::Duration};
+
+/// Shares the health check results of several instances of the load balancer via Redis. Per backend pool, one
+/// instance leads: it checks the backend servers and publishes their effective healthiness. The other instances take
+/// it over instead of checking the backend servers themselves, so all instances agree on the healthiness and the
+/// backend servers are checked only once per interval. If the leader stops, its lease expires after three intervals
+/// and another instance takes over.
+#[derive(Debug)]
+pub struct HealthCoordination {
+  url: String,
+  connection: RedisConnection,
+  key_prefix: String,
+  /* Identifies this instance as leader of a backend pool */
+  instance: String,
+}
+
+pub const DEFAULT_KEY_PREFIX: &str = "arlb:health";
+/* Redis is asked in the background of the health checks, so it may take longer than on the request path */
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl HealthCoordination {
+  pub fn new(url: &str, key_prefix: String) -> RedisResult<HealthCoordination> {
+    Ok(HealthCoordination {
+      url: url.to_string(),
+      connection: RedisConnection::new(url, REDIS_TIMEOUT)?,
+      key_prefix,
+      instance: format!("{}:{}", gethostname().to_string_lossy(), process::id()),
+    })
+  }
+
+  /// Takes or extends the lease on the health checks of the backend pool. Returns `true` if this instance leads.
+  pub async fn lead(&self, pool: &str, interval: Duration) -> io::Result<bool> {
+    let key = self.key(pool, "leader");
+    let lease = lease(interval);
+    let pipeline = redis::pipe()
+      .cmd("SET")
+      .arg(&key)
+      .arg(&self.instance)
+      .arg("PX")
+      .arg(lease)
+      .arg("NX")
+      .ignore()
+      .get(&key)
+      .clone();
+    let (leader,) = self.connection.query::<(Option<String>,)>(&pipeline).await?;
+    if leader.as_deref() != Some(self.instance.as_str()) {
+      return Ok(false);
+    }
+    self
+      .connection
+      .query::<()>(redis::pipe().pexpire(&key, lease as usize).ignore())
+      .await?;
+    Ok(true)
+  }
+
+  /// Publishes the effective healthiness of the backend servers of the pool.
+  pub async fn publish(&self, pool: &str, interval: Duration, servers: &[(&str, Healthiness)]) -> io::Result<()> {
+    if servers.is_empty() {
+      return Ok(());
+    }
+    let mut pipeline = redis::pipe();
+    for (address, healthiness) in servers {
+      pipeline
+        .cmd("SET")
+        .arg(self.key(pool, address))
+        .arg(encode(healthiness))
+        .arg("PX")
+        .arg(lease(interval))
+        .ignore();
+    }
+    self.connection.query::<()>(&pipeline).await
+  }
+
+  /// Returns the effective healthiness of the backend servers at `addresses` published by the leader, `None` for
+  /// servers it did not publish a valid healthiness of.
+  pub async fn fetch(&self, pool: &str, addresses: &[&str]) -> io::Result<Vec<Option<Healthiness>>> {
+    if addresses.is_empty() {
+      return Ok(Vec::new());
+    }
+    let mut pipeline = redis::pipe();
+    for address in addresses {
+      pipeline.get(self.key(pool, address));
+    }
+    let values = self.connection.query::<Vec<Option<String>>>(&pipeline).await?;
+    Ok(values.iter().map(|value| value.as_deref().and_then(decode)).collect())
+  }
+
+  fn key(&self, pool: &str, name: &str) -> String {
+    format!("{}:{}:{}", self.key_prefix, pool, name)
+  }
+}
+
+/* Coordinations of the same Redis and key prefix are the same, regardless of the connection */
+impl PartialEq for HealthCoordination {
+  fn eq(&self, other: &Self) -> bool {
+    self.url == other.url && self.key_prefix == other.key_prefix
+  }
+}
+
+impl Eq for HealthCoordination {}
+
+/* Leases and results outlive a few missed intervals of the leader */
+fn lease(interval: Duration) -> u64 {
+  (interval * 3).as_millis() as u64
+}
+
+fn encode(healthiness: &Healthiness) -> String {
+  match healthiness {
+    Healthiness::Healthy => "healthy".to_string(),
+    Healthiness::Slow(response_time) => format!("slow:{}", response_time),
+    Healthiness::Unresponsive(Some(status_code)) => format!("unresponsive:{}", status_code.as_u16()),
+    Healthiness::Unresponsive(None) => "unresponsive".to_string(),
+    Healthiness::Unknown => "unknown".to_string(),
+  }
+}
+
+fn decode(value: &str) -> Option<Healthiness> {
+  let (state, detail) = match split_once(value, ':') {
+    Some((state, detail)) => (state, Some(detail)),
+    None => (value, None),
+  };
+  match (state, detail) {
+    ("healthy", None) => Some(Healthiness::Healthy),
+    ("slow", Some(response_time)) => response_time.parse().ok().map(Healthiness::Slow),
+    ("unresponsive", Some(status_code)) => status_code
+      .parse()
+      .ok()
+      .and_then(|it| StatusCode::from_u16(it).ok())
+      .map(|it| Healthiness::Unresponsive(Some(it))),
+    ("unresponsive", None) => Some(Healthiness::Unresponsive(None)),
+    ("unknown", None) => Some(Healthiness::Unknown),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn decodes_encoded_healthiness() {
+    let all = vec![
+      Healthiness::Healthy,
+      Healthiness::Slow(420),
+      Healthiness::Unresponsive(Some(StatusCode::SERVICE_UNAVAILABLE)),
+      Healthiness::Unresponsive(None),
+      Healthiness::Unknown,
+    ];
+
+    for healthiness in all {
+      assert_eq!(decode(&encode(&healthiness)), Some(healthiness));
+    }
+    assert_eq!(decode("slow:fast"), None);
+    assert_eq!(decode("healthy:1"), None);
+  }
+}
@@ -0,0 +1,272 @@
+use crate::{
+  configuration::RuntimeConfig,
+  error_response::{method_not_allowed, not_found, text_response},
+  listeners::plain_acceptor,
+  server::BackendPool,
+};
+use arc_swap::ArcSwap;
+use futures::TryFutureExt;
+use hyper::{
+  service::{make_service_fn, service_fn},
+  Body, Method, Request, Response, Server, StatusCode,
+};
+use log::info;
+use std::{convert::Infallible, io, net::SocketAddr, sync::Arc};
+use tokio::net::{TcpListener, TcpStream};
+
+/* Liveness and readiness probes for cloud load balancers and Kubernetes */
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthEndpointConfig {
+  pub address: SocketAddr,
+  pub liveness_path: String,
+  pub readiness_path: String,
+  /* Starts and ends with `/`, the name of a pool follows, e.g. `/pools/whoami` */
+  pub pool_path_prefix: String,
+}
+
+pub async fn serve(listener: TcpListener, config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
+  info!("Started listening for health probes on {}", listener.local_addr()?);
+  let service = make_service_fn(move |_: &TcpStream| {
+    let config = config.clone();
+    async move {
+      Ok::<_, io::Error>(service_fn(move |request| {
+        let config = config.load();
+        let response = match &config.health_endpoint {
+          Some(health_endpoint) => respond(&request, health_endpoint, &config.shared_data.backend_pools),
+          None => not_found(),
+        };
+        async move { Ok::<_, Infallible>(response) }
+      }))
+    }
+  });
+  Server::builder(plain_acceptor(listener))
+    .serve(service)
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to listen health endpoint: {}", e)))
+    .await
+}
+
+fn respond(
+  request: &Request<Body>,
+  config: &HealthEndpointConfig,
+  backend_pools: &[Arc<BackendPool>],
+) -> Response<Body> {
+  let path = request.uri().path();
+  let pool_name = path
+    .strip_prefix(config.pool_path_prefix.as_str())
+    .filter(|name| !name.is_empty())
+    .map(percent_decode);
+  if path != config.liveness_path && path != config.readiness_path && pool_name.is_none() {
+    return not_found();
+  }
+  if request.method() != Method::GET && request.method() != Method::HEAD {
+    return method_not_allowed();
+  }
+  if path == config.liveness_path {
+    text_response(StatusCode::OK, "ok\n")
+  } else if path == config.readiness_path {
+    if is_ready(backend_pools) {
+      text_response(StatusCode::OK, "ready\n")
+    } else {
+      text_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "no backend pool has a working backend server\n",
+      )
+    }
+  } else {
+    match pool_name
+      .flatten()
+      .and_then(|name| backend_pools.iter().find(|pool| pool.name == name))
+    {
+      Some(pool) => pool_health(request, pool),
+      None => not_found(),
+    }
+  }
+}
+
+/* Healthy as long as the pool is not paused and has at least `min_up` backend servers able to handle requests, one by
+default. The latest results of the health checks are reported, the backend servers are not contacted. */
+fn pool_health(request: &Request<Body>, pool: &BackendPool) -> Response<Body> {
+  let min_up = request
+    .uri()
+    .query()
+    .and_then(|query| url::form_urlencoded::parse(query.as_bytes()).find(|(name, _)| name == "min_up"))
+    .map_or(Ok(1), |(_, value)| value.parse::<usize>());
+  let min_up = match min_up {
+    Ok(min_up) => min_up,
+    Err(_) => return text_response(StatusCode::BAD_REQUEST, "min_up must be a number\n"),
+  };
+  if pool.is_paused() {
+    return text_response(StatusCode::SERVICE_UNAVAILABLE, "the backend pool is paused\n");
+  }
+  let up = pool.working_addresses().len();
+  let status = if up >= min_up {
+    StatusCode::OK
+  } else {
+    StatusCode::SERVICE_UNAVAILABLE
+  };
+  text_response(
+    status,
+    format!("{} of {} backend servers are working\n", up, pool.addresses.len()),
+  )
+}
+
+/* Decodes the pool name, as names may contain characters which are percent-encoded in paths, e.g. spaces */
+fn percent_decode(name: &str) -> Option<String> {
+  let bytes = name.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut index = 0;
+  while index < bytes.len() {
+    if bytes[index] == b'%' {
+      let hex = name
+        .get(index + 1..index + 3)
+        .filter(|hex| hex.bytes().all(|it| it.is_ascii_hexdigit()))?;
+      decoded.push(u8::from_str_radix(hex, 16).ok()?);
+      index += 3;
+    } else {
+      decoded.push(bytes[index]);
+      index += 1;
+    }
+  }
+  String::from_utf8(decoded).ok()
+}
+
+/* Ready as long as at least one pool has a backend server able to handle requests */
+fn is_ready(backend_pools: &[Arc<BackendPool>]) -> bool {
+  backend_pools.iter().any(|pool| !pool.working_addresses().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    backend_pool_matcher::BackendPoolMatcher,
+    health::{BackendHealth, Healthiness},
+    load_balancing::random::Random,
+    server::test_pool_builder,
+  };
+
+  fn config() -> HealthEndpointConfig {
+    HealthEndpointConfig {
+      address: "127.0.0.1:8082".parse().unwrap(),
+      liveness_path: "/healthz".to_string(),
+      readiness_path: "/readyz".to_string(),
+      pool_path_prefix: "/pools/".to_string(),
+    }
+  }
+
+  fn pool(healthiness: Healthiness) -> Arc<BackendPool> {
+    named_pool("whoami", healthiness)
+  }
+
+  fn named_pool(name: &str, healthiness: Healthiness) -> Arc<BackendPool> {
+    let pool = test_pool_builder(
+      name,
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      vec![("127.0.0.1:8084".into(), BackendHealth::new(healthiness))],
+      Box::new(Random::new()),
+    );
+    Arc::new(pool.build())
+  }
+
+  fn request(method: Method, path: &str) -> Request<Body> {
+    Request::builder().method(method).uri(path).body(Body::empty()).unwrap()
+  }
+
+  #[test]
+  fn liveness_is_always_ok() {
+    // given:
+    let pools = vec![pool(Healthiness::Unresponsive(None))];
+
+    // when:
+    let response = respond(&request(Method::GET, "/healthz"), &config(), &pools);
+
+    // then:
+    assert_eq!(response.status(), StatusCode::OK);
+  }
+
+  #[test]
+  fn ready_if_any_pool_has_a_working_backend() {
+    // given:
+    let pools = vec![pool(Healthiness::Unresponsive(None)), pool(Healthiness::Slow(400))];
+
+    // when:
+    let response = respond(&request(Method::HEAD, "/readyz"), &config(), &pools);
+
+    // then:
+    assert_eq!(response.status(), StatusCode::OK);
+  }
+
+  #[test]
+  fn not_ready_without_working_backends() {
+    // given:
+    let pools = vec![pool(Healthiness::Unresponsive(None))];
+
+    // when:
+    let response = respond(&request(Method::GET, "/readyz"), &config(), &pools);
+
+    // then:
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+      respond(&request(Method::GET, "/readyz"), &config(), &[]).status(),
+      StatusCode::SERVICE_UNAVAILABLE
+    );
+  }
+
+  #[test]
+  fn pool_is_healthy_with_enough_working_backends() {
+    // given:
+    let pools = vec![pool(Healthiness::Slow(400))];
+
+    // when:
+    let response = |path: &str| respond(&request(Method::GET, path), &config(), &pools).status();
+
+    // then:
+    assert_eq!(response("/pools/whoami"), StatusCode::OK);
+    assert_eq!(response("/pools/whoami?min_up=1"), StatusCode::OK);
+    assert_eq!(response("/pools/whoami?min_up=2"), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response("/pools/whoami?min_up=many"), StatusCode::BAD_REQUEST);
+    assert_eq!(response("/pools/unknown"), StatusCode::NOT_FOUND);
+    assert_eq!(response("/pools/"), StatusCode::NOT_FOUND);
+  }
+
+  #[test]
+  fn pool_names_are_percent_decoded() {
+    // given:
+    let pools = vec![named_pool("who am i", Healthiness::Healthy)];
+
+    // when:
+    let response = |path: &str| respond(&request(Method::GET, path), &config(), &pools).status();
+
+    // then:
+    assert_eq!(response("/pools/who%20am%20i"), StatusCode::OK);
+    assert_eq!(response("/pools/who%2"), StatusCode::NOT_FOUND);
+    assert_eq!(response("/pools/who%zzam"), StatusCode::NOT_FOUND);
+  }
+
+  #[test]
+  fn pool_is_not_healthy_without_working_backends_or_if_paused() {
+    // given:
+    let unresponsive = vec![pool(Healthiness::Unresponsive(None))];
+    let paused = vec![pool(Healthiness::Healthy)];
+    paused[0].pause();
+
+    // when:
+    let response = |pools: &[Arc<BackendPool>]| respond(&request(Method::HEAD, "/pools/whoami"), &config(), pools);
+
+    // then:
+    assert_eq!(response(&unresponsive).status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response(&paused).status(), StatusCode::SERVICE_UNAVAILABLE);
+  }
+
+  #[test]
+  fn unknown_paths_and_methods_are_rejected() {
+    assert_eq!(
+      respond(&request(Method::GET, "/other"), &config(), &[]).status(),
+      StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+      respond(&request(Method::POST, "/healthz"), &config(), &[]).status(),
+      StatusCode::METHOD_NOT_ALLOWED
+    );
+  }
+}
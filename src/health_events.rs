@@ -0,0 +1,278 @@
+use crate::health::Healthiness;
+use chrono::{DateTime, SecondsFormat, Utc};
+use gethostname::gethostname;
+use hyper::{header::CONTENT_TYPE, Body, Client, Method, Request, Uri};
+use log::warn;
+use serde_json::{json, Value};
+use std::{io, net::SocketAddr, path::PathBuf, sync::Mutex, time::Duration};
+use tokio::{
+  fs::OpenOptions,
+  io::AsyncWriteExt,
+  net::UdpSocket,
+  sync::mpsc::{self, error::TrySendError, Sender},
+  time::timeout,
+};
+
+/// Version of the JSON schema of the health events, see docs/health_checks.md
+const SCHEMA_VERSION: u32 = 1;
+/* syslog facility local0 */
+const SYSLOG_FACILITY: u8 = 16;
+/* Events waiting for the sink, further events are dropped until it catches up */
+const QUEUE_CAPACITY: usize = 1024;
+/* A sink answering slower only delays the following events, it doesn't block the health checks */
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where changes of the effective healthiness of backend servers are reported to, so external status pages and
+/// incident tooling can track the availability history without polling the admin API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthEventSink {
+  /* Appends one JSON object per line */
+  File(PathBuf),
+  /* Sends one JSON object per datagram, optionally wrapped in a RFC 5424 syslog message */
+  Udp { address: SocketAddr, syslog: bool },
+  /* POSTs each JSON object */
+  Http(Uri),
+}
+
+/// A change of the effective healthiness of a backend server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthEvent {
+  pub timestamp: DateTime<Utc>,
+  pub pool: String,
+  pub server: String,
+  pub previous: Healthiness,
+  pub current: Healthiness,
+}
+
+impl HealthEvent {
+  pub fn new(pool: &str, server: &str, previous: Healthiness, current: Healthiness) -> HealthEvent {
+    HealthEvent {
+      timestamp: Utc::now(),
+      pool: pool.to_string(),
+      server: server.to_string(),
+      previous,
+      current,
+    }
+  }
+
+  pub fn to_json(&self) -> Value {
+    json!({
+      "version": SCHEMA_VERSION,
+      "timestamp": self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+      "pool": self.pool,
+      "server": self.server,
+      "up": self.current.is_up(),
+      "previous": healthiness_json(&self.previous),
+      "current": healthiness_json(&self.current),
+    })
+  }
+
+  /* Warnings for servers going down, notices for everything else */
+  fn syslog_severity(&self) -> u8 {
+    if self.current.is_up() {
+      5
+    } else {
+      4
+    }
+  }
+}
+
+pub fn healthiness_json(healthiness: &Healthiness) -> Value {
+  match healthiness {
+    Healthiness::Healthy => json!({ "state": "healthy" }),
+    Healthiness::Slow(response_time) => json!({ "state": "slow", "response_time_ms": response_time }),
+    Healthiness::Unresponsive(status) => json!({
+      "state": "unresponsive",
+      "status_code": status.map(|it| it.as_u16()),
+    }),
+    Healthiness::Unknown => json!({ "state": "unknown" }),
+  }
+}
+
+/// Reports events to a sink in the background, one after another in the order they happened. Failures are only logged.
+#[derive(Debug)]
+pub struct HealthEvents {
+  sink: HealthEventSink,
+  /* The single writer is started with the first event, as the configuration may be loaded outside of the runtime */
+  sender: Mutex<Option<Sender<HealthEvent>>>,
+}
+
+impl HealthEvents {
+  pub fn new(sink: HealthEventSink) -> HealthEvents {
+    HealthEvents {
+      sink,
+      sender: Mutex::new(None),
+    }
+  }
+
+  /// Queues the event for the writer of the sink.
+  pub fn emit(&self, event: HealthEvent) {
+    let mut sender = self.sender.lock().unwrap();
+    let sender = sender.get_or_insert_with(|| spawn_writer(self.sink.clone()));
+    if let Err(TrySendError::Full(event)) = sender.try_send(event) {
+      warn!(
+        "Dropped health event of {} in backend pool '{}', since {:?} does not keep up",
+        event.server, event.pool, self.sink
+      );
+    }
+  }
+}
+
+/* Events of the same sink are equal, regardless of the writer */
+impl PartialEq for HealthEvents {
+  fn eq(&self, other: &Self) -> bool {
+    self.sink == other.sink
+  }
+}
+
+impl Eq for HealthEvents {}
+
+/* The writer stops once the sender is dropped together with the configuration */
+fn spawn_writer(sink: HealthEventSink) -> Sender<HealthEvent> {
+  let (sender, mut receiver) = mpsc::channel::<HealthEvent>(QUEUE_CAPACITY);
+  tokio::spawn(async move {
+    while let Some(event) = receiver.recv().await {
+      if let Err(e) = send(&sink, &event).await {
+        warn!("Could not report health event to {:?} due to: {}", sink, e);
+      }
+    }
+  });
+  sender
+}
+
+async fn send(sink: &HealthEventSink, event: &HealthEvent) -> io::Result<()> {
+  let json = event.to_json().to_string();
+  match sink {
+    HealthEventSink::File(path) => {
+      let mut file = OpenOptions::new().create(true).append(true).open(path).await?;
+      file.write_all(format!("{}\n", json).as_bytes()).await
+    }
+    HealthEventSink::Udp { address, syslog } => {
+      let local_address = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+      let socket = UdpSocket::bind(local_address).await?;
+      let message = if *syslog { syslog_message(event, &json) } else { json };
+      socket.send_to(message.as_bytes(), address).await.map(|_| ())
+    }
+    HealthEventSink::Http(uri) => {
+      let request = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+      let response = timeout(HTTP_TIMEOUT, Client::new().request(request))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no response in time"))?
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+      if response.status().is_success() {
+        Ok(())
+      } else {
+        Err(io::Error::new(
+          io::ErrorKind::Other,
+          format!("unexpected status {}", response.status()),
+        ))
+      }
+    }
+  }
+}
+
+/* Wraps the JSON in a RFC 5424 syslog message, see https://tools.ietf.org/html/rfc5424#section-6 */
+fn syslog_message(event: &HealthEvent, json: &str) -> String {
+  let hostname = gethostname().into_string().unwrap_or_else(|_| "-".to_string());
+  format!(
+    "<{}>1 {} {} arlb - health - {}",
+    SYSLOG_FACILITY * 8 + event.syslog_severity(),
+    event.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+    hostname,
+    json
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::TimeZone;
+  use hyper::StatusCode;
+
+  fn event() -> HealthEvent {
+    HealthEvent {
+      timestamp: Utc.ymd(2021, 3, 1).and_hms_milli(12, 30, 0, 250),
+      pool: "whoami".into(),
+      server: "127.0.0.1:8080".into(),
+      previous: Healthiness::Slow(400),
+      current: Healthiness::Unresponsive(Some(StatusCode::SERVICE_UNAVAILABLE)),
+    }
+  }
+
+  #[test]
+  fn event_follows_documented_schema() {
+    let actual = event().to_json();
+
+    assert_eq!(
+      actual,
+      json!({
+        "version": 1,
+        "timestamp": "2021-03-01T12:30:00.250Z",
+        "pool": "whoami",
+        "server": "127.0.0.1:8080",
+        "up": false,
+        "previous": { "state": "slow", "response_time_ms": 400 },
+        "current": { "state": "unresponsive", "status_code": 503 },
+      })
+    );
+  }
+
+  #[test]
+  fn syslog_message_has_rfc5424_header() {
+    let message = syslog_message(&event(), "{}");
+
+    assert!(message.starts_with("<132>1 2021-03-01T12:30:00.250Z "), "{}", message);
+    assert!(message.ends_with(" arlb - health - {}"), "{}", message);
+  }
+
+  #[tokio::test]
+  async fn sends_event_over_udp() {
+    // given:
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let sink = HealthEventSink::Udp {
+      address: receiver.local_addr().unwrap(),
+      syslog: false,
+    };
+
+    // when:
+    send(&sink, &event()).await.unwrap();
+
+    // then:
+    let mut buffer = [0; 1024];
+    let length = receiver.recv(&mut buffer).await.unwrap();
+    let received: Value = serde_json::from_slice(&buffer[..length]).unwrap();
+    assert_eq!(received, event().to_json());
+  }
+
+  #[tokio::test]
+  async fn reports_events_in_order() {
+    // given:
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let events = HealthEvents::new(HealthEventSink::Udp {
+      address: receiver.local_addr().unwrap(),
+      syslog: false,
+    });
+    let servers = vec!["127.0.0.1:8080", "127.0.0.1:8081", "127.0.0.1:8082"];
+
+    // when:
+    for server in &servers {
+      events.emit(HealthEvent {
+        server: server.to_string(),
+        ..event()
+      });
+    }
+
+    // then:
+    let mut buffer = [0; 1024];
+    for server in servers {
+      let length = receiver.recv(&mut buffer).await.unwrap();
+      let received: Value = serde_json::from_slice(&buffer[..length]).unwrap();
+      assert_eq!(received["server"], server);
+    }
+  }
+}
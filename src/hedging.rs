@@ -0,0 +1,217 @@
+use crate::{
+  backend_limit::{BackendConnection, BackendLimit},
+  error_response::UpstreamFailure,
+};
+use hyper::{body::HttpBody, Body, Method, Request, Response, Uri};
+use log::debug;
+use std::{future::Future, time::Duration};
+
+/// Sends a second copy of idempotent requests to another backend server of the pool, if the selected backend server
+/// hasn't responded within `delay`. Whichever backend server responds first answers the request, the other request
+/// is cancelled. This trades some additional load for a lower tail latency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hedging {
+  pub delay: Duration,
+}
+
+/// The second backend server a request may be sent to, see [`Hedging`].
+#[derive(Debug, Clone)]
+pub struct Hedge<'l> {
+  pub backend_uri: Uri,
+  pub delay: Duration,
+  pub backend_address: &'l str,
+  /// The connection limit of the backend pool, a connection to the second backend server is only taken once the
+  /// request is hedged.
+  pub backend_limit: Option<&'l BackendLimit>,
+}
+
+/// Marks the response of the second backend server, if it answered a hedged request. Holds the connection to it
+/// until the response body has been sent.
+#[derive(Debug)]
+pub struct Hedged(pub Option<BackendConnection>);
+
+/// Only requests which can be sent twice without side effects are hedged: `GET`, `HEAD` and `OPTIONS` requests
+/// without body, since a streamed body can't be sent twice.
+pub fn is_hedgeable(request: &Request<Body>) -> bool {
+  matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS) && request.body().is_end_stream()
+}
+
+/// Returns the backend server following `selected` in `addresses`, which the request is hedged to.
+pub fn next_address<'l>(addresses: &[&'l str], selected: &str) -> Option<&'l str> {
+  let index = addresses.iter().position(|address| *address == selected)?;
+  let next = addresses[(index + 1) % addresses.len()];
+  if next == selected {
+    None
+  } else {
+    Some(next)
+  }
+}
+
+/// Copies the bodyless `request` for the backend server at `backend_uri`.
+pub fn duplicate(request: &Request<Body>, backend_uri: Uri) -> Request<Body> {
+  let mut duplicate = Request::new(Body::empty());
+  *duplicate.method_mut() = request.method().clone();
+  *duplicate.uri_mut() = backend_uri;
+  *duplicate.version_mut() = request.version();
+  *duplicate.headers_mut() = request.headers().clone();
+  duplicate
+}
+
+/// Awaits the response of `first`, but starts `second` if `first` hasn't responded after `delay`. Returns the
+/// response which arrives first, unless it is an upstream failure, in which case the other response is awaited.
+/// The slower request is cancelled by dropping it. If `second` can't be sent, it resolves to `None` and `first` is
+/// awaited.
+pub async fn race<F, S>(first: F, second: S, delay: Duration) -> Response<Body>
+where
+  F: Future<Output = Response<Body>>,
+  S: Future<Output = Option<Response<Body>>>,
+{
+  tokio::pin!(first);
+  tokio::select! {
+    response = &mut first => return response,
+    _ = tokio::time::sleep(delay) => {}
+  }
+
+  debug!("Backend server did not respond within {:?}, hedging request", delay);
+  tokio::pin!(second);
+  tokio::select! {
+    response = &mut first => {
+      if is_upstream_failure(&response) {
+        second.await.unwrap_or(response)
+      } else {
+        response
+      }
+    }
+    response = &mut second => match response {
+      Some(response) if !is_upstream_failure(&response) => {
+        debug!("Hedged request was answered first");
+        response
+      }
+      _ => first.await,
+    }
+  }
+}
+
+fn is_upstream_failure(response: &Response<Body>) -> bool {
+  response.extensions().get::<UpstreamFailure>().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::{header::HOST, Version};
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use tokio::time::sleep;
+
+  async fn respond(after: Duration, body: &'static str) -> Response<Body> {
+    sleep(after).await;
+    Response::new(Body::from(body))
+  }
+
+  async fn body(response: Response<Body>) -> String {
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn race_does_not_hedge_fast_responses() {
+    // given:
+    let hedged = AtomicBool::new(false);
+    let second = async {
+      hedged.store(true, Ordering::Relaxed);
+      Some(Response::new(Body::from("second")))
+    };
+
+    // when:
+    let response = race(respond(Duration::ZERO, "first"), second, Duration::from_millis(100)).await;
+
+    // then:
+    assert_eq!(body(response).await, "first");
+    assert!(!hedged.load(Ordering::Relaxed));
+  }
+
+  #[tokio::test]
+  async fn race_returns_the_faster_hedged_response() {
+    let response = race(
+      respond(Duration::from_secs(5), "first"),
+      async { Some(respond(Duration::ZERO, "second").await) },
+      Duration::from_millis(10),
+    )
+    .await;
+
+    assert_eq!(body(response).await, "second");
+  }
+
+  #[tokio::test]
+  async fn race_waits_for_the_other_response_after_an_upstream_failure() {
+    // given:
+    let failure = async {
+      let mut response = Response::new(Body::empty());
+      response.extensions_mut().insert(UpstreamFailure::ConnectionRefused);
+      Some(response)
+    };
+
+    // when:
+    let response = race(
+      respond(Duration::from_millis(200), "first"),
+      failure,
+      Duration::from_millis(10),
+    )
+    .await;
+
+    // then:
+    assert_eq!(body(response).await, "first");
+  }
+
+  #[tokio::test]
+  async fn race_awaits_the_first_response_if_the_request_can_not_be_hedged() {
+    let response = race(
+      respond(Duration::from_millis(50), "first"),
+      async { None },
+      Duration::from_millis(10),
+    )
+    .await;
+
+    assert_eq!(body(response).await, "first");
+  }
+
+  #[test]
+  fn hedges_only_safe_requests_without_body() {
+    let request = |method: Method, body: Body| Request::builder().method(method).body(body).unwrap();
+
+    assert!(is_hedgeable(&request(Method::GET, Body::empty())));
+    assert!(is_hedgeable(&request(Method::OPTIONS, Body::empty())));
+    assert!(!is_hedgeable(&request(Method::GET, Body::from("query"))));
+    assert!(!is_hedgeable(&request(Method::POST, Body::empty())));
+    assert!(!is_hedgeable(&request(Method::DELETE, Body::empty())));
+  }
+
+  #[test]
+  fn hedges_to_the_next_backend_server() {
+    let addresses = ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"];
+
+    assert_eq!(next_address(&addresses, "127.0.0.1:1"), Some("127.0.0.1:2"));
+    assert_eq!(next_address(&addresses, "127.0.0.1:3"), Some("127.0.0.1:1"));
+    assert_eq!(next_address(&addresses[..1], "127.0.0.1:1"), None);
+    assert_eq!(next_address(&addresses, "127.0.0.1:4"), None);
+  }
+
+  #[test]
+  fn duplicates_requests_for_the_other_backend_server() {
+    // given:
+    let request = Request::get("http://127.0.0.1:1/api?page=2")
+      .version(Version::HTTP_11)
+      .header(HOST, "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let duplicate = duplicate(&request, "http://127.0.0.1:2/api?page=2".parse().unwrap());
+
+    // then:
+    assert_eq!(*duplicate.method(), Method::GET);
+    assert_eq!(duplicate.uri(), "http://127.0.0.1:2/api?page=2");
+    assert_eq!(duplicate.version(), Version::HTTP_11);
+    assert_eq!(duplicate.headers()[HOST], "whoami.localhost");
+  }
+}
@@ -5,7 +5,7 @@ use std::{
   task::{Context, Poll},
 };
 
-use crate::load_balancing::LoadBalancingStrategy;
+use crate::{load_balancing::LoadBalancingStrategy, process_stats::OpenConnection, timing::ConnectTiming};
 use futures::Future;
 use hyper::{
   client::{connect::Connection, HttpConnector},
@@ -16,6 +16,7 @@ use pin_project::{pin_project, pinned_drop};
 use tokio::{
   io::{AsyncRead, AsyncWrite},
   net::TcpStream,
+  time::Instant,
 };
 
 /// A wrapper around any async stream. Notifies the given strategy once the stream is closed
@@ -25,14 +26,18 @@ pub struct StrategyNotifyStream<T: AsyncRead + AsyncWrite + Connection + Send> {
   inner: T,
   target: Uri,
   strategy: Arc<Box<dyn LoadBalancingStrategy>>,
+  timing: ConnectTiming,
+  _open: OpenConnection,
 }
 
 impl<T: AsyncRead + AsyncWrite + Connection + Send> StrategyNotifyStream<T> {
-  pub fn new(inner: T, target: Uri, strategy: Arc<Box<dyn LoadBalancingStrategy>>) -> Self {
+  pub fn new(inner: T, target: Uri, strategy: Arc<Box<dyn LoadBalancingStrategy>>, timing: ConnectTiming) -> Self {
     StrategyNotifyStream {
       inner,
       target,
       strategy,
+      timing,
+      _open: OpenConnection::backend(),
     }
   }
 }
@@ -46,13 +51,25 @@ impl<T: AsyncRead + AsyncWrite + Connection + Send> PinnedDrop for StrategyNotif
 
 impl<T: AsyncRead + AsyncWrite + Connection + Send + Sync> AsyncRead for StrategyNotifyStream<T> {
   fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<io::Result<()>> {
-    self.project().inner.poll_read(cx, buf)
+    let this = self.project();
+    let filled_before = buf.filled().len();
+    let result = this.inner.poll_read(cx, buf);
+    let bytes = buf.filled().len() - filled_before;
+    if bytes > 0 {
+      this.strategy.on_bytes_transferred(this.target, bytes);
+    }
+    result
   }
 }
 
 impl<T: AsyncRead + AsyncWrite + Connection + Send + Sync> AsyncWrite for StrategyNotifyStream<T> {
   fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
-    self.project().inner.poll_write(cx, buf)
+    let this = self.project();
+    let result = this.inner.poll_write(cx, buf);
+    if let Poll::Ready(Ok(bytes)) = result {
+      this.strategy.on_bytes_transferred(this.target, bytes);
+    }
+    result
   }
 
   fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
@@ -65,7 +82,7 @@ impl<T: AsyncRead + AsyncWrite + Connection + Send + Sync> AsyncWrite for Strate
 }
 impl<T: AsyncRead + AsyncWrite + Connection + Send + Sync> Connection for StrategyNotifyStream<T> {
   fn connected(&self) -> hyper::client::connect::Connected {
-    self.inner.connected()
+    self.inner.connected().extra(self.timing)
   }
 }
 
@@ -82,6 +99,12 @@ impl StrategyNotifyHttpConnector {
       strategy,
     }
   }
+
+  /// Returns the underlying connector, which does not notify the strategy. Used for health checks, which should reach
+  /// the backend servers like forwarded requests without influencing the load balancing.
+  pub fn connector(&self) -> HttpConnector {
+    self.inner.clone()
+  }
 }
 
 impl Service<Uri> for StrategyNotifyHttpConnector {
@@ -102,10 +125,15 @@ impl Service<Uri> for StrategyNotifyHttpConnector {
     let req_ = req.clone();
 
     Box::pin(async move {
+      let started_at = Instant::now();
       match self_.inner.call(req).await {
         Ok(stream) => {
           self_.strategy.on_tcp_open(&req_);
-          Ok(StrategyNotifyStream::new(stream, req_, self_.strategy))
+          let timing = ConnectTiming {
+            started_at,
+            duration: started_at.elapsed(),
+          };
+          Ok(StrategyNotifyStream::new(stream, req_, self_.strategy, timing))
         }
         Err(e) => Err(e.into()),
       }
@@ -0,0 +1,130 @@
+use crate::{error_response::not_found, server::SharedData};
+use hyper::{Body, Request, Response};
+use log::debug;
+use std::net::IpAddr;
+
+pub const DEFAULT_RESERVED_PREFIX: &str = "/.well-known/arlb/";
+
+/// Endpoints answered by the load balancer itself before a backend pool is selected. Requests below the reserved
+/// prefix are never forwarded to a backend server, so the endpoints can't be shadowed by a matcher or vice versa.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InternalEndpoints {
+  /// Starts and ends with `/`, e.g. `/.well-known/arlb/`
+  pub reserved_prefix: String,
+}
+
+impl Default for InternalEndpoints {
+  fn default() -> Self {
+    InternalEndpoints {
+      reserved_prefix: DEFAULT_RESERVED_PREFIX.to_string(),
+    }
+  }
+}
+
+impl InternalEndpoints {
+  /// Returns the response of the internal endpoint for the request or `None` if the request has to be forwarded.
+  pub fn respond(
+    &self,
+    request: &Request<Body>,
+    shared_data: &SharedData,
+    client_ip: &IpAddr,
+  ) -> Option<Response<Body>> {
+    // the path of ACME challenges is defined by RFC 8555, so it can't be moved below the reserved prefix
+    if let Some(response) = shared_data.acme_handler.respond_to_challenge(request) {
+      return Some(response);
+    }
+    let endpoint = request.uri().path().strip_prefix(self.reserved_prefix.as_str())?;
+    let response = match endpoint.strip_prefix("status/") {
+      Some(pool_name) => pool_status(request, shared_data, pool_name, client_ip),
+      None => {
+        debug!("Unknown internal endpoint {}", request.uri().path());
+        not_found()
+      }
+    };
+    Some(response)
+  }
+}
+
+/* Serves the status page of a pool by its name, if the pool has one */
+fn pool_status(
+  request: &Request<Body>,
+  shared_data: &SharedData,
+  pool_name: &str,
+  client_ip: &IpAddr,
+) -> Response<Body> {
+  let pool = shared_data.backend_pools.iter().find(|pool| pool.name == pool_name);
+  match pool.and_then(|pool| Some((pool, pool.status.as_ref()?))) {
+    Some((pool, status)) => status.respond(pool, request, client_ip),
+    None => not_found(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    acme::AcmeHandler,
+    backend_pool_matcher::BackendPoolMatcher,
+    health::{BackendHealth, Healthiness},
+    load_balancing::round_robin::RoundRobin,
+    pool_index::PoolIndex,
+    pool_status::PoolStatus,
+    server::test_pool_builder,
+  };
+  use hyper::StatusCode;
+  use std::{collections::HashMap, sync::Arc};
+
+  fn shared_data() -> SharedData {
+    let mut builder = test_pool_builder(
+      "whoami",
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      vec![("127.0.0.1:8080".into(), BackendHealth::new(Healthiness::Healthy))],
+      Box::new(RoundRobin::new()),
+    );
+    builder.status(PoolStatus {
+      path: "/_arlb/status".into(),
+      allowed_ips: vec!["127.0.0.1".parse().unwrap()],
+    });
+    SharedData {
+      backend_pools: vec![Arc::new(builder.build())],
+      default_pool: None,
+      http10_pools: HashMap::new(),
+      services: Vec::new(),
+      pool_index: PoolIndex::default(),
+      acme_handler: Arc::new(AcmeHandler::new()),
+    }
+  }
+
+  fn respond(path: &str) -> Option<StatusCode> {
+    let request = Request::get(path).body(Body::empty()).unwrap();
+    InternalEndpoints::default()
+      .respond(&request, &shared_data(), &"127.0.0.1".parse().unwrap())
+      .map(|response| response.status())
+  }
+
+  #[test]
+  fn serves_status_of_pools_below_reserved_prefix() {
+    assert_eq!(respond("/.well-known/arlb/status/whoami"), Some(StatusCode::OK));
+    assert_eq!(respond("/.well-known/arlb/status/unknown"), Some(StatusCode::NOT_FOUND));
+  }
+
+  #[test]
+  fn never_forwards_requests_below_reserved_prefix() {
+    assert_eq!(respond("/.well-known/arlb/metrics"), Some(StatusCode::NOT_FOUND));
+    assert_eq!(respond("/.well-known/arlb/"), Some(StatusCode::NOT_FOUND));
+  }
+
+  #[test]
+  fn forwards_other_requests() {
+    assert_eq!(respond("/.well-known/arlb"), None);
+    assert_eq!(respond("/status/whoami"), None);
+  }
+
+  #[test]
+  fn answers_acme_challenges() {
+    assert_eq!(
+      respond("/.well-known/acme-challenge/unknown-token"),
+      Some(StatusCode::NOT_FOUND)
+    );
+  }
+}
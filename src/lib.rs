@@ -0,0 +1,43 @@
+pub mod acme;
+mod acme_account;
+pub mod admin;
+mod backend_address;
+mod backend_limit;
+pub mod backend_pool_matcher;
+mod bans;
+pub mod certificate_expiry;
+pub mod configuration;
+mod connection_limit;
+mod cooldown;
+mod debug_headers;
+mod error_response;
+mod fallback;
+pub mod health;
+pub mod health_coordination;
+pub mod health_endpoint;
+pub mod health_events;
+mod hedging;
+pub mod http_client;
+mod internal_endpoints;
+pub mod lifecycle;
+pub mod listeners;
+pub mod load_balancing;
+mod log_redaction;
+pub mod logging;
+pub mod middleware;
+mod no_healthy_backends;
+mod normalization;
+mod pool_index;
+mod pool_status;
+pub mod process_stats;
+mod redis_connection;
+pub mod server;
+mod service;
+mod shadow_strategy;
+pub mod simulate_strategy;
+pub mod test_matcher;
+mod timing;
+pub mod tls;
+pub mod tls_fingerprint;
+mod traffic;
+mod utils;
@@ -0,0 +1,226 @@
+use std::io;
+use tokio::select;
+
+/// Events sent to the process from the outside, e.g. by an init system or a service wrapper.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LifecycleEvent {
+  /// Re-read the configuration file, even if it was not modified
+  ReloadConfig,
+  /// Reopen the log file, e.g. after it was rotated
+  ReopenLogs,
+  Shutdown,
+}
+
+/// Unix signals: `SIGHUP` reloads the configuration, `SIGUSR1` reopens the logs and `SIGTERM` or `SIGINT` shut down.
+#[cfg(unix)]
+pub struct Signals {
+  hangup: tokio::signal::unix::Signal,
+  user_defined1: tokio::signal::unix::Signal,
+  terminate: tokio::signal::unix::Signal,
+  interrupt: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+  pub fn new() -> io::Result<Signals> {
+    use tokio::signal::unix::{signal, SignalKind};
+    Ok(Signals {
+      hangup: signal(SignalKind::hangup())?,
+      user_defined1: signal(SignalKind::user_defined1())?,
+      terminate: signal(SignalKind::terminate())?,
+      interrupt: signal(SignalKind::interrupt())?,
+    })
+  }
+
+  pub async fn recv(&mut self) -> Option<LifecycleEvent> {
+    select! {
+      Some(()) = self.hangup.recv() => Some(LifecycleEvent::ReloadConfig),
+      Some(()) = self.user_defined1.recv() => Some(LifecycleEvent::ReopenLogs),
+      Some(()) = self.terminate.recv() => Some(LifecycleEvent::Shutdown),
+      Some(()) = self.interrupt.recv() => Some(LifecycleEvent::Shutdown),
+      else => None,
+    }
+  }
+}
+
+/// Windows console control events: `Ctrl+C` and `Ctrl+Break` shut down. When running as a Windows service, the
+/// controls of the service control manager are received as well: `stop` and `shutdown` shut down, `paramchange`
+/// reloads the configuration.
+#[cfg(windows)]
+pub struct Signals {
+  ctrl_break: tokio::signal::windows::CtrlBreak,
+  service_controls: Option<tokio::sync::mpsc::UnboundedReceiver<LifecycleEvent>>,
+}
+
+#[cfg(windows)]
+impl Signals {
+  pub fn new() -> io::Result<Signals> {
+    Ok(Signals {
+      ctrl_break: tokio::signal::windows::ctrl_break()?,
+      service_controls: service::take_controls(),
+    })
+  }
+
+  pub async fn recv(&mut self) -> Option<LifecycleEvent> {
+    let service_controls = &mut self.service_controls;
+    let service_control = async move {
+      match service_controls {
+        Some(service_controls) => service_controls.recv().await,
+        None => std::future::pending().await,
+      }
+    };
+    select! {
+      Ok(()) = tokio::signal::ctrl_c() => Some(LifecycleEvent::Shutdown),
+      Some(()) = self.ctrl_break.recv() => Some(LifecycleEvent::Shutdown),
+      Some(event) = service_control => Some(event),
+      else => None,
+    }
+  }
+}
+
+/// Runs the load balancer as a Windows service, reporting its state to the service control manager.
+#[cfg(windows)]
+pub mod service {
+  use super::LifecycleEvent;
+  use log::warn;
+  use std::{
+    ffi::OsString,
+    io::{self, ErrorKind},
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+  };
+  use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+  use windows_service::{
+    define_windows_service,
+    service::{ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType},
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+  };
+
+  /* Services running in their own process may use any name */
+  const NAME: &str = "another-rust-load-balancer";
+
+  /* The service control manager calls the service main function without any context, so it hands the service over
+  to `start` and `Signals` through these */
+  static STARTED: Mutex<Option<mpsc::Sender<io::Result<ServiceStatusHandle>>>> = Mutex::new(None);
+  static CONTROLS: Mutex<Option<UnboundedReceiver<LifecycleEvent>>> = Mutex::new(None);
+
+  define_windows_service!(ffi_service_main, service_main);
+
+  /// The running service, reported as stopped once dropped. Unless it finished successfully, the service control
+  /// manager is told that it failed, so the recovery actions of the service apply.
+  pub struct Service {
+    status_handle: ServiceStatusHandle,
+    exit_code: ServiceExitCode,
+  }
+
+  impl Service {
+    pub fn finish(mut self, result: &io::Result<()>) {
+      if result.is_ok() {
+        self.exit_code = ServiceExitCode::NO_ERROR;
+      }
+    }
+  }
+
+  impl Drop for Service {
+    fn drop(&mut self) {
+      let stopped = status(ServiceState::Stopped, ServiceControlAccept::empty(), self.exit_code);
+      if let Err(e) = self.status_handle.set_service_status(stopped) {
+        warn!("Could not report the stopped service: {}", e);
+      }
+    }
+  }
+
+  /// Connects to the service control manager, which has to have started the process, and reports the service as
+  /// running. Blocks until the service control manager called back, which it does right away.
+  pub fn start() -> io::Result<Service> {
+    let (sender, receiver) = mpsc::channel();
+    *STARTED.lock().unwrap() = Some(sender.clone());
+    // blocks until the service stopped
+    thread::spawn(move || {
+      if let Err(e) = service_dispatcher::start(NAME, ffi_service_main) {
+        let _ = sender.send(Err(io::Error::new(
+          ErrorKind::Other,
+          format!("Could not connect to the service control manager: {}", e),
+        )));
+      }
+    });
+    let status_handle = receiver
+      .recv()
+      .map_err(|_| io::Error::new(ErrorKind::Other, "The service was not started"))??;
+    Ok(Service {
+      status_handle,
+      exit_code: ServiceExitCode::ServiceSpecific(1),
+    })
+  }
+
+  fn service_main(_arguments: Vec<OsString>) {
+    let (controls, receiver) = unbounded_channel();
+    let result = service_control_handler::register(NAME, move |control| handle_control(&controls, control))
+      .and_then(|status_handle| {
+        let accepted = ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN | ServiceControlAccept::PARAM_CHANGE;
+        status_handle.set_service_status(status(ServiceState::Running, accepted, ServiceExitCode::NO_ERROR))?;
+        Ok(status_handle)
+      })
+      .map_err(|e| io::Error::new(ErrorKind::Other, format!("Could not register the service: {}", e)));
+    *CONTROLS.lock().unwrap() = Some(receiver);
+    if let Some(started) = STARTED.lock().unwrap().take() {
+      let _ = started.send(result);
+    }
+  }
+
+  fn handle_control(
+    controls: &UnboundedSender<LifecycleEvent>,
+    control: ServiceControl,
+  ) -> ServiceControlHandlerResult {
+    let event = match control {
+      ServiceControl::Stop | ServiceControl::Shutdown => LifecycleEvent::Shutdown,
+      ServiceControl::ParamChange => LifecycleEvent::ReloadConfig,
+      ServiceControl::Interrogate => return ServiceControlHandlerResult::NoError,
+      _ => return ServiceControlHandlerResult::NotImplemented,
+    };
+    // fails only if the load balancer is shutting down already
+    let _ = controls.send(event);
+    ServiceControlHandlerResult::NoError
+  }
+
+  /* Returns the controls of the service control manager, if the process runs as a service */
+  pub(super) fn take_controls() -> Option<UnboundedReceiver<LifecycleEvent>> {
+    CONTROLS.lock().unwrap().take()
+  }
+
+  fn status(state: ServiceState, accepted: ServiceControlAccept, exit_code: ServiceExitCode) -> ServiceStatus {
+    ServiceStatus {
+      service_type: ServiceType::OWN_PROCESS,
+      current_state: state,
+      controls_accepted: accepted,
+      exit_code,
+      checkpoint: 0,
+      wait_hint: Duration::default(),
+      process_id: None,
+    }
+  }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+  use std::process::Command;
+
+  #[tokio::test]
+  async fn signals_are_mapped_to_events() {
+    // given:
+    let mut signals = Signals::new().unwrap();
+
+    // when:
+    let status = Command::new("kill")
+      .args(["-USR1", &std::process::id().to_string()])
+      .status()
+      .unwrap();
+
+    // then:
+    assert!(status.success());
+    assert_eq!(signals.recv().await, Some(LifecycleEvent::ReopenLogs));
+  }
+}
@@ -1,24 +1,42 @@
+use crate::{
+  connection_limit::{ConnectionLimit, ConnectionPermit, LimitedStream},
+  tls_fingerprint::TlsFingerprint,
+};
 use async_stream::stream;
 use async_trait::async_trait;
-use futures::Stream;
-use log::{error, info};
+use futures::{stream::FuturesUnordered, Stream, StreamExt};
+use hyper::server::accept::Accept;
+use log::{debug, info, warn};
+use pin_project::pin_project;
 use std::{
+  collections::BTreeMap,
   io,
   net::SocketAddr,
   pin::Pin,
-  sync::Arc,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
   task::{Context, Poll},
+  time::Duration,
+};
+use tokio::{
+  io::{AsyncRead, AsyncWrite, ReadBuf},
+  net::{TcpListener, TcpStream},
+  select,
 };
-use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::server::TlsStream;
-use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use tokio_rustls::{
+  rustls::{internal::msgs::enums::AlertDescription, ServerConfig, TLSError},
+  TlsAcceptor,
+};
 
 pub struct HyperAcceptor<'a, T> {
   acceptor: Pin<Box<dyn Stream<Item = Result<T, io::Error>> + Send + 'a>>,
 }
 
-impl hyper::server::accept::Accept for HyperAcceptor<'_, TcpStream> {
-  type Conn = TcpStream;
+impl<T> Accept for HyperAcceptor<'_, T> {
+  type Conn = T;
   type Error = io::Error;
 
   fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
@@ -26,31 +44,112 @@ impl hyper::server::accept::Accept for HyperAcceptor<'_, TcpStream> {
   }
 }
 
-impl hyper::server::accept::Accept for HyperAcceptor<'_, TlsStream<TcpStream>> {
-  type Conn = TlsStream<TcpStream>;
-  type Error = io::Error;
+#[async_trait]
+pub trait AcceptorProducer<T> {
+  async fn produce_acceptor(self, listener: TcpListener) -> Result<HyperAcceptor<'async_trait, T>, io::Error>;
+}
 
-  fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
-    Pin::new(&mut self.acceptor).poll_next(cx)
+/// Accepts plain TCP connections without connection limits, for the internal endpoints of the load balancer.
+pub fn plain_acceptor(listener: TcpListener) -> impl Accept<Conn = TcpStream, Error = io::Error> {
+  hyper::server::accept::from_stream(stream! {
+    loop {
+      yield listener.accept().await.map(|(socket, _)| socket);
+    }
+  })
+}
+
+/// How often binding a listener is retried, e.g. while the previous process is still shutting down.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BindRetry {
+  pub attempts: u32,
+  pub initial_delay: Duration,
+  pub max_delay: Duration,
+}
+
+impl Default for BindRetry {
+  fn default() -> Self {
+    BindRetry {
+      attempts: 0,
+      initial_delay: Duration::from_millis(500),
+      max_delay: Duration::from_secs(10),
+    }
   }
 }
 
-#[async_trait]
-pub trait AcceptorProducer<T> {
-  async fn produce_acceptor(self, address: SocketAddr) -> Result<HyperAcceptor<'async_trait, T>, io::Error>;
+/// Binds the listener for `scheme` requests to `address`, retrying with an exponential backoff. The returned error
+/// names the address and the reason.
+pub async fn bind(scheme: &str, address: SocketAddr, retry: &BindRetry) -> Result<TcpListener, io::Error> {
+  let mut delay = retry.initial_delay;
+  let mut attempt = 0;
+  loop {
+    match TcpListener::bind(address).await {
+      Ok(listener) => return Ok(listener),
+      Err(e) if attempt < retry.attempts => {
+        attempt += 1;
+        warn!(
+          "Could not bind the {} listener to {} due to: {}, retrying in {}ms ({}/{})",
+          scheme,
+          address,
+          e,
+          delay.as_millis(),
+          attempt,
+          retry.attempts
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(retry.max_delay);
+      }
+      Err(e) => return Err(bind_error(scheme, address, e)),
+    }
+  }
+}
+
+fn bind_error(scheme: &str, address: SocketAddr, error: io::Error) -> io::Error {
+  let hint = match error.kind() {
+    io::ErrorKind::AddrInUse => ", is another process listening on it?",
+    io::ErrorKind::PermissionDenied if address.port() < 1024 => {
+      ", binding to ports below 1024 requires root or the CAP_NET_BIND_SERVICE capability"
+    }
+    _ => "",
+  };
+  io::Error::new(
+    error.kind(),
+    format!(
+      "Could not bind the {} listener to {} due to: {}{}",
+      scheme, address, error, hint
+    ),
+  )
 }
 
-pub struct Http;
+/// Reserves a connection for the client, returning `Err` if the connection must be rejected.
+fn admit(connection_limit: &Option<Arc<ConnectionLimit>>, remote: &SocketAddr) -> Result<Option<ConnectionPermit>, ()> {
+  match connection_limit {
+    Some(connection_limit) => connection_limit.acquire(remote.ip()).map(Some).ok_or(()),
+    None => Ok(None),
+  }
+}
+
+pub struct Http {
+  pub connection_limit: Option<Arc<ConnectionLimit>>,
+}
 
 #[async_trait]
-impl AcceptorProducer<TcpStream> for Http {
-  async fn produce_acceptor(self, address: SocketAddr) -> Result<HyperAcceptor<'async_trait, TcpStream>, io::Error> {
-    let listener = TcpListener::bind(address).await?;
+impl AcceptorProducer<LimitedStream<TcpStream>> for Http {
+  async fn produce_acceptor(
+    self,
+    listener: TcpListener,
+  ) -> Result<HyperAcceptor<'async_trait, LimitedStream<TcpStream>>, io::Error> {
+    let address = listener.local_addr()?;
+    let connection_limit = self.connection_limit;
 
     let incoming_stream = stream! {
       loop {
-        let (socket, _) = listener.accept().await?;
-        yield Ok(socket);
+        let (socket, remote) = listener.accept().await?;
+        // dropping the socket closes the connection before any HTTP parsing
+        let permit = match admit(&connection_limit, &remote) {
+          Ok(permit) => permit,
+          Err(()) => continue,
+        };
+        yield Ok(LimitedStream::new(socket, permit));
       }
     };
 
@@ -64,24 +163,39 @@ impl AcceptorProducer<TcpStream> for Http {
 
 pub struct Https {
   pub tls_config: ServerConfig,
+  pub connection_limit: Option<Arc<ConnectionLimit>>,
 }
 
 #[async_trait]
-impl AcceptorProducer<TlsStream<TcpStream>> for Https {
+impl AcceptorProducer<LimitedStream<TlsConnection>> for Https {
   async fn produce_acceptor(
     self,
-    address: SocketAddr,
-  ) -> Result<HyperAcceptor<'async_trait, TlsStream<TcpStream>>, io::Error> {
+    listener: TcpListener,
+  ) -> Result<HyperAcceptor<'async_trait, LimitedStream<TlsConnection>>, io::Error> {
     let tls_acceptor = TlsAcceptor::from(Arc::new(self.tls_config));
-    let listener = TcpListener::bind(address).await?;
+    let address = listener.local_addr()?;
+    let connection_limit = self.connection_limit;
 
     let incoming_stream = stream! {
+      // the handshakes run concurrently, so a slow or silent client doesn't hold up the other connections
+      let mut handshakes = FuturesUnordered::new();
       loop {
-          let (socket, _) = listener.accept().await?;
-          match tls_acceptor.accept(socket).await {
-            Ok(tls_stream) => yield Ok(tls_stream),
-            Err(e) => error!("Failed to accept TLS socket: {}", e)
-          }
+        let (accepted, handshaken) = select! {
+          accepted = listener.accept() => (Some(accepted), None),
+          Some(handshaken) = handshakes.next() => (None, handshaken),
+        };
+        if let Some(connection) = handshaken {
+          yield Ok(connection);
+        }
+        if let Some(accepted) = accepted {
+          let (socket, remote) = accepted?;
+          // reject before the TLS handshake to avoid its cost
+          let permit = match admit(&connection_limit, &remote) {
+            Ok(permit) => permit,
+            Err(()) => continue,
+          };
+          handshakes.push(handshake(tls_acceptor.clone(), socket, remote, permit));
+        }
       }
     };
 
@@ -93,6 +207,160 @@ impl AcceptorProducer<TlsStream<TcpStream>> for Https {
   }
 }
 
+/* Peeks the fingerprint of the client and does the TLS handshake, returns `None` if it failed */
+async fn handshake(
+  tls_acceptor: TlsAcceptor,
+  socket: TcpStream,
+  remote: SocketAddr,
+  permit: Option<ConnectionPermit>,
+) -> Option<LimitedStream<TlsConnection>> {
+  let fingerprint = TlsFingerprint::peek(&socket).await;
+  if let Some(fingerprint) = &fingerprint {
+    debug!("TLS client hello from {} with fingerprint {}", remote, fingerprint);
+  }
+  match tls_acceptor.accept(socket).await {
+    Ok(stream) => Some(LimitedStream::new(TlsConnection { stream, fingerprint }, permit)),
+    Err(e) => {
+      TlsHandshakeFailure::of(&e).record(&remote, fingerprint.as_ref(), &e);
+      None
+    }
+  }
+}
+
+/// Why a TLS handshake on the HTTPS listener failed. Scanners mostly cause unknown server names, malformed handshakes and
+/// aborted connections, while the other kinds point to clients which are not compatible with the load balancer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsHandshakeFailure {
+  /* No certificate for the requested server name (SNI), or no server name at all */
+  UnknownServerName,
+  ProtocolVersion,
+  /* A certificate was rejected, either the one of the client or the one of the load balancer */
+  Certificate,
+  /* No cipher suite or signature scheme in common */
+  Incompatible,
+  /* Not TLS at all, e.g. plain HTTP, or a broken handshake */
+  Malformed,
+  /* The connection was closed or reset during the handshake */
+  Aborted,
+  Other,
+}
+
+static TLS_HANDSHAKE_FAILURES: [AtomicU64; 7] = [
+  AtomicU64::new(0),
+  AtomicU64::new(0),
+  AtomicU64::new(0),
+  AtomicU64::new(0),
+  AtomicU64::new(0),
+  AtomicU64::new(0),
+  AtomicU64::new(0),
+];
+
+impl TlsHandshakeFailure {
+  pub const ALL: [TlsHandshakeFailure; 7] = [
+    TlsHandshakeFailure::UnknownServerName,
+    TlsHandshakeFailure::ProtocolVersion,
+    TlsHandshakeFailure::Certificate,
+    TlsHandshakeFailure::Incompatible,
+    TlsHandshakeFailure::Malformed,
+    TlsHandshakeFailure::Aborted,
+    TlsHandshakeFailure::Other,
+  ];
+
+  /// Classifies the error of a TLS handshake. tokio-rustls wraps the errors of rustls in `io::Error`s.
+  pub fn of(error: &io::Error) -> TlsHandshakeFailure {
+    let tls_error = error.get_ref().and_then(|it| it.downcast_ref::<TLSError>());
+    match tls_error {
+      // the error of rustls if the certificate resolver returns no certificate
+      Some(TLSError::General(message)) if message.contains("no server certificate chain resolved") => {
+        TlsHandshakeFailure::UnknownServerName
+      }
+      Some(TLSError::PeerIncompatibleError(message)) if message.contains("TLS") => TlsHandshakeFailure::ProtocolVersion,
+      Some(TLSError::AlertReceived(AlertDescription::ProtocolVersion)) => TlsHandshakeFailure::ProtocolVersion,
+      Some(TLSError::NoCertificatesPresented)
+      | Some(TLSError::WebPKIError(_))
+      | Some(TLSError::AlertReceived(AlertDescription::BadCertificate))
+      | Some(TLSError::AlertReceived(AlertDescription::UnsupportedCertificate))
+      | Some(TLSError::AlertReceived(AlertDescription::CertificateRevoked))
+      | Some(TLSError::AlertReceived(AlertDescription::CertificateExpired))
+      | Some(TLSError::AlertReceived(AlertDescription::CertificateUnknown))
+      | Some(TLSError::AlertReceived(AlertDescription::UnknownCA)) => TlsHandshakeFailure::Certificate,
+      Some(TLSError::PeerIncompatibleError(_))
+      | Some(TLSError::AlertReceived(AlertDescription::HandshakeFailure))
+      | Some(TLSError::AlertReceived(AlertDescription::InsufficientSecurity)) => TlsHandshakeFailure::Incompatible,
+      Some(TLSError::CorruptMessage)
+      | Some(TLSError::CorruptMessagePayload(_))
+      | Some(TLSError::InappropriateMessage { .. })
+      | Some(TLSError::InappropriateHandshakeMessage { .. })
+      | Some(TLSError::PeerMisbehavedError(_))
+      | Some(TLSError::PeerSentOversizedRecord) => TlsHandshakeFailure::Malformed,
+      Some(_) => TlsHandshakeFailure::Other,
+      None => match error.kind() {
+        io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::TimedOut => TlsHandshakeFailure::Aborted,
+        _ => TlsHandshakeFailure::Other,
+      },
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      TlsHandshakeFailure::UnknownServerName => "unknown_server_name",
+      TlsHandshakeFailure::ProtocolVersion => "protocol_version",
+      TlsHandshakeFailure::Certificate => "certificate",
+      TlsHandshakeFailure::Incompatible => "incompatible",
+      TlsHandshakeFailure::Malformed => "malformed",
+      TlsHandshakeFailure::Aborted => "aborted",
+      TlsHandshakeFailure::Other => "other",
+    }
+  }
+
+  /* Failures which are mostly caused by scanners, logged on debug level only */
+  fn is_noise(self) -> bool {
+    matches!(
+      self,
+      TlsHandshakeFailure::UnknownServerName | TlsHandshakeFailure::Malformed | TlsHandshakeFailure::Aborted
+    )
+  }
+
+  fn record(self, remote: &SocketAddr, fingerprint: Option<&TlsFingerprint>, error: &io::Error) {
+    TLS_HANDSHAKE_FAILURES[self as usize].fetch_add(1, Ordering::Relaxed);
+    let fingerprint = fingerprint.map_or("none", TlsFingerprint::as_str);
+    if self.is_noise() {
+      debug!(
+        "TLS handshake with {} (fingerprint {}) failed ({}): {}",
+        remote,
+        fingerprint,
+        self.name(),
+        error
+      );
+    } else {
+      warn!(
+        "TLS handshake with {} (fingerprint {}) failed ({}): {}",
+        remote,
+        fingerprint,
+        self.name(),
+        error
+      );
+    }
+  }
+}
+
+/// Returns the number of failed TLS handshakes per kind since the start of the process.
+pub fn tls_handshake_failures() -> BTreeMap<&'static str, u64> {
+  TlsHandshakeFailure::ALL
+    .iter()
+    .map(|failure| {
+      (
+        failure.name(),
+        TLS_HANDSHAKE_FAILURES[*failure as usize].load(Ordering::Relaxed),
+      )
+    })
+    .collect()
+}
+
 pub trait RemoteAddress {
   fn remote_addr(&self) -> io::Result<SocketAddr>;
 }
@@ -103,9 +371,199 @@ impl RemoteAddress for TcpStream {
   }
 }
 
-impl RemoteAddress for TlsStream<TcpStream> {
+impl RemoteAddress for TlsConnection {
   fn remote_addr(&self) -> io::Result<SocketAddr> {
-    let (stream, _) = self.get_ref();
+    let (stream, _) = self.stream.get_ref();
     stream.peer_addr()
   }
 }
+
+/// The server name (SNI) requested by the client in the TLS handshake, `None` for plain connections.
+pub trait TlsServerName {
+  fn server_name(&self) -> Option<String>;
+}
+
+impl TlsServerName for TcpStream {
+  fn server_name(&self) -> Option<String> {
+    None
+  }
+}
+
+impl TlsServerName for TlsConnection {
+  fn server_name(&self) -> Option<String> {
+    let (_, session) = self.stream.get_ref();
+    session.get_sni_hostname().map(str::to_string)
+  }
+}
+
+/// The fingerprint of the ClientHello the client sent in the TLS handshake, `None` for plain connections.
+pub trait ClientFingerprint {
+  fn tls_fingerprint(&self) -> Option<TlsFingerprint>;
+}
+
+impl ClientFingerprint for TcpStream {
+  fn tls_fingerprint(&self) -> Option<TlsFingerprint> {
+    None
+  }
+}
+
+impl ClientFingerprint for TlsConnection {
+  fn tls_fingerprint(&self) -> Option<TlsFingerprint> {
+    self.fingerprint.clone()
+  }
+}
+
+/// An established TLS connection together with the fingerprint of its ClientHello, which is not kept by rustls.
+#[pin_project]
+pub struct TlsConnection {
+  #[pin]
+  stream: TlsStream<TcpStream>,
+  fingerprint: Option<TlsFingerprint>,
+}
+
+impl AsyncRead for TlsConnection {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    self.project().stream.poll_read(cx, buf)
+  }
+}
+
+impl AsyncWrite for TlsConnection {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+    self.project().stream.poll_write(cx, buf)
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    self.project().stream.poll_flush(cx)
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    self.project().stream.poll_shutdown(cx)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::{io::AsyncWriteExt, time::timeout};
+  use tokio_rustls::rustls::NoClientAuth;
+
+  #[tokio::test]
+  async fn bind_reports_address_in_use() {
+    // given:
+    let occupied = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = occupied.local_addr().unwrap();
+
+    // when:
+    let error = bind("HTTP", address, &BindRetry::default()).await.unwrap_err();
+
+    // then:
+    assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+    assert!(error.to_string().contains(&address.to_string()));
+    assert!(error.to_string().starts_with("Could not bind the HTTP listener"));
+  }
+
+  #[tokio::test]
+  async fn bind_retries_until_address_is_free() {
+    // given:
+    let occupied = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = occupied.local_addr().unwrap();
+    let retry = BindRetry {
+      attempts: 10,
+      initial_delay: Duration::from_millis(10),
+      max_delay: Duration::from_millis(50),
+    };
+    tokio::spawn(async move {
+      tokio::time::sleep(Duration::from_millis(30)).await;
+      drop(occupied);
+    });
+
+    // when:
+    let listener = bind("HTTP", address, &retry).await.unwrap();
+
+    // then:
+    assert_eq!(listener.local_addr().unwrap(), address);
+  }
+
+  #[tokio::test]
+  async fn handshakes_do_not_wait_for_silent_clients() {
+    // given: a client which connects, but never sends its client hello
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let https = Https {
+      tls_config: ServerConfig::new(NoClientAuth::new()),
+      connection_limit: None,
+    };
+    let mut acceptor = https.produce_acceptor(listener).await.unwrap();
+    let _silent = TcpStream::connect(address).await.unwrap();
+    let before = tls_handshake_failures()["malformed"];
+
+    // when: another client sends plain HTTP
+    let mut plain = TcpStream::connect(address).await.unwrap();
+    plain.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let accepted = timeout(Duration::from_millis(500), acceptor.acceptor.next()).await;
+
+    // then:
+    assert!(accepted.is_err());
+    assert_eq!(tls_handshake_failures()["malformed"], before + 1);
+  }
+
+  fn tls_error(error: TLSError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+  }
+
+  #[test]
+  fn classifies_tls_handshake_failures() {
+    assert_eq!(
+      TlsHandshakeFailure::of(&tls_error(TLSError::General(
+        "no server certificate chain resolved".into()
+      ))),
+      TlsHandshakeFailure::UnknownServerName
+    );
+    assert_eq!(
+      TlsHandshakeFailure::of(&tls_error(TLSError::PeerIncompatibleError(
+        "client does not support TLSv1_2".into()
+      ))),
+      TlsHandshakeFailure::ProtocolVersion
+    );
+    assert_eq!(
+      TlsHandshakeFailure::of(&tls_error(TLSError::PeerIncompatibleError(
+        "no ciphersuites in common".into()
+      ))),
+      TlsHandshakeFailure::Incompatible
+    );
+    assert_eq!(
+      TlsHandshakeFailure::of(&tls_error(TLSError::AlertReceived(AlertDescription::UnknownCA))),
+      TlsHandshakeFailure::Certificate
+    );
+    assert_eq!(
+      TlsHandshakeFailure::of(&tls_error(TLSError::NoCertificatesPresented)),
+      TlsHandshakeFailure::Certificate
+    );
+    assert_eq!(
+      TlsHandshakeFailure::of(&tls_error(TLSError::CorruptMessage)),
+      TlsHandshakeFailure::Malformed
+    );
+    assert_eq!(
+      TlsHandshakeFailure::of(&io::Error::from(io::ErrorKind::UnexpectedEof)),
+      TlsHandshakeFailure::Aborted
+    );
+    assert_eq!(
+      TlsHandshakeFailure::of(&tls_error(TLSError::FailedToGetCurrentTime)),
+      TlsHandshakeFailure::Other
+    );
+  }
+
+  #[test]
+  fn counts_tls_handshake_failures_per_kind() {
+    let before = tls_handshake_failures()["protocol_version"];
+
+    TlsHandshakeFailure::ProtocolVersion.record(
+      &"127.0.0.1:443".parse().unwrap(),
+      None,
+      &io::Error::from(io::ErrorKind::Other),
+    );
+
+    assert_eq!(tls_handshake_failures()["protocol_version"], before + 1);
+    assert_eq!(tls_handshake_failures().len(), TlsHandshakeFailure::ALL.len());
+  }
+}
@@ -0,0 +1,125 @@
+use super::{Context, LoadBalancingStrategy, RequestForwarder};
+use hyper::{Body, Request, Uri};
+
+/// Selects the backend server with the `primary` strategy. If it can not select a suitable backend server, see
+/// [`try_select_backend`](LoadBalancingStrategy::try_select_backend), the `fallback` strategy is used instead. Both
+/// strategies can be composed further, e.g. to chain several fallbacks.
+#[derive(Debug)]
+pub struct WithFallback {
+  pub primary: Box<dyn LoadBalancingStrategy>,
+  pub fallback: Box<dyn LoadBalancingStrategy>,
+}
+
+impl WithFallback {
+  pub fn new(primary: Box<dyn LoadBalancingStrategy>, fallback: Box<dyn LoadBalancingStrategy>) -> WithFallback {
+    WithFallback { primary, fallback }
+  }
+}
+
+impl LoadBalancingStrategy for WithFallback {
+  fn select_backend<'l>(&'l self, request: &Request<Body>, context: &'l Context) -> RequestForwarder {
+    self
+      .primary
+      .try_select_backend(request, context)
+      .unwrap_or_else(|| self.fallback.select_backend(request, context))
+  }
+
+  fn try_select_backend<'l>(&'l self, request: &Request<Body>, context: &'l Context) -> Option<RequestForwarder> {
+    self
+      .primary
+      .try_select_backend(request, context)
+      .or_else(|| self.fallback.try_select_backend(request, context))
+  }
+
+  fn on_tcp_open(&self, remote: &Uri) {
+    self.primary.on_tcp_open(remote);
+    self.fallback.on_tcp_open(remote);
+  }
+
+  fn on_tcp_close(&self, remote: &Uri) {
+    self.primary.on_tcp_close(remote);
+    self.fallback.on_tcp_close(remote);
+  }
+
+  fn on_bytes_transferred(&self, remote: &Uri, bytes: usize) {
+    self.primary.on_bytes_transferred(remote, bytes);
+    self.fallback.on_bytes_transferred(remote, bytes);
+  }
+
+  fn metrics(&self) -> Vec<(&'static str, u64)> {
+    let mut metrics = self.primary.metrics();
+    metrics.extend(self.fallback.metrics());
+    metrics
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::load_balancing::{ip_hash::IPHash, round_robin::RoundRobin};
+
+  #[test]
+  fn uses_primary_strategy_while_its_backend_is_working() {
+    // given:
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let pool_addresses = ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"];
+    let context = Context {
+      client_address: &"10.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &pool_addresses,
+      pool_addresses: &pool_addresses,
+      backend_weights: &[],
+    };
+    let strategy = WithFallback::new(Box::new(IPHash::new()), Box::new(RoundRobin::new()));
+    let ip_hash = IPHash::new();
+    let expected = ip_hash.select_backend(&request, &context).backend_address;
+
+    // when:
+    let actual = (0..3)
+      .map(|_| strategy.select_backend(&request, &context).backend_address)
+      .collect::<Vec<_>>();
+
+    // then:
+    assert_eq!(actual, vec![expected; 3]);
+  }
+
+  #[test]
+  fn uses_fallback_strategy_if_primary_can_not_select() {
+    // given:
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let pool_addresses = ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"];
+    let client_address = "10.0.0.1:3000".parse().unwrap();
+    let hashed = IPHash::new()
+      .select_backend(
+        &request,
+        &Context {
+          client_address: &client_address,
+          backend_addresses: &pool_addresses,
+          pool_addresses: &pool_addresses,
+          backend_weights: &[],
+        },
+      )
+      .backend_address
+      .to_string();
+    let working = pool_addresses
+      .iter()
+      .copied()
+      .filter(|address| *address != hashed)
+      .collect::<Vec<_>>();
+    let context = Context {
+      client_address: &client_address,
+      backend_addresses: &working,
+      pool_addresses: &pool_addresses,
+      backend_weights: &[],
+    };
+    let strategy = WithFallback::new(Box::new(IPHash::new()), Box::new(RoundRobin::new()));
+
+    // when:
+    let mut actual = (0..2)
+      .map(|_| strategy.select_backend(&request, &context).backend_address)
+      .collect::<Vec<_>>();
+
+    // then:
+    actual.sort_unstable();
+    assert_eq!(actual, working);
+  }
+}
@@ -1,11 +1,12 @@
 use super::{Context, LoadBalancingStrategy, RequestForwarder};
+use crate::utils::canonical_ip;
 use hyper::{Body, Request};
 use std::{
   collections::hash_map::DefaultHasher,
   hash::{Hash, Hasher},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct IPHash {}
 
 impl IPHash {
@@ -14,13 +15,32 @@ impl IPHash {
   }
 }
 
+impl IPHash {
+  fn select_from<'l>(&self, context: &Context, addresses: &'l [&'l str]) -> &'l str {
+    let mut hasher = DefaultHasher::new();
+    // IPv4 clients of dual-stack listeners are hashed like clients of IPv4 listeners
+    canonical_ip(context.client_address.ip()).hash(&mut hasher);
+    let index = (hasher.finish() % (addresses.len() as u64)) as usize;
+    addresses[index]
+  }
+}
+
 impl LoadBalancingStrategy for IPHash {
   fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder {
-    let mut hasher = DefaultHasher::new();
-    context.client_address.ip().hash(&mut hasher);
-    let index = (hasher.finish() % (context.backend_addresses.len() as u64)) as usize;
-    let address = &context.backend_addresses[index];
-    RequestForwarder::new(address)
+    RequestForwarder::new(self.select_from(context, context.backend_addresses))
+  }
+
+  /// Hashes over all backend servers of the pool, so clients keep their backend server while others fail. Returns
+  /// `None` if the backend server of the client is not working.
+  fn try_select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> Option<RequestForwarder> {
+    if context.pool_addresses.is_empty() {
+      return None;
+    }
+    let address = self.select_from(context, context.pool_addresses);
+    context
+      .backend_addresses
+      .contains(&address)
+      .then(|| RequestForwarder::new(address))
   }
 }
 
@@ -34,6 +54,8 @@ mod tests {
     let context = Context {
       client_address: &"127.0.0.1:3000".parse().unwrap(),
       backend_addresses: &mut ["127.0.0.1:1", "127.0.0.1:2"],
+      pool_addresses: &mut ["127.0.0.1:1", "127.0.0.1:2"],
+      backend_weights: &[],
     };
     let strategy = IPHash::new();
 
@@ -50,12 +72,16 @@ mod tests {
     let context_1 = Context {
       client_address: &"127.0.0.1:3000".parse().unwrap(),
       backend_addresses: &mut ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3", "127.0.0.1:4"],
+      pool_addresses: &mut ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3", "127.0.0.1:4"],
+      backend_weights: &[],
     };
 
     let request_2 = Request::builder().body(Body::empty()).unwrap();
     let context_2 = Context {
       client_address: &"192.168.0.4:3000".parse().unwrap(),
       backend_addresses: &mut ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3", "127.0.0.1:4"],
+      pool_addresses: &mut ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3", "127.0.0.1:4"],
+      backend_weights: &[],
     };
 
     let strategy = IPHash::new();
@@ -65,4 +91,67 @@ mod tests {
       strategy.select_backend(&request_2, &context_2).backend_address
     );
   }
+
+  #[test]
+  pub fn ip_hash_try_select_backend_of_failed_backend() {
+    // given:
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let pool_addresses = ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"];
+    let all_working = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &pool_addresses,
+      pool_addresses: &pool_addresses,
+      backend_weights: &[],
+    };
+    let strategy = IPHash::new();
+    let address = strategy
+      .try_select_backend(&request, &all_working)
+      .unwrap()
+      .backend_address;
+    let others = pool_addresses
+      .iter()
+      .copied()
+      .filter(|it| *it != address)
+      .collect::<Vec<_>>();
+    let failed = Context {
+      client_address: all_working.client_address,
+      backend_addresses: &others,
+      pool_addresses: &pool_addresses,
+      backend_weights: &[],
+    };
+
+    // when:
+    let actual = strategy.try_select_backend(&request, &failed);
+
+    // then:
+    assert!(actual.is_none());
+  }
+
+  #[test]
+  pub fn ip_hash_strategy_ipv4_mapped_ipv6_ip() {
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let addresses = [
+      "127.0.0.1:1",
+      "127.0.0.1:2",
+      "127.0.0.1:3",
+      "127.0.0.1:4",
+      "127.0.0.1:5",
+    ];
+    let ipv4 = Context {
+      client_address: &"192.168.0.4:3000".parse().unwrap(),
+      backend_addresses: &addresses,
+      pool_addresses: &addresses,
+      backend_weights: &[],
+    };
+    let ipv4_mapped = Context {
+      client_address: &"[::ffff:192.168.0.4]:3000".parse().unwrap(),
+      ..ipv4
+    };
+    let strategy = IPHash::new();
+
+    assert_eq!(
+      strategy.select_backend(&request, &ipv4).backend_address,
+      strategy.select_backend(&request, &ipv4_mapped).backend_address
+    );
+  }
 }
@@ -0,0 +1,175 @@
+use std::{
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use hyper::{Body, Request, Uri};
+use rand::{thread_rng, Rng};
+
+use super::{Context, LoadBalancingStrategy, PerBackend, RequestForwarder};
+
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+
+/// The bytes transferred to and from a backend server. Older transfers decay exponentially, so the value reflects the
+/// recent throughput.
+#[derive(Debug, Clone, Copy)]
+struct Throughput {
+  bytes: f64,
+  updated_at: Instant,
+}
+
+impl Default for Throughput {
+  fn default() -> Self {
+    Throughput {
+      bytes: 0.0,
+      updated_at: Instant::now(),
+    }
+  }
+}
+
+impl Throughput {
+  fn decayed(&self, now: Instant, window: Duration) -> f64 {
+    let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+    self.bytes * (-elapsed / window.as_secs_f64()).exp()
+  }
+}
+
+/// Selects the backend server with the least bytes transferred recently. Transfers older than `window` have less and
+/// less influence.
+#[derive(Debug)]
+pub struct LeastBandwidth {
+  window: Duration,
+  /* locked per backend server, since the bytes and their time are updated together */
+  throughput: PerBackend<Mutex<Throughput>>,
+}
+
+impl LeastBandwidth {
+  pub fn new(window: Duration) -> LeastBandwidth {
+    LeastBandwidth {
+      window,
+      throughput: PerBackend::default(),
+    }
+  }
+}
+
+impl Default for LeastBandwidth {
+  fn default() -> Self {
+    LeastBandwidth::new(DEFAULT_WINDOW)
+  }
+}
+
+impl LoadBalancingStrategy for LeastBandwidth {
+  fn on_bytes_transferred(&self, remote: &Uri, bytes: usize) {
+    if let Some(authority) = remote.authority() {
+      self.throughput.update(authority.as_str(), |throughput| {
+        let mut throughput = throughput.lock().unwrap();
+        let now = Instant::now();
+        *throughput = Throughput {
+          bytes: throughput.decayed(now, self.window) + bytes as f64,
+          updated_at: now,
+        };
+      });
+    }
+  }
+
+  fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder {
+    let now = Instant::now();
+    let throughput = self.throughput.read();
+    let bytes = context
+      .backend_addresses
+      .iter()
+      .enumerate()
+      .map(|(index, address)| {
+        let bytes = throughput
+          .get(*address)
+          .map_or(0.0, |throughput| throughput.lock().unwrap().decayed(now, self.window));
+        // slow backend servers with a lower weight appear busier
+        match context.weight(index) {
+          weight if weight > 0.0 => bytes / weight,
+          _ => f64::INFINITY,
+        }
+      })
+      .collect::<Vec<_>>();
+    drop(throughput);
+
+    let least_bytes = bytes.iter().copied().fold(f64::INFINITY, f64::min);
+    let address_indices = bytes
+      .iter()
+      .enumerate()
+      .filter(|(_, bytes)| **bytes <= least_bytes)
+      .map(|(index, _)| index)
+      .collect::<Vec<_>>();
+
+    let index = thread_rng().gen_range(0..address_indices.len());
+    RequestForwarder::new(context.backend_addresses[address_indices[index]])
+  }
+
+  fn metrics(&self) -> Vec<(&'static str, u64)> {
+    vec![("window_sec", self.window.as_secs())]
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn selects_address_with_least_bytes() {
+    // given:
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
+      backend_weights: &[],
+    };
+    let strategy = LeastBandwidth::default();
+    strategy.on_bytes_transferred(&"127.0.0.1:1".parse().unwrap(), 1_000_000);
+    strategy.on_bytes_transferred(&"127.0.0.1:2".parse().unwrap(), 500);
+    strategy.on_bytes_transferred(&"127.0.0.1:3".parse().unwrap(), 2_000);
+
+    // when:
+    let backend = strategy.select_backend(&request, &context);
+
+    // then:
+    assert_eq!(backend.backend_address, "127.0.0.1:2");
+  }
+
+  #[test]
+  fn prefers_unused_addresses() {
+    // given:
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
+      backend_weights: &[],
+    };
+    let strategy = LeastBandwidth::default();
+    strategy.on_bytes_transferred(&"127.0.0.1:1".parse().unwrap(), 1);
+
+    // when:
+    let backends = (0..10)
+      .map(|_| strategy.select_backend(&request, &context).backend_address)
+      .collect::<Vec<_>>();
+
+    // then:
+    assert!(backends.iter().all(|backend| *backend != "127.0.0.1:1"));
+  }
+
+  #[test]
+  fn throughput_decays_over_time() {
+    // given:
+    let now = Instant::now();
+    let throughput = Throughput {
+      bytes: 1000.0,
+      updated_at: now,
+    };
+
+    // when:
+    let decayed = throughput.decayed(now + Duration::from_secs(10), Duration::from_secs(10));
+
+    // then:
+    assert!((decayed - 1000.0 / std::f64::consts::E).abs() < 0.001);
+  }
+}
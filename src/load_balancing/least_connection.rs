@@ -1,70 +1,57 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use hyper::{Body, Request, Uri};
 use rand::{thread_rng, Rng};
 
-use super::{Context, LoadBalancingStrategy, RequestForwarder};
+use super::{Context, LoadBalancingStrategy, PerBackend, RequestForwarder};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct LeastConnection {
-  connections: RwLock<HashMap<String, usize>>,
+  connections: PerBackend<AtomicUsize>,
 }
 
 impl LeastConnection {
   pub fn new() -> LeastConnection {
-    LeastConnection {
-      connections: RwLock::new(HashMap::new()),
-    }
+    LeastConnection::default()
   }
 }
 
 impl LoadBalancingStrategy for LeastConnection {
   fn on_tcp_open(&self, remote: &Uri) {
     if let Some(authority) = remote.authority() {
-      let mut connections = self.connections.write().unwrap();
-      *connections.entry(authority.to_string()).or_insert(0) += 1;
+      self.connections.update(authority.as_str(), |connections| {
+        connections.fetch_add(1, Ordering::Relaxed);
+      });
     }
   }
 
   fn on_tcp_close(&self, remote: &Uri) {
     if let Some(authority) = remote.authority() {
-      let mut connections = self.connections.write().unwrap();
-      *connections.entry(authority.to_string()).or_insert(1) -= 1;
+      self.connections.update(authority.as_str(), |connections| {
+        let _ = connections.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| count.checked_sub(1));
+      });
     }
   }
 
   fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder {
-    // ok to unwrap - only panics when we panic somewhere else :)
-    let connections = self.connections.read().unwrap();
-
-    let address_indices: Vec<usize> = if connections.len() == 0 || context.backend_addresses.len() > connections.len() {
-      // if no TCP connections have been opened yet, or some backend servers are not used yet, we'll use them for the next request
-      context
-        .backend_addresses
-        .iter()
-        .enumerate()
-        .filter(|(_, address)| !connections.contains_key(**address))
-        .map(|(index, _)| index)
-        .collect()
-    } else {
-      let backend_address_map = context
-        .backend_addresses
-        .iter()
-        .enumerate()
-        .map(|(index, address)| (*address, index))
-        .collect::<HashMap<_, _>>();
-      let mut least_connections = connections.iter().collect::<Vec<_>>();
-
-      least_connections.sort_by(|a, b| a.1.cmp(b.1));
-
-      let min_connection_count = least_connections[0].1;
-      least_connections
-        .iter()
-        .take_while(|(_, connection_count)| *connection_count == min_connection_count)
-        .map(|tuple| tuple.0)
-        .map(|address| *backend_address_map.get(address.as_str()).unwrap())
-        .collect()
-    };
+    // connections to servers which are currently not available, e.g. unhealthy ones, are not taken into account
+    let connections = self.connections.read();
+    let counts = context
+      .backend_addresses
+      .iter()
+      .map(|address| connections.get(*address).map(|count| count.load(Ordering::Relaxed)))
+      .collect::<Vec<_>>();
+    drop(connections);
+
+    // if no TCP connections have been opened yet, or some backend servers are not used yet, we'll use them for the
+    // next request, `None` is less than any count
+    let least_connections = counts.iter().min().copied().flatten();
+    let address_indices = counts
+      .iter()
+      .enumerate()
+      .filter(|(_, count)| **count == least_connections)
+      .map(|(index, _)| index)
+      .collect::<Vec<_>>();
 
     if address_indices.len() == 1 {
       RequestForwarder::new(&context.backend_addresses[address_indices[0]])
@@ -87,6 +74,8 @@ mod tests {
     let context = Context {
       client_address: &"127.0.0.1:3000".parse().unwrap(),
       backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      backend_weights: &[],
     };
 
     let strategy = LeastConnection::new();
@@ -106,6 +95,8 @@ mod tests {
     let context = Context {
       client_address: &"127.0.0.1:3000".parse().unwrap(),
       backend_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
+      backend_weights: &[],
     };
 
     let strategy = LeastConnection::new();
@@ -124,4 +115,74 @@ mod tests {
       context.backend_addresses[0]
     );
   }
+
+  #[test]
+  pub fn least_connection_counts_connections_per_host_name() {
+    let request = Request::builder().body(Body::empty()).unwrap();
+
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["app1.internal:8080", "app2.internal:8080"],
+      pool_addresses: &["app1.internal:8080", "app2.internal:8080"],
+      backend_weights: &[],
+    };
+
+    let strategy = LeastConnection::new();
+    strategy.on_tcp_open(&"http://app1.internal:8080/".parse().unwrap());
+    strategy.on_tcp_open(&"http://app1.internal:8080/".parse().unwrap());
+    strategy.on_tcp_open(&"http://app2.internal:8080/".parse().unwrap());
+
+    assert_eq!(
+      strategy.select_backend(&request, &context).backend_address,
+      context.backend_addresses[1]
+    );
+  }
+
+  #[test]
+  pub fn least_connection_ignores_connections_to_unavailable_servers() {
+    let request = Request::builder().body(Body::empty()).unwrap();
+
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:2", "127.0.0.1:3"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
+      backend_weights: &[],
+    };
+
+    let strategy = LeastConnection::new();
+    strategy.on_tcp_open(&"127.0.0.1:1".parse().unwrap());
+    strategy.on_tcp_open(&"127.0.0.1:2".parse().unwrap());
+    strategy.on_tcp_open(&"127.0.0.1:3".parse().unwrap());
+    strategy.on_tcp_open(&"127.0.0.1:3".parse().unwrap());
+
+    assert_eq!(
+      strategy.select_backend(&request, &context).backend_address,
+      context.backend_addresses[0]
+    );
+  }
+
+  #[test]
+  pub fn least_connection_counts_closed_connections() {
+    let request = Request::builder().body(Body::empty()).unwrap();
+
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      backend_weights: &[],
+    };
+
+    let strategy = LeastConnection::new();
+    strategy.on_tcp_open(&"127.0.0.1:1".parse().unwrap());
+    strategy.on_tcp_open(&"127.0.0.1:1".parse().unwrap());
+    strategy.on_tcp_open(&"127.0.0.1:2".parse().unwrap());
+    strategy.on_tcp_close(&"127.0.0.1:1".parse().unwrap());
+    strategy.on_tcp_close(&"127.0.0.1:1".parse().unwrap());
+    strategy.on_tcp_close(&"127.0.0.1:1".parse().unwrap());
+
+    assert_eq!(
+      strategy.select_backend(&request, &context).backend_address,
+      context.backend_addresses[0]
+    );
+  }
 }
@@ -1,20 +1,41 @@
 use crate::{
+  backend_limit::BackendLimit,
+  health::Healthiness,
+  hedging::Hedge,
   http_client::StrategyNotifyHttpConnector,
+  log_redaction::LogRedaction,
   middleware::{self, Middleware, MiddlewareChain},
   server::Scheme,
+  tls_fingerprint::TlsFingerprint,
 };
 use async_trait::async_trait;
 use hyper::{Body, Client, Request, Response, Uri};
-use std::{convert::identity, fmt::Debug, net::SocketAddr};
+use std::{
+  collections::HashMap,
+  convert::identity,
+  fmt::Debug,
+  net::SocketAddr,
+  sync::{RwLock, RwLockReadGuard},
+  time::Duration,
+};
 
+pub mod fallback;
 pub mod ip_hash;
+pub mod least_bandwidth;
 pub mod least_connection;
 pub mod random;
 pub mod round_robin;
 pub mod sticky_cookie;
+pub mod sticky_ip;
 
 /// A trait for implementing load balancing, see
 /// [`select_backend`](LoadBalancingStrategy::select_backend) for more details.
+///
+/// A strategy is shared by all requests and backend connections of a backend
+/// pool, so its methods are called concurrently from all worker threads. Since
+/// they are called for every request or connection, they should not wait for
+/// each other: state is best kept in atomics, or per backend server in a
+/// [`PerBackend`], so only requests to the same backend server contend.
 #[async_trait]
 pub trait LoadBalancingStrategy: Send + Sync + std::fmt::Debug {
   /// Select the appropriate backend server and return a [`RequestForwarder`]
@@ -26,16 +47,128 @@ pub trait LoadBalancingStrategy: Send + Sync + std::fmt::Debug {
   /// [`StickyCookie`](sticky_cookie::StickyCookie).
   fn select_backend<'l>(&'l self, request: &Request<Body>, context: &'l Context) -> RequestForwarder;
 
+  /// Like [`select_backend`](LoadBalancingStrategy::select_backend), but returns `None` if the strategy can not select
+  /// a suitable backend server, e.g. because the backend server assigned to the client is not working. This allows
+  /// [`WithFallback`](fallback::WithFallback) to use another strategy instead.
+  fn try_select_backend<'l>(&'l self, request: &Request<Body>, context: &'l Context) -> Option<RequestForwarder> {
+    Some(self.select_backend(request, context))
+  }
+
   /// Called when a new TCP connection to a backend server opened.
   fn on_tcp_open(&self, _remote: &Uri) {}
 
   /// Called when an existing backend TCP connection is closed.
   fn on_tcp_close(&self, _remote: &Uri) {}
+
+  /// Called whenever bytes were read from or written to a backend TCP connection.
+  fn on_bytes_transferred(&self, _remote: &Uri, _bytes: usize) {}
+
+  /// Named values describing the internal state of the strategy, e.g. the size of a table. Reported by the status
+  /// endpoint of the admin API.
+  fn metrics(&self) -> Vec<(&'static str, u64)> {
+    Vec::new()
+  }
+}
+
+/// A simpler alternative to [`LoadBalancingStrategy`] for strategies which only pick one of the backend servers and
+/// neither modify the response nor need to know about backend connections. Every [`SelectIndex`] is a
+/// [`LoadBalancingStrategy`], so strategies outside of this crate can be implemented without a [`RequestForwarder`].
+pub trait SelectIndex: Send + Sync + std::fmt::Debug {
+  /// Returns the index of the backend server in
+  /// [`backend_addresses`](Context::backend_addresses) to forward the request
+  /// to. Indexes out of bounds wrap around.
+  fn select_index(&self, request: &Request<Body>, context: &Context) -> usize;
+}
+
+impl<T: SelectIndex> LoadBalancingStrategy for T {
+  fn select_backend<'l>(&'l self, request: &Request<Body>, context: &'l Context) -> RequestForwarder {
+    let index = self.select_index(request, context) % context.backend_addresses.len();
+    RequestForwarder::new(context.backend_addresses[index])
+  }
+}
+
+/// The state of a strategy per backend server, keyed by the authority of the
+/// connections to it. The state is read and updated under a shared lock, e.g.
+/// as atomic counters, so requests and connections to different backend
+/// servers never block each other. Only adding a backend server seen for the
+/// first time takes the exclusive lock.
+#[derive(Debug, Default)]
+pub struct PerBackend<T> {
+  backends: RwLock<HashMap<String, T>>,
+}
+
+impl<T: Default> PerBackend<T> {
+  /// Calls `update` with the state of the backend server at `authority`, which
+  /// is added first if it is new.
+  pub fn update<R>(&self, authority: &str, update: impl FnOnce(&T) -> R) -> R {
+    if let Some(state) = self.backends.read().unwrap().get(authority) {
+      return update(state);
+    }
+    let mut backends = self.backends.write().unwrap();
+    update(backends.entry(authority.to_string()).or_default())
+  }
+
+  /// Returns the states of all backend servers seen so far.
+  pub fn read(&self) -> RwLockReadGuard<'_, HashMap<String, T>> {
+    self.backends.read().unwrap()
+  }
 }
 
 pub struct Context<'l> {
   pub client_address: &'l SocketAddr,
+  /// The backend servers a request may be forwarded to.
   pub backend_addresses: &'l [&'l str],
+  /// All backend servers of the pool, including those which are not working.
+  pub pool_addresses: &'l [&'l str],
+  /// The response time and weight of each of the `backend_addresses`. Empty if all of them are weighted equally.
+  pub backend_weights: &'l [BackendWeight],
+}
+
+impl Context<'_> {
+  /// Returns the weight multiplier of the backend server at `index` of the `backend_addresses`.
+  pub fn weight(&self, index: usize) -> f64 {
+    self.backend_weights.get(index).map_or(1.0, |it| it.weight)
+  }
+}
+
+/// The response time of a backend server, if it is slow, and the resulting weight multiplier.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackendWeight {
+  pub latency: Option<i64>,
+  pub weight: f64,
+}
+
+/// Maps the response times of slow backend servers to weight multipliers, so they receive less requests the slower
+/// they get instead of being used only if no backend server is healthy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowWeights {
+  /// Pairs of a response time in milliseconds and the weight of backend servers responding slower, in ascending order
+  ranges: Vec<(i64, f64)>,
+}
+
+impl SlowWeights {
+  pub fn new(mut ranges: Vec<(i64, f64)>) -> SlowWeights {
+    ranges.sort_by_key(|(above_ms, _)| *above_ms);
+    SlowWeights { ranges }
+  }
+
+  pub fn weight(&self, healthiness: &Healthiness) -> BackendWeight {
+    match healthiness {
+      Healthiness::Slow(latency) => BackendWeight {
+        latency: Some(*latency),
+        weight: self
+          .ranges
+          .iter()
+          .rev()
+          .find(|(above_ms, _)| latency > above_ms)
+          .map_or(1.0, |(_, weight)| *weight),
+      },
+      _ => BackendWeight {
+        latency: None,
+        weight: 1.0,
+      },
+    }
+  }
 }
 
 /// A struct representing a backend server and allowing a final transformation
@@ -51,6 +184,9 @@ pub struct Context<'l> {
 pub struct RequestForwarder<'l> {
   backend_address: &'l str,
   response_mapper: Box<dyn Fn(Response<Body>) -> Response<Body> + Send + Sync + 'l>,
+  early_response: Option<Box<dyn Fn() -> Response<Body> + Send + Sync + 'l>>,
+  /* The backend server, delay and connection limit of hedged requests */
+  hedge: Option<(&'l str, Duration, Option<&'l BackendLimit>)>,
 }
 
 impl<'l> RequestForwarder<'l> {
@@ -69,6 +205,20 @@ impl<'l> RequestForwarder<'l> {
     RequestForwarder {
       backend_address: address,
       response_mapper: Box::new(response_mapper),
+      early_response: None,
+      hedge: None,
+    }
+  }
+
+  /// Answers the request with the `early_response` instead of forwarding it to the backend server. The final
+  /// response transformation is still applied.
+  fn respond_early<F>(self, early_response: F) -> RequestForwarder<'l>
+  where
+    F: Fn() -> Response<Body> + Send + Sync + 'l,
+  {
+    RequestForwarder {
+      early_response: Some(Box::new(early_response)),
+      ..self
     }
   }
 
@@ -77,12 +227,39 @@ impl<'l> RequestForwarder<'l> {
   where
     F: Fn(Response<Body>) -> Response<Body> + Send + Sync + 'l,
   {
+    let RequestForwarder {
+      backend_address,
+      response_mapper: previous_mapper,
+      early_response,
+      hedge,
+    } = self;
     RequestForwarder {
-      backend_address: self.backend_address,
-      response_mapper: Box::new(move |response| response_mapper((self.response_mapper)(response))),
+      backend_address,
+      response_mapper: Box::new(move |response| response_mapper(previous_mapper(response))),
+      early_response,
+      hedge,
     }
   }
 
+  /// Sends idempotent requests additionally to `backend_address`, if the selected backend server hasn't responded
+  /// within `delay`, see [`Hedging`](crate::hedging::Hedging). With a `backend_limit`, requests are only hedged if
+  /// a connection to `backend_address` is available once the delay passed.
+  pub fn hedge(
+    self,
+    backend_address: &'l str,
+    delay: Duration,
+    backend_limit: Option<&'l BackendLimit>,
+  ) -> RequestForwarder<'l> {
+    RequestForwarder {
+      hedge: Some((backend_address, delay, backend_limit)),
+      ..self
+    }
+  }
+
+  pub fn backend_address(&self) -> &str {
+    self.backend_address
+  }
+
   /// Forwards the `request` through `chain` to the backend server and applies
   /// the final response transformation of this [`RequestForwarder`].
   pub async fn forward_request_to_backend(
@@ -92,30 +269,52 @@ impl<'l> RequestForwarder<'l> {
     client_scheme: &Scheme,
     client_address: &SocketAddr,
     client: &Client<StrategyNotifyHttpConnector, Body>,
+    max_buffer_bytes: usize,
+    log_redaction: &LogRedaction,
   ) -> Response<Body> {
     let context = middleware::Context {
       client_scheme,
       client_address,
-      backend_uri: self.backend_uri(&request),
+      backend_uri: backend_uri(self.backend_address, &request),
       client,
+      max_buffer_bytes,
+      log_redaction,
+      tls_fingerprint: request.extensions().get::<TlsFingerprint>().cloned(),
+      hedge: self.hedge.map(|(backend_address, delay, backend_limit)| Hedge {
+        backend_uri: backend_uri(backend_address, &request),
+        delay,
+        backend_address,
+        backend_limit,
+      }),
     };
-    self.forward_request(request, chain, &context).await
+    let method = request.method().clone();
+    let response = self.forward_request(request, chain, &context).await;
+    middleware::without_forbidden_body(&method, response)
   }
+}
 
-  fn backend_uri(&self, request: &Request<Body>) -> Uri {
-    let path = request.uri().path_and_query().unwrap().clone();
-    Uri::builder()
-      .scheme("http")
-      .authority(self.backend_address)
-      .path_and_query(path)
-      .build()
-      .unwrap()
-  }
+fn backend_uri(backend_address: &str, request: &Request<Body>) -> Uri {
+  let path = request.uri().path_and_query().unwrap().clone();
+  Uri::builder()
+    .scheme("http")
+    .authority(backend_address)
+    .path_and_query(path)
+    .build()
+    .unwrap()
 }
 
 #[async_trait]
 impl Middleware for RequestForwarder<'_> {
-  async fn modify_response(&self, response: Response<Body>, _context: &middleware::Context<'_>) -> Response<Body> {
+  async fn forward_request(
+    &self,
+    request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &middleware::Context<'_>,
+  ) -> Response<Body> {
+    let response = match &self.early_response {
+      Some(early_response) => early_response(),
+      None => chain.forward_request(request, context).await,
+    };
     (self.response_mapper)(response)
   }
 }
@@ -127,3 +326,27 @@ impl Debug for RequestForwarder<'_> {
       .finish()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn slow_weights_use_weight_of_highest_exceeded_latency() {
+    // given:
+    let slow_weights = SlowWeights::new(vec![(1000, 0.1), (200, 0.5)]);
+
+    // when:
+    let healthy = slow_weights.weight(&Healthiness::Healthy);
+    let fast = slow_weights.weight(&Healthiness::Slow(150));
+    let slow = slow_weights.weight(&Healthiness::Slow(500));
+    let slowest = slow_weights.weight(&Healthiness::Slow(3000));
+
+    // then:
+    assert_eq!(healthy.weight, 1.0);
+    assert_eq!(fast.weight, 1.0);
+    assert_eq!(slow.weight, 0.5);
+    assert_eq!(slowest.weight, 0.1);
+    assert_eq!(slowest.latency, Some(3000));
+  }
+}
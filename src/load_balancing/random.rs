@@ -1,9 +1,11 @@
-use super::{Context, LoadBalancingStrategy, RequestForwarder};
-use async_trait::async_trait;
+use super::{Context, SelectIndex};
 use hyper::{Body, Request};
-use rand::{thread_rng, Rng};
+use rand::{
+  distributions::{Distribution, WeightedIndex},
+  thread_rng, Rng,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Random {}
 
 impl Random {
@@ -12,12 +14,55 @@ impl Random {
   }
 }
 
-#[async_trait]
-impl LoadBalancingStrategy for Random {
-  fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder {
+impl SelectIndex for Random {
+  fn select_index(&self, _request: &Request<Body>, context: &Context) -> usize {
     let mut rng = thread_rng();
-    let index = rng.gen_range(0..context.backend_addresses.len());
-    let address = &context.backend_addresses[index];
-    RequestForwarder::new(address)
+    let count = context.backend_addresses.len();
+    if context.backend_weights.is_empty() {
+      rng.gen_range(0..count)
+    } else {
+      // backend servers are chosen with a probability proportional to their weight
+      match WeightedIndex::new((0..count).map(|index| context.weight(index))) {
+        Ok(distribution) => distribution.sample(&mut rng),
+        Err(_) => rng.gen_range(0..count),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::load_balancing::{BackendWeight, LoadBalancingStrategy};
+
+  #[test]
+  fn random_never_selects_backend_without_weight() {
+    // given:
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let backend_weights = [
+      BackendWeight {
+        latency: None,
+        weight: 1.0,
+      },
+      BackendWeight {
+        latency: Some(5000),
+        weight: 0.0,
+      },
+    ];
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      backend_weights: &backend_weights,
+    };
+    let strategy = Random::new();
+
+    // when:
+    let backends = (0..20)
+      .map(|_| strategy.select_backend(&request, &context).backend_address)
+      .collect::<Vec<_>>();
+
+    // then:
+    assert!(backends.iter().all(|backend| *backend == "127.0.0.1:1"));
   }
 }
@@ -1,32 +1,31 @@
-use super::{Context, LoadBalancingStrategy, RequestForwarder};
+use super::{Context, SelectIndex};
 use hyper::{Body, Request};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct RoundRobin {
-  rrc: Arc<Mutex<u32>>,
+  /* number of requests so far, concurrent requests must not get the same index */
+  counter: AtomicUsize,
 }
 
 impl RoundRobin {
   pub fn new() -> RoundRobin {
-    RoundRobin {
-      rrc: Arc::new(Mutex::new(0)),
-    }
+    RoundRobin::default()
   }
 }
 
-impl LoadBalancingStrategy for RoundRobin {
-  fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder {
-    let mut rrc_handle = self.rrc.lock().unwrap();
-    *rrc_handle = (*rrc_handle + 1) % context.backend_addresses.len() as u32;
-    let address = &context.backend_addresses[*rrc_handle as usize];
-    RequestForwarder::new(address)
+impl SelectIndex for RoundRobin {
+  fn select_index(&self, _request: &Request<Body>, context: &Context) -> usize {
+    let previous = self.counter.fetch_add(1, Ordering::Relaxed);
+    previous.wrapping_add(1) % context.backend_addresses.len()
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::load_balancing::LoadBalancingStrategy;
+  use std::{sync::Arc, thread};
 
   #[test]
   pub fn round_robin_strategy_single_address() {
@@ -35,6 +34,8 @@ mod tests {
     let context = Context {
       client_address: &"127.0.0.1:3000".parse().unwrap(),
       backend_addresses: &mut [address],
+      pool_addresses: &mut [address],
+      backend_weights: &[],
     };
     let strategy = RoundRobin::new();
 
@@ -51,6 +52,8 @@ mod tests {
     let context = Context {
       client_address: &"127.0.0.1:3000".parse().unwrap(),
       backend_addresses: &mut [address_1, address_2],
+      pool_addresses: &mut [address_1, address_2],
+      backend_weights: &[],
     };
     let strategy = RoundRobin::new();
 
@@ -59,4 +62,39 @@ mod tests {
     assert_eq!(strategy.select_backend(&request, &context).backend_address, address_2);
     assert_eq!(strategy.select_backend(&request, &context).backend_address, address_1);
   }
+
+  #[test]
+  pub fn round_robin_strategy_spreads_concurrent_requests_evenly() {
+    // given:
+    let strategy = Arc::new(RoundRobin::new());
+    let addresses = ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3", "127.0.0.1:4"];
+
+    // when:
+    let threads = (0..4)
+      .map(|_| {
+        let strategy = strategy.clone();
+        thread::spawn(move || {
+          let request = Request::builder().body(Body::empty()).unwrap();
+          let context = Context {
+            client_address: &"127.0.0.1:3000".parse().unwrap(),
+            backend_addresses: &addresses,
+            pool_addresses: &addresses,
+            backend_weights: &[],
+          };
+          (0..1000)
+            .map(|_| strategy.select_index(&request, &context))
+            .collect::<Vec<_>>()
+        })
+      })
+      .collect::<Vec<_>>();
+    let mut selections = [0; 4];
+    for thread in threads {
+      for index in thread.join().unwrap() {
+        selections[index] += 1;
+      }
+    }
+
+    // then:
+    assert_eq!(selections, [1000; 4]);
+  }
 }
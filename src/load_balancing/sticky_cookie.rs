@@ -1,11 +1,47 @@
 use super::{Context, LoadBalancingStrategy, RequestForwarder};
+use crate::{error_response::service_unavailable, utils::find_cookie};
 use async_trait::async_trait;
-use cookie::{Cookie, SameSite};
+use cookie::{Cookie, CookieBuilder, SameSite};
 use hyper::{
-  header::{Entry, HeaderValue, COOKIE, SET_COOKIE},
-  Body, Request, Response,
+  header::{Entry, HeaderValue, SET_COOKIE},
+  Body, Request, Response, Uri,
 };
+use std::time::Duration;
 
+/// Cookies with this name prefix are only accepted by browsers if they are `Secure`.
+const SECURE_PREFIX: &str = "__Secure-";
+/// Cookies with this name prefix are only accepted by browsers if they are `Secure`, have the path `/` and no domain.
+const HOST_PREFIX: &str = "__Host-";
+
+/// Checks the constraints of the cookie name prefixes, which can not be fulfilled automatically.
+pub fn validate_cookie_prefix(cookie_name: &str, path: Option<&str>, domain: Option<&str>) -> Result<(), String> {
+  if !cookie_name.starts_with(HOST_PREFIX) {
+    return Ok(());
+  }
+  if domain.is_some() {
+    return Err(format!("cookie '{}' must not have a domain", cookie_name));
+  }
+  match path {
+    Some(path) if path != "/" => Err(format!("cookie '{}' must have the path '/'", cookie_name)),
+    _ => Ok(()),
+  }
+}
+
+/// What happens to clients whose backend server is still in the pool, but not working.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AffinityFailover {
+  /// Selects another backend server with the inner strategy and updates the cookie
+  Reassign,
+  /// Answers with `503 Service Unavailable` and removes the cookie, so the client starts a new session, e.g. logs in
+  /// again, with its next request
+  Reject,
+  /// Keeps forwarding to the backend server, for stateful applications which lose data on a failover
+  Keep,
+}
+
+/// Sticks clients to a backend server with a cookie. Browsers drop cookies with `SameSite=None` or with a `__Secure-`
+/// or `__Host-` name prefix unless they are `Secure`, so `Secure` is added automatically to them. `__Host-` cookies
+/// also get the path `/`.
 #[derive(Debug)]
 pub struct StickyCookie {
   pub cookie_name: String,
@@ -13,6 +49,10 @@ pub struct StickyCookie {
   pub http_only: bool,
   pub secure: bool,
   pub same_site: SameSite,
+  pub max_age: Option<Duration>,
+  pub path: Option<String>,
+  pub domain: Option<String>,
+  pub on_unhealthy_backend: AffinityFailover,
 }
 
 impl StickyCookie {
@@ -29,56 +69,280 @@ impl StickyCookie {
       http_only,
       secure,
       same_site,
+      max_age: None,
+      path: None,
+      domain: None,
+      on_unhealthy_backend: AffinityFailover::Reassign,
     }
   }
 
-  fn try_parse_sticky_cookie<'a>(&self, request: &'a Request<Body>) -> Option<Cookie<'a>> {
-    let cookie_header = request.headers().get(COOKIE)?;
+  /// Returns true if browsers require the cookie to be `Secure`.
+  pub fn requires_secure(&self) -> bool {
+    self.same_site == SameSite::None
+      || self.cookie_name.starts_with(SECURE_PREFIX)
+      || self.cookie_name.starts_with(HOST_PREFIX)
+  }
 
-    cookie_header.to_str().ok()?.split(';').find_map(|cookie_str| {
-      let cookie = Cookie::parse(cookie_str).ok()?;
-      if cookie.name() == self.cookie_name {
-        Some(cookie)
-      } else {
-        None
-      }
-    })
+  fn modify_response(&self, response: Response<Body>, backend_address: &str) -> Response<Body> {
+    let mut cookie = self.cookie(backend_address);
+    if let Some(max_age) = self.max_age {
+      cookie = cookie.max_age(time::Duration::seconds(max_age.as_secs() as i64));
+    }
+    append_cookie(response, cookie.finish())
+  }
+
+  /// Removes the cookie from the client.
+  fn expire_cookie(&self, response: Response<Body>) -> Response<Body> {
+    append_cookie(response, self.cookie("").max_age(time::Duration::seconds(0)).finish())
   }
 
-  fn modify_response(&self, mut response: Response<Body>, backend_address: &str) -> Response<Body> {
-    let cookie = Cookie::build(self.cookie_name.as_str(), backend_address)
+  fn cookie<'c>(&'c self, backend_address: &'c str) -> CookieBuilder<'c> {
+    let mut cookie = Cookie::build(self.cookie_name.as_str(), backend_address)
       .http_only(self.http_only)
-      .secure(self.secure)
-      .same_site(self.same_site)
-      .finish();
+      .secure(self.secure || self.requires_secure())
+      .same_site(self.same_site);
+    if let Some(path) = &self.path {
+      cookie = cookie.path(path.as_str());
+    } else if self.cookie_name.starts_with(HOST_PREFIX) {
+      cookie = cookie.path("/");
+    }
+    if let Some(domain) = &self.domain {
+      cookie = cookie.domain(domain.as_str());
+    }
+    cookie
+  }
+}
 
-    let cookie_val = HeaderValue::from_str(&cookie.to_string()).unwrap();
+fn append_cookie(mut response: Response<Body>, cookie: Cookie) -> Response<Body> {
+  let cookie_val = HeaderValue::from_str(&cookie.to_string()).unwrap();
 
-    match response.headers_mut().entry(SET_COOKIE) {
-      Entry::Occupied(mut entry) => {
-        entry.append(cookie_val);
-      }
-      Entry::Vacant(entry) => {
-        entry.insert(cookie_val);
-      }
+  match response.headers_mut().entry(SET_COOKIE) {
+    Entry::Occupied(mut entry) => {
+      entry.append(cookie_val);
+    }
+    Entry::Vacant(entry) => {
+      entry.insert(cookie_val);
     }
-    response
   }
+  response
 }
 
 #[async_trait]
 impl LoadBalancingStrategy for StickyCookie {
   fn select_backend<'l>(&'l self, request: &Request<Body>, context: &'l Context) -> RequestForwarder {
-    let backend_address = self
-      .try_parse_sticky_cookie(&request)
-      .and_then(|cookie| context.backend_addresses.iter().find(|it| **it == cookie.value()));
-
-    if let Some(backend_address) = backend_address {
-      RequestForwarder::new(backend_address)
-    } else {
-      let backend = self.inner.select_backend(request, context);
-      let backend_address = backend.backend_address;
-      backend.map_response(move |response| self.modify_response(response, backend_address))
+    if let Some(cookie) = find_cookie(request.headers(), &self.cookie_name) {
+      if let Some(backend_address) = context.backend_addresses.iter().find(|it| **it == cookie.value()) {
+        return RequestForwarder::new(backend_address);
+      }
+      // backend servers removed from the pool are always reassigned
+      let unhealthy_address = context.pool_addresses.iter().find(|it| **it == cookie.value());
+      match (unhealthy_address, self.on_unhealthy_backend) {
+        (Some(backend_address), AffinityFailover::Keep) => return RequestForwarder::new(backend_address),
+        (Some(backend_address), AffinityFailover::Reject) => {
+          return RequestForwarder::new(backend_address)
+            .respond_early(service_unavailable)
+            .map_response(move |response| self.expire_cookie(response))
+        }
+        _ => {}
+      }
     }
+
+    let backend = self.inner.select_backend(request, context);
+    let backend_address = backend.backend_address;
+    backend.map_response(move |response| self.modify_response(response, backend_address))
+  }
+
+  /// Returns `None` for clients whose backend server is in the pool, but not working, if they would be reassigned. They
+  /// keep their cookie, so they return to their backend server once it works again.
+  fn try_select_backend<'l>(&'l self, request: &Request<Body>, context: &'l Context) -> Option<RequestForwarder> {
+    if let Some(cookie) = find_cookie(request.headers(), &self.cookie_name) {
+      let is_unavailable =
+        !context.backend_addresses.contains(&cookie.value()) && context.pool_addresses.contains(&cookie.value());
+      if is_unavailable && self.on_unhealthy_backend == AffinityFailover::Reassign {
+        return None;
+      }
+    }
+    Some(self.select_backend(request, context))
+  }
+
+  fn on_tcp_open(&self, remote: &Uri) {
+    self.inner.on_tcp_open(remote);
+  }
+
+  fn on_tcp_close(&self, remote: &Uri) {
+    self.inner.on_tcp_close(remote);
+  }
+
+  fn on_bytes_transferred(&self, remote: &Uri, bytes: usize) {
+    self.inner.on_bytes_transferred(remote, bytes);
+  }
+
+  fn metrics(&self) -> Vec<(&'static str, u64)> {
+    self.inner.metrics()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::load_balancing::{least_connection::LeastConnection, random::Random};
+
+  fn set_cookie(strategy: &StickyCookie) -> String {
+    let response = strategy.modify_response(Response::new(Body::empty()), "127.0.0.1:1");
+    response.headers()[SET_COOKIE].to_str().unwrap().to_string()
+  }
+
+  #[test]
+  fn sticky_cookie_session_cookie_by_default() {
+    let strategy = StickyCookie::new("lb".into(), Box::new(Random::new()), true, false, SameSite::Lax);
+
+    assert_eq!(set_cookie(&strategy), "lb=127.0.0.1:1; HttpOnly; SameSite=Lax");
+  }
+
+  #[test]
+  fn sticky_cookie_with_expiry_and_scope() {
+    let mut strategy = StickyCookie::new("lb".into(), Box::new(Random::new()), false, true, SameSite::Strict);
+    strategy.max_age = Some(Duration::from_secs(3600));
+    strategy.path = Some("/app".into());
+    strategy.domain = Some("example.com".into());
+
+    let set_cookie = set_cookie(&strategy);
+
+    assert!(set_cookie.contains("Secure"));
+    assert!(set_cookie.contains("Max-Age=3600"));
+    assert!(set_cookie.contains("Path=/app"));
+    assert!(set_cookie.contains("Domain=example.com"));
+  }
+
+  #[test]
+  fn sticky_cookie_same_site_none_is_secure() {
+    let strategy = StickyCookie::new("lb".into(), Box::new(Random::new()), true, false, SameSite::None);
+
+    assert_eq!(set_cookie(&strategy), "lb=127.0.0.1:1; HttpOnly; SameSite=None; Secure");
+  }
+
+  #[test]
+  fn sticky_cookie_host_prefix_is_secure_with_root_path() {
+    let strategy = StickyCookie::new("__Host-lb".into(), Box::new(Random::new()), true, false, SameSite::Lax);
+
+    let set_cookie = set_cookie(&strategy);
+
+    assert!(set_cookie.contains("Secure"));
+    assert!(set_cookie.contains("Path=/"));
+  }
+
+  fn select(strategy: &StickyCookie, cookie: &str) -> (String, bool) {
+    let request = Request::builder().header("cookie", cookie).body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:2"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      backend_weights: &[],
+    };
+    let backend = strategy.select_backend(&request, &context);
+    (backend.backend_address.to_string(), backend.early_response.is_some())
+  }
+
+  #[test]
+  fn sticky_cookie_passes_connections_to_inner_strategy() {
+    // given:
+    let strategy = StickyCookie::new(
+      "lb".into(),
+      Box::new(LeastConnection::new()),
+      true,
+      false,
+      SameSite::Lax,
+    );
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      backend_weights: &[],
+    };
+
+    // when:
+    strategy.on_tcp_open(&"127.0.0.1:1".parse().unwrap());
+    strategy.on_tcp_open(&"127.0.0.1:2".parse().unwrap());
+    strategy.on_tcp_open(&"127.0.0.1:2".parse().unwrap());
+    strategy.on_tcp_close(&"127.0.0.1:2".parse().unwrap());
+    strategy.on_tcp_close(&"127.0.0.1:2".parse().unwrap());
+
+    // then:
+    assert_eq!(
+      strategy.select_backend(&request, &context).backend_address,
+      "127.0.0.1:2"
+    );
+  }
+
+  #[test]
+  fn sticky_cookie_failover_of_unhealthy_backend() {
+    // given:
+    let mut strategy = StickyCookie::new("lb".into(), Box::new(Random::new()), true, false, SameSite::Lax);
+
+    // when:
+    let reassigned = select(&strategy, "lb=127.0.0.1:1");
+    strategy.on_unhealthy_backend = AffinityFailover::Reject;
+    let rejected = select(&strategy, "lb=127.0.0.1:1");
+    strategy.on_unhealthy_backend = AffinityFailover::Keep;
+    let kept = select(&strategy, "lb=127.0.0.1:1");
+    let removed = select(&strategy, "lb=127.0.0.1:3");
+
+    // then:
+    assert_eq!(reassigned, ("127.0.0.1:2".to_string(), false));
+    assert_eq!(rejected, ("127.0.0.1:1".to_string(), true));
+    assert_eq!(kept, ("127.0.0.1:1".to_string(), false));
+    assert_eq!(removed, ("127.0.0.1:2".to_string(), false));
+  }
+
+  #[test]
+  fn sticky_cookie_try_select_backend_of_unhealthy_backend() {
+    // given:
+    let mut strategy = StickyCookie::new("lb".into(), Box::new(Random::new()), true, false, SameSite::Lax);
+    let request = |cookie: &str| Request::builder().header("cookie", cookie).body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:2"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      backend_weights: &[],
+    };
+    let selected = |strategy: &StickyCookie, cookie: &str| {
+      strategy
+        .try_select_backend(&request(cookie), &context)
+        .map(|backend| backend.backend_address.to_string())
+    };
+
+    // when:
+    let unhealthy = selected(&strategy, "lb=127.0.0.1:1");
+    let healthy = selected(&strategy, "lb=127.0.0.1:2");
+    let removed = selected(&strategy, "lb=127.0.0.1:3");
+    strategy.on_unhealthy_backend = AffinityFailover::Keep;
+    let kept = selected(&strategy, "lb=127.0.0.1:1");
+
+    // then:
+    assert_eq!(unhealthy, None);
+    assert_eq!(healthy, Some("127.0.0.1:2".to_string()));
+    assert_eq!(removed, Some("127.0.0.1:2".to_string()));
+    assert_eq!(kept, Some("127.0.0.1:1".to_string()));
+  }
+
+  #[test]
+  fn sticky_cookie_expires_cookie() {
+    let strategy = StickyCookie::new("lb".into(), Box::new(Random::new()), true, false, SameSite::Lax);
+
+    let response = strategy.expire_cookie(Response::new(Body::empty()));
+
+    assert_eq!(response.headers()[SET_COOKIE], "lb=; HttpOnly; SameSite=Lax; Max-Age=0");
+  }
+
+  #[test]
+  fn validate_cookie_prefix_of_host_cookies() {
+    assert_eq!(validate_cookie_prefix("__Host-lb", Some("/"), None), Ok(()));
+    assert_eq!(
+      validate_cookie_prefix("__Secure-lb", Some("/app"), Some("example.com")),
+      Ok(())
+    );
+    assert!(validate_cookie_prefix("__Host-lb", Some("/app"), None).is_err());
+    assert!(validate_cookie_prefix("__Host-lb", None, Some("example.com")).is_err());
   }
 }
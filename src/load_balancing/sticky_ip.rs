@@ -0,0 +1,253 @@
+use super::{Context, LoadBalancingStrategy, RequestForwarder};
+use hyper::{Body, Request, Uri};
+use linked_hash_map::LinkedHashMap;
+use std::{
+  fmt::{self, Debug},
+  net::IpAddr,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+pub const DEFAULT_MAX_CLIENTS: usize = 100_000;
+
+/// Remembers the backend server selected by the `inner` strategy for every client IP. Unlike [`IPHash`], clients keep
+/// their backend server if other backend servers are added, removed or become unhealthy.
+///
+/// The clients are kept in the order of their last request, so idle clients can be evicted from the front without
+/// scanning all clients. At most `max_clients` clients are remembered, if more clients send requests the clients idle
+/// for the longest time are evicted.
+///
+/// [`IPHash`]: super::ip_hash::IPHash
+pub struct StickyIP {
+  inner: Box<dyn LoadBalancingStrategy>,
+  idle_timeout: Duration,
+  max_clients: usize,
+  clients: Mutex<LinkedHashMap<IpAddr, (String, Instant)>>,
+}
+
+impl StickyIP {
+  pub fn new(inner: Box<dyn LoadBalancingStrategy>, idle_timeout: Duration, max_clients: usize) -> StickyIP {
+    StickyIP {
+      inner,
+      idle_timeout,
+      max_clients: max_clients.max(1),
+      clients: Mutex::new(LinkedHashMap::new()),
+    }
+  }
+
+  fn select_backend_at<'l>(&'l self, request: &Request<Body>, context: &'l Context, now: Instant) -> RequestForwarder {
+    let client_ip = context.client_address.ip();
+    let mut clients = self.clients.lock().unwrap();
+
+    while let Some((_, (_, last_request))) = clients.front() {
+      if now.duration_since(*last_request) < self.idle_timeout {
+        break;
+      }
+      clients.pop_front();
+    }
+
+    if let Some((backend_address, last_request)) = clients.get_refresh(&client_ip) {
+      let backend_address = context
+        .backend_addresses
+        .iter()
+        .find(|it| **it == backend_address.as_str());
+      if let Some(backend_address) = backend_address {
+        *last_request = now;
+        return RequestForwarder::new(backend_address);
+      }
+    }
+
+    let backend = self.inner.select_backend(request, context);
+    if clients.len() >= self.max_clients && !clients.contains_key(&client_ip) {
+      clients.pop_front();
+    }
+    clients.insert(client_ip, (backend.backend_address.to_string(), now));
+    backend
+  }
+
+  fn try_select_backend_at<'l>(
+    &'l self,
+    request: &Request<Body>,
+    context: &'l Context,
+    now: Instant,
+  ) -> Option<RequestForwarder> {
+    let client_ip = context.client_address.ip();
+    let clients = self.clients.lock().unwrap();
+    if let Some((backend_address, last_request)) = clients.get(&client_ip) {
+      let is_unavailable = !context.backend_addresses.contains(&backend_address.as_str())
+        && context.pool_addresses.contains(&backend_address.as_str());
+      if is_unavailable && now.duration_since(*last_request) < self.idle_timeout {
+        return None;
+      }
+    }
+    drop(clients);
+    Some(self.select_backend_at(request, context, now))
+  }
+}
+
+impl LoadBalancingStrategy for StickyIP {
+  fn select_backend<'l>(&'l self, request: &Request<Body>, context: &'l Context) -> RequestForwarder {
+    self.select_backend_at(request, context, Instant::now())
+  }
+
+  /// Returns `None` for clients whose backend server is in the pool, but not working. They are not reassigned, so they
+  /// return to their backend server once it works again.
+  fn try_select_backend<'l>(&'l self, request: &Request<Body>, context: &'l Context) -> Option<RequestForwarder> {
+    self.try_select_backend_at(request, context, Instant::now())
+  }
+
+  fn on_bytes_transferred(&self, remote: &Uri, bytes: usize) {
+    self.inner.on_bytes_transferred(remote, bytes);
+  }
+
+  fn metrics(&self) -> Vec<(&'static str, u64)> {
+    let clients = self.clients.lock().unwrap().len();
+    vec![("clients", clients as u64), ("max_clients", self.max_clients as u64)]
+  }
+}
+
+// The affinity table is left out, since it may contain many clients
+impl Debug for StickyIP {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("StickyIP")
+      .field("inner", &self.inner)
+      .field("idle_timeout", &self.idle_timeout)
+      .field("max_clients", &self.max_clients)
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::load_balancing::round_robin::RoundRobin;
+
+  fn strategy(max_clients: usize) -> StickyIP {
+    StickyIP::new(Box::new(RoundRobin::new()), Duration::from_secs(60), max_clients)
+  }
+
+  fn select(strategy: &StickyIP, client: &str, backend_addresses: &[&str], now: Instant) -> String {
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &client.parse().unwrap(),
+      backend_addresses,
+      pool_addresses: backend_addresses,
+      backend_weights: &[],
+    };
+    let backend_address = strategy.select_backend_at(&request, &context, now).backend_address;
+    backend_address.to_string()
+  }
+
+  #[test]
+  fn client_keeps_backend_when_backends_change() {
+    // given:
+    let strategy = strategy(10);
+    let now = Instant::now();
+    let first = select(&strategy, "10.0.0.1:1000", &["127.0.0.1:1", "127.0.0.1:2"], now);
+
+    // when:
+    let second = select(
+      &strategy,
+      "10.0.0.1:2000",
+      &["127.0.0.1:3", "127.0.0.1:2", "127.0.0.1:1"],
+      now + Duration::from_secs(30),
+    );
+
+    // then:
+    assert_eq!(first, second);
+  }
+
+  #[test]
+  fn client_is_reassigned_when_backend_is_gone() {
+    // given:
+    let strategy = strategy(10);
+    let now = Instant::now();
+    select(&strategy, "10.0.0.1:1000", &["127.0.0.1:1"], now);
+
+    // when:
+    let backend = select(&strategy, "10.0.0.1:1000", &["127.0.0.1:2"], now);
+
+    // then:
+    assert_eq!(backend, "127.0.0.1:2");
+    assert_eq!(
+      select(&strategy, "10.0.0.1:1000", &["127.0.0.1:1", "127.0.0.1:2"], now),
+      "127.0.0.1:2"
+    );
+  }
+
+  #[test]
+  fn try_select_backend_of_unhealthy_backend() {
+    // given:
+    let strategy = strategy(10);
+    let now = Instant::now();
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let client_address = "10.0.0.1:1000".parse().unwrap();
+    let unhealthy_context = Context {
+      client_address: &client_address,
+      backend_addresses: &["127.0.0.1:2"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      backend_weights: &[],
+    };
+    let recovered_context = Context {
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      ..unhealthy_context
+    };
+    select(&strategy, "10.0.0.1:1000", &["127.0.0.1:1"], now);
+
+    // when:
+    let unhealthy = strategy.try_select_backend_at(&request, &unhealthy_context, now);
+    let recovered = strategy.try_select_backend_at(&request, &recovered_context, now);
+
+    // then:
+    assert!(unhealthy.is_none());
+    assert_eq!(recovered.unwrap().backend_address, "127.0.0.1:1");
+  }
+
+  #[test]
+  fn idle_clients_expire() {
+    // given:
+    let strategy = strategy(10);
+    let now = Instant::now();
+    select(&strategy, "10.0.0.1:1000", &["127.0.0.1:1", "127.0.0.1:2"], now);
+    select(
+      &strategy,
+      "10.0.0.2:1000",
+      &["127.0.0.1:1", "127.0.0.1:2"],
+      now + Duration::from_secs(50),
+    );
+
+    // when:
+    select(
+      &strategy,
+      "10.0.0.3:1000",
+      &["127.0.0.1:1", "127.0.0.1:2"],
+      now + Duration::from_secs(70),
+    );
+
+    // then:
+    let clients = strategy.clients.lock().unwrap();
+    assert!(!clients.contains_key(&"10.0.0.1".parse::<IpAddr>().unwrap()));
+    assert_eq!(clients.len(), 2);
+  }
+
+  #[test]
+  fn least_recently_used_client_is_evicted_when_full() {
+    // given:
+    let strategy = strategy(2);
+    let now = Instant::now();
+    select(&strategy, "10.0.0.1:1000", &["127.0.0.1:1"], now);
+    select(&strategy, "10.0.0.2:1000", &["127.0.0.1:1"], now);
+    select(&strategy, "10.0.0.1:1000", &["127.0.0.1:1"], now);
+
+    // when:
+    select(&strategy, "10.0.0.3:1000", &["127.0.0.1:1"], now);
+
+    // then:
+    let clients = strategy.clients.lock().unwrap();
+    assert!(clients.contains_key(&"10.0.0.1".parse::<IpAddr>().unwrap()));
+    assert!(!clients.contains_key(&"10.0.0.2".parse::<IpAddr>().unwrap()));
+    drop(clients);
+    assert_eq!(strategy.metrics(), vec![("clients", 2), ("max_clients", 2)]);
+  }
+}
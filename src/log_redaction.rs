@@ -0,0 +1,152 @@
+use hyper::{header::HeaderName, Body, HeaderMap, Request, Uri};
+
+pub const REDACTED: &str = "[redacted]";
+
+/// Configures which parts of a request are masked before it is written to the logs, so enabling the `debug` or
+/// `trace` level doesn't leak credentials or session tokens to the log file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRedaction {
+  /// Headers whose values are replaced by `[redacted]`
+  pub headers: Vec<HeaderName>,
+  /// Query parameters whose values are replaced by `[redacted]`, compared case-insensitively
+  pub query_parameters: Vec<String>,
+}
+
+impl Default for LogRedaction {
+  fn default() -> Self {
+    LogRedaction {
+      headers: default_headers(),
+      query_parameters: default_query_parameters(),
+    }
+  }
+}
+
+pub fn default_headers() -> Vec<HeaderName> {
+  vec![
+    hyper::header::AUTHORIZATION,
+    hyper::header::PROXY_AUTHORIZATION,
+    hyper::header::COOKIE,
+    hyper::header::SET_COOKIE,
+  ]
+}
+
+pub fn default_query_parameters() -> Vec<String> {
+  [
+    "access_token",
+    "api_key",
+    "apikey",
+    "code",
+    "key",
+    "password",
+    "secret",
+    "signature",
+    "token",
+  ]
+  .iter()
+  .map(|it| it.to_string())
+  .collect()
+}
+
+impl LogRedaction {
+  /// Returns the `uri` with the values of sensitive query parameters masked.
+  pub fn uri(&self, uri: &Uri) -> String {
+    let full = uri.to_string();
+    let query = match uri.query() {
+      Some(query) => query,
+      None => return full,
+    };
+    // the query is the last part of a request URI
+    let prefix = &full[..full.len() - query.len()];
+    let query = query
+      .split('&')
+      .map(|pair| {
+        let name = pair.split('=').next().unwrap_or_default();
+        if self.is_sensitive_parameter(name) {
+          format!("{}={}", name, REDACTED)
+        } else {
+          pair.to_string()
+        }
+      })
+      .collect::<Vec<_>>()
+      .join("&");
+    format!("{}{}", prefix, query)
+  }
+
+  /// Returns the method and the redacted URI of the `request`, e.g. `GET /login?token=[redacted]`.
+  pub fn request_line(&self, request: &Request<Body>) -> String {
+    format!("{} {}", request.method(), self.uri(request.uri()))
+  }
+
+  /// Returns one `name: value` line per header, with the values of sensitive headers masked.
+  pub fn headers(&self, headers: &HeaderMap) -> String {
+    headers
+      .iter()
+      .map(|(name, value)| {
+        let value = if self.headers.contains(name) {
+          REDACTED
+        } else {
+          value.to_str().unwrap_or("[binary]")
+        };
+        format!("{}: {}", name, value)
+      })
+      .collect::<Vec<_>>()
+      .join("\n")
+  }
+
+  fn is_sensitive_parameter(&self, name: &str) -> bool {
+    self
+      .query_parameters
+      .iter()
+      .any(|parameter| parameter.eq_ignore_ascii_case(name))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::header::{AUTHORIZATION, COOKIE, HOST};
+
+  #[test]
+  fn redacts_sensitive_query_parameters() {
+    let redaction = LogRedaction::default();
+    let uri: Uri = "/login?user=alice&Token=abc123&next=%2F".parse().unwrap();
+
+    assert_eq!(redaction.uri(&uri), "/login?user=alice&Token=[redacted]&next=%2F");
+  }
+
+  #[test]
+  fn keeps_uris_without_sensitive_query_parameters() {
+    let redaction = LogRedaction::default();
+
+    assert_eq!(redaction.uri(&"/".parse().unwrap()), "/");
+    assert_eq!(
+      redaction.uri(&"http://whoami.localhost/search?q=rust".parse().unwrap()),
+      "http://whoami.localhost/search?q=rust"
+    );
+  }
+
+  #[test]
+  fn redacts_sensitive_headers() {
+    // given:
+    let redaction = LogRedaction {
+      headers: vec![AUTHORIZATION, COOKIE],
+      query_parameters: Vec::new(),
+    };
+    let request = Request::get("/?token=abc")
+      .header(HOST, "whoami.localhost")
+      .header(AUTHORIZATION, "Basic dXNlcjpwYXNz")
+      .header(COOKIE, "session=abc")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let headers = redaction.headers(request.headers());
+
+    // then:
+    assert_eq!(
+      headers,
+      "host: whoami.localhost\nauthorization: [redacted]\ncookie: [redacted]"
+    );
+    assert_eq!(redaction.request_line(&request), "GET /?token=abc");
+  }
+}
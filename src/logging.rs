@@ -1,28 +1,48 @@
-use log::{info, LevelFilter};
+use log::{error, info, LevelFilter};
 use log4rs::{
-  append::console::ConsoleAppender,
+  append::{console::ConsoleAppender, file::FileAppender, Append},
   config::{Appender, Logger, Root},
   encode::pattern,
-  Config,
+  Config, Handle,
 };
 use pattern::PatternEncoder;
+use std::io;
 
-pub fn initialize() -> log4rs::Handle {
+pub fn initialize() -> Handle {
   let log_level = std::env::var("LOG_LEVEL").unwrap_or_else(|_| "INFO".into());
   let level_filter = parse_level_filter(&log_level).unwrap_or_else(|| panic!("Invalid log level: {}", &log_level));
 
+  let config = build_config(level_filter).expect("Opening the log file should not fail");
+  let handle = log4rs::init_config(config).expect("Initializing logging should not fail");
+  info!("Logging Level: {}", &level_filter);
+  handle
+}
+
+/// Reopens the log file given by `LOG_FILE`, so logs can be rotated without restarting the process.
+pub fn reopen(handle: &Handle) {
+  let level_filter = log::max_level();
+  match build_config(level_filter) {
+    Ok(config) => {
+      handle.set_config(config);
+      info!("Reopened logs");
+    }
+    Err(e) => error!("Could not reopen logs due to: {}", e),
+  }
+}
+
+fn build_config(level_filter: LevelFilter) -> io::Result<Config> {
   let pattern = PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S%.9f)} {({l}):5} {t} - {m}{n}");
 
-  let stdout = ConsoleAppender::builder().encoder(Box::new(pattern)).build();
+  let appender: Box<dyn Append> = match std::env::var("LOG_FILE") {
+    Ok(path) => Box::new(FileAppender::builder().encoder(Box::new(pattern)).build(path)?),
+    Err(_) => Box::new(ConsoleAppender::builder().encoder(Box::new(pattern)).build()),
+  };
   let config = Config::builder()
     .logger(Logger::builder().build("ureq", LevelFilter::Warn))
-    .appender(Appender::builder().build("stdout", Box::new(stdout)))
-    .build(Root::builder().appender("stdout").build(level_filter))
+    .appender(Appender::builder().build("log", appender))
+    .build(Root::builder().appender("log").build(level_filter))
     .unwrap();
-
-  let handle = log4rs::init_config(config).expect("Initializing logging should not fail");
-  info!("Logging Level: {}", &level_filter);
-  handle
+  Ok(config)
 }
 
 fn parse_level_filter(str: &str) -> Option<LevelFilter> {
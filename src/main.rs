@@ -1,30 +1,28 @@
+#[cfg(windows)]
+use another_rust_load_balancer::lifecycle::service;
+use another_rust_load_balancer::{
+  admin::{self, AdminApi},
+  certificate_expiry,
+  configuration::{issue_acme_certificates, read_initial_config, schema, watch_config, RuntimeConfig},
+  health, health_endpoint,
+  lifecycle::{LifecycleEvent, Signals},
+  listeners::{self, AcceptorProducer, Https},
+  logging,
+  process_stats::{self, ProcessStats},
+  server::{self, Scheme},
+  simulate_strategy, test_matcher,
+  tls::ReconfigurableCertificateResolver,
+};
 use arc_swap::{access::Map, ArcSwap};
-use clap::{App, Arg};
-use configuration::{read_initial_config, watch_config, RuntimeConfig};
-use listeners::{AcceptorProducer, Https};
-use server::Scheme;
-use std::{io, sync::Arc};
-use tls::ReconfigurableCertificateResolver;
-use tokio::try_join;
+use clap::{App, AppSettings, Arg};
+use log::{error, info};
+use std::{io, net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::{net::TcpListener, select, sync::Notify, try_join};
 use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
 
-mod acme;
-mod backend_pool_matcher;
-mod configuration;
-mod error_response;
-mod health;
-mod http_client;
-mod listeners;
-mod load_balancing;
-mod logging;
-mod middleware;
-mod server;
-mod tls;
-mod utils;
-
 #[tokio::main]
 pub async fn main() -> Result<(), io::Error> {
-  let matches = App::new("Another Rust Load Balancer")
+  let app = App::new("Another Rust Load Balancer")
     .version("1.0")
     .about("It's basically just another rust load balancer")
     .arg(
@@ -36,21 +34,114 @@ pub async fn main() -> Result<(), io::Error> {
         .required(true)
         .takes_value(true),
     )
-    .get_matches();
+    .arg(
+      Arg::with_name("config-dir")
+        .long("config-dir")
+        .value_name("DIRECTORY")
+        .help("The directory relative paths in the configuration are resolved against. Defaults to the directory of the configuration file.")
+        .takes_value(true),
+    )
+    .subcommand(test_matcher::subcommand())
+    .subcommand(simulate_strategy::subcommand())
+    .subcommand(schema::subcommand())
+    .setting(AppSettings::SubcommandsNegateReqs);
+  #[cfg(windows)]
+  let app = app.arg(
+    Arg::with_name("service")
+      .long("service")
+      .help("Runs as a Windows service. The process has to be started by the service control manager."),
+  );
+  let matches = app.get_matches();
+  if let Some(matches) = matches.subcommand_matches(test_matcher::NAME) {
+    return test_matcher::run(matches).await;
+  }
+  if let Some(matches) = matches.subcommand_matches(simulate_strategy::NAME) {
+    return simulate_strategy::run(matches).await;
+  }
+  if matches.subcommand_matches(schema::NAME).is_some() {
+    return schema::run();
+  }
   let config_path = matches.value_of("config").unwrap().to_string();
+  // the service control manager expects the service to connect right after starting it
+  #[cfg(windows)]
+  let windows_service = if matches.is_present("service") {
+    Some(service::start()?)
+  } else {
+    None
+  };
+
+  let log_handle = logging::initialize();
+  process_stats::start();
 
-  logging::initialize();
+  let config_dir = matches.value_of("config-dir").map(PathBuf::from);
+  let config = read_initial_config(&config_path, config_dir).await?;
+  let listeners = match bind_listeners(&config.load()).await {
+    Ok(listeners) => listeners,
+    Err(e) => {
+      error!("{}", e);
+      return Err(e);
+    }
+  };
+  info!(
+    "Started version {} with pid {}, listening for HTTP on {}, HTTPS on {} and the admin API on {}",
+    env!("CARGO_PKG_VERSION"),
+    std::process::id(),
+    describe_address(config.load().http_address),
+    describe_address(config.load().https_address),
+    describe_address(config.load().admin_address)
+  );
+  ProcessStats::collect().log("startup");
+  if config.load().health_interval.initial_check {
+    // the listeners are bound, but no request is accepted before the backend servers are checked
+    info!("Checking the health of all backend servers");
+    let config = config.load();
+    health::check_health_initially(&config.shared_data.backend_pools, &config.health_interval).await;
+  }
+  // the HTTP listener is bound, so the ACME challenges can be answered once the listeners are served below
+  tokio::spawn(issue_acme_certificates(config.clone()));
+  let reload_requests = Arc::new(Notify::new());
+  let run = async {
+    try_join!(
+      watch_config(config_path.clone(), config.clone(), reload_requests.clone()),
+      watch_health(config.clone()),
+      watch_certificate_expiry(config.clone()),
+      watch_process_stats(config.clone()),
+      listen_for_http_request(config.clone(), listeners.http),
+      listen_for_https_request(config.clone(), listeners.https),
+      serve_admin_api(config.clone(), config_path.clone().into(), listeners.admin),
+      serve_health_endpoint(config.clone(), listeners.health_endpoint)
+    )
+  };
+  let result = select! {
+    result = run => result.map(|_| ()),
+    result = handle_lifecycle_events(reload_requests.clone(), log_handle) => result,
+  };
+  #[cfg(windows)]
+  if let Some(windows_service) = windows_service {
+    windows_service.finish(&result);
+  }
+  result
+}
 
-  let config = read_initial_config(&config_path).await?;
-  try_join!(
-    watch_config(config_path, config.clone()),
-    watch_health(config.clone()),
-    listen_for_http_request(config.clone()),
-    listen_for_https_request(config.clone())
-  )?;
+/* Returns once the process should shut down */
+async fn handle_lifecycle_events(reload_requests: Arc<Notify>, log_handle: log4rs::Handle) -> Result<(), io::Error> {
+  let mut signals = Signals::new()?;
+  while let Some(event) = signals.recv().await {
+    match event {
+      LifecycleEvent::ReloadConfig => reload_requests.notify_one(),
+      LifecycleEvent::ReopenLogs => logging::reopen(&log_handle),
+      LifecycleEvent::Shutdown => break,
+    }
+  }
+  info!("Shutting down");
+  ProcessStats::collect().log("shutdown");
   Ok(())
 }
 
+fn describe_address(address: Option<SocketAddr>) -> String {
+  address.map_or_else(|| "nothing".to_string(), |it| it.to_string())
+}
+
 async fn watch_health(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
   let backend_pools = Map::new(config.clone(), |it: &RuntimeConfig| &it.shared_data.backend_pools);
   let health_interval = Map::new(config, |it: &RuntimeConfig| &it.health_interval);
@@ -58,23 +149,114 @@ async fn watch_health(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Err
   Ok(())
 }
 
-async fn listen_for_http_request(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
-  let http = listeners::Http;
-  let address = config.load().http_address;
-  let acceptor = http.produce_acceptor(address).await?;
+async fn watch_certificate_expiry(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
+  let certificates = Map::new(config.clone(), |it: &RuntimeConfig| &it.certificates);
+  let certificate_expiry = Map::new(config, |it: &RuntimeConfig| &it.certificate_expiry);
+  certificate_expiry::watch_certificate_expiry(certificates, certificate_expiry).await;
+  Ok(())
+}
+
+async fn watch_process_stats(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
+  process_stats::watch_process_stats(|| config.load().process_stats_interval).await;
+  Ok(())
+}
+
+struct Listeners {
+  http: Option<TcpListener>,
+  https: Option<TcpListener>,
+  admin: Option<TcpListener>,
+  health_endpoint: Option<TcpListener>,
+}
+
+/* Binds all listeners before serving any request, so a failing address is reported right away */
+async fn bind_listeners(config: &RuntimeConfig) -> Result<Listeners, io::Error> {
+  let http = match config.http_address {
+    Some(address) => Some(listeners::bind("HTTP", address, &config.bind_retry).await?),
+    None => None,
+  };
+  let https = match config.https_address {
+    Some(address) => Some(listeners::bind("HTTPS", address, &config.bind_retry).await?),
+    None => None,
+  };
+  let admin = match config.admin_address {
+    Some(address) => Some(listeners::bind("admin API", address, &config.bind_retry).await?),
+    None => None,
+  };
+  let health_endpoint = match config.health_endpoint.as_ref().map(|it| it.address) {
+    Some(address) => Some(listeners::bind("health endpoint", address, &config.bind_retry).await?),
+    None => None,
+  };
+  Ok(Listeners {
+    http,
+    https,
+    admin,
+    health_endpoint,
+  })
+}
+
+async fn serve_health_endpoint(
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  listener: Option<TcpListener>,
+) -> Result<(), io::Error> {
+  match listener {
+    Some(listener) => health_endpoint::serve(listener, config).await,
+    None => Ok(()),
+  }
+}
+
+async fn serve_admin_api(
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  config_path: PathBuf,
+  listener: Option<TcpListener>,
+) -> Result<(), io::Error> {
+  let listener = match listener {
+    Some(listener) => listener,
+    None => return Ok(()),
+  };
+  admin::serve(listener, AdminApi::new(config, config_path)).await
+}
+
+async fn listen_for_http_request(
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  listener: Option<TcpListener>,
+) -> Result<(), io::Error> {
+  let listener = match listener {
+    Some(listener) => listener,
+    None => {
+      info!("The HTTP listener is disabled");
+      return Ok(());
+    }
+  };
+  let http = listeners::Http {
+    connection_limit: config.load().connection_limit.clone(),
+  };
+  let acceptor = http.produce_acceptor(listener).await?;
 
   server::create(acceptor, config, Scheme::HTTP).await
 }
 
-async fn listen_for_https_request(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
+async fn listen_for_https_request(
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  listener: Option<TcpListener>,
+) -> Result<(), io::Error> {
+  let listener = match listener {
+    Some(listener) => listener,
+    None => {
+      info!("The HTTPS listener is disabled");
+      return Ok(());
+    }
+  };
   let mut tls_config = ServerConfig::new(NoClientAuth::new());
   let certificates = Map::new(config.clone(), |it: &RuntimeConfig| &it.certificates);
   let cert_resolver = ReconfigurableCertificateResolver::new(certificates);
   tls_config.cert_resolver = Arc::new(cert_resolver);
+  config.load().tls_sessions.apply(&mut tls_config)?;
 
-  let https = Https { tls_config };
-  let address = config.load().https_address;
-  let acceptor = https.produce_acceptor(address).await?;
+  let https = Https {
+    tls_config,
+    connection_limit: config.load().connection_limit.clone(),
+  };
+  let acceptor = https.produce_acceptor(listener).await?;
 
   server::create(acceptor, config, Scheme::HTTPS).await
 }
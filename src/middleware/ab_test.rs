@@ -0,0 +1,144 @@
+use super::{Context, Middleware, MiddlewareChain};
+use crate::utils::{append_vary, find_cookie};
+use async_trait::async_trait;
+use cookie::Cookie;
+use hyper::{
+  header::{HeaderName, HeaderValue, COOKIE, SET_COOKIE},
+  Body, Request, Response,
+};
+use rand::{thread_rng, Rng};
+use std::convert::TryFrom;
+use toml::{value::Table, Value};
+
+/// Assigns each new client a variant according to the configured weights and remembers it in a cookie. The variant
+/// is passed to the backend server in a request header.
+#[derive(Debug)]
+pub struct ABTest {
+  cookie_name: String,
+  header_name: HeaderName,
+  variants: Vec<(String, u64)>,
+}
+
+#[async_trait]
+impl Middleware for ABTest {
+  async fn forward_request(
+    &self,
+    mut request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
+    let existing_variant = find_cookie(request.headers(), &self.cookie_name)
+      .map(|cookie| cookie.value().to_string())
+      .filter(|variant| self.variants.iter().any(|(name, _)| name == variant));
+    let (variant, assigned) = match existing_variant {
+      Some(variant) => (variant, false),
+      None => (self.choose_variant().to_string(), true),
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&variant) {
+      request.headers_mut().insert(&self.header_name, value);
+    }
+    let mut response = chain.forward_request(request, context).await;
+    // the response depends on the variant, which is remembered in a cookie
+    append_vary(response.headers_mut(), &COOKIE);
+
+    if assigned {
+      let cookie = Cookie::build(self.cookie_name.as_str(), variant.as_str())
+        .path("/")
+        .finish();
+      if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+        response.headers_mut().append(SET_COOKIE, value);
+      }
+    }
+    response
+  }
+}
+
+impl ABTest {
+  fn choose_variant(&self) -> &str {
+    let total_weight = self.variants.iter().map(|(_, weight)| weight).sum();
+    let mut remaining = thread_rng().gen_range(0..total_weight);
+    for (name, weight) in &self.variants {
+      if remaining < *weight {
+        return name;
+      }
+      remaining -= weight;
+    }
+    // unreachable, because the random number is smaller than the total weight
+    &self.variants[self.variants.len() - 1].0
+  }
+}
+
+impl TryFrom<Table> for ABTest {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let cookie_name = t
+      .get("cookie_name")
+      .and_then(Value::as_str)
+      .unwrap_or("ab_variant")
+      .to_string();
+    let header_name = t.get("header_name").and_then(Value::as_str).unwrap_or("x-ab-variant");
+    let header_name = HeaderName::from_bytes(header_name.as_bytes()).map_err(|_| ())?;
+    let variants = t
+      .get("variants")
+      .and_then(Value::as_table)
+      .ok_or(())?
+      .iter()
+      .map(|(name, weight)| {
+        let weight = weight.as_integer().and_then(|it| u64::try_from(it).ok()).ok_or(())?;
+        Ok((name.clone(), weight))
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+
+    if variants.iter().map(|(_, weight)| weight).sum::<u64>() == 0 {
+      return Err(());
+    }
+    Ok(ABTest {
+      cookie_name,
+      header_name,
+      variants,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ab_test(toml: &str) -> Result<ABTest, ()> {
+    ABTest::try_from(toml::from_str::<Table>(toml).unwrap())
+  }
+
+  #[test]
+  fn test_try_from_defaults() {
+    // when:
+    let actual = ab_test("variants = { a = 50, b = 50 }").unwrap();
+
+    // then:
+    assert_eq!(actual.cookie_name, "ab_variant");
+    assert_eq!(actual.header_name, "x-ab-variant");
+    assert_eq!(actual.variants, vec![("a".to_string(), 50), ("b".to_string(), 50)]);
+  }
+
+  #[test]
+  fn test_try_from_without_weights() {
+    // when:
+    let actual = ab_test("variants = { a = 0 }");
+
+    // then:
+    assert!(actual.is_err());
+  }
+
+  #[test]
+  fn test_choose_variant_respects_weights() {
+    // given:
+    let ab_test = ab_test("variants = { a = 0, b = 1, c = 0 }").unwrap();
+
+    // when:
+    let actual = (0..10).map(|_| ab_test.choose_variant()).collect::<Vec<_>>();
+
+    // then:
+    assert!(actual.iter().all(|variant| *variant == "b"));
+  }
+}
@@ -0,0 +1,101 @@
+use super::{super::error_response, Context, Middleware};
+use async_trait::async_trait;
+use hyper::{
+  header::{HeaderValue, ALLOW},
+  Body, Method, Request, Response,
+};
+use std::{convert::TryFrom, str::FromStr};
+use toml::{value::Table, Value};
+
+/// Rejects all requests whose method is not contained in `methods` with `405 Method Not Allowed`.
+#[derive(Debug)]
+pub struct AllowedMethods {
+  methods: Vec<Method>,
+  allow: HeaderValue,
+}
+
+#[async_trait]
+impl Middleware for AllowedMethods {
+  async fn modify_request(
+    &self,
+    request: Request<Body>,
+    _context: &Context<'_>,
+  ) -> Result<Request<Body>, Response<Body>> {
+    match self.reject(request.method()) {
+      Some(response) => Err(response),
+      None => Ok(request),
+    }
+  }
+}
+
+impl AllowedMethods {
+  fn reject(&self, method: &Method) -> Option<Response<Body>> {
+    if self.methods.contains(method) {
+      return None;
+    }
+    let mut response = error_response::method_not_allowed();
+    response.headers_mut().insert(ALLOW, self.allow.clone());
+    Some(response)
+  }
+}
+
+impl TryFrom<Table> for AllowedMethods {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let methods = t
+      .get("methods")
+      .and_then(Value::as_array)
+      .ok_or(())?
+      .iter()
+      .map(|method| method.as_str().and_then(|it| Method::from_str(it).ok()).ok_or(()))
+      .collect::<Result<Vec<_>, _>>()?;
+    let allow = methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+    let allow = HeaderValue::from_str(&allow).map_err(|_| ())?;
+    Ok(AllowedMethods { methods, allow })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::StatusCode;
+
+  fn allowed_methods(toml: &str) -> AllowedMethods {
+    AllowedMethods::try_from(toml::from_str::<Table>(toml).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_allowed_method_passes() {
+    // given:
+    let middleware = allowed_methods("methods = ['GET', 'HEAD']");
+
+    // when:
+    let actual = middleware.reject(&Method::HEAD);
+
+    // then:
+    assert!(actual.is_none());
+  }
+
+  #[test]
+  fn test_disallowed_method_is_rejected() {
+    // given:
+    let middleware = allowed_methods("methods = ['GET', 'HEAD']");
+
+    // when:
+    let actual = middleware.reject(&Method::POST).unwrap();
+
+    // then:
+    assert_eq!(actual.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(actual.headers().get(ALLOW).unwrap(), "GET, HEAD");
+  }
+
+  #[test]
+  fn test_try_from_invalid_method() {
+    // when:
+    let actual = AllowedMethods::try_from(toml::from_str::<Table>("methods = ['GET', 'NOT A METHOD']").unwrap());
+
+    // then:
+    assert!(actual.is_err());
+  }
+}
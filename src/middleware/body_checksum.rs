@@ -0,0 +1,315 @@
+use super::{super::error_response, buffer_body, BufferedBody, Context, Middleware};
+use async_trait::async_trait;
+use hyper::{
+  header::{HeaderName, HeaderValue},
+  Body, HeaderMap, Request, Response,
+};
+use log::debug;
+use openssl::{
+  base64,
+  hash::{hash, MessageDigest},
+};
+use ring::hmac;
+use std::{convert::TryFrom, fs, path::Path, str::FromStr};
+use toml::value::Table;
+
+/// Verifies a checksum or signature header against the request body before forwarding the request, rejecting
+/// tampered or truncated uploads with `400 Bad Request`. The body is buffered for the verification, bodies larger
+/// than the buffer limit are rejected with `413 Payload Too Large`.
+#[derive(Debug)]
+pub struct BodyChecksum {
+  checksum: Checksum,
+  header: HeaderName,
+  /* Stripped from the header value before decoding it, e.g. `sha256=` */
+  prefix: String,
+  encoding: Encoding,
+  /* Whether requests without the header are rejected or forwarded unchecked */
+  required: bool,
+}
+
+#[derive(Debug)]
+enum Checksum {
+  /// The MD5 digest of the body, as sent in `Content-MD5`, see https://tools.ietf.org/html/rfc1864
+  Md5,
+  /// The HMAC of the body with a shared secret, as sent by webhooks
+  Hmac(hmac::Key),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+  Hex,
+  Base64,
+}
+
+impl TryFrom<(Table, &Path)> for BodyChecksum {
+  type Error = ();
+
+  fn try_from((t, config_dir): (Table, &Path)) -> Result<Self, Self::Error> {
+    let string = |key: &str| t.get(key).map(|it| it.as_str().ok_or(())).transpose();
+    let secret = match (string("secret")?, string("secret_file")?) {
+      (Some(secret), None) => Some(secret.to_string()),
+      (None, Some(secret_file)) => {
+        let secret = fs::read(config_dir.join(secret_file)).map_err(|_| ())?;
+        Some(String::from_utf8_lossy(&secret).trim().to_string())
+      }
+      (None, None) => None,
+      _ => return Err(()),
+    };
+    let checksum = match (string("algorithm")?, secret) {
+      (Some("md5"), None) => Checksum::Md5,
+      (Some(algorithm), Some(secret)) if !secret.is_empty() => {
+        let algorithm = match algorithm {
+          "hmac-sha1" => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+          "hmac-sha256" => hmac::HMAC_SHA256,
+          "hmac-sha512" => hmac::HMAC_SHA512,
+          _ => return Err(()),
+        };
+        Checksum::Hmac(hmac::Key::new(algorithm, secret.as_bytes()))
+      }
+      _ => return Err(()),
+    };
+    let header = match (string("header")?, &checksum) {
+      (Some(header), _) => HeaderName::from_str(header).map_err(|_| ())?,
+      (None, Checksum::Md5) => HeaderName::from_static("content-md5"),
+      (None, Checksum::Hmac(_)) => return Err(()),
+    };
+    let encoding = match (string("encoding")?, &checksum) {
+      (Some("hex"), _) | (None, Checksum::Hmac(_)) => Encoding::Hex,
+      (Some("base64"), _) | (None, Checksum::Md5) => Encoding::Base64,
+      (Some(_), _) => return Err(()),
+    };
+    let required = match t.get("required") {
+      Some(required) => required.as_bool().ok_or(())?,
+      None => true,
+    };
+    Ok(BodyChecksum {
+      checksum,
+      header,
+      prefix: string("prefix")?.unwrap_or_default().to_string(),
+      encoding,
+      required,
+    })
+  }
+}
+
+#[async_trait]
+impl Middleware for BodyChecksum {
+  async fn modify_request(
+    &self,
+    request: Request<Body>,
+    context: &Context<'_>,
+  ) -> Result<Request<Body>, Response<Body>> {
+    let expected = match self.expected_checksum(request.headers())? {
+      Some(expected) => expected,
+      None => return Ok(request),
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = match buffer_body(body, context.max_buffer_bytes).await {
+      Ok(BufferedBody::Complete(bytes)) => bytes,
+      Ok(BufferedBody::TooLarge(_)) => {
+        debug!(
+          "Rejecting request body larger than {} bytes, since its checksum can't be verified",
+          context.max_buffer_bytes
+        );
+        return Err(error_response::request_entity_to_large());
+      }
+      Err(e) => {
+        debug!("Could not read request body: {}", e);
+        return Err(error_response::bad_request("400 - could not read request body"));
+      }
+    };
+    if !self.checksum.matches(&body, &expected) {
+      debug!("Rejecting request body not matching its {} header", self.header);
+      return Err(error_response::bad_request("400 - invalid body checksum"));
+    }
+    Ok(Request::from_parts(parts, Body::from(body)))
+  }
+}
+
+impl BodyChecksum {
+  /// Returns the decoded checksum sent by the client, or `None` if the request may be forwarded without one.
+  fn expected_checksum(&self, headers: &HeaderMap) -> Result<Option<Vec<u8>>, Response<Body>> {
+    let mut values = headers.get_all(&self.header).iter();
+    match (values.next(), values.next()) {
+      (Some(value), None) => match self.decode(value) {
+        Some(expected) => Ok(Some(expected)),
+        None => {
+          debug!("Rejecting request with malformed {} header", self.header);
+          Err(error_response::bad_request("400 - malformed body checksum"))
+        }
+      },
+      (None, _) if !self.required => Ok(None),
+      _ => {
+        debug!("Rejecting request without a single {} header", self.header);
+        Err(error_response::bad_request("400 - missing body checksum"))
+      }
+    }
+  }
+
+  fn decode(&self, value: &HeaderValue) -> Option<Vec<u8>> {
+    let value = value.to_str().ok()?.trim().strip_prefix(self.prefix.as_str())?;
+    match self.encoding {
+      Encoding::Hex => decode_hex(value),
+      Encoding::Base64 => base64::decode_block(value).ok(),
+    }
+  }
+}
+
+impl Checksum {
+  fn matches(&self, body: &[u8], expected: &[u8]) -> bool {
+    match self {
+      Checksum::Md5 => hash(MessageDigest::md5(), body).map_or(false, |digest| &*digest == expected),
+      /* compares in constant time, so the signature can't be guessed byte by byte */
+      Checksum::Hmac(key) => hmac::verify(key, body, expected).is_ok(),
+    }
+  }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+  if hex.len() % 2 != 0 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+    return None;
+  }
+  (0..hex.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    http_client::StrategyNotifyHttpConnector,
+    load_balancing::{random::Random, LoadBalancingStrategy},
+    log_redaction::LogRedaction,
+    server::Scheme,
+  };
+  use hyper::{Client, StatusCode};
+  use std::sync::Arc;
+
+  const PAYLOAD: &str = r#"{"action":"opened"}"#;
+  const PAYLOAD_HMAC_SHA256: &str = "d42142b53efbc7cf5cd20b6e074eb33707e0de3b368f698e6d6f6c824ffb8d37";
+
+  fn body_checksum(toml: &str) -> BodyChecksum {
+    BodyChecksum::try_from((toml::from_str::<Table>(toml).unwrap(), Path::new("."))).unwrap()
+  }
+
+  async fn verify(
+    middleware: &BodyChecksum,
+    headers: &[(&str, &str)],
+    body: &'static str,
+  ) -> Result<String, StatusCode> {
+    let mut request = Request::post("/webhook");
+    for (name, value) in headers {
+      request = request.header(*name, *value);
+    }
+    let request = request.body(Body::from(body)).unwrap();
+    let strategy: Arc<Box<dyn LoadBalancingStrategy>> = Arc::new(Box::new(Random::new()));
+    let client = Client::builder().build(StrategyNotifyHttpConnector::new(strategy));
+    let client_address = "127.0.0.1:3000".parse().unwrap();
+    let context = Context {
+      client_scheme: &Scheme::HTTPS,
+      client_address: &client_address,
+      backend_uri: "http://127.0.0.1:8084/webhook".parse().unwrap(),
+      client: &client,
+      max_buffer_bytes: 64,
+      log_redaction: &LogRedaction::default(),
+      tls_fingerprint: None,
+      hedge: None,
+    };
+    match middleware.modify_request(request, &context).await {
+      Ok(request) => {
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        Ok(String::from_utf8(body.to_vec()).unwrap())
+      }
+      Err(response) => Err(response.status()),
+    }
+  }
+
+  #[tokio::test]
+  async fn verifies_content_md5() {
+    // given:
+    let middleware = body_checksum("algorithm = 'md5'");
+
+    // when:
+    let valid = verify(&middleware, &[("content-md5", "XUFAKrxLKna5cZ2REBfFkg==")], "hello").await;
+    let tampered = verify(&middleware, &[("content-md5", "XUFAKrxLKna5cZ2REBfFkg==")], "hellO").await;
+    let missing = verify(&middleware, &[], "hello").await;
+
+    // then:
+    assert_eq!(valid, Ok("hello".to_string()));
+    assert_eq!(tampered, Err(StatusCode::BAD_REQUEST));
+    assert_eq!(missing, Err(StatusCode::BAD_REQUEST));
+  }
+
+  #[tokio::test]
+  async fn verifies_hmac_signatures_with_prefix() {
+    // given:
+    let middleware =
+      body_checksum("algorithm = 'hmac-sha256'\nheader = 'x-hub-signature-256'\nprefix = 'sha256='\nsecret = 'secret'");
+    let signature = format!("sha256={}", PAYLOAD_HMAC_SHA256);
+
+    // when:
+    let valid = verify(&middleware, &[("x-hub-signature-256", signature.as_str())], PAYLOAD).await;
+    let tampered = verify(
+      &middleware,
+      &[("x-hub-signature-256", signature.as_str())],
+      r#"{"action":"closed"}"#,
+    )
+    .await;
+    let unprefixed = verify(&middleware, &[("x-hub-signature-256", PAYLOAD_HMAC_SHA256)], PAYLOAD).await;
+
+    // then:
+    assert_eq!(valid, Ok(PAYLOAD.to_string()));
+    assert_eq!(tampered, Err(StatusCode::BAD_REQUEST));
+    assert_eq!(unprefixed, Err(StatusCode::BAD_REQUEST));
+  }
+
+  #[tokio::test]
+  async fn forwards_requests_without_optional_checksum() {
+    // given:
+    let middleware = body_checksum("algorithm = 'md5'\nrequired = false");
+
+    // when:
+    let missing = verify(&middleware, &[], "hello").await;
+    let tampered = verify(&middleware, &[("content-md5", "XUFAKrxLKna5cZ2REBfFkg==")], "hellO").await;
+
+    // then:
+    assert_eq!(missing, Ok("hello".to_string()));
+    assert_eq!(tampered, Err(StatusCode::BAD_REQUEST));
+  }
+
+  #[tokio::test]
+  async fn rejects_bodies_exceeding_the_buffer() {
+    // given:
+    let middleware = body_checksum("algorithm = 'md5'");
+    let body = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef!";
+
+    // when:
+    let actual = verify(&middleware, &[("content-md5", "XUFAKrxLKna5cZ2REBfFkg==")], body).await;
+
+    // then:
+    assert_eq!(actual, Err(StatusCode::PAYLOAD_TOO_LARGE));
+  }
+
+  #[test]
+  fn rejects_invalid_configurations() {
+    let parse = |toml: &str| BodyChecksum::try_from((toml::from_str::<Table>(toml).unwrap(), Path::new(".")));
+
+    assert!(parse("").is_err());
+    assert!(parse("algorithm = 'md5'\nsecret = 'secret'").is_err());
+    assert!(parse("algorithm = 'hmac-sha256'\nheader = 'x-signature'").is_err());
+    assert!(parse("algorithm = 'hmac-sha256'\nsecret = 'secret'").is_err());
+    assert!(parse("algorithm = 'hmac-md5'\nheader = 'x-signature'\nsecret = 'secret'").is_err());
+    assert!(parse("algorithm = 'md5'\nencoding = 'base32'").is_err());
+    assert!(parse("algorithm = 'hmac-sha1'\nheader = 'x-signature'\nsecret = ''").is_err());
+  }
+
+  #[test]
+  fn decodes_hex() {
+    assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+    assert_eq!(decode_hex("0"), None);
+    assert_eq!(decode_hex("+f"), None);
+  }
+}
@@ -0,0 +1,219 @@
+use super::{Context, Middleware};
+use crate::{
+  error_response::{bad_request, handle_internal_server_error},
+  log_redaction::LogRedaction,
+  normalization::OriginalHost,
+  server::Scheme,
+};
+use async_trait::async_trait;
+use hyper::{
+  header::{HOST, LOCATION},
+  http::uri::Authority,
+  Body, Request, Response, StatusCode,
+};
+use log::debug;
+use std::{convert::TryFrom, net::IpAddr};
+use toml::value::Table;
+
+/// Redirects requests to a canonical variant of the requested host with `301 Moved Permanently`, keeping scheme,
+/// port, path and query.
+#[derive(Debug)]
+pub struct CanonicalHost {
+  www: Option<Www>,
+  lowercase: bool,
+}
+
+/// Whether the canonical host starts with `www.`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Www {
+  /// `example.org` is redirected to `www.example.org`
+  Add,
+  /// `www.example.org` is redirected to `example.org`
+  Remove,
+}
+
+const WWW: &str = "www.";
+
+impl TryFrom<Table> for CanonicalHost {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let www = match t.get("www").map(|it| it.as_str()) {
+      Some(Some("add")) => Some(Www::Add),
+      Some(Some("remove")) => Some(Www::Remove),
+      Some(_) => return Err(()),
+      None => None,
+    };
+    let lowercase = match t.get("lowercase") {
+      Some(lowercase) => lowercase.as_bool().ok_or(())?,
+      None => false,
+    };
+    if www.is_none() && !lowercase {
+      return Err(());
+    }
+    Ok(CanonicalHost { www, lowercase })
+  }
+}
+
+#[async_trait]
+impl Middleware for CanonicalHost {
+  async fn modify_request(
+    &self,
+    request: Request<Body>,
+    context: &Context<'_>,
+  ) -> Result<Request<Body>, Response<Body>> {
+    match self.redirect(&request, context.client_scheme, context.log_redaction)? {
+      Some(response) => Err(response),
+      None => Ok(request),
+    }
+  }
+}
+
+impl CanonicalHost {
+  /// Returns the redirect to the canonical host, or `None` if the `request` already targets it.
+  fn redirect(
+    &self,
+    request: &Request<Body>,
+    scheme: &Scheme,
+    log_redaction: &LogRedaction,
+  ) -> Result<Option<Response<Body>>, Response<Body>> {
+    let host = received_host(request)?;
+    let canonical = match self.canonical_host(&host) {
+      Some(canonical) => canonical,
+      None => return Ok(None),
+    };
+    let path_and_query = request.uri().path_and_query().map_or("/", |it| it.as_str());
+    let location = format!("{}://{}{}", scheme, canonical, path_and_query);
+    let response = Response::builder()
+      .status(StatusCode::MOVED_PERMANENTLY)
+      .header(LOCATION, location)
+      .body(Body::empty())
+      .map_err(handle_internal_server_error)?;
+
+    debug!(
+      "Redirecting {} to canonical host {}",
+      log_redaction.uri(request.uri()),
+      canonical
+    );
+    Ok(Some(response))
+  }
+
+  /// Returns the canonical variant of `authority`, or `None` if it is canonical already.
+  fn canonical_host(&self, authority: &Authority) -> Option<String> {
+    let mut host = authority.host().to_string();
+    if self.lowercase {
+      host = host.to_ascii_lowercase();
+    }
+    /* IP addresses have no www variant */
+    let is_ip = host.starts_with('[') || host.parse::<IpAddr>().is_ok();
+    let has_www = host.len() > WWW.len() && host[..WWW.len()].eq_ignore_ascii_case(WWW);
+    match self.www {
+      Some(Www::Add) if !is_ip && !has_www => host = format!("{}{}", WWW, host),
+      Some(Www::Remove) if has_www => host = host[WWW.len()..].to_string(),
+      _ => {}
+    }
+    let canonical = match authority.port() {
+      Some(port) => format!("{}:{}", host, port),
+      None => host,
+    };
+    if canonical == authority.as_str() {
+      None
+    } else {
+      Some(canonical)
+    }
+  }
+}
+
+/// The host as sent by the client, since the `Host` header has been lowercased already, see
+/// [`normalize_host`](crate::normalization::normalize_host).
+fn received_host(request: &Request<Body>) -> Result<Authority, Response<Body>> {
+  let host = match request.extensions().get::<OriginalHost>() {
+    Some(OriginalHost(host)) => host.as_str(),
+    None => request
+      .headers()
+      .get(HOST)
+      .ok_or_else(|| bad_request("missing host header"))?
+      .to_str()
+      .map_err(|error| bad_request(error.to_string()))?,
+  };
+  Authority::try_from(host).map_err(|_error| bad_request("invalid host header"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::normalization::normalize_host;
+
+  fn canonical_host(toml: &str) -> CanonicalHost {
+    CanonicalHost::try_from(toml::from_str::<Table>(toml).unwrap()).unwrap()
+  }
+
+  fn location(middleware: &CanonicalHost, scheme: &Scheme, uri: &str, host: &str) -> Option<String> {
+    let mut request = Request::get(uri).header(HOST, host).body(Body::empty()).unwrap();
+    normalize_host(&mut request).unwrap();
+    let response = middleware
+      .redirect(&request, scheme, &LogRedaction::default())
+      .unwrap()?;
+    assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+    Some(response.headers()[LOCATION].to_str().unwrap().to_string())
+  }
+
+  #[test]
+  fn removes_www_keeping_scheme_port_path_and_query() {
+    // given:
+    let middleware = canonical_host("www = 'remove'");
+
+    // when:
+    let https = location(&middleware, &Scheme::HTTPS, "/shop?page=2", "www.example.org");
+    let http = location(&middleware, &Scheme::HTTP, "/", "www.example.org:8080");
+    let canonical = location(&middleware, &Scheme::HTTPS, "/", "example.org");
+
+    // then:
+    assert_eq!(https.as_deref(), Some("https://example.org/shop?page=2"));
+    assert_eq!(http.as_deref(), Some("http://example.org:8080/"));
+    assert_eq!(canonical, None);
+  }
+
+  #[test]
+  fn adds_www_except_to_ip_addresses() {
+    // given:
+    let middleware = canonical_host("www = 'add'");
+
+    // when:
+    let added = location(&middleware, &Scheme::HTTPS, "/", "example.org");
+    let canonical = location(&middleware, &Scheme::HTTPS, "/", "www.example.org");
+    let ipv4 = location(&middleware, &Scheme::HTTP, "/", "127.0.0.1:8080");
+    let ipv6 = location(&middleware, &Scheme::HTTP, "/", "[::1]:8080");
+
+    // then:
+    assert_eq!(added.as_deref(), Some("https://www.example.org/"));
+    assert_eq!(canonical, None);
+    assert_eq!(ipv4, None);
+    assert_eq!(ipv6, None);
+  }
+
+  #[test]
+  fn lowercases_the_host_sent_by_the_client() {
+    // given:
+    let lowercase = canonical_host("lowercase = true");
+    let www_only = canonical_host("www = 'remove'");
+
+    // when:
+    let lowercased = location(&lowercase, &Scheme::HTTPS, "/Docs", "Example.ORG");
+    let www_removed = location(&www_only, &Scheme::HTTPS, "/", "WWW.Example.org");
+
+    // then:
+    assert_eq!(lowercased.as_deref(), Some("https://example.org/Docs"));
+    assert_eq!(www_removed.as_deref(), Some("https://Example.org/"));
+  }
+
+  #[test]
+  fn rejects_invalid_configurations() {
+    let parse = |toml: &str| CanonicalHost::try_from(toml::from_str::<Table>(toml).unwrap());
+
+    assert!(parse("").is_err());
+    assert!(parse("lowercase = false").is_err());
+    assert!(parse("www = 'keep'").is_err());
+    assert!(parse("lowercase = 'yes'").is_err());
+  }
+}
@@ -1,14 +1,16 @@
-use crate::utils::split_once;
+use crate::utils::{append_vary, split_once};
 
-use super::{Context, Middleware, MiddlewareChain};
+use super::{may_have_body, Context, Middleware, MiddlewareChain};
 use async_compression::tokio::bufread::{BrotliEncoder, DeflateEncoder, GzipEncoder};
 use async_trait::async_trait;
 use futures::TryStreamExt;
 use hyper::{
-  header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH},
-  Body, HeaderMap, Request, Response,
+  body::HttpBody,
+  header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+  Body, HeaderMap, Method, Request, Response,
 };
 use std::{
+  convert::{TryFrom, TryInto},
   fmt::Display,
   io::{self, ErrorKind},
 };
@@ -17,34 +19,147 @@ use tokio_util::{
   codec::{BytesCodec, FramedRead},
   io::StreamReader,
 };
+use toml::value::Table;
 use Encoding::{BROTLI, DEFLATE, GZIP};
 
+/* Responses with a smaller `Content-Length` are not compressed by default */
+pub const DEFAULT_MIN_BYTES: u64 = 1024;
+/* Media types which are streamed or already compressed */
+pub const DEFAULT_SKIP_CONTENT_TYPES: &[&str] = &[
+  "text/event-stream",
+  "application/grpc",
+  "application/gzip",
+  "application/zip",
+  "application/x-gzip",
+  "image/avif",
+  "image/gif",
+  "image/jpeg",
+  "image/png",
+  "image/webp",
+  "audio/",
+  "video/",
+  "font/woff",
+];
+
+/// Compresses responses with the encoding preferred by the client. Responses smaller than `min_bytes` aren't worth the
+/// overhead. Responses with one of the `skip_content_types` are passed through unchanged, since they are compressed
+/// already or must be delivered in real time, like Server-Sent Events, which the encoder would hold back.
 #[derive(Debug)]
-pub struct Compression;
+pub struct Compression {
+  min_bytes: u64,
+  /* Prefixes of the media types */
+  skip_content_types: Vec<String>,
+  accept_encoding: AcceptEncoding,
+}
+
+/// What the backend server gets to see of the `Accept-Encoding` header of the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AcceptEncoding {
+  /// The backend server may compress responses itself, which are passed through instead of being compressed again.
+  Forward,
+  /// The backend server is asked for uncompressed responses, so compression is controlled by the load balancer alone.
+  Strip,
+}
+
+impl Default for Compression {
+  fn default() -> Self {
+    Compression {
+      min_bytes: DEFAULT_MIN_BYTES,
+      skip_content_types: DEFAULT_SKIP_CONTENT_TYPES.iter().map(|it| it.to_string()).collect(),
+      accept_encoding: AcceptEncoding::Forward,
+    }
+  }
+}
+
+impl TryFrom<Table> for Compression {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let defaults = Compression::default();
+    let min_bytes = match t.get("min_bytes") {
+      Some(min_bytes) => min_bytes.as_integer().and_then(|it| it.try_into().ok()).ok_or(())?,
+      None => defaults.min_bytes,
+    };
+    let mut skip_content_types = defaults.skip_content_types;
+    if let Some(content_types) = t.get("skip_content_types") {
+      for content_type in content_types.as_array().ok_or(())? {
+        skip_content_types.push(content_type.as_str().ok_or(())?.to_ascii_lowercase());
+      }
+    }
+    let accept_encoding = match t.get("accept_encoding").map(|it| it.as_str()) {
+      Some(Some("forward")) => AcceptEncoding::Forward,
+      Some(Some("strip")) => AcceptEncoding::Strip,
+      Some(_) => return Err(()),
+      None => defaults.accept_encoding,
+    };
+    Ok(Compression {
+      min_bytes,
+      skip_content_types,
+      accept_encoding,
+    })
+  }
+}
 
 #[async_trait]
 impl Middleware for Compression {
   async fn forward_request(
     &self,
-    request: Request<Body>,
+    mut request: Request<Body>,
     chain: &MiddlewareChain,
     context: &Context<'_>,
   ) -> Response<Body> {
-    let encoding = get_preferred_encoding(request.headers());
+    let encoding = self.negotiate_encoding(request.headers_mut());
+    let method = request.method().clone();
     let response = chain.forward_request(request, context).await;
-    if let Some(encoding) = encoding.filter(|_| !response.headers().contains_key(CONTENT_ENCODING)) {
-      self.compress_response(response, &encoding)
-    } else {
-      response
-    }
+    self.encode_response(&method, encoding, response)
+  }
+
+  /* the encoder emits compressed data in blocks, not per chunk of the backend server */
+  fn buffers_response(&self) -> bool {
+    true
   }
 }
 
 impl Compression {
+  /// Returns the encoding preferred by the client and removes the `Accept-Encoding` header of the request, if the
+  /// backend server must not compress the response.
+  fn negotiate_encoding(&self, headers: &mut HeaderMap) -> Option<Encoding> {
+    let encoding = get_preferred_encoding(headers);
+    if self.accept_encoding == AcceptEncoding::Strip {
+      headers.remove(ACCEPT_ENCODING);
+    }
+    encoding
+  }
+
+  /// Compresses the response, if it should be compressed and the client accepts one of the encodings. Caches are told
+  /// that such responses depend on the `Accept-Encoding` header, whether they were compressed or not, so they don't
+  /// serve a compressed response to clients which didn't ask for it or vice versa. The same applies to all responses
+  /// if the header is forwarded, since the backend server may have chosen an encoding itself.
+  fn encode_response(
+    &self,
+    method: &Method,
+    encoding: Option<Encoding>,
+    mut response: Response<Body>,
+  ) -> Response<Body> {
+    let compress = self.should_compress(method, &response);
+    if compress || self.accept_encoding == AcceptEncoding::Forward {
+      append_vary(response.headers_mut(), &ACCEPT_ENCODING);
+    }
+    if !compress {
+      return response;
+    }
+    match encoding {
+      Some(encoding) => self.compress_response(response, &encoding),
+      None => response,
+    }
+  }
+
   fn compress_response(&self, response: Response<Body>, encoding: &Encoding) -> Response<Body> {
     let (parts, body) = response.into_parts();
 
-    let stream = StreamReader::new(body.map_err(|error| io::Error::new(ErrorKind::Other, error)));
+    let stream = StreamReader::new(TryStreamExt::map_err(body, |error| {
+      io::Error::new(ErrorKind::Other, error)
+    }));
 
     let body = match encoding {
       BROTLI => to_body(BrotliEncoder::new(stream)),
@@ -66,6 +181,36 @@ impl Compression {
   }
 }
 
+impl Compression {
+  /// Returns `true` unless the response is already encoded, has no body to compress or is skipped by its length or
+  /// content type. Compressing an empty body would still produce the header of the encoding, which must not be sent
+  /// e.g. for `204 No Content` responses. Responses to `HEAD` requests keep their `Content-Length`, since there is no
+  /// body to determine the compressed length from.
+  fn should_compress(&self, method: &Method, response: &Response<Body>) -> bool {
+    let headers = response.headers();
+    let too_small = content_length(headers).map_or(false, |length| length < self.min_bytes);
+    let skipped_content_type = headers
+      .get(CONTENT_TYPE)
+      .and_then(|it| it.to_str().ok())
+      .map(|it| it.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+      .map_or(false, |media_type| {
+        self
+          .skip_content_types
+          .iter()
+          .any(|it| media_type.starts_with(it.as_str()))
+      });
+    may_have_body(method, response.status())
+      && !response.body().is_end_stream()
+      && !headers.contains_key(CONTENT_ENCODING)
+      && !too_small
+      && !skipped_content_type
+  }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+  headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
 #[derive(Debug, PartialEq)]
 enum Encoding {
   BROTLI,
@@ -147,6 +292,146 @@ fn parse_qvalue(qvalue: &str) -> Option<u32> {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use hyper::{header::VARY, StatusCode};
+
+  fn response(status: StatusCode, content_type: &str, body: &'static str) -> Response<Body> {
+    Response::builder()
+      .status(status)
+      .header(CONTENT_TYPE, content_type)
+      .header(CONTENT_LENGTH, body.len())
+      .body(Body::from(body))
+      .unwrap()
+  }
+
+  #[test]
+  fn test_should_compress_only_responses_with_body() {
+    let compression = Compression {
+      min_bytes: 0,
+      ..Compression::default()
+    };
+    let text = |status: StatusCode, body: &'static str| response(status, "text/plain", body);
+
+    assert!(compression.should_compress(&Method::GET, &text(StatusCode::OK, "hello")));
+    assert!(!compression.should_compress(&Method::HEAD, &text(StatusCode::OK, "hello")));
+    assert!(!compression.should_compress(&Method::GET, &text(StatusCode::NO_CONTENT, "hello")));
+    assert!(!compression.should_compress(&Method::GET, &text(StatusCode::NOT_MODIFIED, "hello")));
+    assert!(!compression.should_compress(&Method::GET, &text(StatusCode::OK, "")));
+  }
+
+  #[test]
+  fn test_should_compress_skips_small_responses_and_content_types() {
+    let compression = Compression::try_from(toml::from_str::<Table>("min_bytes = 10").unwrap()).unwrap();
+    let body = "hello world";
+
+    assert!(compression.should_compress(&Method::GET, &response(StatusCode::OK, "text/html", body)));
+    assert!(!compression.should_compress(&Method::GET, &response(StatusCode::OK, "text/html", "hello")));
+    assert!(!compression.should_compress(
+      &Method::GET,
+      &response(StatusCode::OK, "Text/Event-Stream; charset=utf-8", body)
+    ));
+    assert!(!compression.should_compress(&Method::GET, &response(StatusCode::OK, "application/grpc+proto", body)));
+    assert!(!compression.should_compress(&Method::GET, &response(StatusCode::OK, "image/png", body)));
+    assert!(compression.should_compress(&Method::GET, &response(StatusCode::OK, "image/svg+xml", body)));
+  }
+
+  #[test]
+  fn test_should_compress_streams_without_content_length() {
+    let toml = r#"
+      min_bytes = 0
+      skip_content_types = ["application/json"]
+    "#;
+    let compression = Compression::try_from(toml::from_str::<Table>(toml).unwrap()).unwrap();
+    let (sender, body) = Body::channel();
+    let streamed = Response::builder()
+      .header(CONTENT_TYPE, "text/html")
+      .body(body)
+      .unwrap();
+
+    assert!(compression.should_compress(&Method::GET, &streamed));
+    assert!(!compression.should_compress(&Method::GET, &response(StatusCode::OK, "application/json", "{}")));
+    drop(sender);
+  }
+
+  #[test]
+  fn test_skip_content_types_extend_the_defaults() {
+    let toml = r#"
+      min_bytes = 0
+      skip_content_types = ["Application/PDF"]
+    "#;
+    let compression = Compression::try_from(toml::from_str::<Table>(toml).unwrap()).unwrap();
+
+    assert!(!compression.should_compress(&Method::GET, &response(StatusCode::OK, "application/pdf", "%PDF")));
+    assert!(!compression.should_compress(&Method::GET, &response(StatusCode::OK, "image/png", "png")));
+    assert!(compression.should_compress(&Method::GET, &response(StatusCode::OK, "text/html", "hello")));
+  }
+
+  #[test]
+  fn test_encode_response_varies_by_accept_encoding() {
+    let compression = Compression {
+      min_bytes: 0,
+      ..Compression::default()
+    };
+    let vary = |response: &Response<Body>| response.headers().get_all(VARY).iter().cloned().collect::<Vec<_>>();
+
+    let compressed =
+      compression.encode_response(&Method::GET, Some(GZIP), response(StatusCode::OK, "text/html", "hello"));
+    assert_eq!(compressed.headers()[CONTENT_ENCODING], "gzip");
+    assert_eq!(vary(&compressed), vec!["accept-encoding"]);
+
+    let mut uncompressed = response(StatusCode::OK, "text/html", "hello");
+    uncompressed
+      .headers_mut()
+      .insert(VARY, HeaderValue::from_static("Origin"));
+    let uncompressed = compression.encode_response(&Method::GET, None, uncompressed);
+    assert!(uncompressed.headers().get(CONTENT_ENCODING).is_none());
+    assert_eq!(vary(&uncompressed), vec!["Origin", "accept-encoding"]);
+
+    let skipped = compression.encode_response(&Method::GET, Some(GZIP), response(StatusCode::OK, "image/png", "hello"));
+    assert_eq!(vary(&skipped), vec!["accept-encoding"]);
+
+    let stripped = Compression {
+      accept_encoding: AcceptEncoding::Strip,
+      ..compression
+    };
+    let skipped = stripped.encode_response(&Method::GET, Some(GZIP), response(StatusCode::OK, "image/png", "hello"));
+    assert!(vary(&skipped).is_empty());
+  }
+
+  #[test]
+  fn test_encode_response_varies_by_accept_encoding_for_responses_of_the_backend_server() {
+    let compression = Compression::default();
+    let mut encoded = response(StatusCode::OK, "text/html", "hello");
+    encoded
+      .headers_mut()
+      .insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+
+    let encoded = compression.encode_response(&Method::GET, Some(GZIP), encoded);
+
+    assert_eq!(encoded.headers()[CONTENT_ENCODING], "br");
+    assert_eq!(encoded.headers()[VARY], "accept-encoding");
+  }
+
+  #[test]
+  fn test_negotiate_encoding_forwards_or_strips_accept_encoding() {
+    let accept_encoding = |toml: &str| {
+      let compression = Compression::try_from(toml::from_str::<Table>(toml).unwrap()).unwrap();
+      let mut headers = HeaderMap::new();
+      headers.insert(ACCEPT_ENCODING, "gzip, br;q=0.5".parse().unwrap());
+      let encoding = compression.negotiate_encoding(&mut headers);
+      (encoding, headers.get(ACCEPT_ENCODING).cloned())
+    };
+
+    assert_eq!(
+      accept_encoding(""),
+      (Some(GZIP), Some("gzip, br;q=0.5".parse().unwrap()))
+    );
+    assert_eq!(
+      accept_encoding(r#"accept_encoding = "forward""#),
+      (Some(GZIP), Some("gzip, br;q=0.5".parse().unwrap()))
+    );
+    assert_eq!(accept_encoding(r#"accept_encoding = "strip""#), (Some(GZIP), None));
+    assert!(Compression::try_from(toml::from_str::<Table>(r#"accept_encoding = "drop""#).unwrap()).is_err());
+  }
 
   #[test]
   fn test_get_preferred_encoding_no_headers() {
@@ -0,0 +1,262 @@
+use super::{buffer_body, BufferedBody, Context, Middleware, MiddlewareChain};
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+use hyper::{
+  header::{
+    HeaderValue, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_LOCATION, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, LAST_MODIFIED, SET_COOKIE, VARY,
+  },
+  Body, HeaderMap, Method, Request, Response, StatusCode,
+};
+use log::error;
+use ring::digest::{digest, SHA256};
+use std::convert::TryFrom;
+use toml::value::Table;
+
+/// Answers conditional `GET` and `HEAD` requests (`If-None-Match` and `If-Modified-Since`) with `304 Not Modified`
+/// if the response of the backend server did not change, so the body is not sent to the client again. Responses
+/// without an `ETag` get one computed from their body, unless `compute_etags` is disabled or the body is larger than
+/// the buffer limit of the pool.
+#[derive(Debug)]
+pub struct ConditionalRequests {
+  compute_etags: bool,
+}
+
+#[async_trait]
+impl Middleware for ConditionalRequests {
+  async fn forward_request(
+    &self,
+    request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
+    let method = request.method().clone();
+    let if_none_match = request.headers().get(IF_NONE_MATCH).cloned();
+    let if_modified_since = request.headers().get(IF_MODIFIED_SINCE).cloned();
+    let response = chain.forward_request(request, context).await;
+    if !matches!(method, Method::GET | Method::HEAD) || response.status() != StatusCode::OK {
+      return response;
+    }
+
+    // HEAD responses have no body to compute the ETag from
+    let response = if self.compute_etags && method == Method::GET && !response.headers().contains_key(ETAG) {
+      with_etag(response, context.max_buffer_bytes).await
+    } else {
+      response
+    };
+
+    if is_not_modified(response.headers(), if_none_match.as_ref(), if_modified_since.as_ref()) {
+      not_modified(response.headers())
+    } else {
+      response
+    }
+  }
+
+  fn buffers_response(&self) -> bool {
+    self.compute_etags
+  }
+}
+
+/// Adds a strong `ETag` derived from the body, if the body fits into the buffer.
+async fn with_etag(response: Response<Body>, max_buffer_bytes: usize) -> Response<Body> {
+  let exceeds = response
+    .headers()
+    .get(CONTENT_LENGTH)
+    .and_then(|it| it.to_str().ok()?.parse::<usize>().ok())
+    .map_or(false, |length| length > max_buffer_bytes);
+  if exceeds {
+    return response;
+  }
+
+  let (mut parts, body) = response.into_parts();
+  let bytes = match buffer_body(body, max_buffer_bytes).await {
+    Ok(BufferedBody::Complete(bytes)) => bytes,
+    Ok(BufferedBody::TooLarge(body)) => return Response::from_parts(parts, body),
+    Err(e) => {
+      error!("Could not read response body to compute the ETag: {}", e);
+      return Response::from_parts(parts, Body::empty());
+    }
+  };
+  parts.headers.insert(ETAG, etag(&bytes));
+  Response::from_parts(parts, Body::from(bytes))
+}
+
+/// Hashes the body, so all instances of the load balancer compute the same `ETag`.
+fn etag(body: &[u8]) -> HeaderValue {
+  let hash = digest(&SHA256, body);
+  let hex = hash.as_ref()[..16]
+    .iter()
+    .map(|byte| format!("{:02x}", byte))
+    .collect::<String>();
+  HeaderValue::from_str(&format!("\"{}\"", hex)).unwrap()
+}
+
+/// Evaluates the preconditions of the request against the response headers. `If-Modified-Since` is only evaluated
+/// without `If-None-Match`, see https://tools.ietf.org/html/rfc7232#section-6
+fn is_not_modified(
+  headers: &HeaderMap,
+  if_none_match: Option<&HeaderValue>,
+  if_modified_since: Option<&HeaderValue>,
+) -> bool {
+  if let Some(if_none_match) = if_none_match {
+    return match (headers.get(ETAG), if_none_match.to_str()) {
+      (Some(etag), Ok(if_none_match)) => etag_matches(etag, if_none_match),
+      _ => false,
+    };
+  }
+  match (
+    headers.get(LAST_MODIFIED).and_then(parse_http_date),
+    if_modified_since.and_then(parse_http_date),
+  ) {
+    (Some(last_modified), Some(since)) => last_modified <= since,
+    _ => false,
+  }
+}
+
+/// Compares the `etag` with the list of `If-None-Match` using the weak comparison.
+fn etag_matches(etag: &HeaderValue, if_none_match: &str) -> bool {
+  let etag = match etag.to_str() {
+    Ok(etag) => opaque_tag(etag.trim()),
+    Err(_) => return false,
+  };
+  if_none_match
+    .split(',')
+    .map(str::trim)
+    .any(|tag| tag == "*" || opaque_tag(tag) == etag)
+}
+
+fn opaque_tag(etag: &str) -> &str {
+  etag.strip_prefix("W/").unwrap_or(etag)
+}
+
+fn parse_http_date(value: &HeaderValue) -> Option<DateTime<FixedOffset>> {
+  DateTime::parse_from_rfc2822(value.to_str().ok()?).ok()
+}
+
+/// Keeps the headers listed in https://tools.ietf.org/html/rfc7232#section-4.1 of the replaced response.
+fn not_modified(headers: &HeaderMap) -> Response<Body> {
+  let names = [
+    CACHE_CONTROL,
+    CONTENT_LOCATION,
+    DATE,
+    ETAG,
+    EXPIRES,
+    LAST_MODIFIED,
+    SET_COOKIE,
+    VARY,
+  ];
+  let mut response = Response::builder()
+    .status(StatusCode::NOT_MODIFIED)
+    .body(Body::empty())
+    .unwrap();
+  for name in names.iter() {
+    for value in headers.get_all(name) {
+      response.headers_mut().append(name, value.clone());
+    }
+  }
+  response
+}
+
+impl TryFrom<Table> for ConditionalRequests {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let compute_etags = match t.get("compute_etags") {
+      Some(compute_etags) => compute_etags.as_bool().ok_or(())?,
+      None => true,
+    };
+    Ok(ConditionalRequests { compute_etags })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::{body, header::HeaderName};
+
+  fn headers(name: HeaderName, value: &'static str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(name, HeaderValue::from_static(value));
+    headers
+  }
+
+  #[test]
+  fn etag_matches_list_and_weak_tags() {
+    let etag = HeaderValue::from_static("\"abc\"");
+
+    assert!(etag_matches(&etag, "\"abc\""));
+    assert!(etag_matches(&etag, "\"xyz\", W/\"abc\""));
+    assert!(etag_matches(&etag, "*"));
+    assert!(!etag_matches(&etag, "\"xyz\""));
+  }
+
+  #[test]
+  fn is_not_modified_since_last_modified() {
+    // given:
+    let headers = headers(LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT");
+
+    // when:
+    let same = is_not_modified(
+      &headers,
+      None,
+      Some(&HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT")),
+    );
+    let earlier = is_not_modified(
+      &headers,
+      None,
+      Some(&HeaderValue::from_static("Tue, 20 Oct 2015 07:28:00 GMT")),
+    );
+
+    // then:
+    assert!(same);
+    assert!(!earlier);
+  }
+
+  #[test]
+  fn if_none_match_takes_precedence_over_if_modified_since() {
+    // given:
+    let mut headers = headers(LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT");
+    headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+
+    // when:
+    let actual = is_not_modified(
+      &headers,
+      Some(&HeaderValue::from_static("\"xyz\"")),
+      Some(&HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT")),
+    );
+
+    // then:
+    assert!(!actual);
+  }
+
+  #[tokio::test]
+  async fn with_etag_hashes_body() {
+    // given:
+    let response = Response::new(Body::from("hello"));
+
+    // when:
+    let actual = with_etag(response, 1024).await;
+
+    // then:
+    assert_eq!(actual.headers()[ETAG], "\"2cf24dba5fb0a30e26e83b2ac5b9e29e\"");
+    let body = body::to_bytes(actual.into_body()).await.unwrap();
+    assert_eq!(body, "hello");
+  }
+
+  #[test]
+  fn not_modified_keeps_caching_headers() {
+    // given:
+    let mut headers = headers(ETAG, "\"abc\"");
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from_static("5"));
+
+    // when:
+    let actual = not_modified(&headers);
+
+    // then:
+    assert_eq!(actual.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(actual.headers()[ETAG], "\"abc\"");
+    assert_eq!(actual.headers()[CACHE_CONTROL], "max-age=60");
+    assert!(actual.headers().get(CONTENT_LENGTH).is_none());
+  }
+}
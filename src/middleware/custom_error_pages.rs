@@ -1,16 +1,18 @@
 use super::{Context, Middleware};
+use crate::error_response::{replace_body, BuiltInErrorResponse, TEXT_HTML, TEXT_PLAIN};
 use async_trait::async_trait;
-use hyper::{
-  header::{CONTENT_ENCODING, CONTENT_LENGTH},
-  Body, Response,
-};
+use hyper::{header::HeaderValue, Body, Response};
 use log::error;
-use std::{convert::TryFrom, fs, path::Path};
+use std::{
+  convert::TryFrom,
+  fs,
+  path::{Path, PathBuf},
+};
 use toml::value::Table;
 
 #[derive(Debug)]
 pub struct CustomErrorPages {
-  location: String,
+  location: PathBuf,
   errors: Vec<u16>,
 }
 
@@ -29,7 +31,7 @@ impl TryFrom<Table> for CustomErrorPages {
   type Error = ();
 
   fn try_from(t: Table) -> Result<Self, Self::Error> {
-    let location = t.get("location").ok_or(())?.as_str().ok_or(())?.to_string();
+    let location = PathBuf::from(t.get("location").ok_or(())?.as_str().ok_or(())?);
     let errors = t
       .get("errors")
       .ok_or(())?
@@ -47,24 +49,31 @@ impl TryFrom<Table> for CustomErrorPages {
 }
 
 impl CustomErrorPages {
+  /// Resolves a relative `location` against the `config_dir`.
+  pub fn relative_to(self, config_dir: &Path) -> CustomErrorPages {
+    CustomErrorPages {
+      location: config_dir.join(self.location),
+      ..self
+    }
+  }
+
   fn replace_response(&self, response: Response<Body>) -> Response<Body> {
-    let filepath = Path::new(&self.location)
-      .join(response.status().as_str())
-      .with_extension("html");
-    let custom_body = fs::read_to_string(filepath);
+    let filepath = self.location.join(response.status().as_str()).with_extension("html");
+    let custom_body = fs::read(filepath);
     let canocial_body = format!(
       "{} - {}\n",
       response.status().as_str(),
       response.status().canonical_reason().unwrap_or("")
     );
     let (mut parts, _) = response.into_parts();
-    parts.headers.remove(CONTENT_LENGTH);
-    parts.headers.remove(CONTENT_ENCODING);
+    // the page of the backend pool takes precedence over the global error pages
+    parts.extensions.remove::<BuiltInErrorResponse>();
+    // the content type of the backend server's response doesn't apply to the page
     match custom_body {
-      Ok(payload) => Response::from_parts(parts, Body::from(payload)),
+      Ok(payload) => replace_body(parts, HeaderValue::from_static(TEXT_HTML), payload),
       Err(_) => {
         error!("Custom error page for error {} not found!", parts.status.as_str());
-        Response::from_parts(parts, Body::from(canocial_body))
+        replace_body(parts, HeaderValue::from_static(TEXT_PLAIN), canocial_body)
       }
     }
   }
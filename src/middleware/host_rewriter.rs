@@ -0,0 +1,238 @@
+use super::{Context, Middleware, MiddlewareChain};
+use crate::server::Scheme;
+use async_trait::async_trait;
+use hyper::{
+  header::{HeaderValue, HOST, LOCATION, SET_COOKIE},
+  http::uri::{Authority, Uri},
+  Body, HeaderMap, Request, Response,
+};
+use std::convert::TryFrom;
+use toml::value::Table;
+
+/// Rewrites `Location` headers and cookie `Domain` attributes of responses which point at the backend server (or one
+/// of the `internal_hosts`) to the host requested by the client, so internal addresses don't leak to clients.
+#[derive(Debug)]
+pub struct HostRewriter {
+  internal_hosts: Vec<String>,
+}
+
+#[async_trait]
+impl Middleware for HostRewriter {
+  async fn forward_request(
+    &self,
+    request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
+    let external_host = request.headers().get(HOST).cloned();
+    let mut response = chain.forward_request(request, context).await;
+    if let Some(external_host) = external_host.as_ref().and_then(|it| it.to_str().ok()) {
+      self.rewrite(response.headers_mut(), external_host, context);
+    }
+    response
+  }
+}
+
+impl HostRewriter {
+  fn rewrite(&self, headers: &mut HeaderMap, external_host: &str, context: &Context) {
+    let backend_authority = context.backend_uri.authority();
+    let is_internal = |authority: &Authority| {
+      backend_authority.map_or(false, |backend| backend.host().eq_ignore_ascii_case(authority.host()))
+        || self
+          .internal_hosts
+          .iter()
+          .any(|host| host.eq_ignore_ascii_case(authority.as_str()) || host.eq_ignore_ascii_case(authority.host()))
+    };
+
+    let location = headers
+      .get(LOCATION)
+      .and_then(|it| rewrite_location(it, external_host, context.client_scheme, &is_internal));
+    if let Some(location) = location {
+      headers.insert(LOCATION, location);
+    }
+
+    let external_domain =
+      Authority::try_from(external_host).map_or_else(|_| external_host.to_string(), |it| it.host().to_string());
+    let cookies = headers
+      .get_all(SET_COOKIE)
+      .iter()
+      .map(|cookie| rewrite_cookie_domain(cookie, &external_domain, &is_internal).unwrap_or_else(|| cookie.clone()))
+      .collect::<Vec<_>>();
+    if cookies.is_empty() {
+      return;
+    }
+    headers.remove(SET_COOKIE);
+    for cookie in cookies {
+      headers.append(SET_COOKIE, cookie);
+    }
+  }
+}
+
+fn rewrite_location<F>(
+  location: &HeaderValue,
+  external_host: &str,
+  scheme: &Scheme,
+  is_internal: F,
+) -> Option<HeaderValue>
+where
+  F: Fn(&Authority) -> bool,
+{
+  let uri = location.to_str().ok()?.parse::<Uri>().ok()?;
+  if !uri.authority().map_or(false, is_internal) {
+    return None;
+  }
+  let path_and_query = uri.path_and_query().map_or("/", |it| it.as_str());
+  HeaderValue::from_str(&format!("{}://{}{}", scheme, external_host, path_and_query)).ok()
+}
+
+fn rewrite_cookie_domain<F>(cookie: &HeaderValue, external_domain: &str, is_internal: F) -> Option<HeaderValue>
+where
+  F: Fn(&Authority) -> bool,
+{
+  let cookie = cookie.to_str().ok()?;
+  let mut rewritten = false;
+  let attributes = cookie
+    .split(';')
+    .map(|attribute| match attribute.split_once('=') {
+      Some((name, domain)) if name.trim().eq_ignore_ascii_case("domain") => {
+        let is_internal_domain = Authority::try_from(domain.trim().trim_start_matches('.'))
+          .map(|authority| is_internal(&authority))
+          .unwrap_or(false);
+        if is_internal_domain {
+          rewritten = true;
+          format!(" Domain={}", external_domain)
+        } else {
+          attribute.to_string()
+        }
+      }
+      _ => attribute.to_string(),
+    })
+    .collect::<Vec<_>>();
+  if rewritten {
+    HeaderValue::from_str(&attributes.join(";")).ok()
+  } else {
+    None
+  }
+}
+
+impl TryFrom<Table> for HostRewriter {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let internal_hosts = match t.get("internal_hosts") {
+      Some(hosts) => hosts
+        .as_array()
+        .ok_or(())?
+        .iter()
+        .map(|host| host.as_str().map(str::to_string).ok_or(()))
+        .collect::<Result<Vec<_>, _>>()?,
+      None => Vec::new(),
+    };
+    Ok(HostRewriter { internal_hosts })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    http_client::StrategyNotifyHttpConnector,
+    load_balancing::{random::Random, LoadBalancingStrategy},
+    log_redaction::LogRedaction,
+    middleware::DEFAULT_MAX_BUFFER_BYTES,
+  };
+  use hyper::Client;
+  use std::sync::Arc;
+
+  fn host_rewriter(toml: &str) -> HostRewriter {
+    HostRewriter::try_from(toml::from_str::<Table>(toml).unwrap()).unwrap()
+  }
+
+  fn rewrite(middleware: &HostRewriter, headers: &mut HeaderMap) {
+    let strategy: Arc<Box<dyn LoadBalancingStrategy>> = Arc::new(Box::new(Random::new()));
+    let client = Client::builder().build(StrategyNotifyHttpConnector::new(strategy));
+    let client_address = "127.0.0.1:3000".parse().unwrap();
+    let context = Context {
+      client_scheme: &Scheme::HTTPS,
+      client_address: &client_address,
+      backend_uri: "http://127.0.0.1:8084/login".parse().unwrap(),
+      client: &client,
+      max_buffer_bytes: DEFAULT_MAX_BUFFER_BYTES,
+      log_redaction: &LogRedaction::default(),
+      tls_fingerprint: None,
+      hedge: None,
+    };
+    middleware.rewrite(headers, "whoami.localhost", &context);
+  }
+
+  #[test]
+  fn rewrites_location_pointing_at_backend() {
+    // given:
+    let middleware = host_rewriter("");
+    let mut headers = HeaderMap::new();
+    headers.insert(LOCATION, HeaderValue::from_static("http://127.0.0.1:8084/home?tab=1"));
+
+    // when:
+    rewrite(&middleware, &mut headers);
+
+    // then:
+    assert_eq!(headers[LOCATION], "https://whoami.localhost/home?tab=1");
+  }
+
+  #[test]
+  fn keeps_relative_and_external_locations() {
+    // given:
+    let middleware = host_rewriter("");
+    let mut relative = HeaderMap::new();
+    relative.insert(LOCATION, HeaderValue::from_static("/home"));
+    let mut external = HeaderMap::new();
+    external.insert(LOCATION, HeaderValue::from_static("https://login.example.org/"));
+
+    // when:
+    rewrite(&middleware, &mut relative);
+    rewrite(&middleware, &mut external);
+
+    // then:
+    assert_eq!(relative[LOCATION], "/home");
+    assert_eq!(external[LOCATION], "https://login.example.org/");
+  }
+
+  #[test]
+  fn rewrites_location_pointing_at_internal_host() {
+    // given:
+    let middleware = host_rewriter("internal_hosts = ['app.internal']");
+    let mut headers = HeaderMap::new();
+    headers.insert(LOCATION, HeaderValue::from_static("http://app.internal:8080/home"));
+
+    // when:
+    rewrite(&middleware, &mut headers);
+
+    // then:
+    assert_eq!(headers[LOCATION], "https://whoami.localhost/home");
+  }
+
+  #[test]
+  fn rewrites_cookie_domain_of_internal_host() {
+    // given:
+    let middleware = host_rewriter("internal_hosts = ['app.internal']");
+    let mut headers = HeaderMap::new();
+    headers.append(
+      SET_COOKIE,
+      HeaderValue::from_static("session=abc; Domain=.app.internal; Path=/; HttpOnly"),
+    );
+    headers.append(SET_COOKIE, HeaderValue::from_static("theme=dark; Domain=example.org"));
+
+    // when:
+    rewrite(&middleware, &mut headers);
+
+    // then:
+    let cookies = headers.get_all(SET_COOKIE).iter().collect::<Vec<_>>();
+    assert_eq!(
+      cookies,
+      vec![
+        "session=abc; Domain=whoami.localhost; Path=/; HttpOnly",
+        "theme=dark; Domain=example.org"
+      ]
+    );
+  }
+}
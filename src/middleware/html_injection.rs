@@ -0,0 +1,158 @@
+use super::{buffer_body, BufferedBody, Context, Middleware};
+use async_trait::async_trait;
+use hyper::{
+  header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+  Body, HeaderMap, Request, Response,
+};
+use log::error;
+use std::convert::TryFrom;
+use toml::{value::Table, Value};
+
+/// Injects a `snippet` (e.g. an analytics script) right before the closing `</body>` tag of HTML responses.
+#[derive(Debug)]
+pub struct HtmlInjection {
+  snippet: String,
+}
+
+#[async_trait]
+impl Middleware for HtmlInjection {
+  async fn modify_request(
+    &self,
+    mut request: Request<Body>,
+    _context: &Context<'_>,
+  ) -> Result<Request<Body>, Response<Body>> {
+    // the backend server must not compress the response, otherwise the snippet can't be injected.
+    // The Compression middleware has already read the header if it is placed before this middleware.
+    request.headers_mut().remove(ACCEPT_ENCODING);
+    Ok(request)
+  }
+
+  async fn modify_response(&self, response: Response<Body>, context: &Context<'_>) -> Response<Body> {
+    if !is_uncompressed_html(response.headers()) || exceeds(response.headers(), context.max_buffer_bytes) {
+      return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match buffer_body(body, context.max_buffer_bytes).await {
+      Ok(BufferedBody::Complete(bytes)) => bytes,
+      Ok(BufferedBody::TooLarge(body)) => return Response::from_parts(parts, body),
+      Err(e) => {
+        error!("Could not read response body for html injection: {}", e);
+        return Response::from_parts(parts, Body::empty());
+      }
+    };
+
+    match self.inject(&bytes) {
+      Some(injected) => {
+        parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(injected.len()));
+        Response::from_parts(parts, Body::from(injected))
+      }
+      None => Response::from_parts(parts, Body::from(bytes)),
+    }
+  }
+
+  fn buffers_response(&self) -> bool {
+    true
+  }
+}
+
+impl HtmlInjection {
+  fn inject(&self, html: &[u8]) -> Option<Vec<u8>> {
+    let position = find_closing_body_tag(html)?;
+    let mut injected = Vec::with_capacity(html.len() + self.snippet.len());
+    injected.extend_from_slice(&html[..position]);
+    injected.extend_from_slice(self.snippet.as_bytes());
+    injected.extend_from_slice(&html[position..]);
+    Some(injected)
+  }
+}
+
+impl TryFrom<Table> for HtmlInjection {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let snippet = t.get("snippet").and_then(Value::as_str).ok_or(())?.to_string();
+    Ok(HtmlInjection { snippet })
+  }
+}
+
+fn exceeds(headers: &HeaderMap, max_bytes: usize) -> bool {
+  headers
+    .get(CONTENT_LENGTH)
+    .and_then(|it| it.to_str().ok()?.parse::<usize>().ok())
+    .map_or(false, |length| length > max_bytes)
+}
+
+fn is_uncompressed_html(headers: &HeaderMap) -> bool {
+  let is_html = headers
+    .get(CONTENT_TYPE)
+    .and_then(|it| it.to_str().ok())
+    .map_or(false, |it| {
+      it.trim_start().to_ascii_lowercase().starts_with("text/html")
+    });
+  let is_encoded = headers
+    .get(CONTENT_ENCODING)
+    .and_then(|it| it.to_str().ok())
+    .map_or(false, |it| !it.eq_ignore_ascii_case("identity"));
+  is_html && !is_encoded
+}
+
+/// Returns the position of the last `</body>` tag, ignoring the case.
+fn find_closing_body_tag(html: &[u8]) -> Option<usize> {
+  const TAG: &[u8] = b"</body>";
+  html
+    .windows(TAG.len())
+    .rposition(|window| window.eq_ignore_ascii_case(TAG))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_inject_before_closing_body_tag() {
+    // given:
+    let middleware = HtmlInjection {
+      snippet: "<script src=\"/a.js\"></script>".to_string(),
+    };
+
+    // when:
+    let actual = middleware.inject(b"<html><BODY>hi</BODY></html>");
+
+    // then:
+    assert_eq!(
+      actual,
+      Some(b"<html><BODY>hi<script src=\"/a.js\"></script></BODY></html>".to_vec())
+    );
+  }
+
+  #[test]
+  fn test_inject_without_body_tag() {
+    // given:
+    let middleware = HtmlInjection {
+      snippet: "<script></script>".to_string(),
+    };
+
+    // when:
+    let actual = middleware.inject(b"<p>fragment</p>");
+
+    // then:
+    assert_eq!(actual, None);
+  }
+
+  #[test]
+  fn test_is_uncompressed_html() {
+    // given:
+    let mut html = HeaderMap::new();
+    html.insert(CONTENT_TYPE, "text/html; charset=utf-8".parse().unwrap());
+    let mut compressed_html = html.clone();
+    compressed_html.insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+    let mut json = HeaderMap::new();
+    json.insert(CONTENT_TYPE, "application/json".parse().unwrap());
+
+    // then:
+    assert!(is_uncompressed_html(&html));
+    assert!(!is_uncompressed_html(&compressed_html));
+    assert!(!is_uncompressed_html(&json));
+  }
+}
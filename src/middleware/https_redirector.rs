@@ -43,7 +43,7 @@ impl Middleware for HttpsRedirector {
           .body(Body::empty())
           .map_err(handle_internal_server_error)?;
 
-        debug!("Redirecting to {}", https_uri);
+        debug!("Redirecting to {}", context.log_redaction.uri(&https_uri));
 
         Err(response)
       }
@@ -1,33 +1,214 @@
-use super::{super::error_response, Context, Middleware};
+use super::{super::error_response, buffer_body, BufferedBody, Context, Middleware, MiddlewareChain};
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder};
 use async_trait::async_trait;
-use hyper::{header::CONTENT_LENGTH, Body, HeaderMap, Request, Response};
+use futures::StreamExt;
+use hyper::{
+  body::Bytes,
+  header::{CONTENT_ENCODING, CONTENT_LENGTH},
+  Body, HeaderMap, Request, Response,
+};
+use log::debug;
+use std::{
+  convert::TryFrom,
+  error::Error,
+  io,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use toml::{value::Table, Value};
 
 #[derive(Debug)]
 pub struct MaxBodySize {
   pub(crate) limit: i64,
+  /* Compressed request bodies may not inflate to more than this multiple of their size */
+  pub(crate) max_decompression_ratio: Option<u64>,
 }
 
 #[async_trait]
 impl Middleware for MaxBodySize {
-  async fn modify_request(
+  async fn forward_request(
     &self,
     request: Request<Body>,
-    _context: &Context<'_>,
-  ) -> Result<Request<Body>, Response<Body>> {
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
     match get_content_length(request.headers()) {
-      Some(length) if length > self.limit => Err(error_response::request_entity_to_large()),
-      _ => Ok(request),
+      Some(length) if length > self.limit => error_response::request_entity_to_large(),
+      Some(_) => self.forward_checked_request(request, chain, context).await,
+      // e.g. chunked bodies, which are only known to be too large while they are forwarded
+      None => {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let request = limit_body(request, self.limit, exceeded.clone());
+        let response = self.forward_checked_request(request, chain, context).await;
+        if exceeded.load(Ordering::Relaxed) {
+          debug!("Aborted request body exceeding {} bytes", self.limit);
+          return error_response::request_entity_to_large();
+        }
+        response
+      }
     }
   }
 }
 
+impl MaxBodySize {
+  async fn forward_checked_request(
+    &self,
+    request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
+    let request = match self.max_decompression_ratio {
+      Some(max_ratio) => check_decompression_ratio(request, max_ratio, context.max_buffer_bytes).await,
+      None => Ok(request),
+    };
+    match request {
+      Ok(request) => chain.forward_request(request, context).await,
+      Err(response) => response,
+    }
+  }
+}
+
+impl TryFrom<Table> for MaxBodySize {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let limit = t.get("limit").and_then(Value::as_integer).ok_or(())?;
+    let max_decompression_ratio = t
+      .get("max_decompression_ratio")
+      .map(|it| it.as_integer().filter(|it| *it > 0).map(|it| it as u64).ok_or(()))
+      .transpose()?;
+    Ok(MaxBodySize {
+      limit,
+      max_decompression_ratio,
+    })
+  }
+}
+
 fn get_content_length(headers: &HeaderMap) -> Option<i64> {
   headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
 }
 
+/// Streams the request body to the backend server, but aborts it once it exceeds `limit` bytes and sets `exceeded`.
+/// The backend server sees an aborted request, the client is answered with `413 Payload Too Large`.
+fn limit_body(request: Request<Body>, limit: i64, exceeded: Arc<AtomicBool>) -> Request<Body> {
+  let (parts, body) = request.into_parts();
+  let limit = limit.max(0) as u64;
+  let mut size = 0u64;
+  let limited = body.map(move |chunk| -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+    let chunk = chunk?;
+    size += chunk.len() as u64;
+    if size > limit {
+      exceeded.store(true, Ordering::Relaxed);
+      return Err(format!("request body exceeds {} bytes", limit).into());
+    }
+    Ok(chunk)
+  });
+  Request::from_parts(parts, Body::wrap_stream(limited))
+}
+
+/// Protects the backend servers from decompression bombs, i.e. small compressed bodies inflating to gigabytes. The
+/// compressed body is buffered and decompressed until it exceeds `max_ratio` times its compressed size, which is
+/// answered with `413 Payload Too Large`. Bodies which can't be buffered are rejected as well, since they can't be
+/// checked before forwarding them. Content codings which can't be checked are answered with
+/// `415 Unsupported Media Type`.
+async fn check_decompression_ratio(
+  request: Request<Body>,
+  max_ratio: u64,
+  max_buffer_bytes: usize,
+) -> Result<Request<Body>, Response<Body>> {
+  let encoding = match request.headers().get(CONTENT_ENCODING) {
+    Some(encoding) => encoding.to_str().unwrap_or_default().trim().to_ascii_lowercase(),
+    None => return Ok(request),
+  };
+  if encoding == "identity" {
+    return Ok(request);
+  }
+
+  let (parts, body) = request.into_parts();
+  let compressed = match buffer_body(body, max_buffer_bytes).await {
+    Ok(BufferedBody::Complete(bytes)) => bytes,
+    Ok(BufferedBody::TooLarge(_)) => {
+      debug!(
+        "Rejecting compressed request body larger than {} bytes",
+        max_buffer_bytes
+      );
+      return Err(error_response::request_entity_to_large());
+    }
+    Err(e) => {
+      debug!("Could not read compressed request body: {}", e);
+      return Err(error_response::bad_request("400 - could not read request body"));
+    }
+  };
+  let max_bytes = (compressed.len() as u64).saturating_mul(max_ratio);
+  match decompressed_size(&encoding, &compressed, max_bytes).await {
+    Some(Ok(size)) if size > max_bytes => {
+      debug!(
+        "Rejecting request body inflating from {} to more than {} bytes",
+        compressed.len(),
+        max_bytes
+      );
+      Err(error_response::request_entity_to_large())
+    }
+    Some(Ok(_)) => Ok(Request::from_parts(parts, Body::from(compressed))),
+    Some(Err(e)) => {
+      debug!("Could not decompress request body: {}", e);
+      Err(error_response::bad_request("400 - invalid compressed request body"))
+    }
+    None => Err(error_response::unsupported_media_type()),
+  }
+}
+
+/// Returns the size of the decompressed body, but stops decompressing once it exceeds `limit`. Returns `None` for
+/// unsupported content codings, including multiple ones.
+async fn decompressed_size(encoding: &str, compressed: &[u8], limit: u64) -> Option<io::Result<u64>> {
+  let mut decoder: Pin<Box<dyn AsyncRead + Send + '_>> = match encoding {
+    "gzip" | "x-gzip" => Box::pin(GzipDecoder::new(compressed)),
+    "deflate" => Box::pin(ZlibDecoder::new(compressed)),
+    "br" => Box::pin(BrotliDecoder::new(compressed)),
+    _ => return None,
+  };
+  let mut buffer = [0; 8192];
+  let mut size = 0;
+  loop {
+    match decoder.read(&mut buffer).await {
+      Ok(0) => return Some(Ok(size)),
+      Ok(read) => {
+        size += read as u64;
+        if size > limit {
+          return Some(Ok(size));
+        }
+      }
+      Err(e) => return Some(Err(e)),
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
+  use async_compression::tokio::bufread::GzipEncoder;
+  use hyper::StatusCode;
+
+  async fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    GzipEncoder::new(data).read_to_end(&mut compressed).await.unwrap();
+    compressed
+  }
+
+  fn request_of(size: usize) -> Request<Body> {
+    Request::new(Body::from(vec![0; size]))
+  }
+
+  fn request(encoding: &str, body: Vec<u8>) -> Request<Body> {
+    Request::builder()
+      .header(CONTENT_ENCODING, encoding)
+      .body(Body::from(body))
+      .unwrap()
+  }
 
   #[test]
   fn test_get_content_length_no_headers() {
@@ -66,4 +247,50 @@ mod tests {
     // then:
     assert_eq!(actual, Some(256));
   }
+
+  #[tokio::test]
+  async fn test_limit_body_aborts_bodies_exceeding_the_limit() {
+    // given:
+    let chunks: Vec<Result<_, io::Error>> = vec![Ok(vec![0; 600]), Ok(vec![0; 600])];
+    let request = Request::new(Body::wrap_stream(futures::stream::iter(chunks)));
+    let exceeded = Arc::new(AtomicBool::new(false));
+
+    // when:
+    let small = hyper::body::to_bytes(limit_body(request_of(600), 1024, exceeded.clone()).into_body()).await;
+    let large = hyper::body::to_bytes(limit_body(request, 1024, exceeded.clone()).into_body()).await;
+
+    // then:
+    assert!(large.is_err());
+    assert!(exceeded.load(Ordering::Relaxed));
+    assert_eq!(small.unwrap().len(), 600);
+  }
+
+  #[tokio::test]
+  async fn test_check_decompression_ratio_rejects_bombs() {
+    // given:
+    let bomb = gzip(&vec![0; 1024 * 1024]).await;
+    let text = gzip(b"hello world, hello world").await;
+
+    // when:
+    let rejected = check_decompression_ratio(request("gzip", bomb), 100, 1024 * 1024).await;
+    let accepted = check_decompression_ratio(request("GZIP", text.clone()), 100, 1024 * 1024).await;
+
+    // then:
+    assert_eq!(rejected.unwrap_err().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body = hyper::body::to_bytes(accepted.unwrap().into_body()).await.unwrap();
+    assert_eq!(body, text);
+  }
+
+  #[tokio::test]
+  async fn test_check_decompression_ratio_rejects_unknown_and_invalid_encodings() {
+    // when:
+    let unknown = check_decompression_ratio(request("compress", vec![1, 2, 3]), 100, 1024).await;
+    let invalid = check_decompression_ratio(request("gzip", vec![1, 2, 3]), 100, 1024).await;
+    let too_large = check_decompression_ratio(request("gzip", vec![0; 2048]), 100, 1024).await;
+
+    // then:
+    assert_eq!(unknown.unwrap_err().status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(invalid.unwrap_err().status(), StatusCode::BAD_REQUEST);
+    assert_eq!(too_large.unwrap_err().status(), StatusCode::PAYLOAD_TOO_LARGE);
+  }
 }
@@ -1,17 +1,40 @@
 use crate::{
-  error_response::handle_bad_gateway, http_client::StrategyNotifyHttpConnector, server::Scheme, utils::unwrap_result,
+  backend_pool_matcher::BackendPoolMatcher,
+  error_response::handle_upstream_error,
+  hedging::{duplicate, is_hedgeable, race, Hedge, Hedged},
+  http_client::StrategyNotifyHttpConnector,
+  log_redaction::LogRedaction,
+  server::Scheme,
+  timing::UpstreamTiming,
+  tls_fingerprint::TlsFingerprint,
+  utils::{canonical_ip, unwrap_result},
 };
 use async_trait::async_trait;
+use futures::{stream, StreamExt};
 use gethostname::gethostname;
-use hyper::{header::HeaderValue, Body, Client, Request, Response, Uri};
-use std::net::SocketAddr;
+use hyper::{
+  body::{Bytes, HttpBody},
+  header::{HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING},
+  Body, Client, HeaderMap, Method, Request, Response, StatusCode, Uri, Version,
+};
+use std::net::{IpAddr, SocketAddr};
+use tokio::time::Instant;
 
+pub mod ab_test;
+pub mod allowed_methods;
 pub mod authentication;
+pub mod body_checksum;
+pub mod canonical_host;
 pub mod compression;
+pub mod conditional_requests;
 pub mod custom_error_pages;
+pub mod host_rewriter;
+pub mod html_injection;
 pub mod https_redirector;
 pub mod maxbodysize;
+pub mod range_requests;
 pub mod rate_limiter;
+pub mod response_digest;
 
 /// A trait for implementing middlewares, see
 /// [`forward_request`](Middleware::forward_request) for more details.
@@ -80,6 +103,16 @@ pub trait Middleware: Send + Sync + std::fmt::Debug {
   async fn modify_response(&self, response: Response<Body>, _context: &Context<'_>) -> Response<Body> {
     response
   }
+
+  /// Returns `true` if the middleware holds back the response body, e.g. to
+  /// modify or compress it. Such middlewares are removed from
+  /// [streaming](crate::server::BackendPool::streaming) backend pools, so
+  /// events reach the client as soon as the backend server sends them.
+  ///
+  /// The default implementation returns `false`.
+  fn buffers_response(&self) -> bool {
+    false
+  }
 }
 
 pub struct Context<'l> {
@@ -87,6 +120,45 @@ pub struct Context<'l> {
   pub client_address: &'l SocketAddr,
   pub backend_uri: Uri,
   pub client: &'l Client<StrategyNotifyHttpConnector, Body>,
+  /// The maximum number of bytes a middleware may buffer of a request or
+  /// response body, see [`buffer_body`].
+  pub max_buffer_bytes: usize,
+  /// Masks the requests middlewares log.
+  pub log_redaction: &'l LogRedaction,
+  /// The JA3 fingerprint of the client's TLS connection, `None` for plain HTTP
+  /// or if the ClientHello could not be read.
+  pub tls_fingerprint: Option<TlsFingerprint>,
+  /// The backend server the request is hedged to, if the backend pool hedges
+  /// requests, see [`Hedging`](crate::hedging::Hedging).
+  pub hedge: Option<Hedge<'l>>,
+}
+
+pub const DEFAULT_MAX_BUFFER_BYTES: usize = 1024 * 1024;
+
+/// The result of [`buffer_body`].
+pub enum BufferedBody {
+  /// The whole body fit into the buffer.
+  Complete(Bytes),
+  /// The body exceeded the limit. The returned body streams the already
+  /// buffered bytes followed by the remainder of the original body.
+  TooLarge(Body),
+}
+
+/// Buffers the `body` in memory if it isn't larger than `max_bytes`, otherwise
+/// stops reading and returns a body that continues streaming. Middlewares that
+/// need the whole body should use this instead of `hyper::body::to_bytes` so
+/// large bodies are never held in memory.
+pub async fn buffer_body(mut body: Body, max_bytes: usize) -> Result<BufferedBody, hyper::Error> {
+  let mut buffer = Vec::new();
+  while let Some(chunk) = body.data().await {
+    let chunk = chunk?;
+    if buffer.len() + chunk.len() > max_bytes {
+      let buffered = stream::iter(vec![Ok(Bytes::from(buffer)), Ok(chunk)]);
+      return Ok(BufferedBody::TooLarge(Body::wrap_stream(buffered.chain(body))));
+    }
+    buffer.extend_from_slice(&chunk);
+  }
+  Ok(BufferedBody::Complete(Bytes::from(buffer)))
 }
 
 /// A singly linked list of [`Middleware`]s.
@@ -100,6 +172,9 @@ pub enum MiddlewareChain {
   Empty,
   Entry {
     middleware: Box<dyn Middleware>,
+    /// Restricts the middleware to the requests satisfying the matcher, all
+    /// other requests skip it.
+    matcher: Option<BackendPoolMatcher>,
     chain: Box<MiddlewareChain>,
   },
 }
@@ -108,60 +183,189 @@ impl MiddlewareChain {
   /// If this chain is not empty this function calls
   /// [`forward_request`](Middleware::forward_request) on the first middleware,
   /// passing it the tail of this chain as an argument to be called recursively.
+  /// Middlewares whose matcher is not satisfied by the `request` are skipped.
   ///
   /// Once this chain is empty this function does the final request
   /// transformation, setting all appropriate forwarding headers (like
   /// `x-forwarded-for`) and sends it to the backend server, returning the
   /// response.
   pub async fn forward_request(&self, request: Request<Body>, context: &Context<'_>) -> Response<Body> {
-    match self {
-      MiddlewareChain::Entry { middleware, chain } => middleware.forward_request(request, &chain, &context).await,
+    let mut entry = self;
+    while let MiddlewareChain::Entry {
+      matcher: Some(matcher),
+      chain,
+      ..
+    } = entry
+    {
+      if matcher.matches(&request) {
+        break;
+      }
+      entry = chain;
+    }
+    match entry {
+      MiddlewareChain::Entry { middleware, chain, .. } => middleware.forward_request(request, &chain, &context).await,
       MiddlewareChain::Empty => {
+        let hedge = context.hedge.as_ref().filter(|_| is_hedgeable(&request));
         let backend_request = backend_request(request, context);
-        unwrap_result(
-          context
-            .client
-            .request(backend_request)
-            .await
-            .map_err(handle_bad_gateway),
-        )
+        match hedge {
+          Some(hedge) => {
+            let hedge_request = duplicate(&backend_request, hedge.backend_uri.clone());
+            // only started after the delay, so the connection is taken just for hedged requests
+            let second = async {
+              let connection = match hedge.backend_limit {
+                Some(limit) => Some(limit.try_acquire(hedge.backend_address)?),
+                None => None,
+              };
+              let mut response = send_to_backend(hedge_request, context).await;
+              response.extensions_mut().insert(Hedged(connection));
+              Some(response)
+            };
+            race(send_to_backend(backend_request, context), second, hedge.delay).await
+          }
+          None => send_to_backend(backend_request, context).await,
+        }
+      }
+    }
+  }
+
+  /// Removes the middlewares which hold back response bodies, see
+  /// [`Middleware::buffers_response`], and returns them.
+  pub fn without_buffering(self) -> (MiddlewareChain, Vec<Box<dyn Middleware>>) {
+    let mut entries = Vec::new();
+    let mut removed = Vec::new();
+    let mut next = self;
+    while let MiddlewareChain::Entry {
+      middleware,
+      matcher,
+      chain,
+    } = next
+    {
+      if middleware.buffers_response() {
+        removed.push(middleware);
+      } else {
+        entries.push((middleware, matcher));
       }
+      next = *chain;
     }
+    let chain = entries
+      .into_iter()
+      .rev()
+      .fold(MiddlewareChain::Empty, |chain, (middleware, matcher)| {
+        MiddlewareChain::Entry {
+          middleware,
+          matcher,
+          chain: Box::new(chain),
+        }
+      });
+    (chain, removed)
   }
 }
 
-fn backend_request(request: Request<Body>, context: &Context) -> Request<Body> {
-  let builder = Request::builder().uri(&context.backend_uri);
+async fn send_to_backend(backend_request: Request<Body>, context: &Context<'_>) -> Response<Body> {
+  let sent_at = Instant::now();
+  let mut response = unwrap_result(
+    context
+      .client
+      .request(backend_request)
+      .await
+      .map_err(handle_upstream_error),
+  );
+  remove_hop_by_hop_headers(response.headers_mut());
+  response.extensions_mut().insert(UpstreamTiming {
+    sent_at,
+    time_to_first_byte: sent_at.elapsed(),
+  });
+  response
+}
 
-  let mut builder = request
-    .headers()
-    .iter()
-    .fold(builder, |builder, (key, val)| builder.header(key, val))
-    .header(
-      "x-forwarded-for",
-      forwarded_for_header(
-        request.headers().get("x-forwarded-for"),
-        context.client_address.ip().to_string(),
-      ),
-    )
-    .header("x-real-ip", context.client_address.ip().to_string())
-    .header(
-      "x-forwarded-port",
-      match context.client_scheme {
-        Scheme::HTTP => "80",
-        Scheme::HTTPS => "443",
-      },
-    )
-    .header("x-forwarded-proto", context.client_scheme.to_string())
-    .method(request.method());
-
-  builder = if let Ok(hostname) = gethostname().into_string() {
-    builder.header("x-forwarded-server", hostname)
-  } else {
-    builder
+/// Turns the client `request` into the request for the backend server.
+///
+/// The request is modified in place rather than rebuilt, so the headers and
+/// the body are moved without copying. This is the only path to the backend
+/// server, whether the chain is empty or its middlewares modify the body, as
+/// middlewares pass the request on by value; see the `backend_request`
+/// benchmark.
+pub fn backend_request(request: Request<Body>, context: &Context) -> Request<Body> {
+  let (mut parts, body) = request.into_parts();
+  parts.uri = context.backend_uri.clone();
+  // the protocol to the backend server is negotiated by the client, regardless of the version used by the caller
+  parts.version = Version::default();
+  parts.extensions.clear();
+  remove_hop_by_hop_headers(&mut parts.headers);
+
+  let client_ip = canonical_ip(context.client_address.ip());
+  let headers = &mut parts.headers;
+  let forwarded_for = forwarded_for_header(headers.get("x-forwarded-for"), forwarded_ip(client_ip));
+  headers.insert("x-forwarded-for", HeaderValue::from_str(&forwarded_for).unwrap());
+  headers.insert("x-real-ip", HeaderValue::from_str(&client_ip.to_string()).unwrap());
+  let (port, proto) = match context.client_scheme {
+    Scheme::HTTP => ("80", "http"),
+    Scheme::HTTPS => ("443", "https"),
   };
+  headers.insert("x-forwarded-port", HeaderValue::from_static(port));
+  headers.insert("x-forwarded-proto", HeaderValue::from_static(proto));
 
-  builder.body(request.into_body()).unwrap()
+  if let Some(hostname) = gethostname()
+    .into_string()
+    .ok()
+    .and_then(|it| HeaderValue::from_str(&it).ok())
+  {
+    headers.insert("x-forwarded-server", hostname);
+  }
+
+  Request::from_parts(parts, body)
+}
+
+/// Returns `false` if the response to a request with `method` must not have a body, see
+/// https://tools.ietf.org/html/rfc7230#section-3.3
+pub fn may_have_body(method: &Method, status: StatusCode) -> bool {
+  *method != Method::HEAD
+    && !status.is_informational()
+    && status != StatusCode::NO_CONTENT
+    && status != StatusCode::NOT_MODIFIED
+}
+
+/// Drops any body middlewares added to a response which must not have one. The `Content-Length` of responses to
+/// `HEAD` requests and of `304 Not Modified` responses describes the representation, so it is kept. `1xx` and
+/// `204 No Content` responses must not contain it at all.
+pub fn without_forbidden_body(method: &Method, response: Response<Body>) -> Response<Body> {
+  if may_have_body(method, response.status()) {
+    return response;
+  }
+  let (mut parts, _) = response.into_parts();
+  if parts.status.is_informational() || parts.status == StatusCode::NO_CONTENT {
+    parts.headers.remove(CONTENT_LENGTH);
+  }
+  parts.headers.remove(TRANSFER_ENCODING);
+  Response::from_parts(parts, Body::empty())
+}
+
+/// Removes the headers which only apply to a single connection, see https://tools.ietf.org/html/rfc7230#section-6.1
+/// The connections to the client and to the backend server are independent, e.g. an HTTP/1.0 client may keep its
+/// connection alive while the backend server closes its own.
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+  let listed = headers
+    .get_all(CONNECTION)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+    .collect::<Vec<_>>();
+  for name in listed {
+    headers.remove(name);
+  }
+  headers.remove(CONNECTION);
+  headers.remove(TRANSFER_ENCODING);
+  headers.remove("keep-alive");
+  headers.remove("proxy-connection");
+}
+
+/// Formats IPv6 addresses in brackets, so they can't be confused with an address and a port.
+fn forwarded_ip(ip: IpAddr) -> String {
+  match ip {
+    IpAddr::V4(ip) => ip.to_string(),
+    IpAddr::V6(ip) => format!("[{}]", ip),
+  }
 }
 
 // According to https://docs.oracle.com/en-us/iaas/Content/Balance/Reference/httpheaders.htm
@@ -179,6 +383,8 @@ fn forwarded_for_header(existing_forwarded_for: Option<&HeaderValue>, client_ip:
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::load_balancing::{random::Random, LoadBalancingStrategy};
+  use std::sync::Arc;
 
   #[test]
   fn test_build_forwarded_for_header_empty() {
@@ -187,10 +393,247 @@ mod tests {
     assert_eq!(forwarded_for_header, "127.0.0.1");
   }
 
+  #[test]
+  fn test_forwarded_ip_brackets_ipv6() {
+    assert_eq!(forwarded_ip("127.0.0.1".parse().unwrap()), "127.0.0.1");
+    assert_eq!(forwarded_ip("2001:db8::1".parse().unwrap()), "[2001:db8::1]");
+  }
+
   #[test]
   fn test_build_forwarded_for_header_existing() {
     let forwarded_for_header = forwarded_for_header(Some(&HeaderValue::from_static("127.0.0.2")), "127.0.0.1".into());
 
     assert_eq!(forwarded_for_header, "127.0.0.2, 127.0.0.1");
   }
+
+  #[test]
+  fn test_backend_request_replaces_forwarding_headers() {
+    // given:
+    let strategy: Arc<Box<dyn LoadBalancingStrategy>> = Arc::new(Box::new(Random::new()));
+    let client = Client::builder().build(StrategyNotifyHttpConnector::new(strategy));
+    let client_address = "127.0.0.1:3000".parse().unwrap();
+    let context = Context {
+      client_scheme: &Scheme::HTTPS,
+      client_address: &client_address,
+      backend_uri: "http://127.0.0.1:8084/index.html".parse().unwrap(),
+      client: &client,
+      max_buffer_bytes: DEFAULT_MAX_BUFFER_BYTES,
+      log_redaction: &LogRedaction::default(),
+      tls_fingerprint: None,
+      hedge: None,
+    };
+    let request = Request::builder()
+      .uri("/index.html")
+      .version(Version::HTTP_2)
+      .header("x-forwarded-for", "127.0.0.2")
+      .header("x-forwarded-proto", "http")
+      .header("accept", "text/html")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let actual = backend_request(request, &context);
+
+    // then:
+    assert_eq!(actual.uri(), "http://127.0.0.1:8084/index.html");
+    assert_eq!(actual.version(), Version::HTTP_11);
+    let headers = actual.headers();
+    assert_eq!(
+      headers.get_all("x-forwarded-for").iter().collect::<Vec<_>>(),
+      vec!["127.0.0.2, 127.0.0.1"]
+    );
+    assert_eq!(
+      headers.get_all("x-forwarded-proto").iter().collect::<Vec<_>>(),
+      vec!["https"]
+    );
+    assert_eq!(headers["x-forwarded-port"], "443");
+    assert_eq!(headers["x-real-ip"], "127.0.0.1");
+    assert_eq!(headers["accept"], "text/html");
+  }
+
+  /* Answers every request early with its name */
+  #[derive(Debug)]
+  struct Answer(&'static str);
+
+  #[async_trait]
+  impl Middleware for Answer {
+    async fn modify_request(
+      &self,
+      _request: Request<Body>,
+      _context: &Context<'_>,
+    ) -> Result<Request<Body>, Response<Body>> {
+      Err(Response::new(Body::from(self.0)))
+    }
+  }
+
+  #[tokio::test]
+  async fn test_forward_request_skips_middlewares_of_other_requests() {
+    // given:
+    let strategy: Arc<Box<dyn LoadBalancingStrategy>> = Arc::new(Box::new(Random::new()));
+    let client = Client::builder().build(StrategyNotifyHttpConnector::new(strategy));
+    let client_address = "127.0.0.1:3000".parse().unwrap();
+    let context = Context {
+      client_scheme: &Scheme::HTTP,
+      client_address: &client_address,
+      backend_uri: "http://127.0.0.1:8084/".parse().unwrap(),
+      client: &client,
+      max_buffer_bytes: DEFAULT_MAX_BUFFER_BYTES,
+      log_redaction: &LogRedaction::default(),
+      tls_fingerprint: None,
+      hedge: None,
+    };
+    let chain = MiddlewareChain::Entry {
+      middleware: Box::new(Answer("static")),
+      matcher: Some("Path('/static')".parse().unwrap()),
+      chain: Box::new(MiddlewareChain::Entry {
+        middleware: Box::new(Answer("default")),
+        matcher: None,
+        chain: Box::new(MiddlewareChain::Empty),
+      }),
+    };
+    let request = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+
+    // when:
+    let static_response = chain.forward_request(request("/static"), &context).await;
+    let other_response = chain.forward_request(request("/"), &context).await;
+
+    // then:
+    assert_eq!(
+      hyper::body::to_bytes(static_response.into_body()).await.unwrap(),
+      "static"
+    );
+    assert_eq!(
+      hyper::body::to_bytes(other_response.into_body()).await.unwrap(),
+      "default"
+    );
+  }
+
+  /* Holds back response bodies */
+  #[derive(Debug)]
+  struct Buffering;
+
+  #[async_trait]
+  impl Middleware for Buffering {
+    fn buffers_response(&self) -> bool {
+      true
+    }
+  }
+
+  #[test]
+  fn test_without_buffering_keeps_the_order_of_other_middlewares() {
+    // given:
+    let chain = MiddlewareChain::Entry {
+      middleware: Box::new(Answer("static")),
+      matcher: Some("Path('/static')".parse().unwrap()),
+      chain: Box::new(MiddlewareChain::Entry {
+        middleware: Box::new(Buffering),
+        matcher: None,
+        chain: Box::new(MiddlewareChain::Entry {
+          middleware: Box::new(Answer("default")),
+          matcher: None,
+          chain: Box::new(MiddlewareChain::Empty),
+        }),
+      }),
+    };
+
+    // when:
+    let (chain, removed) = chain.without_buffering();
+
+    // then:
+    assert_eq!(format!("{:?}", removed), "[Buffering]");
+    match chain {
+      MiddlewareChain::Entry {
+        middleware,
+        matcher,
+        chain,
+      } => {
+        assert_eq!(format!("{:?}", middleware), "Answer(\"static\")");
+        assert!(matcher.is_some());
+        assert!(matches!(*chain, MiddlewareChain::Entry { matcher: None, .. }));
+        assert!(format!("{:?}", chain).contains("Answer(\"default\")"));
+      }
+      MiddlewareChain::Empty => panic!("expected a middleware"),
+    }
+  }
+
+  #[test]
+  fn test_remove_hop_by_hop_headers() {
+    // given:
+    let mut headers = HeaderMap::new();
+    headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, x-session"));
+    headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+    headers.insert("x-session", HeaderValue::from_static("abc"));
+    headers.insert("accept", HeaderValue::from_static("text/html"));
+
+    // when:
+    remove_hop_by_hop_headers(&mut headers);
+
+    // then:
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers["accept"], "text/html");
+  }
+
+  #[tokio::test]
+  async fn test_without_forbidden_body() {
+    let response = |status: StatusCode| {
+      Response::builder()
+        .status(status)
+        .header(CONTENT_LENGTH, "5")
+        .body(Body::from("hello"))
+        .unwrap()
+    };
+
+    let head = without_forbidden_body(&Method::HEAD, response(StatusCode::OK));
+    let no_content = without_forbidden_body(&Method::GET, response(StatusCode::NO_CONTENT));
+    let not_modified = without_forbidden_body(&Method::GET, response(StatusCode::NOT_MODIFIED));
+    let ok = without_forbidden_body(&Method::GET, response(StatusCode::OK));
+
+    assert!(head.body().is_end_stream());
+    assert_eq!(head.headers()[CONTENT_LENGTH], "5");
+    assert!(no_content.body().is_end_stream());
+    assert!(no_content.headers().get(CONTENT_LENGTH).is_none());
+    assert!(not_modified.body().is_end_stream());
+    assert_eq!(not_modified.headers()[CONTENT_LENGTH], "5");
+    assert_eq!(hyper::body::to_bytes(ok.into_body()).await.unwrap(), "hello");
+  }
+
+  #[tokio::test]
+  async fn test_buffer_body_complete() {
+    // given:
+    let body = Body::from("small body");
+
+    // when:
+    let actual = buffer_body(body, 1024).await.unwrap();
+
+    // then:
+    match actual {
+      BufferedBody::Complete(bytes) => assert_eq!(bytes, "small body"),
+      BufferedBody::TooLarge(_) => panic!("expected a complete body"),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_buffer_body_streams_multi_gigabyte_body() {
+    // given:
+    const CHUNK: &[u8] = &[0; 64 * 1024];
+    const CHUNKS: usize = 64 * 1024; // 4 GiB in total
+    let chunks = stream::iter((0..CHUNKS).map(|_| Ok::<_, std::io::Error>(Bytes::from_static(CHUNK))));
+    let body = Body::wrap_stream(chunks);
+
+    // when:
+    let actual = buffer_body(body, DEFAULT_MAX_BUFFER_BYTES).await.unwrap();
+
+    // then:
+    let mut body = match actual {
+      BufferedBody::TooLarge(body) => body,
+      BufferedBody::Complete(_) => panic!("expected the body to exceed the limit"),
+    };
+    let mut total = 0;
+    while let Some(chunk) = body.data().await {
+      let chunk = chunk.unwrap();
+      assert!(chunk.len() <= DEFAULT_MAX_BUFFER_BYTES + CHUNK.len());
+      total += chunk.len();
+    }
+    assert_eq!(total, CHUNK.len() * CHUNKS);
+  }
 }
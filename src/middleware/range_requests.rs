@@ -0,0 +1,598 @@
+use super::{buffer_body, BufferedBody, Context, Middleware, MiddlewareChain};
+use crate::utils::split_once;
+use async_stream::try_stream;
+use async_trait::async_trait;
+use futures::Stream;
+use hyper::{
+  body::{Bytes, HttpBody},
+  header::{
+    HeaderMap, HeaderValue, ACCEPT_RANGES, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    COOKIE, ETAG, HOST, IF_RANGE, LAST_MODIFIED, RANGE, SET_COOKIE, VARY,
+  },
+  Body, Method, Request, Response, StatusCode,
+};
+use linked_hash_map::LinkedHashMap;
+use std::{
+  convert::TryFrom,
+  sync::{Arc, Mutex},
+};
+use toml::{value::Table, Value};
+
+pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+pub const DEFAULT_MAX_CACHE_BYTES: u64 = 256 * 1024 * 1024;
+/// The maximum number of chunks assembled for a single response
+const MAX_CHUNKS_PER_RESPONSE: u64 = 8;
+
+/// Handles `Range` requests (e.g. seeking in videos) at the load balancer. Unsatisfiable ranges are answered with
+/// `416 Range Not Satisfiable`, invalid `Range` headers are left to the backend server, see
+/// https://tools.ietf.org/html/rfc7233#section-3.1. If the backend server ignores the range, only the requested part
+/// of its response is sent to the client. With a `cache`, ranges are fetched from the backend server in aligned
+/// chunks which are kept in memory, so repeated seeking doesn't hit the backend server again. Requests with
+/// credentials and responses which are private or vary by request headers are not cached.
+#[derive(Debug)]
+pub struct RangeRequests {
+  cache: Option<ChunkCache>,
+}
+
+#[async_trait]
+impl Middleware for RangeRequests {
+  async fn forward_request(
+    &self,
+    request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
+    // the backend server decides whether the representation changed
+    if request.method() != Method::GET || request.headers().contains_key(IF_RANGE) {
+      return chain.forward_request(request, context).await;
+    }
+    let range = match request.headers().get(RANGE).map(ByteRange::parse) {
+      Some(Ok(Some(range))) => range,
+      _ => return chain.forward_request(request, context).await,
+    };
+
+    if let Some(cache) = self.cache.as_ref().filter(|_| !has_credentials(&request)) {
+      if let Some(response) = cache.serve(&request, range, chain, context).await {
+        return response;
+      }
+    }
+
+    let response = chain.forward_request(request, context).await;
+    if response.status() == StatusCode::OK {
+      partial_content_of(response, range)
+    } else {
+      response
+    }
+  }
+
+  /* ranges are assembled from cached chunks or cut out of the complete response, which holds back its beginning */
+  fn buffers_response(&self) -> bool {
+    true
+  }
+}
+
+/// A single byte range of a `Range` header, see https://tools.ietf.org/html/rfc7233#section-2.1
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ByteRange {
+  /// `bytes=first-` or `bytes=first-last`
+  FromTo(u64, Option<u64>),
+  /// `bytes=-length`, the last `length` bytes
+  Suffix(u64),
+}
+
+impl ByteRange {
+  /// Returns `None` for other units and multiple ranges, which are left to the backend server, and `Err` for invalid
+  /// ranges.
+  fn parse(value: &HeaderValue) -> Result<Option<ByteRange>, ()> {
+    let value = value.to_str().map_err(|_| ())?.trim();
+    let ranges = match value.get(..6) {
+      Some(unit) if unit.eq_ignore_ascii_case("bytes=") => &value[6..],
+      _ => return Ok(None),
+    };
+    if ranges.contains(',') {
+      return Ok(None);
+    }
+    let (first, last) = split_once(ranges, '-').ok_or(())?;
+    let parse = |position: &str| position.trim().parse::<u64>().map_err(|_| ());
+    match (first.trim(), last.trim()) {
+      ("", "") => Err(()),
+      ("", length) => match parse(length)? {
+        0 => Err(()),
+        length => Ok(Some(ByteRange::Suffix(length))),
+      },
+      (first, "") => Ok(Some(ByteRange::FromTo(parse(first)?, None))),
+      (first, last) => {
+        let (first, last) = (parse(first)?, parse(last)?);
+        if last < first {
+          return Err(());
+        }
+        Ok(Some(ByteRange::FromTo(first, Some(last))))
+      }
+    }
+  }
+
+  /// Returns the first and last position of the range within a representation of `total` bytes, or `None` if the
+  /// range is not satisfiable.
+  fn resolve(self, total: u64) -> Option<(u64, u64)> {
+    match self {
+      ByteRange::FromTo(first, _) if first >= total => None,
+      ByteRange::FromTo(first, last) => Some((first, last.map_or(total - 1, |last| last.min(total - 1)))),
+      ByteRange::Suffix(_) if total == 0 => None,
+      ByteRange::Suffix(length) => Some((total.saturating_sub(length), total - 1)),
+    }
+  }
+}
+
+/// Turns the complete response of a backend server which ignored the range into a partial response.
+fn partial_content_of(response: Response<Body>, range: ByteRange) -> Response<Body> {
+  let total = match response
+    .headers()
+    .get(CONTENT_LENGTH)
+    .and_then(|it| it.to_str().ok()?.parse::<u64>().ok())
+  {
+    Some(total) => total,
+    // without the length the range can't be resolved, the complete response is a valid answer as well
+    None => return response,
+  };
+  let (first, last) = match range.resolve(total) {
+    Some(range) => range,
+    None => return range_not_satisfiable(total),
+  };
+
+  let (mut parts, body) = response.into_parts();
+  parts.status = StatusCode::PARTIAL_CONTENT;
+  parts
+    .headers
+    .insert(CONTENT_LENGTH, HeaderValue::from(last - first + 1));
+  parts.headers.insert(CONTENT_RANGE, content_range(first, last, total));
+  parts.headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+  Response::from_parts(parts, Body::wrap_stream(slice_body(body, first, last - first + 1)))
+}
+
+/// Streams `length` bytes of the `body` starting at `first`, skipping the rest.
+fn slice_body(mut body: Body, first: u64, length: u64) -> impl Stream<Item = Result<Bytes, hyper::Error>> {
+  try_stream! {
+    let end = first + length;
+    let mut position = 0;
+    while position < end {
+      let chunk = match body.data().await {
+        Some(chunk) => chunk?,
+        None => break,
+      };
+      let chunk_end = position + chunk.len() as u64;
+      if chunk_end > first {
+        let from = first.saturating_sub(position) as usize;
+        let to = (end.min(chunk_end) - position) as usize;
+        yield chunk.slice(from..to);
+      }
+      position = chunk_end;
+    }
+  }
+}
+
+fn content_range(first: u64, last: u64, total: u64) -> HeaderValue {
+  HeaderValue::from_str(&format!("bytes {}-{}/{}", first, last, total)).unwrap()
+}
+
+/// Parses a `Content-Range` header like `bytes 0-1023/4096` into the first and last position and the total length.
+fn parse_content_range(value: &HeaderValue) -> Option<(u64, u64, u64)> {
+  let range = value.to_str().ok()?.trim().strip_prefix("bytes ")?;
+  let (positions, total) = split_once(range, '/')?;
+  let (first, last) = split_once(positions, '-')?;
+  Some((first.parse().ok()?, last.parse().ok()?, total.parse().ok()?))
+}
+
+fn range_not_satisfiable(total: u64) -> Response<Body> {
+  Response::builder()
+    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+    .header(CONTENT_RANGE, format!("bytes */{}", total))
+    .body(Body::empty())
+    .unwrap()
+}
+
+/// A chunk of a representation, aligned to the chunk size of the [`ChunkCache`].
+#[derive(Debug)]
+struct Chunk {
+  data: Bytes,
+  total: u64,
+  etag: Option<HeaderValue>,
+  last_modified: Option<HeaderValue>,
+  content_type: Option<HeaderValue>,
+}
+
+impl Chunk {
+  fn is_same_representation(&self, other: &Chunk) -> bool {
+    self.total == other.total && self.etag == other.etag && self.last_modified == other.last_modified
+  }
+}
+
+/// The result of fetching a chunk from the backend server.
+enum Fetched {
+  Chunk(Arc<Chunk>),
+  /// The backend server answered with something else than the requested range
+  Response(Response<Body>),
+}
+
+/// Keeps the least recently used chunks up to `max_bytes` in memory. Chunks are keyed by host, URI and position.
+#[derive(Debug)]
+struct ChunkCache {
+  chunk_size: u64,
+  max_bytes: u64,
+  state: Mutex<ChunkCacheState>,
+}
+
+#[derive(Debug, Default)]
+struct ChunkCacheState {
+  chunks: LinkedHashMap<(String, u64), Arc<Chunk>>,
+  bytes: u64,
+}
+
+impl ChunkCache {
+  fn new(chunk_size: u64, max_bytes: u64) -> ChunkCache {
+    ChunkCache {
+      chunk_size,
+      max_bytes,
+      state: Mutex::new(ChunkCacheState::default()),
+    }
+  }
+
+  /// Serves the range from cached or newly fetched chunks. Returns `None` if the range must be forwarded as usual.
+  async fn serve(
+    &self,
+    request: &Request<Body>,
+    range: ByteRange,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Option<Response<Body>> {
+    // suffix ranges can't be mapped to chunks without knowing the length
+    let first = match range {
+      ByteRange::FromTo(first, _) => first,
+      ByteRange::Suffix(_) => return None,
+    };
+    let host = request
+      .headers()
+      .get(HOST)
+      .and_then(|it| it.to_str().ok())
+      .unwrap_or("");
+    let key = format!("{}{}", host, request.uri());
+
+    let first_index = first / self.chunk_size;
+    let first_chunk = match self.chunk(&key, first_index, request, chain, context).await {
+      Fetched::Chunk(chunk) => chunk,
+      Fetched::Response(response) if response.status() == StatusCode::OK => {
+        return Some(partial_content_of(response, range))
+      }
+      // a partial response for another range than the requested chunk
+      Fetched::Response(response) if response.status() == StatusCode::PARTIAL_CONTENT => return None,
+      Fetched::Response(response) => return Some(response),
+    };
+    let (first, mut last) = match range.resolve(first_chunk.total) {
+      Some(range) => range,
+      None => return Some(range_not_satisfiable(first_chunk.total)),
+    };
+    let max_last = self.max_last(first_index);
+    if last > max_last {
+      match range {
+        // clients asking for the rest of the representation continue with another request
+        ByteRange::FromTo(_, None) => last = max_last,
+        _ => return None,
+      }
+    }
+
+    let mut chunks = vec![first_chunk];
+    for index in first_index + 1..=last / self.chunk_size {
+      match self.chunk(&key, index, request, chain, context).await {
+        Fetched::Chunk(chunk) if chunk.is_same_representation(&chunks[0]) => chunks.push(chunk),
+        // the representation changed in the meantime
+        _ => {
+          self.evict(&key);
+          return None;
+        }
+      }
+    }
+
+    let body = assemble(&chunks, first_index * self.chunk_size, first, last);
+    let mut response = Response::builder()
+      .status(StatusCode::PARTIAL_CONTENT)
+      .header(CONTENT_LENGTH, body.len())
+      .header(CONTENT_RANGE, content_range(first, last, chunks[0].total))
+      .header(ACCEPT_RANGES, "bytes")
+      .body(Body::from(body))
+      .unwrap();
+    let headers = response.headers_mut();
+    if let Some(etag) = &chunks[0].etag {
+      headers.insert(ETAG, etag.clone());
+    }
+    if let Some(last_modified) = &chunks[0].last_modified {
+      headers.insert(LAST_MODIFIED, last_modified.clone());
+    }
+    if let Some(content_type) = &chunks[0].content_type {
+      headers.insert(CONTENT_TYPE, content_type.clone());
+    }
+    Some(response)
+  }
+
+  /* The last position a response starting in the chunk at `first_index` may contain */
+  fn max_last(&self, first_index: u64) -> u64 {
+    first_index
+      .saturating_add(MAX_CHUNKS_PER_RESPONSE)
+      .saturating_mul(self.chunk_size)
+      .saturating_sub(1)
+  }
+
+  async fn chunk(
+    &self,
+    key: &str,
+    index: u64,
+    request: &Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Fetched {
+    let cache_key = (key.to_string(), index);
+    if let Some(chunk) = self.state.lock().unwrap().chunks.get_refresh(&cache_key) {
+      return Fetched::Chunk(chunk.clone());
+    }
+
+    let first = index * self.chunk_size;
+    let last = first.saturating_add(self.chunk_size - 1);
+    let mut chunk_request = Request::builder()
+      .method(Method::GET)
+      .uri(request.uri().clone())
+      .body(Body::empty())
+      .unwrap();
+    *chunk_request.headers_mut() = request.headers().clone();
+    chunk_request.headers_mut().insert(
+      RANGE,
+      HeaderValue::from_str(&format!("bytes={}-{}", first, last)).unwrap(),
+    );
+
+    let response = chain.forward_request(chunk_request, context).await;
+    let content_range = response.headers().get(CONTENT_RANGE).and_then(parse_content_range);
+    let total = match (response.status(), content_range) {
+      (StatusCode::PARTIAL_CONTENT, Some((chunk_first, _, total))) if chunk_first == first => total,
+      _ => return Fetched::Response(response),
+    };
+
+    let (parts, body) = response.into_parts();
+    let data = match buffer_body(body, self.chunk_size as usize).await {
+      Ok(BufferedBody::Complete(data)) => data,
+      Ok(BufferedBody::TooLarge(body)) => return Fetched::Response(Response::from_parts(parts, body)),
+      Err(_) => return Fetched::Response(Response::from_parts(parts, Body::empty())),
+    };
+    // only complete chunks can be assembled into responses
+    if data.len() as u64 != self.chunk_size.min(total.saturating_sub(first)) {
+      return Fetched::Response(Response::from_parts(parts, Body::from(data)));
+    }
+    let chunk = Arc::new(Chunk {
+      data,
+      total,
+      etag: parts.headers.get(ETAG).cloned(),
+      last_modified: parts.headers.get(LAST_MODIFIED).cloned(),
+      content_type: parts.headers.get(CONTENT_TYPE).cloned(),
+    });
+    if is_cacheable(&parts.headers) {
+      self.insert(cache_key, chunk.clone());
+    }
+    Fetched::Chunk(chunk)
+  }
+
+  fn insert(&self, key: (String, u64), chunk: Arc<Chunk>) {
+    let size = chunk.data.len() as u64;
+    if size > self.max_bytes {
+      return;
+    }
+    let mut state = self.state.lock().unwrap();
+    while state.bytes + size > self.max_bytes {
+      match state.chunks.pop_front() {
+        Some((_, evicted)) => state.bytes -= evicted.data.len() as u64,
+        None => break,
+      }
+    }
+    if let Some(replaced) = state.chunks.insert(key, chunk) {
+      state.bytes -= replaced.data.len() as u64;
+    }
+    state.bytes += size;
+  }
+
+  /// Removes all chunks of the representation at `key`.
+  fn evict(&self, key: &str) {
+    let mut state = self.state.lock().unwrap();
+    let evicted = state
+      .chunks
+      .keys()
+      .filter(|(chunk_key, _)| chunk_key == key)
+      .cloned()
+      .collect::<Vec<_>>();
+    for evicted in evicted {
+      if let Some(chunk) = state.chunks.remove(&evicted) {
+        state.bytes -= chunk.data.len() as u64;
+      }
+    }
+  }
+}
+
+/// Requests with credentials may be answered differently per user, but the cache is shared by all clients.
+fn has_credentials(request: &Request<Body>) -> bool {
+  request.headers().contains_key(AUTHORIZATION) || request.headers().contains_key(COOKIE)
+}
+
+/// Returns false for responses which must not be served to other clients, as the cache doesn't distinguish the
+/// request headers listed in `Vary`.
+fn is_cacheable(headers: &HeaderMap) -> bool {
+  let private = headers
+    .get_all(CACHE_CONTROL)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .map(|directive| directive.trim().to_ascii_lowercase())
+    .any(|directive| directive == "private" || directive == "no-store" || directive == "no-cache");
+  !private && !headers.contains_key(VARY) && !headers.contains_key(SET_COOKIE)
+}
+
+/// Copies the bytes from `first` to `last` out of consecutive `chunks`, the first one starting at `offset`.
+fn assemble(chunks: &[Arc<Chunk>], offset: u64, first: u64, last: u64) -> Bytes {
+  let mut body = Vec::with_capacity((last - first + 1) as usize);
+  let mut position = offset;
+  for chunk in chunks {
+    let chunk_end = position + chunk.data.len() as u64;
+    let from = first.max(position).min(chunk_end);
+    let to = (last + 1).min(chunk_end).max(from);
+    body.extend_from_slice(&chunk.data[(from - position) as usize..(to - position) as usize]);
+    position = chunk_end;
+  }
+  Bytes::from(body)
+}
+
+impl TryFrom<Table> for RangeRequests {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let cache = match t.get("cache") {
+      Some(Value::Table(cache)) => {
+        let get_u64 = |key: &str, default: u64| match cache.get(key) {
+          Some(value) => value.as_integer().and_then(|it| u64::try_from(it).ok()).ok_or(()),
+          None => Ok(default),
+        };
+        let chunk_size = get_u64("chunk_size", DEFAULT_CHUNK_SIZE)?;
+        if chunk_size == 0 {
+          return Err(());
+        }
+        Some(ChunkCache::new(
+          chunk_size,
+          get_u64("max_bytes", DEFAULT_MAX_CACHE_BYTES)?,
+        ))
+      }
+      Some(_) => return Err(()),
+      None => None,
+    };
+    Ok(RangeRequests { cache })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::body;
+
+  fn chunk(data: &'static str) -> Arc<Chunk> {
+    Arc::new(Chunk {
+      data: Bytes::from(data),
+      total: 10,
+      etag: None,
+      last_modified: None,
+      content_type: None,
+    })
+  }
+
+  #[test]
+  fn parse_byte_ranges() {
+    let parse = |value: &'static str| ByteRange::parse(&HeaderValue::from_static(value));
+
+    assert_eq!(parse("bytes=0-499"), Ok(Some(ByteRange::FromTo(0, Some(499)))));
+    assert_eq!(parse("bytes=500-"), Ok(Some(ByteRange::FromTo(500, None))));
+    assert_eq!(parse("bytes=-500"), Ok(Some(ByteRange::Suffix(500))));
+    assert_eq!(parse("bytes=0-1, 5-6"), Ok(None));
+    assert_eq!(parse("items=0-1"), Ok(None));
+    assert_eq!(parse("bytes=5-1"), Err(()));
+    assert_eq!(parse("bytes=-0"), Err(()));
+    assert_eq!(parse("bytes=a-b"), Err(()));
+  }
+
+  #[test]
+  fn resolve_byte_ranges() {
+    assert_eq!(ByteRange::FromTo(0, Some(499)).resolve(100), Some((0, 99)));
+    assert_eq!(ByteRange::FromTo(10, None).resolve(100), Some((10, 99)));
+    assert_eq!(ByteRange::Suffix(500).resolve(100), Some((0, 99)));
+    assert_eq!(ByteRange::Suffix(10).resolve(100), Some((90, 99)));
+    assert_eq!(ByteRange::FromTo(100, None).resolve(100), None);
+  }
+
+  #[test]
+  fn parse_content_ranges() {
+    assert_eq!(
+      parse_content_range(&HeaderValue::from_static("bytes 0-1023/4096")),
+      Some((0, 1023, 4096))
+    );
+    assert_eq!(parse_content_range(&HeaderValue::from_static("bytes */4096")), None);
+  }
+
+  #[tokio::test]
+  async fn slice_body_across_chunks() {
+    // given:
+    let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("0123"), Ok("4567"), Ok("89")];
+    let body = Body::wrap_stream(futures::stream::iter(chunks));
+
+    // when:
+    let sliced = body::to_bytes(Body::wrap_stream(slice_body(body, 3, 4))).await.unwrap();
+
+    // then:
+    assert_eq!(sliced, "3456");
+  }
+
+  #[test]
+  fn partial_content_of_complete_response() {
+    // given:
+    let response = Response::builder()
+      .header(CONTENT_LENGTH, 10)
+      .body(Body::from("0123456789"))
+      .unwrap();
+
+    // when:
+    let actual = partial_content_of(response, ByteRange::Suffix(3));
+
+    // then:
+    assert_eq!(actual.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(actual.headers()[CONTENT_RANGE], "bytes 7-9/10");
+    assert_eq!(actual.headers()[CONTENT_LENGTH], "3");
+  }
+
+  #[test]
+  fn assemble_range_from_chunks() {
+    let chunks = vec![chunk("4567"), chunk("89")];
+
+    assert_eq!(assemble(&chunks, 4, 5, 8), "5678");
+    assert_eq!(assemble(&chunks, 4, 4, 9), "456789");
+  }
+
+  #[test]
+  fn requests_with_credentials_and_private_responses_are_not_cached() {
+    let request = |name, value| Request::get("/video").header(name, value).body(Body::empty()).unwrap();
+    let headers = |name, value| {
+      let mut headers = HeaderMap::new();
+      headers.insert(name, HeaderValue::from_static(value));
+      headers
+    };
+
+    assert!(has_credentials(&request(AUTHORIZATION, "Bearer token")));
+    assert!(has_credentials(&request(COOKIE, "session=1")));
+    assert!(!has_credentials(&request(RANGE, "bytes=0-1")));
+    assert!(is_cacheable(&headers(CACHE_CONTROL, "public, max-age=60")));
+    assert!(!is_cacheable(&headers(CACHE_CONTROL, "max-age=60, Private")));
+    assert!(!is_cacheable(&headers(CACHE_CONTROL, "no-store")));
+    assert!(!is_cacheable(&headers(VARY, "Accept-Language")));
+    assert!(!is_cacheable(&headers(SET_COOKIE, "session=1")));
+  }
+
+  #[test]
+  fn max_last_position_does_not_overflow() {
+    assert_eq!(ChunkCache::new(4, 8).max_last(1), 35);
+    assert_eq!(ChunkCache::new(4, 8).max_last(u64::MAX / 4), u64::MAX - 1);
+  }
+
+  #[test]
+  fn chunk_cache_evicts_least_recently_used_chunks() {
+    // given:
+    let cache = ChunkCache::new(4, 8);
+    cache.insert(("video".to_string(), 0), chunk("0123"));
+    cache.insert(("video".to_string(), 1), chunk("4567"));
+
+    // when:
+    cache.insert(("video".to_string(), 2), chunk("89"));
+
+    // then:
+    let state = cache.state.lock().unwrap();
+    let keys = state.chunks.keys().map(|(_, index)| *index).collect::<Vec<_>>();
+    assert_eq!(keys, vec![1, 2]);
+    assert_eq!(state.bytes, 6);
+  }
+}
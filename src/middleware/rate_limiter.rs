@@ -1,67 +1,502 @@
-use super::{Context, Middleware};
-use crate::error_response::too_many_requests;
+use super::{Context, Middleware, MiddlewareChain};
+use crate::{bans::Bans, redis_connection::RedisConnection};
 use async_trait::async_trait;
-use hyper::{Body, Request, Response};
+use hyper::{
+  header::{HeaderValue, RETRY_AFTER},
+  Body, HeaderMap, Request, Response, StatusCode,
+};
 use linked_hash_map::LinkedHashMap;
-use std::net::SocketAddr;
-use tokio::{sync::Mutex, time::Instant};
+use log::warn;
+use redis::RedisResult;
+use std::{
+  convert::{TryFrom, TryInto},
+  fmt, io,
+  net::IpAddr,
+  sync::Mutex,
+  time::Duration,
+};
+use tokio::time::Instant;
+use toml::{value::Table, Value};
+
+const RATE_LIMIT_LIMIT: &str = "ratelimit-limit";
+const RATE_LIMIT_REMAINING: &str = "ratelimit-remaining";
+const RATE_LIMIT_RESET: &str = "ratelimit-reset";
+/* Clients are asked to retry after this time if requests are rejected because the store is unavailable */
+const STORE_FAILURE_RETRY_AFTER: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub struct RateLimiter {
-  connections: Mutex<LinkedHashMap<SocketAddr, (u64, Instant)>>,
+  store: Box<dyn RateLimitStore>,
   limit: u64,
   window_sec: u64,
+  status: StatusCode,
+  body: String,
+  bans: Option<Bans>,
+  /* Forwards requests if the store is unavailable instead of rejecting them */
+  fail_open: bool,
 }
 
 impl RateLimiter {
-  pub fn new(limit: u64, window_sec: u64) -> RateLimiter {
-    RateLimiter {
-      connections: Mutex::new(LinkedHashMap::new()),
+  async fn register_request(&self, client_ip: &IpAddr) -> Option<RateLimitWindow> {
+    match self.store.increment(client_ip, self.window_sec).await {
+      Ok(window) => Some(window),
+      Err(e) => {
+        warn!("Could not register request in rate limit store: {}", e);
+        None
+      }
+    }
+  }
+
+  fn reject(&self, retry_after: Duration) -> Response<Body> {
+    let mut response = Response::builder()
+      .status(self.status)
+      .body(Body::from(self.body.clone()))
+      .unwrap();
+    response
+      .headers_mut()
+      .insert(RETRY_AFTER, HeaderValue::from(seconds(retry_after)));
+    response
+  }
+
+  fn insert_headers(&self, headers: &mut HeaderMap, window: &RateLimitWindow) {
+    headers.insert(RATE_LIMIT_LIMIT, HeaderValue::from(self.limit));
+    headers.insert(
+      RATE_LIMIT_REMAINING,
+      HeaderValue::from(self.limit.saturating_sub(window.count)),
+    );
+    headers.insert(RATE_LIMIT_RESET, HeaderValue::from(seconds(window.reset)));
+  }
+}
+
+/// Rounds up to whole seconds, so clients don't retry too early.
+fn seconds(duration: Duration) -> u64 {
+  duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[async_trait]
+impl Middleware for RateLimiter {
+  async fn forward_request(
+    &self,
+    request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
+    let client_ip = context.client_address.ip();
+    if let Some(bans) = &self.bans {
+      if let Some(remaining_ban) = bans.remaining_ban(&client_ip, Instant::now()) {
+        return self.reject(remaining_ban);
+      }
+    }
+
+    let window = match self.register_request(&client_ip).await {
+      Some(window) => window,
+      // don't block all clients just because the store is unavailable, unless configured otherwise
+      None if self.fail_open => return chain.forward_request(request, context).await,
+      None => return self.reject(STORE_FAILURE_RETRY_AFTER),
+    };
+
+    if window.count > self.limit {
+      // count only the first rejected request of a window as a violation
+      let ban = match &self.bans {
+        Some(bans) if window.count == self.limit.saturating_add(1) => bans.record_violation(client_ip, Instant::now()),
+        _ => None,
+      };
+      let mut response = self.reject(ban.unwrap_or(window.reset));
+      self.insert_headers(response.headers_mut(), &window);
+      return response;
+    }
+
+    let mut response = chain.forward_request(request, context).await;
+    self.insert_headers(response.headers_mut(), &window);
+    response
+  }
+}
+
+impl TryFrom<Table> for RateLimiter {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    fn get_u64(t: &Table, key: &str) -> Result<Option<u64>, ()> {
+      t.get(key)
+        .map(|it| it.as_integer().and_then(|it| it.try_into().ok()).ok_or(()))
+        .transpose()
+    }
+
+    let limit = get_u64(&t, "limit")?.ok_or(())?;
+    let window_sec = get_u64(&t, "window_sec")?.ok_or(())?;
+    let max_clients = get_u64(&t, "max_clients")?
+      .map(|it| it.try_into().map_err(|_| ()))
+      .transpose()?
+      .unwrap_or(DEFAULT_MAX_CLIENTS);
+
+    let store: Box<dyn RateLimitStore> = match t.get("redis_url").and_then(Value::as_str) {
+      Some(redis_url) => {
+        let key_prefix = t
+          .get("redis_key_prefix")
+          .and_then(Value::as_str)
+          .unwrap_or("arlb:rate_limiter")
+          .to_string();
+        let timeout = Duration::from_millis(get_u64(&t, "redis_timeout_ms")?.unwrap_or(DEFAULT_REDIS_TIMEOUT_MS));
+        Box::new(RedisStore::new(redis_url, key_prefix, timeout).map_err(|_| ())?)
+      }
+      None => Box::new(LocalStore::new(max_clients)),
+    };
+
+    let status = get_u64(&t, "status")?
+      .map(|it| {
+        u16::try_from(it)
+          .ok()
+          .and_then(|it| StatusCode::from_u16(it).ok())
+          .ok_or(())
+      })
+      .transpose()?
+      .unwrap_or(StatusCode::TOO_MANY_REQUESTS);
+    let body = t
+      .get("body")
+      .and_then(Value::as_str)
+      .unwrap_or("429 - Too Many Requests")
+      .to_string();
+
+    let bans = match get_u64(&t, "ban_after")? {
+      Some(after) => {
+        let duration = Duration::from_secs(get_u64(&t, "ban_sec")?.unwrap_or(600));
+        Some(Bans::new(after, duration, max_clients))
+      }
+      None => None,
+    };
+    let fail_open = match t.get("fail_open") {
+      Some(fail_open) => fail_open.as_bool().ok_or(())?,
+      None => true,
+    };
+
+    Ok(RateLimiter {
+      store,
       limit,
       window_sec,
+      status,
+      body,
+      bans,
+      fail_open,
+    })
+  }
+}
+
+/// The number of requests of a client within the current window and the time until the window resets.
+#[derive(Debug, PartialEq)]
+pub struct RateLimitWindow {
+  pub count: u64,
+  pub reset: Duration,
+}
+
+/// Keeps track of the number of requests per client IP, so a client can't bypass the limit by opening new
+/// connections. Stores can be shared by multiple instances of the load
+/// balancer, so that running several replicas doesn't multiply the limits.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync + fmt::Debug {
+  /// Registers a request of the client and returns the number of its requests within the current window.
+  async fn increment(&self, client_ip: &IpAddr, window_sec: u64) -> io::Result<RateLimitWindow>;
+}
+
+/// Stores the requests in memory, only this instance of the load balancer can access them.
+///
+/// Requests are counted in fixed windows per client. The clients are kept in the order their windows started, so
+/// expired windows can be evicted from the front without scanning all clients. At most `max_clients` clients are
+/// tracked, if more clients send requests the clients with the oldest windows are evicted.
+#[derive(Debug)]
+pub struct LocalStore {
+  clients: Mutex<LinkedHashMap<IpAddr, (u64, Instant)>>,
+  max_clients: usize,
+}
+
+pub const DEFAULT_MAX_CLIENTS: usize = 100_000;
+
+impl Default for LocalStore {
+  fn default() -> Self {
+    LocalStore::new(DEFAULT_MAX_CLIENTS)
+  }
+}
+
+impl LocalStore {
+  pub fn new(max_clients: usize) -> LocalStore {
+    LocalStore {
+      clients: Mutex::new(LinkedHashMap::new()),
+      max_clients: max_clients.max(1),
     }
   }
 
-  async fn register_request(&self, client_address: &SocketAddr) -> bool {
-    let mut connections = self.connections.lock().await;
-    let now = Instant::now();
+  /// `now` may be slightly earlier than the start of windows registered concurrently, so the elapsed time saturates
+  /// at zero instead of panicking.
+  fn increment_at(&self, client_ip: &IpAddr, window: Duration, now: Instant) -> RateLimitWindow {
+    let mut clients = self.clients.lock().unwrap();
 
-    let old_entries = connections
-      .iter()
-      // Due to temporal order in LinkedHashMap stopping early is possible
-      .take_while(|(_client_address, (_count, time))| now.duration_since(*time).as_secs() > self.window_sec)
-      .map(|(client_address, _)| *client_address)
-      .collect::<Vec<_>>();
-    for client_address in old_entries {
-      connections.remove(&client_address);
+    while let Some((_, (_, window_start))) = clients.front() {
+      if now.saturating_duration_since(*window_start) < window {
+        break;
+      }
+      clients.pop_front();
+    }
+
+    if let Some((count, window_start)) = clients.get_mut(client_ip) {
+      // Prevent overflow
+      *count = count.saturating_add(1);
+      return RateLimitWindow {
+        count: *count,
+        reset: window.saturating_sub(now.saturating_duration_since(*window_start)),
+      };
     }
 
-    // Remove and reinsert to ensure temporal order in LinkedHashMap
-    let mut count = connections
-      .remove(client_address)
-      .map(|(count, _time)| count)
-      .unwrap_or(0);
-    // Prevent overflow
-    if count < u64::MAX {
-      count += 1;
+    if clients.len() >= self.max_clients {
+      clients.pop_front();
+    }
+    clients.insert(*client_ip, (1, now));
+    RateLimitWindow {
+      count: 1,
+      reset: window,
     }
-    connections.insert(*client_address, (count, now));
+  }
+}
 
-    count <= self.limit
+#[async_trait]
+impl RateLimitStore for LocalStore {
+  async fn increment(&self, client_ip: &IpAddr, window_sec: u64) -> io::Result<RateLimitWindow> {
+    Ok(self.increment_at(client_ip, Duration::from_secs(window_sec), Instant::now()))
+  }
+}
+
+pub const DEFAULT_REDIS_TIMEOUT_MS: u64 = 100;
+
+/// Stores the requests in Redis, so that all instances of the load balancer connected to the same Redis share them.
+/// Requests are counted in fixed windows.
+#[derive(Debug)]
+pub struct RedisStore {
+  connection: RedisConnection,
+  key_prefix: String,
+}
+
+impl RedisStore {
+  pub fn new(url: &str, key_prefix: String, timeout: Duration) -> RedisResult<RedisStore> {
+    Ok(RedisStore {
+      connection: RedisConnection::new(url, timeout)?,
+      key_prefix,
+    })
   }
 }
 
 #[async_trait]
-impl Middleware for RateLimiter {
-  async fn modify_request(
-    &self,
-    request: Request<Body>,
-    context: &Context<'_>,
-  ) -> Result<Request<Body>, Response<Body>> {
-    if self.register_request(context.client_address).await {
-      Ok(request)
-    } else {
-      Err(too_many_requests())
-    }
+impl RateLimitStore for RedisStore {
+  async fn increment(&self, client_ip: &IpAddr, window_sec: u64) -> io::Result<RateLimitWindow> {
+    let key = format!("{}:{}", self.key_prefix, client_ip);
+    let pipeline = redis::pipe()
+      .atomic()
+      // Only the first request of a window creates the key, so the window is not extended by later requests
+      .cmd("SET")
+      .arg(&key)
+      .arg(0)
+      .arg("EX")
+      .arg(window_sec.max(1))
+      .arg("NX")
+      .ignore()
+      .incr(&key, 1)
+      .ttl(&key)
+      .clone();
+    let (count, ttl) = self.connection.query::<(u64, i64)>(&pipeline).await?;
+    Ok(RateLimitWindow {
+      count,
+      reset: Duration::from_secs(ttl.max(0) as u64),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    http_client::StrategyNotifyHttpConnector,
+    load_balancing::{random::Random, LoadBalancingStrategy},
+    log_redaction::LogRedaction,
+    server::Scheme,
+  };
+  use hyper::Client;
+  use std::sync::Arc;
+  use tokio::net::TcpListener;
+
+  #[tokio::test]
+  async fn test_local_store_counts_per_client() {
+    // given:
+    let store = LocalStore::default();
+    let client = "127.0.0.1".parse().unwrap();
+    let other_client = "127.0.0.2".parse().unwrap();
+
+    // when:
+    store.increment(&client, 10).await.unwrap();
+    let actual = store.increment(&client, 10).await.unwrap();
+    let other_actual = store.increment(&other_client, 10).await.unwrap();
+
+    // then:
+    assert_eq!(actual.count, 2);
+    assert_eq!(other_actual.count, 1);
+  }
+
+  #[tokio::test]
+  async fn test_rate_limiter_counts_connections_of_same_ip() {
+    // given:
+    let rate_limiter = RateLimiter::try_from(toml::from_str::<Table>("limit = 1\nwindow_sec = 10").unwrap()).unwrap();
+    let strategy: Arc<Box<dyn LoadBalancingStrategy>> = Arc::new(Box::new(Random::new()));
+    let client = Client::builder().build(StrategyNotifyHttpConnector::new(strategy));
+    let log_redaction = LogRedaction::default();
+    let client_address = "127.0.0.1:3000".parse().unwrap();
+    let other_port = "127.0.0.1:3001".parse().unwrap();
+    let context = Context {
+      client_scheme: &Scheme::HTTP,
+      client_address: &client_address,
+      backend_uri: "http://127.0.0.1:1".parse().unwrap(),
+      client: &client,
+      max_buffer_bytes: 64,
+      log_redaction: &log_redaction,
+      tls_fingerprint: None,
+      hedge: None,
+    };
+    let request = || Request::get("/").body(Body::empty()).unwrap();
+    rate_limiter
+      .forward_request(request(), &MiddlewareChain::Empty, &context)
+      .await;
+
+    // when: the client opens a new connection from another port
+    let other_context = Context {
+      client_address: &other_port,
+      ..context
+    };
+    let actual = rate_limiter
+      .forward_request(request(), &MiddlewareChain::Empty, &other_context)
+      .await;
+
+    // then:
+    assert_eq!(actual.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(actual.headers().get(RATE_LIMIT_REMAINING).unwrap(), "0");
+  }
+
+  #[test]
+  fn test_local_store_resets_expired_window() {
+    // given:
+    let store = LocalStore::default();
+    let client = "127.0.0.1".parse().unwrap();
+    let window = Duration::from_secs(10);
+    let start = Instant::now();
+    store.increment_at(&client, window, start);
+    let before_reset = store.increment_at(&client, window, start + Duration::from_secs(9));
+
+    // when:
+    let actual = store.increment_at(&client, window, start + Duration::from_secs(10));
+
+    // then:
+    assert_eq!(
+      before_reset,
+      RateLimitWindow {
+        count: 2,
+        reset: Duration::from_secs(1)
+      }
+    );
+    assert_eq!(actual.count, 1);
+    assert_eq!(store.clients.lock().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_local_store_tolerates_window_started_later() {
+    // given:
+    let store = LocalStore::default();
+    let client = "127.0.0.1".parse().unwrap();
+    let window = Duration::from_secs(10);
+    let start = Instant::now() + Duration::from_secs(1);
+    store.increment_at(&client, window, start);
+
+    // when:
+    let actual = store.increment_at(&client, window, start - Duration::from_secs(1));
+
+    // then:
+    assert_eq!(
+      actual,
+      RateLimitWindow {
+        count: 2,
+        reset: window
+      }
+    );
+  }
+
+  #[test]
+  fn test_local_store_evicts_oldest_client_when_full() {
+    // given:
+    let store = LocalStore::new(2);
+    let window = Duration::from_secs(10);
+    let now = Instant::now();
+    let clients = ["127.0.0.1", "127.0.0.2", "127.0.0.3"]
+      .iter()
+      .map(|it| it.parse().unwrap())
+      .collect::<Vec<IpAddr>>();
+    store.increment_at(&clients[0], window, now);
+    store.increment_at(&clients[1], window, now);
+
+    // when:
+    store.increment_at(&clients[2], window, now);
+
+    // then:
+    let tracked = store.clients.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+    assert_eq!(tracked, vec![clients[1], clients[2]]);
+  }
+
+  #[test]
+  fn test_try_from_custom_response() {
+    // given:
+    let table = toml::from_str::<Table>("limit = 2\nwindow_sec = 1\nstatus = 503\nbody = 'slow down'").unwrap();
+
+    // when:
+    let actual = RateLimiter::try_from(table).unwrap();
+
+    // then:
+    let response = actual.reject(Duration::from_millis(1500));
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "2");
+    assert!(actual.bans.is_none());
+  }
+
+  #[tokio::test]
+  async fn test_unavailable_redis_store() {
+    // given: a port nothing listens on
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let redis_url = format!("redis://{}", listener.local_addr().unwrap());
+    drop(listener);
+    let rate_limiter = |fail_open: bool| {
+      let toml = format!(
+        "limit = 2\nwindow_sec = 1\nredis_url = '{}'\nfail_open = {}",
+        redis_url, fail_open
+      );
+      RateLimiter::try_from(toml::from_str::<Table>(&toml).unwrap()).unwrap()
+    };
+    let strategy: Arc<Box<dyn LoadBalancingStrategy>> = Arc::new(Box::new(Random::new()));
+    let client = Client::builder().build(StrategyNotifyHttpConnector::new(strategy));
+    let client_address = "127.0.0.1:3000".parse().unwrap();
+    let context = Context {
+      client_scheme: &Scheme::HTTP,
+      client_address: &client_address,
+      backend_uri: redis_url.replacen("redis", "http", 1).parse().unwrap(),
+      client: &client,
+      max_buffer_bytes: 64,
+      log_redaction: &LogRedaction::default(),
+      tls_fingerprint: None,
+      hedge: None,
+    };
+    let request = || Request::get("/").body(Body::empty()).unwrap();
+
+    // when:
+    let closed = rate_limiter(false)
+      .forward_request(request(), &MiddlewareChain::Empty, &context)
+      .await;
+    let open = rate_limiter(true)
+      .forward_request(request(), &MiddlewareChain::Empty, &context)
+      .await;
+
+    // then: the open rate limiter forwards the request, but nothing listens on the backend either
+    assert_eq!(closed.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(closed.headers().get(RETRY_AFTER).unwrap(), "1");
+    assert_eq!(open.status(), StatusCode::SERVICE_UNAVAILABLE);
   }
 }
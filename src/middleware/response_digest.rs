@@ -0,0 +1,392 @@
+use super::{buffer_body, BufferedBody, Context, Middleware, MiddlewareChain};
+use crate::error_response::bad_gateway;
+use async_trait::async_trait;
+use hyper::{
+  body::HttpBody,
+  header::{HeaderName, HeaderValue, CONTENT_LENGTH, TE, TRAILER},
+  Body, HeaderMap, Method, Request, Response, StatusCode, Version,
+};
+use log::{debug, error};
+use openssl::base64;
+use ring::digest;
+use std::convert::TryFrom;
+use toml::value::Table;
+
+const REPR_DIGEST: &str = "repr-digest";
+const CONTENT_DIGEST: &str = "content-digest";
+const LEGACY_DIGEST: &str = "digest";
+
+/// Adds the digest of the response body as `Repr-Digest` header, see https://www.rfc-editor.org/rfc/rfc9530, so
+/// clients can verify the integrity of the response. Partial responses get a `Content-Digest` of the sent range
+/// instead.
+///
+/// HTTP/2 clients accepting trailers (`TE: trailers`) receive the digest as trailer, which is computed while the body
+/// is streamed. For all other clients, the body is buffered to compute the digest before sending the header, so
+/// bodies larger than the buffer limit of the pool get no digest.
+#[derive(Debug, Clone, Copy)]
+pub struct ResponseDigest {
+  algorithm: Algorithm,
+  /* Additionally sends the obsolete `Digest` header of RFC 3230 for older clients */
+  legacy: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+  Sha256,
+  Sha512,
+}
+
+impl Algorithm {
+  fn ring(self) -> &'static digest::Algorithm {
+    match self {
+      Algorithm::Sha256 => &digest::SHA256,
+      Algorithm::Sha512 => &digest::SHA512,
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      Algorithm::Sha256 => "sha-256",
+      Algorithm::Sha512 => "sha-512",
+    }
+  }
+}
+
+impl Default for ResponseDigest {
+  fn default() -> Self {
+    ResponseDigest {
+      algorithm: Algorithm::Sha256,
+      legacy: false,
+    }
+  }
+}
+
+impl TryFrom<Table> for ResponseDigest {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let defaults = ResponseDigest::default();
+    let algorithm = match t.get("algorithm").map(|it| it.as_str()) {
+      Some(Some("sha-256")) => Algorithm::Sha256,
+      Some(Some("sha-512")) => Algorithm::Sha512,
+      Some(_) => return Err(()),
+      None => defaults.algorithm,
+    };
+    let legacy = match t.get("legacy_digest") {
+      Some(legacy) => legacy.as_bool().ok_or(())?,
+      None => defaults.legacy,
+    };
+    Ok(ResponseDigest { algorithm, legacy })
+  }
+}
+
+#[async_trait]
+impl Middleware for ResponseDigest {
+  async fn forward_request(
+    &self,
+    request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
+    let method = request.method().clone();
+    let trailers = accepts_trailers(&request);
+    let response = chain.forward_request(request, context).await;
+    self
+      .digest_response(&method, trailers, response, context.max_buffer_bytes)
+      .await
+  }
+
+  fn buffers_response(&self) -> bool {
+    true
+  }
+}
+
+impl ResponseDigest {
+  async fn digest_response(
+    &self,
+    method: &Method,
+    trailers: bool,
+    response: Response<Body>,
+    max_buffer_bytes: usize,
+  ) -> Response<Body> {
+    if !has_digestible_body(method, &response) {
+      return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let partial = parts.status == StatusCode::PARTIAL_CONTENT;
+    if trailers {
+      let field = if partial { CONTENT_DIGEST } else { REPR_DIGEST };
+      parts.headers.append(TRAILER, HeaderValue::from_static(field));
+      return Response::from_parts(parts, self.digest_trailers(body, partial));
+    }
+
+    let exceeds = parts
+      .headers
+      .get(CONTENT_LENGTH)
+      .and_then(|it| it.to_str().ok()?.parse::<usize>().ok())
+      .map_or(false, |length| length > max_buffer_bytes);
+    if exceeds {
+      return Response::from_parts(parts, body);
+    }
+    let bytes = match buffer_body(body, max_buffer_bytes).await {
+      Ok(BufferedBody::Complete(bytes)) => bytes,
+      Ok(BufferedBody::TooLarge(body)) => {
+        debug!(
+          "Sending response body larger than {} bytes without digest",
+          max_buffer_bytes
+        );
+        return Response::from_parts(parts, body);
+      }
+      Err(e) => {
+        error!("Could not read response body to compute the digest: {}", e);
+        return bad_gateway();
+      }
+    };
+    let hash = digest::digest(self.algorithm.ring(), &bytes);
+    self.insert_digest(&mut parts.headers, partial, hash.as_ref());
+    Response::from_parts(parts, Body::from(bytes))
+  }
+
+  /// Streams the `body` while hashing it and sends the digest as trailer once the body is complete.
+  fn digest_trailers(&self, mut body: Body, partial: bool) -> Body {
+    let (mut sender, streamed) = Body::channel();
+    let middleware = *self;
+    tokio::spawn(async move {
+      let mut context = digest::Context::new(middleware.algorithm.ring());
+      while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+          Ok(chunk) => chunk,
+          Err(e) => {
+            error!("Could not read response body to compute the digest: {}", e);
+            sender.abort();
+            return;
+          }
+        };
+        context.update(&chunk);
+        if sender.send_data(chunk).await.is_err() {
+          /* the client went away */
+          return;
+        }
+      }
+      let mut trailers = HeaderMap::new();
+      middleware.insert_digest(&mut trailers, partial, context.finish().as_ref());
+      let _ = sender.send_trailers(trailers).await;
+    });
+    streamed
+  }
+
+  fn insert_digest(&self, headers: &mut HeaderMap, partial: bool, hash: &[u8]) {
+    let encoded = base64::encode_block(hash);
+    let field = if partial { CONTENT_DIGEST } else { REPR_DIGEST };
+    let value = format!("{}=:{}:", self.algorithm.name(), encoded);
+    headers.insert(HeaderName::from_static(field), HeaderValue::from_str(&value).unwrap());
+    /* the legacy digest covers the whole representation, not the sent range */
+    if self.legacy && !partial {
+      let value = format!("{}={}", self.algorithm.name().to_ascii_uppercase(), encoded);
+      headers.insert(
+        HeaderName::from_static(LEGACY_DIGEST),
+        HeaderValue::from_str(&value).unwrap(),
+      );
+    }
+  }
+}
+
+/// Responses to `HEAD` requests and responses without content have nothing to digest. Digests of the backend server
+/// are passed on unchanged.
+fn has_digestible_body(method: &Method, response: &Response<Body>) -> bool {
+  let status = response.status();
+  let headers = response.headers();
+  method != Method::HEAD
+    && !status.is_informational()
+    && status != StatusCode::NO_CONTENT
+    && status != StatusCode::NOT_MODIFIED
+    && !headers.contains_key(REPR_DIGEST)
+    && !headers.contains_key(CONTENT_DIGEST)
+}
+
+/// hyper sends trailers over HTTP/2 only, and only clients announcing `TE: trailers` may be sent some, see
+/// https://tools.ietf.org/html/rfc7230#section-4.3
+fn accepts_trailers(request: &Request<Body>) -> bool {
+  request.version() == Version::HTTP_2
+    && request
+      .headers()
+      .get_all(TE)
+      .iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','))
+      .any(|coding| {
+        coding
+          .split(';')
+          .next()
+          .unwrap_or_default()
+          .trim()
+          .eq_ignore_ascii_case("trailers")
+      })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const HELLO_SHA256: &str = "sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:";
+
+  fn response_digest(toml: &str) -> ResponseDigest {
+    ResponseDigest::try_from(toml::from_str::<Table>(toml).unwrap()).unwrap()
+  }
+
+  fn response(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder().status(status).body(Body::from(body)).unwrap()
+  }
+
+  #[tokio::test]
+  async fn adds_repr_digest_header() {
+    // given:
+    let middleware = response_digest("");
+
+    // when:
+    let actual = middleware
+      .digest_response(&Method::GET, false, response(StatusCode::OK, "hello"), 1024)
+      .await;
+
+    // then:
+    assert_eq!(actual.headers()[REPR_DIGEST], HELLO_SHA256);
+    assert!(actual.headers().get(LEGACY_DIGEST).is_none());
+    let body = hyper::body::to_bytes(actual.into_body()).await.unwrap();
+    assert_eq!(body, "hello");
+  }
+
+  #[tokio::test]
+  async fn adds_legacy_digest_with_configured_algorithm() {
+    // given:
+    let middleware = response_digest("algorithm = 'sha-512'\nlegacy_digest = true");
+
+    // when:
+    let actual = middleware
+      .digest_response(&Method::GET, false, response(StatusCode::OK, "hello"), 1024)
+      .await;
+
+    // then:
+    let expected = "m3HSJL1i83hdltRq0+o9czGb+8KJDKra4t/3JRlnPKcjI8PZm6XBHXx6zG4UuMXaDEZjR1wuXDre9G9zvN7AQw==";
+    assert_eq!(
+      actual.headers()[REPR_DIGEST],
+      format!("sha-512=:{}:", expected).as_str()
+    );
+    assert_eq!(
+      actual.headers()[LEGACY_DIGEST],
+      format!("SHA-512={}", expected).as_str()
+    );
+  }
+
+  #[tokio::test]
+  async fn digests_the_content_of_partial_responses() {
+    // given:
+    let middleware = response_digest("legacy_digest = true");
+
+    // when:
+    let actual = middleware
+      .digest_response(
+        &Method::GET,
+        false,
+        response(StatusCode::PARTIAL_CONTENT, "hello"),
+        1024,
+      )
+      .await;
+
+    // then:
+    assert_eq!(actual.headers()[CONTENT_DIGEST], HELLO_SHA256);
+    assert!(actual.headers().get(REPR_DIGEST).is_none());
+    assert!(actual.headers().get(LEGACY_DIGEST).is_none());
+  }
+
+  #[tokio::test]
+  async fn skips_head_requests_existing_digests_and_large_bodies() {
+    // given:
+    let middleware = response_digest("");
+    let mut digested = response(StatusCode::OK, "hello");
+    digested
+      .headers_mut()
+      .insert(REPR_DIGEST, HeaderValue::from_static("sha-256=:AAAA:"));
+
+    // when:
+    let head = middleware
+      .digest_response(&Method::HEAD, false, response(StatusCode::OK, ""), 1024)
+      .await;
+    let existing = middleware.digest_response(&Method::GET, false, digested, 1024).await;
+    let large = middleware
+      .digest_response(&Method::GET, false, response(StatusCode::OK, "hello"), 4)
+      .await;
+
+    // then:
+    assert!(head.headers().get(REPR_DIGEST).is_none());
+    assert_eq!(existing.headers()[REPR_DIGEST], "sha-256=:AAAA:");
+    assert!(large.headers().get(REPR_DIGEST).is_none());
+    let body = hyper::body::to_bytes(large.into_body()).await.unwrap();
+    assert_eq!(body, "hello");
+  }
+
+  #[tokio::test]
+  async fn sends_digest_of_streamed_body_as_trailer() {
+    // given:
+    let middleware = response_digest("");
+
+    // when:
+    let actual = middleware
+      .digest_response(&Method::GET, true, response(StatusCode::OK, "hello"), 4)
+      .await;
+
+    // then:
+    assert_eq!(actual.headers()[TRAILER], REPR_DIGEST);
+    assert!(actual.headers().get(REPR_DIGEST).is_none());
+    let mut body = actual.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+      bytes.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(bytes, b"hello");
+    let trailers = body.trailers().await.unwrap().unwrap();
+    assert_eq!(trailers[REPR_DIGEST], HELLO_SHA256);
+  }
+
+  #[tokio::test]
+  async fn answers_with_bad_gateway_if_the_body_can_not_be_read() {
+    // given:
+    let middleware = response_digest("");
+    let body = Body::wrap_stream(futures::stream::iter(vec![
+      Ok("hel"),
+      Err(std::io::Error::new(std::io::ErrorKind::Other, "connection reset")),
+    ]));
+    let response = Response::builder().header(CONTENT_LENGTH, "5").body(body).unwrap();
+
+    // when:
+    let actual = middleware.digest_response(&Method::GET, false, response, 1024).await;
+
+    // then:
+    assert_eq!(actual.status(), StatusCode::BAD_GATEWAY);
+    assert!(actual.headers().get(REPR_DIGEST).is_none());
+  }
+
+  #[test]
+  fn accepts_trailers_of_http2_clients_announcing_them() {
+    let request = |version: Version, te: &str| {
+      Request::builder()
+        .version(version)
+        .header(TE, te)
+        .body(Body::empty())
+        .unwrap()
+    };
+
+    assert!(accepts_trailers(&request(Version::HTTP_2, "trailers")));
+    assert!(accepts_trailers(&request(Version::HTTP_2, "gzip, Trailers")));
+    assert!(!accepts_trailers(&request(Version::HTTP_2, "gzip")));
+    assert!(!accepts_trailers(&request(Version::HTTP_11, "trailers")));
+  }
+
+  #[test]
+  fn rejects_invalid_configurations() {
+    let parse = |toml: &str| ResponseDigest::try_from(toml::from_str::<Table>(toml).unwrap());
+
+    assert!(parse("algorithm = 'md5'").is_err());
+    assert!(parse("legacy_digest = 'yes'").is_err());
+  }
+}
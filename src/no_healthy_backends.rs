@@ -0,0 +1,112 @@
+use crate::error_response::bad_gateway;
+use hyper::{
+  header::{HeaderValue, CONTENT_TYPE, LOCATION},
+  Body, Response, StatusCode,
+};
+
+/// Decides how a [`BackendPool`](crate::server::BackendPool) handles requests while none of its backend servers is
+/// healthy.
+#[derive(Debug)]
+pub struct NoHealthyBackends {
+  /// Forward requests to slow backend servers before resorting to the `response`
+  pub use_slow_backends: bool,
+  pub response: NoHealthyBackendsResponse,
+}
+
+impl Default for NoHealthyBackends {
+  fn default() -> Self {
+    NoHealthyBackends {
+      use_slow_backends: true,
+      response: NoHealthyBackendsResponse::BadGateway,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum NoHealthyBackendsResponse {
+  BadGateway,
+  StaticPage {
+    status: StatusCode,
+    content_type: HeaderValue,
+    body: String,
+  },
+  Redirect {
+    location: HeaderValue,
+  },
+  /// Forward requests to any backend server, regardless of its health
+  FailOpen,
+}
+
+impl NoHealthyBackendsResponse {
+  /// Returns the response to send instead of forwarding the request, or `None` if the request should be forwarded
+  /// anyway.
+  pub fn respond(&self) -> Option<Response<Body>> {
+    match self {
+      NoHealthyBackendsResponse::BadGateway => Some(bad_gateway()),
+      NoHealthyBackendsResponse::StaticPage {
+        status,
+        content_type,
+        body,
+      } => Some(
+        Response::builder()
+          .status(*status)
+          .header(CONTENT_TYPE, content_type)
+          .body(Body::from(body.clone()))
+          .unwrap(),
+      ),
+      NoHealthyBackendsResponse::Redirect { location } => Some(
+        Response::builder()
+          .status(StatusCode::FOUND)
+          .header(LOCATION, location)
+          .body(Body::empty())
+          .unwrap(),
+      ),
+      NoHealthyBackendsResponse::FailOpen => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::body;
+
+  #[tokio::test]
+  async fn static_page_is_served_with_configured_status() {
+    // given:
+    let response = NoHealthyBackendsResponse::StaticPage {
+      status: StatusCode::SERVICE_UNAVAILABLE,
+      content_type: HeaderValue::from_static("text/html"),
+      body: "<h1>All backends are down</h1>".to_string(),
+    };
+
+    // when:
+    let actual = response.respond().unwrap();
+
+    // then:
+    assert_eq!(actual.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(actual.headers()[CONTENT_TYPE], "text/html");
+    let body = body::to_bytes(actual.into_body()).await.unwrap();
+    assert_eq!(body, "<h1>All backends are down</h1>");
+  }
+
+  #[test]
+  fn redirect_points_to_location() {
+    // given:
+    let response = NoHealthyBackendsResponse::Redirect {
+      location: HeaderValue::from_static("https://status.example.org"),
+    };
+
+    // when:
+    let actual = response.respond().unwrap();
+
+    // then:
+    assert_eq!(actual.status(), StatusCode::FOUND);
+    assert_eq!(actual.headers()[LOCATION], "https://status.example.org");
+  }
+
+  #[test]
+  fn fail_open_forwards_the_request() {
+    assert!(NoHealthyBackendsResponse::FailOpen.respond().is_none());
+  }
+}
@@ -0,0 +1,357 @@
+use hyper::{
+  header::{HeaderValue, CONTENT_LENGTH, HOST, TRANSFER_ENCODING},
+  http::uri::{Authority, PathAndQuery, Uri},
+  Body, Request, Version,
+};
+use std::{convert::TryFrom, str};
+
+/// Normalizes the path of the request's URI in place, see [normalize_path].
+/// The asterisk form of `OPTIONS * HTTP/1.1` has no path and is kept as is.
+/// Returns `Err` if the path is malformed or escapes the root.
+pub fn normalize_request(request: &mut Request<Body>) -> Result<(), ()> {
+  if request.uri() == "*" {
+    return Ok(());
+  }
+  let path = normalize_path(request.uri().path())?;
+  if path == request.uri().path() {
+    return Ok(());
+  }
+
+  let path_and_query = match request.uri().query() {
+    Some(query) => format!("{}?{}", path, query),
+    None => path,
+  };
+  let mut parts = request.uri().clone().into_parts();
+  parts.path_and_query = Some(PathAndQuery::try_from(path_and_query.as_str()).map_err(|_| ())?);
+  *request.uri_mut() = Uri::from_parts(parts).map_err(|_| ())?;
+  Ok(())
+}
+
+/// The host as received from the client, stored in the request's extensions by [normalize_host] if lowercasing
+/// changed it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OriginalHost(pub String);
+
+/// Normalizes the target host of the request, so matchers and middlewares can rely on the `Host` header:
+/// the authority of absolute-form URIs (e.g. `GET http://example.org/ HTTP/1.1`) replaces the `Host` header, as
+/// required by https://tools.ietf.org/html/rfc7230#section-5.4, and the host is lowercased.
+/// Returns `Err` if an HTTP/1.1 request has no `Host` header, several ones or a malformed one.
+pub fn normalize_host(request: &mut Request<Body>) -> Result<(), ()> {
+  let authority = match request.uri().authority() {
+    Some(authority) => authority.clone(),
+    None => {
+      let mut hosts = request.headers().get_all(HOST).iter();
+      match (hosts.next(), hosts.next()) {
+        (Some(host), None) => Authority::try_from(host.as_bytes()).map_err(|_| ())?,
+        (None, _) if request.version() < Version::HTTP_11 => return Ok(()),
+        _ => return Err(()),
+      }
+    }
+  };
+  let lowercase = authority.as_str().to_ascii_lowercase();
+  let host = HeaderValue::from_str(&lowercase).map_err(|_| ())?;
+  request.headers_mut().insert(HOST, host);
+  if lowercase != authority.as_str() {
+    request.extensions_mut().insert(OriginalHost(authority.to_string()));
+  }
+  Ok(())
+}
+
+/// Rejects requests whose body length is ambiguous. A backend server determining the length differently than the load
+/// balancer would see the rest of the body as another request, which smuggles it past all checks, see
+/// https://tools.ietf.org/html/rfc7230#section-3.3.3
+/// Only a single `Transfer-Encoding: chunked` of an HTTP/1.1 request is accepted, and not together with a
+/// `Content-Length`. Repeated identical `Content-Length` values are merged into one.
+/// Returns `Err` for other transfer codings, obfuscated ones like `chunked, identity` and differing or malformed
+/// `Content-Length` values.
+pub fn normalize_framing(request: &mut Request<Body>) -> Result<(), ()> {
+  let headers = request.headers();
+  let has_content_length = headers.contains_key(CONTENT_LENGTH);
+  let mut transfer_encodings = headers.get_all(TRANSFER_ENCODING).iter();
+  match (transfer_encodings.next(), transfer_encodings.next()) {
+    (None, _) => {}
+    (Some(transfer_encoding), None)
+      if request.version() == Version::HTTP_11
+        && transfer_encoding.as_bytes().eq_ignore_ascii_case(b"chunked")
+        && !has_content_length =>
+    {
+      return Ok(())
+    }
+    _ => return Err(()),
+  }
+  if !has_content_length {
+    return Ok(());
+  }
+
+  let mut lengths = headers
+    .get_all(CONTENT_LENGTH)
+    .iter()
+    .flat_map(|value| value.as_bytes().split(|byte| *byte == b','))
+    .map(parse_content_length);
+  let length = lengths.next().ok_or(())??;
+  for other in lengths {
+    if other? != length {
+      return Err(());
+    }
+  }
+  request.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(length));
+  Ok(())
+}
+
+/* Parses a Content-Length value consisting of digits only, surrounded by optional whitespace */
+fn parse_content_length(value: &[u8]) -> Result<u64, ()> {
+  let digits = str::from_utf8(value)
+    .map_err(|_| ())?
+    .trim_matches(|it| it == ' ' || it == '\t');
+  if digits.is_empty() || !digits.bytes().all(|it| it.is_ascii_digit()) {
+    return Err(());
+  }
+  digits.parse().map_err(|_| ())
+}
+
+/// Normalizes a request path by decoding percent-encoded unreserved characters, collapsing duplicate slashes and
+/// resolving `.` and `..` segments. Returns `Err` if the path contains an invalid percent-encoding or a `..` segment
+/// would escape the root.
+pub fn normalize_path(path: &str) -> Result<String, ()> {
+  let decoded = decode_unreserved(path)?;
+
+  let mut segments: Vec<&str> = Vec::new();
+  for segment in decoded.split('/').skip(1) {
+    match segment {
+      "" | "." => {}
+      ".." => {
+        segments.pop().ok_or(())?;
+      }
+      _ => segments.push(segment),
+    }
+  }
+
+  let mut normalized = format!("/{}", segments.join("/"));
+  // keep a trailing slash, because backend servers may treat `/dir` and `/dir/` differently
+  let last_segment = decoded.rsplit('/').next().unwrap_or_default();
+  if !segments.is_empty() && matches!(last_segment, "" | "." | "..") {
+    normalized.push('/');
+  }
+  Ok(normalized)
+}
+
+/// Decodes percent-encoded unreserved characters (rf https://tools.ietf.org/html/rfc3986#section-2.3) and uppercases
+/// the hex digits of all other percent-encodings.
+fn decode_unreserved(path: &str) -> Result<String, ()> {
+  let bytes = path.as_bytes();
+  let mut decoded = String::with_capacity(path.len());
+  let mut index = 0;
+  while index < bytes.len() {
+    if bytes[index] == b'%' {
+      let hex = bytes.get(index + 1..index + 3).ok_or(())?;
+      let hex = str::from_utf8(hex).map_err(|_| ())?;
+      let byte = u8::from_str_radix(hex, 16).map_err(|_| ())?;
+      if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+        decoded.push(byte as char);
+      } else {
+        decoded.push_str(&format!("%{:02X}", byte));
+      }
+      index += 3;
+    } else {
+      // everything except '%' is ASCII in a valid request path, so the char boundaries match the byte indices
+      let next = path[index..].chars().next().ok_or(())?;
+      decoded.push(next);
+      index += next.len_utf8();
+    }
+  }
+  Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::Method;
+
+  #[test]
+  fn normalize_path_collapses_slashes() {
+    assert_eq!(normalize_path("//api///users"), Ok("/api/users".to_string()));
+    assert_eq!(normalize_path("/api/users//"), Ok("/api/users/".to_string()));
+    assert_eq!(normalize_path("/"), Ok("/".to_string()));
+  }
+
+  #[test]
+  fn normalize_path_resolves_dot_segments() {
+    assert_eq!(normalize_path("/api/./users"), Ok("/api/users".to_string()));
+    assert_eq!(normalize_path("/api/admin/../users"), Ok("/api/users".to_string()));
+    assert_eq!(normalize_path("/api/users/.."), Ok("/api/".to_string()));
+    assert_eq!(normalize_path("/api/.."), Ok("/".to_string()));
+  }
+
+  #[test]
+  fn normalize_path_decodes_unreserved_characters() {
+    assert_eq!(normalize_path("/%61pi/%7euser"), Ok("/api/~user".to_string()));
+    assert_eq!(normalize_path("/a%2fb%20c"), Ok("/a%2Fb%20c".to_string()));
+    assert_eq!(normalize_path("/api/%2E%2E/admin"), Ok("/admin".to_string()));
+  }
+
+  #[test]
+  fn normalize_path_rejects_invalid_paths() {
+    assert_eq!(normalize_path("/../etc/passwd"), Err(()));
+    assert_eq!(normalize_path("/api/../../etc"), Err(()));
+    assert_eq!(normalize_path("/%2e%2e/etc"), Err(()));
+    assert_eq!(normalize_path("/api%"), Err(()));
+    assert_eq!(normalize_path("/api%zz"), Err(()));
+  }
+
+  #[test]
+  fn normalize_request_keeps_query() {
+    // given:
+    let mut request = Request::builder()
+      .uri("http://whoami.localhost//api/./users?name=../x")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let result = normalize_request(&mut request);
+
+    // then:
+    assert_eq!(result, Ok(()));
+    assert_eq!(request.uri(), "http://whoami.localhost/api/users?name=../x");
+  }
+
+  #[test]
+  fn normalize_request_keeps_asterisk_form() {
+    // given:
+    let mut request = Request::builder()
+      .method(Method::OPTIONS)
+      .uri("*")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let result = normalize_request(&mut request);
+
+    // then:
+    assert_eq!(result, Ok(()));
+    assert_eq!(request.uri(), "*");
+  }
+
+  #[test]
+  fn normalize_host_uses_authority_of_absolute_uri() {
+    // given:
+    let mut request = Request::builder()
+      .uri("http://WhoAmI.localhost:8080/api")
+      .header(HOST, "other.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let result = normalize_host(&mut request);
+
+    // then:
+    assert_eq!(result, Ok(()));
+    assert_eq!(request.headers()[HOST], "whoami.localhost:8080");
+    assert_eq!(
+      request.extensions().get::<OriginalHost>(),
+      Some(&OriginalHost("WhoAmI.localhost:8080".to_string()))
+    );
+  }
+
+  #[test]
+  fn normalize_host_keeps_no_original_of_lowercase_hosts() {
+    // given:
+    let mut request = Request::builder()
+      .uri("/api")
+      .header(HOST, "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let result = normalize_host(&mut request);
+
+    // then:
+    assert_eq!(result, Ok(()));
+    assert!(request.extensions().get::<OriginalHost>().is_none());
+  }
+
+  #[test]
+  fn normalize_host_rejects_missing_or_malformed_host() {
+    let mut missing = Request::builder().uri("/api").body(Body::empty()).unwrap();
+    let mut duplicated = Request::builder()
+      .uri("/api")
+      .header(HOST, "whoami.localhost")
+      .header(HOST, "other.localhost")
+      .body(Body::empty())
+      .unwrap();
+    let mut malformed = Request::builder()
+      .uri("/api")
+      .header(HOST, "whoami.localhost/admin")
+      .body(Body::empty())
+      .unwrap();
+
+    assert_eq!(normalize_host(&mut missing), Err(()));
+    assert_eq!(normalize_host(&mut duplicated), Err(()));
+    assert_eq!(normalize_host(&mut malformed), Err(()));
+  }
+
+  #[test]
+  fn normalize_host_accepts_http_10_without_host() {
+    // given:
+    let mut request = Request::builder()
+      .version(Version::HTTP_10)
+      .uri("/api")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let result = normalize_host(&mut request);
+
+    // then:
+    assert_eq!(result, Ok(()));
+    assert!(request.headers().get(HOST).is_none());
+  }
+
+  fn framed_request(version: Version, headers: &[(&str, &str)]) -> Request<Body> {
+    let mut request = Request::builder().version(version).uri("/api");
+    for (name, value) in headers {
+      request = request.header(*name, *value);
+    }
+    request.body(Body::empty()).unwrap()
+  }
+
+  #[test]
+  fn normalize_framing_accepts_unambiguous_lengths() {
+    let mut chunked = framed_request(Version::HTTP_11, &[("transfer-encoding", "Chunked")]);
+    let mut repeated = framed_request(
+      Version::HTTP_11,
+      &[("content-length", "5, 5"), ("content-length", " 5")],
+    );
+
+    assert_eq!(normalize_framing(&mut chunked), Ok(()));
+    assert_eq!(normalize_framing(&mut framed_request(Version::HTTP_10, &[])), Ok(()));
+    assert_eq!(normalize_framing(&mut repeated), Ok(()));
+    assert_eq!(
+      repeated.headers().get_all(CONTENT_LENGTH).iter().collect::<Vec<_>>(),
+      vec!["5"]
+    );
+  }
+
+  #[test]
+  fn normalize_framing_rejects_ambiguous_lengths() {
+    let ambiguous = vec![
+      framed_request(
+        Version::HTTP_11,
+        &[("transfer-encoding", "chunked"), ("content-length", "5")],
+      ),
+      framed_request(Version::HTTP_11, &[("transfer-encoding", "chunked, identity")]),
+      framed_request(Version::HTTP_11, &[("transfer-encoding", "xchunked")]),
+      framed_request(
+        Version::HTTP_11,
+        &[("transfer-encoding", "chunked"), ("transfer-encoding", "chunked")],
+      ),
+      framed_request(Version::HTTP_10, &[("transfer-encoding", "chunked")]),
+      framed_request(Version::HTTP_11, &[("content-length", "5"), ("content-length", "6")]),
+      framed_request(Version::HTTP_11, &[("content-length", "+5")]),
+      framed_request(Version::HTTP_11, &[("content-length", "")]),
+    ];
+
+    for mut request in ambiguous {
+      assert_eq!(normalize_framing(&mut request), Err(()), "{:?}", request.headers());
+    }
+  }
+}
@@ -0,0 +1,120 @@
+use crate::server::BackendPool;
+use hyper::{header::HOST, Body, Request};
+use std::{collections::HashMap, iter, sync::Arc};
+
+/// Narrows down the backend pools a request may match by its `Host` header, so configurations with many backend
+/// pools don't test the matcher of every pool per request. Pools whose matcher requires one of a set of hosts, e.g.
+/// `Host('a') && Path('/api')`, are only candidates for requests to these hosts. All other pools are candidates for
+/// every request.
+#[derive(Debug, Default)]
+pub struct PoolIndex {
+  /* indices of the backend pools requiring the host, ascending */
+  by_host: HashMap<String, Vec<usize>>,
+  /* indices of the backend pools accepting any host, ascending */
+  any_host: Vec<usize>,
+  /* backend pools from this index on are not indexed and candidates for every request */
+  indexed: usize,
+}
+
+impl PoolIndex {
+  pub fn new(backend_pools: &[Arc<BackendPool>]) -> PoolIndex {
+    let mut index = PoolIndex {
+      indexed: backend_pools.len(),
+      ..PoolIndex::default()
+    };
+    for (pool_index, pool) in backend_pools.iter().enumerate() {
+      match pool.matcher.required_hosts() {
+        Some(hosts) => {
+          for host in hosts {
+            let pools = index.by_host.entry(host.to_string()).or_default();
+            /* a host may be required by several branches of the same matcher */
+            if pools.last() != Some(&pool_index) {
+              pools.push(pool_index);
+            }
+          }
+        }
+        None => index.any_host.push(pool_index),
+      }
+    }
+    index
+  }
+
+  /// Returns the indices of the backend pools the `request` may match in the order of the configuration, followed by
+  /// the pools which were not indexed out of `pool_count`.
+  pub fn candidates<'l>(&'l self, request: &Request<Body>, pool_count: usize) -> impl Iterator<Item = usize> + 'l {
+    let by_host = request
+      .headers()
+      .get(HOST)
+      .and_then(|host| self.by_host.get(host.to_str().ok()?))
+      .map_or(&[][..], Vec::as_slice);
+    merge(by_host, &self.any_host).chain(self.indexed..pool_count)
+  }
+}
+
+/* Merges two ascending lists into one */
+fn merge<'l>(left: &'l [usize], right: &'l [usize]) -> impl Iterator<Item = usize> + 'l {
+  let mut left = left.iter().copied().peekable();
+  let mut right = right.iter().copied().peekable();
+  iter::from_fn(move || match (left.peek().copied(), right.peek().copied()) {
+    (Some(l), Some(r)) if r < l => right.next(),
+    (Some(_), _) => left.next(),
+    (None, _) => right.next(),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    health::{BackendHealth, Healthiness},
+    load_balancing::random::Random,
+    server::test_pool_builder,
+  };
+
+  fn pool(matcher: &str) -> Arc<BackendPool> {
+    Arc::new(
+      test_pool_builder(
+        matcher,
+        matcher.parse().unwrap(),
+        vec![("127.0.0.1:8084".into(), BackendHealth::new(Healthiness::Healthy))],
+        Box::new(Random::new()),
+      )
+      .build(),
+    )
+  }
+
+  fn request(host: &str) -> Request<Body> {
+    Request::get("/").header(HOST, host).body(Body::empty()).unwrap()
+  }
+
+  #[test]
+  fn candidates_keep_the_order_of_the_configuration() {
+    // given:
+    let pools = vec![
+      pool("Host('a.localhost') && Path('/api')"),
+      pool("PathRegexp('^/static')"),
+      pool("Host('b.localhost')"),
+      pool("Host('a.localhost') || Host('b.localhost')"),
+      pool("Method('OPTIONS')"),
+    ];
+
+    // when:
+    let index = PoolIndex::new(&pools);
+
+    // then:
+    let candidates = |host: &str| index.candidates(&request(host), pools.len()).collect::<Vec<_>>();
+    assert_eq!(candidates("a.localhost"), vec![0, 1, 3, 4]);
+    assert_eq!(candidates("b.localhost"), vec![1, 2, 3, 4]);
+    assert_eq!(candidates("c.localhost"), vec![1, 4]);
+  }
+
+  #[test]
+  fn pools_which_are_not_indexed_are_always_candidates() {
+    let index = PoolIndex::default();
+
+    assert_eq!(
+      index.candidates(&request("a.localhost"), 3).collect::<Vec<_>>(),
+      vec![0, 1, 2]
+    );
+  }
+}
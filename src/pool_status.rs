@@ -0,0 +1,212 @@
+use crate::{
+  error_response::{forbidden, method_not_allowed},
+  health::Healthiness,
+  health_events::healthiness_json,
+  server::BackendPool,
+};
+use hyper::{
+  header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE},
+  Body, Method, Request, Response,
+};
+use log::debug;
+use serde_json::json;
+use std::net::IpAddr;
+
+const TEMPLATE: &str = include_str!("pool_status.html");
+
+/// Serves a summary of the backend servers of a single backend pool on a path of the pool itself, for teams owning a
+/// service but not the whole load balancer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStatus {
+  pub path: String,
+  /// Only these clients may see the status, everybody else is answered with `403 Forbidden`
+  pub allowed_ips: Vec<IpAddr>,
+}
+
+impl PoolStatus {
+  pub fn is_requested(&self, request: &Request<Body>) -> bool {
+    request.uri().path() == self.path
+  }
+
+  pub fn respond(&self, pool: &BackendPool, request: &Request<Body>, client_ip: &IpAddr) -> Response<Body> {
+    if !self.allowed_ips.contains(client_ip) {
+      debug!(
+        "Rejecting status request of {} for backend pool '{}'",
+        client_ip, pool.name
+      );
+      return forbidden();
+    }
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+      return method_not_allowed();
+    }
+    let (content_type, body) = if accepts_html(request) {
+      ("text/html; charset=utf-8", render_html(pool))
+    } else {
+      ("application/json", render_json(pool))
+    };
+    Response::builder()
+      .header(CONTENT_TYPE, content_type)
+      .header(CACHE_CONTROL, "no-store")
+      .body(Body::from(body))
+      .unwrap()
+  }
+}
+
+/* Browsers get the HTML page, everybody else JSON */
+fn accepts_html(request: &Request<Body>) -> bool {
+  request
+    .headers()
+    .get(ACCEPT)
+    .and_then(|accept| accept.to_str().ok())
+    .map_or(false, |accept| accept.contains("text/html"))
+}
+
+fn render_json(pool: &BackendPool) -> String {
+  let backends = pool
+    .addresses
+    .iter()
+    .map(|(address, health)| {
+      let mut backend = healthiness_json(health.load().as_ref());
+      backend["address"] = json!(address);
+      backend
+    })
+    .collect::<Vec<_>>();
+  json!({
+    "pool": pool.name,
+    "paused": pool.is_paused(),
+    "backends": backends,
+  })
+  .to_string()
+}
+
+fn render_html(pool: &BackendPool) -> String {
+  let backends = pool
+    .addresses
+    .iter()
+    .map(|(address, health)| {
+      let (state, details) = match health.load().as_ref() {
+        Healthiness::Healthy => ("healthy", String::new()),
+        Healthiness::Slow(response_time) => ("slow", format!("{} ms", response_time)),
+        Healthiness::Unresponsive(Some(status)) => ("unresponsive", status.to_string()),
+        Healthiness::Unresponsive(None) => ("unresponsive", String::new()),
+        Healthiness::Unknown => ("unknown", String::new()),
+      };
+      format!(
+        "    <tr><td>{}</td><td class=\"{}\">{}</td><td>{}</td></tr>",
+        escape(address),
+        state,
+        state,
+        escape(&details)
+      )
+    })
+    .collect::<Vec<_>>()
+    .join("\n");
+  let up = pool
+    .addresses
+    .iter()
+    .filter(|(_, health)| health.load().is_up())
+    .count();
+  let summary = format!(
+    "{} of {} backend servers are up{}",
+    up,
+    pool.addresses.len(),
+    if pool.is_paused() { ", the pool is paused" } else { "" }
+  );
+  TEMPLATE
+    .replace("{{summary}}", &summary)
+    .replace("{{backends}}", &backends)
+    .replace("{{pool}}", &escape(&pool.name))
+}
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    backend_pool_matcher::BackendPoolMatcher, health::BackendHealth, load_balancing::round_robin::RoundRobin,
+    server::test_pool_builder,
+  };
+  use hyper::{body, StatusCode};
+
+  fn pool() -> BackendPool {
+    test_pool_builder(
+      "<shop>",
+      BackendPoolMatcher::Host("shop.localhost".into()),
+      vec![
+        ("127.0.0.1:8080".into(), BackendHealth::new(Healthiness::Healthy)),
+        (
+          "127.0.0.1:8081".into(),
+          BackendHealth::new(Healthiness::Unresponsive(Some(StatusCode::SERVICE_UNAVAILABLE))),
+        ),
+      ],
+      Box::new(RoundRobin::new()),
+    )
+    .build()
+  }
+
+  fn status() -> PoolStatus {
+    PoolStatus {
+      path: "/_arlb/status".into(),
+      allowed_ips: vec!["10.0.0.1".parse().unwrap()],
+    }
+  }
+
+  #[tokio::test]
+  async fn responds_with_json_to_allowed_clients() {
+    // given:
+    let request = Request::get("/_arlb/status").body(Body::empty()).unwrap();
+
+    // when:
+    let response = status().respond(&pool(), &request, &"10.0.0.1".parse().unwrap());
+
+    // then:
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+    assert_eq!(
+      body,
+      json!({
+        "pool": "<shop>",
+        "paused": false,
+        "backends": [
+          { "address": "127.0.0.1:8080", "state": "healthy" },
+          { "address": "127.0.0.1:8081", "state": "unresponsive", "status_code": 503 },
+        ],
+      })
+    );
+  }
+
+  #[tokio::test]
+  async fn responds_with_escaped_html_to_browsers() {
+    // given:
+    let request = Request::get("/_arlb/status")
+      .header(ACCEPT, "text/html,application/xhtml+xml")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = status().respond(&pool(), &request, &"10.0.0.1".parse().unwrap());
+
+    // then:
+    let body = body::to_bytes(response.into_body()).await.unwrap();
+    let html = String::from_utf8_lossy(&body);
+    assert!(html.contains("<h1>&lt;shop&gt;</h1>"), "{}", html);
+    assert!(html.contains("1 of 2 backend servers are up"), "{}", html);
+    assert!(html.contains("503 Service Unavailable"), "{}", html);
+  }
+
+  #[test]
+  fn rejects_other_clients() {
+    let request = Request::get("/_arlb/status").body(Body::empty()).unwrap();
+
+    let response = status().respond(&pool(), &request, &"10.0.0.2".parse().unwrap());
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+  }
+}
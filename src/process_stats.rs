@@ -0,0 +1,160 @@
+use log::info;
+use serde_json::{json, Value};
+use std::{
+  fs,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    OnceLock,
+  },
+  time::{Duration, Instant},
+};
+
+static CLIENT_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static BACKEND_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+/* Unset until `start` was called, monotonic so changes of the system clock don't affect the uptime */
+static STARTED_AT: OnceLock<Instant> = OnceLock::new();
+
+/// Counts an open connection until it is dropped.
+#[derive(Debug)]
+pub struct OpenConnection(&'static AtomicUsize);
+
+impl OpenConnection {
+  /// Counts an open connection of a client to one of the listeners.
+  pub fn client() -> OpenConnection {
+    OpenConnection::open(&CLIENT_CONNECTIONS)
+  }
+
+  /// Counts an open connection to a backend server.
+  pub fn backend() -> OpenConnection {
+    OpenConnection::open(&BACKEND_CONNECTIONS)
+  }
+
+  fn open(counter: &'static AtomicUsize) -> OpenConnection {
+    counter.fetch_add(1, Ordering::Relaxed);
+    OpenConnection(counter)
+  }
+}
+
+impl Drop for OpenConnection {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// Remembers the start of the process to compute the uptime. Later calls keep the first start.
+pub fn start() {
+  STARTED_AT.get_or_init(Instant::now);
+}
+
+/// A snapshot of the resource usage of the load balancer, to spot saturation trends without an external profiler.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessStats {
+  pub uptime: Duration,
+  pub client_connections: usize,
+  pub backend_connections: usize,
+  /* `None` if the platform doesn't provide it */
+  pub resident_memory_bytes: Option<u64>,
+}
+
+impl ProcessStats {
+  pub fn collect() -> ProcessStats {
+    ProcessStats {
+      uptime: STARTED_AT.get().map_or(Duration::from_secs(0), Instant::elapsed),
+      client_connections: CLIENT_CONNECTIONS.load(Ordering::Relaxed),
+      backend_connections: BACKEND_CONNECTIONS.load(Ordering::Relaxed),
+      resident_memory_bytes: fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| parse_resident_memory(&status)),
+    }
+  }
+
+  pub fn to_json(&self) -> Value {
+    json!({
+      "uptime_sec": self.uptime.as_secs(),
+      "client_connections": self.client_connections,
+      "backend_connections": self.backend_connections,
+      "resident_memory_bytes": self.resident_memory_bytes,
+    })
+  }
+
+  /// Logs the stats as `key=value` pairs, so they can be extracted by log processors.
+  pub fn log(&self, event: &str) {
+    info!(
+      target: "process_stats",
+      "event={} uptime_sec={} client_connections={} backend_connections={} resident_memory_bytes={}",
+      event,
+      self.uptime.as_secs(),
+      self.client_connections,
+      self.backend_connections,
+      self
+        .resident_memory_bytes
+        .map_or_else(|| "unknown".to_string(), |it| it.to_string())
+    );
+  }
+}
+
+/* Parses the `VmRSS` line of /proc/self/status, see proc(5) */
+fn parse_resident_memory(status: &str) -> Option<u64> {
+  let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+  let kilobytes = line["VmRSS:".len()..]
+    .trim()
+    .strip_suffix("kB")?
+    .trim()
+    .parse::<u64>()
+    .ok()?;
+  Some(kilobytes * 1024)
+}
+
+/// Logs the process stats every `interval`, if any. The interval is read from the configuration before every log,
+/// so a reload takes effect after the current interval.
+pub async fn watch_process_stats<F>(interval: F)
+where
+  F: Fn() -> Option<Duration>,
+{
+  loop {
+    match interval() {
+      Some(interval) => {
+        tokio::time::sleep(interval).await;
+        ProcessStats::collect().log("stats");
+      }
+      /* Check again later, in case logging is enabled by a reload */
+      None => tokio::time::sleep(Duration::from_secs(60)).await,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_resident_memory_of_proc_status() {
+    let status = "Name:\tanother-rust-lo\nVmPeak:\t  123456 kB\nVmRSS:\t    2048 kB\nThreads:\t8\n";
+
+    assert_eq!(parse_resident_memory(status), Some(2048 * 1024));
+    assert_eq!(parse_resident_memory("Name:\tarlb\n"), None);
+  }
+
+  #[test]
+  fn counts_open_connections_until_dropped() {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let first = OpenConnection::open(&COUNTER);
+    let second = OpenConnection::open(&COUNTER);
+    assert_eq!(COUNTER.load(Ordering::Relaxed), 2);
+
+    drop(first);
+    assert_eq!(COUNTER.load(Ordering::Relaxed), 1);
+    drop(second);
+    assert_eq!(COUNTER.load(Ordering::Relaxed), 0);
+  }
+
+  #[test]
+  fn uptime_counts_from_the_first_start() {
+    start();
+    let first = ProcessStats::collect().uptime;
+    start();
+
+    assert!(ProcessStats::collect().uptime >= first);
+  }
+}
@@ -0,0 +1,121 @@
+use redis::{aio::MultiplexedConnection, Client, FromRedisValue, Pipeline, RedisError, RedisResult};
+use std::{
+  fmt, io,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+use tokio::time::timeout;
+
+/* How long no new connection is attempted after Redis failed */
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A connection to a Redis server shared by several instances of the load balancer. It is established on first use.
+/// Connecting and every query are bounded by `timeout`. While a connection is established and for a second after a
+/// failure, queries fail right away instead of waiting for Redis, so an unreachable Redis doesn't slow down requests.
+pub struct RedisConnection {
+  client: Client,
+  timeout: Duration,
+  state: Mutex<ConnectionState>,
+}
+
+#[derive(Default)]
+struct ConnectionState {
+  connection: Option<MultiplexedConnection>,
+  /* No connection is attempted before this point in time */
+  retry_at: Option<Instant>,
+}
+
+impl RedisConnection {
+  pub fn new(url: &str, timeout: Duration) -> RedisResult<RedisConnection> {
+    Ok(RedisConnection {
+      client: Client::open(url)?,
+      timeout,
+      state: Mutex::new(ConnectionState::default()),
+    })
+  }
+
+  /// Sends the `pipeline` to Redis and returns its result.
+  pub async fn query<T: FromRedisValue>(&self, pipeline: &Pipeline) -> io::Result<T> {
+    let mut connection = self.connection().await?;
+    match timeout(self.timeout, pipeline.query_async(&mut connection)).await {
+      Ok(Ok(result)) => Ok(result),
+      Ok(Err(e)) => Err(self.failed(redis_error(e))),
+      Err(_) => Err(self.failed(timed_out())),
+    }
+  }
+
+  async fn connection(&self) -> io::Result<MultiplexedConnection> {
+    {
+      let mut state = self.state.lock().unwrap();
+      if let Some(connection) = &state.connection {
+        return Ok(connection.clone());
+      }
+      let now = Instant::now();
+      if matches!(state.retry_at, Some(retry_at) if now < retry_at) {
+        return Err(io::Error::new(io::ErrorKind::NotConnected, "Redis is not available"));
+      }
+      // only this query connects, the others fail right away until it is done
+      state.retry_at = Some(now + self.timeout);
+    }
+    match timeout(self.timeout, self.client.get_multiplexed_tokio_connection()).await {
+      Ok(Ok(connection)) => {
+        let mut state = self.state.lock().unwrap();
+        state.connection = Some(connection.clone());
+        state.retry_at = None;
+        Ok(connection)
+      }
+      Ok(Err(e)) => Err(self.failed(redis_error(e))),
+      Err(_) => Err(self.failed(timed_out())),
+    }
+  }
+
+  /* Drops the connection and backs off, the next query after the backoff reconnects */
+  fn failed(&self, error: io::Error) -> io::Error {
+    let mut state = self.state.lock().unwrap();
+    state.connection = None;
+    state.retry_at = Some(Instant::now() + RECONNECT_BACKOFF);
+    error
+  }
+}
+
+fn redis_error(error: RedisError) -> io::Error {
+  io::Error::new(io::ErrorKind::Other, error)
+}
+
+fn timed_out() -> io::Error {
+  io::Error::new(io::ErrorKind::TimedOut, "Redis did not respond in time")
+}
+
+impl fmt::Debug for RedisConnection {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("RedisConnection")
+      .field("client", &self.client)
+      .field("timeout", &self.timeout)
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::net::TcpListener;
+
+  #[tokio::test]
+  async fn backs_off_after_a_timeout() {
+    // given: a server which accepts connections but never answers
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("redis://{}", listener.local_addr().unwrap());
+    let connection = RedisConnection::new(&url, Duration::from_millis(50)).unwrap();
+    let pipeline = redis::pipe().get("key").clone();
+
+    // when:
+    let started_at = Instant::now();
+    let first = connection.query::<(Option<String>,)>(&pipeline).await;
+    let second = connection.query::<(Option<String>,)>(&pipeline).await;
+
+    // then:
+    assert_eq!(first.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    assert_eq!(second.unwrap_err().kind(), io::ErrorKind::NotConnected);
+    assert!(started_at.elapsed() < Duration::from_millis(500));
+  }
+}
@@ -1,36 +1,66 @@
 use crate::{
   acme::AcmeHandler,
+  backend_limit::{BackendConnection, BackendLimit},
   backend_pool_matcher::BackendPoolMatcher,
   configuration::RuntimeConfig,
-  error_response::{bad_gateway, not_found},
-  health::{HealthConfig, Healthiness},
+  cooldown::Cooldown,
+  debug_headers::{Route, RoutingDecision},
+  error_response::{bad_gateway, bad_request, misdirected_request, not_found, service_unavailable},
+  fallback::Fallback,
+  health::{BackendHealth, HealthConfig, HealthSchedule, Healthiness},
+  hedging::{self, Hedged, Hedging},
   http_client::StrategyNotifyHttpConnector,
-  listeners::RemoteAddress,
-  load_balancing::{self, LoadBalancingStrategy},
-  middleware::MiddlewareChain,
+  listeners::{ClientFingerprint, RemoteAddress, TlsServerName},
+  load_balancing::{self, BackendWeight, LoadBalancingStrategy, SlowWeights},
+  log_redaction::LogRedaction,
+  middleware::{MiddlewareChain, DEFAULT_MAX_BUFFER_BYTES},
+  no_healthy_backends::NoHealthyBackends,
+  normalization::{normalize_framing, normalize_host, normalize_request},
+  pool_index::PoolIndex,
+  pool_status::PoolStatus,
+  process_stats::OpenConnection,
+  service::Service,
+  shadow_strategy::ShadowStrategy,
+  timing::RequestTiming,
+  tls_fingerprint::TlsFingerprint,
+  traffic::Traffic,
+  utils::canonical_address,
 };
 use arc_swap::ArcSwap;
-use futures::Future;
-use futures::TryFutureExt;
+use futures::{future::poll_fn, Future};
 use hyper::{
-  server::accept::Accept,
-  service::{make_service_fn, Service},
-  Body, Client, Request, Response, Server,
+  header::{ACCEPT, HOST},
+  http::uri::Authority,
+  server::{
+    accept::Accept,
+    conn::{Connection, Http},
+  },
+  service::Service as HyperService,
+  Body, Client, Request, Response,
 };
-use log::debug;
+use log::{debug, info, log_enabled, trace, warn, Level};
+use schemars::JsonSchema;
 use serde::Deserialize;
 use std::{
-  collections::HashSet,
+  collections::{HashMap, HashSet},
+  convert::TryFrom,
   error::Error,
   fmt::Display,
   io,
   net::SocketAddr,
   pin::Pin,
-  sync::Arc,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
   task::{Context, Poll},
   time::Duration,
 };
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  select,
+  time::Instant,
+};
 
 pub async fn create<'a, I, IE, IO>(
   acceptor: I,
@@ -40,36 +70,99 @@ pub async fn create<'a, I, IE, IO>(
 where
   I: Accept<Conn = IO, Error = IE>,
   IE: Into<Box<dyn Error + Send + Sync>>,
-  IO: AsyncRead + AsyncWrite + Unpin + Send + RemoteAddress + 'static,
+  IO: AsyncRead + AsyncWrite + Unpin + Send + RemoteAddress + TlsServerName + ClientFingerprint + 'static,
 {
-  let service = make_service_fn(move |stream: &IO| {
-    let client_address = stream.remote_addr().expect("No remote SocketAddr");
-    let config = config.clone();
-
-    async move {
-      Ok::<_, io::Error>(MainService {
-        client_address,
-        config,
-        scheme,
-      })
-    }
-  });
-  Server::builder(acceptor)
-    .serve(service)
-    .map_err(|e| {
+  let http = Http::new();
+  let mut acceptor = Box::pin(acceptor);
+  while let Some(stream) = poll_fn(|cx| acceptor.as_mut().poll_accept(cx)).await {
+    let stream = stream.map_err(|e| {
+      let e: Box<dyn Error + Send + Sync> = e.into();
       let msg = format!("Failed to listen server: {}", e);
       io::Error::new(io::ErrorKind::Other, msg)
+    })?;
+    let routes = Arc::new(ConnectionRoutes::default());
+    let service = MainService {
+      client_address: canonical_address(stream.remote_addr().expect("No remote SocketAddr")),
+      server_name: stream.server_name(),
+      tls_fingerprint: stream.tls_fingerprint(),
+      config: config.clone(),
+      scheme,
+      routes: routes.clone(),
+    };
+    let connection = http.serve_connection(stream, service);
+    tokio::spawn(serve_connection(connection, config.clone(), routes));
+  }
+  Ok(())
+}
+
+/* Serves the client connection until it is closed. Once a reloaded configuration removes a backend pool or backend
+server the connection was forwarded to, it is closed gracefully: HTTP/1 connections after the current response,
+HTTP/2 connections with a GOAWAY frame. This way long-lived keep-alive connections pick up the new configuration. */
+async fn serve_connection<IO>(
+  connection: Connection<IO, MainService>,
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  routes: Arc<ConnectionRoutes>,
+) where
+  IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+  let _open = OpenConnection::client();
+  tokio::pin!(connection);
+  let result = loop {
+    let mut replaced = config.load().replacement.subscribe();
+    select! {
+      result = &mut connection => break result,
+      _ = replaced.changed() => {
+        if routes.is_stale(&config.load().shared_data) {
+          debug!("Closing client connection, the backend servers it used were removed from the configuration");
+          connection.as_mut().graceful_shutdown();
+          break connection.await;
+        }
+      }
+    }
+  };
+  if let Err(e) = result {
+    debug!("Client connection failed: {}", e);
+  }
+}
+
+/// Remembers the backend pools and backend servers the requests of a client connection were forwarded to.
+#[derive(Debug, Default)]
+pub struct ConnectionRoutes {
+  used: Mutex<HashSet<(String, String)>>,
+}
+
+impl ConnectionRoutes {
+  fn record(&self, pool: &str, address: &str) {
+    self
+      .used
+      .lock()
+      .unwrap()
+      .insert((pool.to_string(), address.to_string()));
+  }
+
+  /// Returns `true` if one of the used backend pools or backend servers is not part of `shared_data` anymore.
+  fn is_stale(&self, shared_data: &SharedData) -> bool {
+    self.used.lock().unwrap().iter().any(|(pool_name, address)| {
+      !shared_data
+        .backend_pools
+        .iter()
+        .any(|pool| &pool.name == pool_name && pool.addresses.iter().any(|(it, _)| it == address))
     })
-    .await
+  }
 }
 
 pub struct MainService {
   client_address: SocketAddr,
+  /* the SNI of HTTPS connections */
+  server_name: Option<String>,
+  /* the JA3 fingerprint of HTTPS connections */
+  tls_fingerprint: Option<TlsFingerprint>,
   config: Arc<ArcSwap<RuntimeConfig>>,
   scheme: Scheme,
+  routes: Arc<ConnectionRoutes>,
 }
 
-impl Service<Request<Body>> for MainService {
+impl HyperService<Request<Body>> for MainService {
   type Response = Response<Body>;
   type Error = hyper::Error;
 
@@ -82,89 +175,539 @@ impl Service<Request<Body>> for MainService {
   }
 
   fn call(&mut self, request: Request<Body>) -> Self::Future {
-    debug!("{:#?} {} {}", request.version(), request.method(), request.uri());
+    let error_pages = match &self.config.load().error_pages {
+      Some(error_pages) => error_pages.clone(),
+      None => return self.handle(request),
+    };
+    let accept = request.headers().get(ACCEPT).cloned();
+    let response = self.handle(request);
+    Box::pin(async move { Ok(error_pages.apply(response.await?, accept.as_ref())) })
+  }
+}
 
+impl MainService {
+  fn handle(&self, mut request: Request<Body>) -> <Self as HyperService<Request<Body>>>::Future {
+    let received_at = Instant::now();
     let config = self.config.load();
     let shared_data = &config.shared_data;
+    debug!(
+      "{:#?} {}",
+      request.version(),
+      config.log_redaction.request_line(&request)
+    );
+    if log_enabled!(Level::Trace) {
+      trace!("Request headers:\n{}", config.log_redaction.headers(request.headers()));
+    }
+
+    // before any matcher is evaluated, as matchers may select by the fingerprint
+    if let Some(fingerprint) = &self.tls_fingerprint {
+      request.extensions_mut().insert(fingerprint.clone());
+    }
+
+    if normalize_host(&mut request).is_err() {
+      debug!(
+        "Rejecting request without valid host {}",
+        config.log_redaction.uri(request.uri())
+      );
+      return Box::pin(async { Ok(bad_request("400 - missing or invalid host")) });
+    }
 
-    if let Some(response) = shared_data.acme_handler.respond_to_challenge(&request) {
+    if normalize_framing(&mut request).is_err() {
+      debug!("Rejecting request with ambiguous Transfer-Encoding or Content-Length");
+      return Box::pin(async { Ok(bad_request("400 - ambiguous message length")) });
+    }
+
+    if config.normalize_paths && normalize_request(&mut request).is_err() {
+      debug!("Rejecting request with invalid path {}", request.uri().path());
+      return Box::pin(async { Ok(bad_request("400 - invalid request path")) });
+    }
+
+    let client_ip = self.client_address.ip();
+    if let Some(response) = config.internal_endpoints.respond(&request, shared_data, &client_ip) {
       return Box::pin(async move { Ok(response) });
     }
 
-    match pool_by_req(&shared_data, &request, &self.scheme) {
-      Some(pool) => {
+    let strict_sni = config.strict_sni && self.scheme == Scheme::HTTPS;
+    if strict_sni && !self.is_requested_server_name(&request) {
+      debug!("Rejecting request whose host doesn't match the server name of the TLS connection");
+      return Box::pin(async { Ok(misdirected_request()) });
+    }
+
+    match select_pool(&shared_data, &request, &self.scheme, !strict_sni) {
+      // the status is answered by the load balancer, even if the pool is paused or down
+      Some((pool, _)) if pool.status.as_ref().map_or(false, |it| it.is_requested(&request)) => {
+        let status = pool.status.as_ref().unwrap();
+        let response = status.respond(&pool, &request, &self.client_address.ip());
+        Box::pin(async move { Ok(response) })
+      }
+      Some((pool, _)) if pool.is_paused() => {
+        debug!("Backend pool '{}' is paused, rejecting request", pool.name);
+        Box::pin(async { Ok(service_unavailable()) })
+      }
+      Some((pool, route)) => {
         let client_scheme = self.scheme;
         let client_address = self.client_address;
+        let routes = self.routes.clone();
+        /* responses of streaming pools take as long as the client stays connected */
+        let slow_request_threshold = config.slow_request_threshold.filter(|_| !pool.streaming);
+        let request_line = config.log_redaction.request_line(&request);
+        let debug_headers = config
+          .debug_headers
+          .is_requested(&request, &client_address.ip())
+          .then(|| route.describe(&pool.name));
 
         Box::pin(async move {
-          // clone, filter, map, LoadBalancingContext:backend_addresses
-          let mut working_addresses = pool
-            .addresses
-            .iter()
-            .filter(|(_, healthiness)| healthiness.load().as_ref() == &Healthiness::Healthy)
-            .map(|(address, _)| address.as_str())
-            .collect::<Vec<_>>();
-
+          let mut working_addresses = pool.working_addresses();
           if working_addresses.is_empty() {
-            // replace healthy addresses with slow addresses
-            working_addresses = pool
-              .addresses
-              .iter()
-              .filter(|(_, healthiness)| matches!(healthiness.load().as_ref(), Healthiness::Slow(_)))
-              .map(|(address, _)| address.as_str())
-              .collect::<Vec<_>>();
+            if let Some(response) = pool.no_healthy_backends.response.respond() {
+              return Ok(response);
+            }
+            debug!(
+              "No backend server of backend pool '{}' is healthy, forwarding request anyway",
+              pool.name
+            );
+            working_addresses = pool.addresses.iter().map(|(address, _)| address.as_str()).collect();
+          }
+          // backend servers asking to retry later are left alone, even if all of them do
+          if !working_addresses.is_empty() {
+            working_addresses.retain(|address| !pool.is_cooling_down(address));
+            if working_addresses.is_empty() {
+              debug!("All backend servers of backend pool '{}' are cooling down", pool.name);
+              return Ok(service_unavailable());
+            }
           }
           if working_addresses.is_empty() {
             // we don't have any working addresses, so don't call load balancer strategy and abort early
             // middlewares are also not running
             Ok(bad_gateway())
           } else {
-            let context = load_balancing::Context {
-              client_address: &client_address,
-              backend_addresses: &working_addresses,
-            };
-            let backend = pool.strategy.select_backend(&request, &context);
-            let result = backend
-              .forward_request_to_backend(request, &pool.chain, &client_scheme, &client_address, &pool.client)
-              .await;
-            Ok(result)
+            let started_waiting = Instant::now();
+            let pool_addresses = pool
+              .addresses
+              .iter()
+              .map(|(address, _)| address.as_str())
+              .collect::<Vec<_>>();
+            loop {
+              // exclude backend servers which reached their maximum number of connections or requests in flight
+              let mut available_addresses = working_addresses.clone();
+              for limit in pool.backend_limits() {
+                let deadline = started_waiting + limit.max_wait();
+                available_addresses = match limit.available(&available_addresses, deadline).await {
+                  Some(available_addresses) => available_addresses,
+                  None => {
+                    debug!("All backend servers of the backend pool reached their limit, rejecting request");
+                    return Ok(service_unavailable());
+                  }
+                };
+              }
+
+              let backend_weights = pool.backend_weights(&available_addresses);
+              let context = load_balancing::Context {
+                client_address: &client_address,
+                backend_addresses: &available_addresses,
+                pool_addresses: &pool_addresses,
+                backend_weights: &backend_weights,
+              };
+              let backend = pool.strategy.select_backend(&request, &context);
+              // hold the connection until the response body has been sent, the request until the response headers
+              // are received
+              let (connection, _request) = match pool.try_acquire(backend.backend_address()) {
+                Some(acquired) => acquired,
+                // the strategy insists on a backend server at its limit, e.g. the one a client sticks to
+                None if !available_addresses.contains(&backend.backend_address()) => {
+                  return Ok(service_unavailable());
+                }
+                // another request took the last connection in the meantime
+                None => continue,
+              };
+              if let Some(shadow_strategy) = &pool.shadow_strategy {
+                shadow_strategy.evaluate(&request, &context, backend.backend_address(), &pool.log_redaction);
+              }
+              let hedge_address = pool.hedge_address(&available_addresses, backend.backend_address());
+              let backend = match hedge_address {
+                Some((address, delay)) => backend.hedge(address, delay, pool.backend_limit.as_ref()),
+                None => backend,
+              };
+              routes.record(&pool.name, backend.backend_address());
+              let selected_at = Instant::now();
+              let request = pool.traffic.count_request(backend.backend_address(), request);
+              // dropped together with this future if the client goes away, which also aborts the backend request
+              let abort = pool.traffic.abort_guard(backend.backend_address());
+              let mut result = backend
+                .forward_request_to_backend(
+                  request,
+                  &pool.chain,
+                  &client_scheme,
+                  &client_address,
+                  &pool.client,
+                  pool.max_buffer_bytes,
+                  &pool.log_redaction,
+                )
+                .await;
+              abort.finish();
+              // a hedged request is attributed to the backend server which answered it
+              let (answered_by, connection) = match (result.extensions_mut().remove::<Hedged>(), hedge_address) {
+                (Some(Hedged(hedge_connection)), Some((address, _))) => (address, hedge_connection),
+                _ => (backend.backend_address(), connection),
+              };
+              pool.record_response(answered_by, &result);
+              let mut result = pool.traffic.count_response(answered_by, result, connection);
+
+              let timing = RequestTiming::new(received_at, selected_at, &result, Instant::now());
+              match slow_request_threshold {
+                Some(threshold) if timing.total > threshold => {
+                  warn!("Slow request {} to {}: {}", request_line, answered_by, timing)
+                }
+                _ => debug!("Request {} to {}: {}", request_line, answered_by, timing),
+              }
+              if let Some(route) = &debug_headers {
+                let decision = RoutingDecision {
+                  route,
+                  pool: &pool,
+                  backend_address: answered_by,
+                  timing: &timing,
+                };
+                decision.annotate(&mut result);
+              }
+              return Ok(result);
+            }
           }
         })
       }
-      _ => Box::pin(async { Ok(not_found()) }),
+      // the client must not send requests for other hosts over this connection
+      None if strict_sni => Box::pin(async { Ok(misdirected_request()) }),
+      None => Box::pin(async { Ok(not_found()) }),
+    }
+  }
+
+  /// Returns `true` if the host of the request is the server name requested in the TLS handshake. Connections
+  /// without SNI are only accepted if a certificate is configured for them, so any host is allowed for them.
+  fn is_requested_server_name(&self, request: &Request<Body>) -> bool {
+    let server_name = match &self.server_name {
+      Some(server_name) => server_name,
+      None => return true,
+    };
+    match request.headers().get(HOST) {
+      Some(host) => {
+        Authority::try_from(host.as_bytes()).map_or(false, |host| host.host().eq_ignore_ascii_case(server_name))
+      }
+      // HTTP/1.0 requests without host can't contradict the server name
+      None => true,
     }
   }
 }
 
-fn pool_by_req(shared_data: &SharedData, request: &Request<Body>, scheme: &Scheme) -> Option<Arc<BackendPool>> {
-  shared_data
+pub fn pool_by_req(shared_data: &SharedData, request: &Request<Body>, scheme: &Scheme) -> Option<Arc<BackendPool>> {
+  select_pool(shared_data, request, scheme, true).map(|(pool, _)| pool)
+}
+
+/// Returns the backend pool handling the `request` together with the route which selected it.
+fn select_pool<'l>(
+  shared_data: &'l SharedData,
+  request: &Request<Body>,
+  scheme: &Scheme,
+  use_default_pool: bool,
+) -> Option<(Arc<BackendPool>, Route<'l>)> {
+  let (pool, route) = http10_pool(shared_data, request, scheme)
+    .map(|pool| (pool, Route::Http10))
+    .or_else(|| service_pool(shared_data, request, scheme).map(|(pool, service)| (pool, Route::Service(service))))
+    .or_else(|| {
+      let pools = &shared_data.backend_pools;
+      shared_data
+        .pool_index
+        .candidates(request, pools.len())
+        .map(|index| &pools[index])
+        .filter(|pool| pool.supports(scheme))
+        .find(|pool| pool.matches(request))
+        .map(|pool| (pool, Route::Matcher))
+    })
+    .or_else(|| {
+      if use_default_pool {
+        default_pool(shared_data, scheme).map(|pool| (pool, Route::DefaultPool))
+      } else {
+        None
+      }
+    })?;
+
+  match &pool.fallback {
+    Some(fallback) if !pool.is_available() => match shared_data.backend_pools.get(fallback.pool) {
+      // the fallback pool has to be able to handle the request, otherwise the pool itself is the better choice
+      Some(fallback_pool) if fallback_pool.supports(scheme) && !fallback_pool.working_addresses().is_empty() => {
+        debug!(
+          "Backend pool '{}' is not available, routing request to fallback pool '{}'",
+          pool.name, fallback_pool.name
+        );
+        Some((fallback_pool.clone(), Route::Fallback(&pool.name)))
+      }
+      _ => {
+        debug!(
+          "Backend pool '{}' is not available, but neither is its fallback pool",
+          pool.name
+        );
+        Some((pool.clone(), route))
+      }
+    },
+    _ => Some((pool.clone(), route)),
+  }
+}
+
+/// Returns the backend pool configured for HTTP/1.0 requests without `Host` header on the listener for `scheme`.
+fn http10_pool<'l>(
+  shared_data: &'l SharedData,
+  request: &Request<Body>,
+  scheme: &Scheme,
+) -> Option<&'l Arc<BackendPool>> {
+  // only HTTP/1.0 requests may lack the host, see `normalize_host`
+  if request.headers().contains_key(HOST) {
+    return None;
+  }
+  let pool = shared_data
     .backend_pools
+    .get(*shared_data.http10_pools.get(scheme)?)
+    .filter(|pool| pool.supports(scheme))?;
+  debug!("Routing HTTP/1.0 request without host to backend pool '{}'", pool.name);
+  Some(pool)
+}
+
+/// Returns the backend pool selected by the first service matching the request, together with the name of the service.
+fn service_pool<'l>(
+  shared_data: &'l SharedData,
+  request: &Request<Body>,
+  scheme: &Scheme,
+) -> Option<(&'l Arc<BackendPool>, &'l str)> {
+  let service = shared_data
+    .services
     .iter()
-    .filter(|pool| pool.supports(scheme))
-    .find(|pool| pool.matcher.matches(request))
-    .cloned()
+    .find(|service| service.matcher.matches(request))?;
+  let pools = &shared_data.backend_pools;
+  let index = service.select(
+    |index| pools[index].supports(scheme),
+    |index| pools[index].is_available(),
+  )?;
+  debug!(
+    "Routing request of service '{}' to backend pool '{}'",
+    service.name, pools[index].name
+  );
+  Some((&pools[index], &service.name))
+}
+
+fn default_pool<'l>(shared_data: &'l SharedData, scheme: &Scheme) -> Option<&'l Arc<BackendPool>> {
+  let index = shared_data.default_pool?;
+  let pool = shared_data
+    .backend_pools
+    .get(index)
+    .filter(|pool| pool.supports(scheme))?;
+  debug!(
+    "No backend pool matches, routing request to default pool '{}'",
+    pool.name
+  );
+  Some(pool)
 }
 
 pub struct SharedData {
   pub backend_pools: Vec<Arc<BackendPool>>,
+  /// The index of the backend pool handling requests which match no backend pool.
+  pub default_pool: Option<usize>,
+  /// The index of the backend pool handling HTTP/1.0 requests without `Host` header, per listener.
+  pub http10_pools: HashMap<Scheme, usize>,
+  /// Services spreading requests over several backend pools, matched before the backend pools.
+  pub services: Vec<Service>,
+  /// The backend pools by the hosts their matchers require, see [`PoolIndex`].
+  pub pool_index: PoolIndex,
   pub acme_handler: Arc<AcmeHandler>,
 }
 
 #[derive(Debug)]
 pub struct BackendPool {
+  pub name: String,
   pub matcher: BackendPoolMatcher,
-  pub addresses: Vec<(String, ArcSwap<Healthiness>)>,
+  pub addresses: Vec<(String, BackendHealth)>,
   pub health_config: HealthConfig,
+  pub health_schedule: HealthSchedule,
   pub strategy: Arc<Box<dyn LoadBalancingStrategy>>,
+  pub shadow_strategy: Option<ShadowStrategy>,
+  pub hedging: Option<Hedging>,
+  /// Whether the backend servers hold connections open for a long time, e.g. for server-sent events or long polling.
+  pub streaming: bool,
   pub chain: MiddlewareChain,
   pub client: Client<StrategyNotifyHttpConnector, Body>,
+  pub connector: StrategyNotifyHttpConnector,
   pub schemes: HashSet<Scheme>,
+  pub fallback: Option<Fallback>,
+  pub trace_matcher: bool,
+  /// Masks the requests this pool logs, e.g. when tracing its matcher.
+  pub log_redaction: LogRedaction,
+  pub admission_queue: Option<BackendLimit>,
+  pub backend_limit: Option<BackendLimit>,
+  pub no_healthy_backends: NoHealthyBackends,
+  pub slow_weights: Option<SlowWeights>,
+  pub max_buffer_bytes: usize,
+  pub cooldown: Option<Cooldown>,
+  pub status: Option<PoolStatus>,
+  pub traffic: Traffic,
+  paused: AtomicBool,
+  /* indices of the addresses requests are forwarded to, see `refresh_working_addresses` */
+  working_addresses: ArcSwap<Vec<usize>>,
+  /* index of each address, so backend servers are found without scanning all addresses */
+  address_indices: HashMap<String, usize>,
 }
 
 impl BackendPool {
+  /// Answers all requests routed to this pool with `503 Service Unavailable` until it is resumed.
+  pub fn pause(&self) {
+    self.paused.store(true, Ordering::Relaxed);
+  }
+
+  pub fn resume(&self) {
+    self.paused.store(false, Ordering::Relaxed);
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.paused.load(Ordering::Relaxed)
+  }
+
   fn supports(&self, scheme: &Scheme) -> bool {
     self.schemes.contains(scheme)
   }
+
+  fn matches(&self, request: &Request<Body>) -> bool {
+    if !self.trace_matcher {
+      return self.matcher.matches(request);
+    }
+    let mut trace = String::new();
+    let result = self.matcher.trace(request, &mut trace);
+    info!(
+      "Evaluated matcher for {}:\n{}",
+      self.log_redaction.request_line(request),
+      trace.trim_end()
+    );
+    result
+  }
+
+  /// A pool is available if at least one backend server is working and its error budget is not exhausted.
+  fn is_available(&self) -> bool {
+    let error_budget_exhausted = self
+      .fallback
+      .as_ref()
+      .and_then(|fallback| fallback.error_budget.as_ref())
+      .map_or(false, |error_budget| error_budget.is_exhausted());
+    let has_working_address = self
+      .addresses
+      .iter()
+      .any(|(address, healthiness)| healthiness.load().is_up() && !self.is_cooling_down(address));
+    has_working_address && !error_budget_exhausted
+  }
+
+  /// Returns the backend servers requests are forwarded to: the healthy ones, together with the slow ones if they
+  /// are weighted, or the slow ones if none is healthy and [`use_slow_backends`](NoHealthyBackends::use_slow_backends)
+  /// is enabled. Empty if no backend server is working.
+  pub(crate) fn working_addresses(&self) -> Vec<&str> {
+    self
+      .working_addresses
+      .load()
+      .iter()
+      .filter_map(|index| self.addresses.get(*index))
+      .map(|(address, _)| address.as_str())
+      .collect()
+  }
+
+  /// Takes a new snapshot of the [working addresses](BackendPool::working_addresses), so requests don't check the
+  /// healthiness of every backend server. Called whenever the healthiness of a backend server changes.
+  pub fn refresh_working_addresses(&self) {
+    let indices = working_address_indices(
+      &self.addresses,
+      self.slow_weights.is_some(),
+      self.no_healthy_backends.use_slow_backends,
+    );
+    self.working_addresses.store(Arc::new(indices));
+  }
+
+  /// Returns the limits backend servers have to be below to be selected.
+  fn backend_limits(&self) -> impl Iterator<Item = &BackendLimit> {
+    self.backend_limit.iter().chain(&self.admission_queue)
+  }
+
+  /// Takes a connection and a request in flight to the backend server, unless it is at one of its limits.
+  fn try_acquire(&self, address: &str) -> Option<(Option<BackendConnection>, Option<BackendConnection>)> {
+    let connection = match &self.backend_limit {
+      Some(limit) => Some(limit.try_acquire(address)?),
+      None => None,
+    };
+    let request = match &self.admission_queue {
+      Some(queue) => Some(queue.try_acquire(address)?),
+      None => None,
+    };
+    Some((connection, request))
+  }
+
+  fn is_cooling_down(&self, address: &str) -> bool {
+    self
+      .cooldown
+      .as_ref()
+      .map_or(false, |cooldown| cooldown.is_cooling_down(address))
+  }
+
+  /// Returns the weights of the backend servers at `addresses`, or nothing if slow backend servers are not weighted.
+  fn backend_weights(&self, addresses: &[&str]) -> Vec<BackendWeight> {
+    let slow_weights = match &self.slow_weights {
+      Some(slow_weights) => slow_weights,
+      None => return Vec::new(),
+    };
+    addresses
+      .iter()
+      .map(|address| match self.address_indices.get(*address) {
+        Some(index) => slow_weights.weight(self.addresses[*index].1.load().as_ref()),
+        None => slow_weights.weight(&Healthiness::Healthy),
+      })
+      .collect()
+  }
+
+  /// Returns the backend server a request forwarded to `selected` is hedged to, together with the hedging delay.
+  fn hedge_address<'l>(&self, addresses: &[&'l str], selected: &str) -> Option<(&'l str, Duration)> {
+    let hedging = self.hedging.as_ref()?;
+    let address = hedging::next_address(addresses, selected)?;
+    Some((address, hedging.delay))
+  }
+
+  fn record_response(&self, address: &str, response: &Response<Body>) {
+    if let Some(cooldown) = &self.cooldown {
+      cooldown.record(address, response);
+    }
+    if let Some(error_budget) = self
+      .fallback
+      .as_ref()
+      .and_then(|fallback| fallback.error_budget.as_ref())
+    {
+      error_budget.record(response.status().is_server_error());
+    }
+  }
+}
+
+fn working_address_indices(
+  addresses: &[(String, BackendHealth)],
+  weight_slow_backends: bool,
+  use_slow_backends: bool,
+) -> Vec<usize> {
+  let indices = |is_working: &dyn Fn(&Healthiness) -> bool| {
+    addresses
+      .iter()
+      .enumerate()
+      .filter(|(_, (_, health))| is_working(health.load().as_ref()))
+      .map(|(index, _)| index)
+      .collect::<Vec<_>>()
+  };
+  let working = indices(&|healthiness| match healthiness {
+    Healthiness::Healthy => true,
+    // weighted slow addresses are used together with healthy addresses
+    Healthiness::Slow(_) => weight_slow_backends,
+    Healthiness::Unresponsive(_) | Healthiness::Unknown => false,
+  });
+  if working.is_empty() && use_slow_backends {
+    // replace healthy addresses with slow addresses
+    indices(&|healthiness| matches!(healthiness, Healthiness::Slow(_)))
+  } else {
+    working
+  }
 }
 
 impl PartialEq for BackendPool {
@@ -174,26 +717,42 @@ impl PartialEq for BackendPool {
 }
 
 pub struct BackendPoolBuilder {
+  name: String,
   matcher: BackendPoolMatcher,
-  addresses: Vec<(String, ArcSwap<Healthiness>)>,
+  addresses: Vec<(String, BackendHealth)>,
   health_config: HealthConfig,
   strategy: Box<dyn LoadBalancingStrategy>,
   chain: MiddlewareChain,
   schemes: HashSet<Scheme>,
   pool_idle_timeout: Option<Duration>,
   pool_max_idle_per_host: Option<usize>,
+  fallback: Option<Fallback>,
+  trace_matcher: bool,
+  log_redaction: LogRedaction,
+  admission_queue: Option<BackendLimit>,
+  backend_limit: Option<BackendLimit>,
+  no_healthy_backends: NoHealthyBackends,
+  slow_weights: Option<SlowWeights>,
+  max_buffer_bytes: usize,
+  cooldown: Option<Cooldown>,
+  status: Option<PoolStatus>,
+  shadow_strategy: Option<ShadowStrategy>,
+  hedging: Option<Hedging>,
+  streaming: bool,
 }
 
 impl BackendPoolBuilder {
   pub fn new(
+    name: String,
     matcher: BackendPoolMatcher,
-    addresses: Vec<(String, ArcSwap<Healthiness>)>,
+    addresses: Vec<(String, BackendHealth)>,
     health_config: HealthConfig,
     strategy: Box<dyn LoadBalancingStrategy>,
     chain: MiddlewareChain,
     schemes: HashSet<Scheme>,
   ) -> BackendPoolBuilder {
     BackendPoolBuilder {
+      name,
       matcher,
       addresses,
       health_config,
@@ -202,6 +761,19 @@ impl BackendPoolBuilder {
       schemes,
       pool_idle_timeout: None,
       pool_max_idle_per_host: None,
+      fallback: None,
+      trace_matcher: false,
+      log_redaction: LogRedaction::default(),
+      admission_queue: None,
+      backend_limit: None,
+      no_healthy_backends: NoHealthyBackends::default(),
+      slow_weights: None,
+      max_buffer_bytes: DEFAULT_MAX_BUFFER_BYTES,
+      cooldown: None,
+      status: None,
+      shadow_strategy: None,
+      hedging: None,
+      streaming: false,
     }
   }
 
@@ -215,6 +787,71 @@ impl BackendPoolBuilder {
     self
   }
 
+  pub fn fallback(&mut self, fallback: Fallback) -> &BackendPoolBuilder {
+    self.fallback = Some(fallback);
+    self
+  }
+
+  pub fn trace_matcher(&mut self) -> &BackendPoolBuilder {
+    self.trace_matcher = true;
+    self
+  }
+
+  pub fn log_redaction(&mut self, log_redaction: LogRedaction) -> &BackendPoolBuilder {
+    self.log_redaction = log_redaction;
+    self
+  }
+
+  pub fn admission_queue(&mut self, admission_queue: BackendLimit) -> &BackendPoolBuilder {
+    self.admission_queue = Some(admission_queue);
+    self
+  }
+
+  pub fn backend_limit(&mut self, backend_limit: BackendLimit) -> &BackendPoolBuilder {
+    self.backend_limit = Some(backend_limit);
+    self
+  }
+
+  pub fn no_healthy_backends(&mut self, no_healthy_backends: NoHealthyBackends) -> &BackendPoolBuilder {
+    self.no_healthy_backends = no_healthy_backends;
+    self
+  }
+
+  pub fn slow_weights(&mut self, slow_weights: SlowWeights) -> &BackendPoolBuilder {
+    self.slow_weights = Some(slow_weights);
+    self
+  }
+
+  pub fn max_buffer_bytes(&mut self, max_buffer_bytes: usize) -> &BackendPoolBuilder {
+    self.max_buffer_bytes = max_buffer_bytes;
+    self
+  }
+
+  pub fn cooldown(&mut self, cooldown: Cooldown) -> &BackendPoolBuilder {
+    self.cooldown = Some(cooldown);
+    self
+  }
+
+  pub fn status(&mut self, status: PoolStatus) -> &BackendPoolBuilder {
+    self.status = Some(status);
+    self
+  }
+
+  pub fn shadow_strategy(&mut self, shadow_strategy: ShadowStrategy) -> &BackendPoolBuilder {
+    self.shadow_strategy = Some(shadow_strategy);
+    self
+  }
+
+  pub fn hedging(&mut self, hedging: Hedging) -> &BackendPoolBuilder {
+    self.hedging = Some(hedging);
+    self
+  }
+
+  pub fn streaming(&mut self) -> &BackendPoolBuilder {
+    self.streaming = true;
+    self
+  }
+
   pub fn build(self) -> BackendPool {
     let mut client_builder = Client::builder();
     if let Some(pool_idle_timeout) = self.pool_idle_timeout {
@@ -225,21 +862,73 @@ impl BackendPoolBuilder {
     }
 
     let strategy = Arc::new(self.strategy);
-    let client: Client<_, Body> = client_builder.build(StrategyNotifyHttpConnector::new(strategy.clone()));
+    let connector = StrategyNotifyHttpConnector::new(strategy.clone());
+    let client: Client<_, Body> = client_builder.build(connector.clone());
+    let traffic = Traffic::new(self.addresses.iter().map(|(address, _)| address.as_str()));
+    let working_addresses = working_address_indices(
+      &self.addresses,
+      self.slow_weights.is_some(),
+      self.no_healthy_backends.use_slow_backends,
+    );
+    let address_indices = self
+      .addresses
+      .iter()
+      .enumerate()
+      .map(|(index, (address, _))| (address.clone(), index))
+      .collect();
 
     BackendPool {
+      name: self.name,
       matcher: self.matcher,
       addresses: self.addresses,
       health_config: self.health_config,
+      health_schedule: HealthSchedule::default(),
       strategy,
+      shadow_strategy: self.shadow_strategy,
+      hedging: self.hedging,
+      streaming: self.streaming,
       chain: self.chain,
       client,
+      connector,
       schemes: self.schemes,
+      fallback: self.fallback,
+      trace_matcher: self.trace_matcher,
+      log_redaction: self.log_redaction,
+      admission_queue: self.admission_queue,
+      backend_limit: self.backend_limit,
+      no_healthy_backends: self.no_healthy_backends,
+      slow_weights: self.slow_weights,
+      max_buffer_bytes: self.max_buffer_bytes,
+      cooldown: self.cooldown,
+      status: self.status,
+      traffic,
+      paused: AtomicBool::new(false),
+      working_addresses: ArcSwap::from_pointee(working_addresses),
+      address_indices,
     }
   }
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Hash)]
+/* A plain HTTP pool with the default health checks and no middlewares, shared by the tests of all modules */
+#[cfg(test)]
+pub(crate) fn test_pool_builder(
+  name: &str,
+  matcher: BackendPoolMatcher,
+  addresses: Vec<(String, BackendHealth)>,
+  strategy: Box<dyn LoadBalancingStrategy>,
+) -> BackendPoolBuilder {
+  BackendPoolBuilder::new(
+    name.into(),
+    matcher,
+    addresses,
+    HealthConfig::default(),
+    strategy,
+    MiddlewareChain::Empty,
+    vec![Scheme::HTTP].into_iter().collect(),
+  )
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, JsonSchema, Hash)]
 pub enum Scheme {
   HTTP,
   HTTPS,
@@ -258,38 +947,92 @@ impl Display for Scheme {
 mod tests {
 
   use super::*;
-  use crate::load_balancing::random::Random;
-  use std::{collections::HashMap, iter::FromIterator};
+  use crate::{
+    backend_pool_matcher::MatcherCache, certificate_expiry::CertificateExpiryConfig, configuration::Replacement,
+    debug_headers::DebugHeaders, health::HealthInterval, internal_endpoints::InternalEndpoints,
+    listeners::plain_acceptor, listeners::BindRetry, load_balancing::random::Random, log_redaction::LogRedaction,
+    middleware::maxbodysize::MaxBodySize, no_healthy_backends::NoHealthyBackendsResponse, service::WeightedPool,
+    tls::TlsSessionSettings,
+  };
+  use futures::stream;
+  use hyper::{
+    header::{HeaderValue, LOCATION},
+    service::{make_service_fn, service_fn},
+    Server, StatusCode, Version,
+  };
+  use std::{convert::Infallible, iter::FromIterator};
+  use tokio::net::{TcpListener, TcpStream};
 
   fn generate_config(shared_data: SharedData) -> RuntimeConfig {
     RuntimeConfig {
+      config_dir_override: None,
       shared_data,
-      http_address: "0.0.0.0:80".parse().unwrap(),
-      https_address: "0.0.0.0:443".parse().unwrap(),
-      certificates: HashMap::new(),
-      health_interval: std::time::Duration::from_secs(60),
+      http_address: Some("0.0.0.0:80".parse().unwrap()),
+      https_address: Some("0.0.0.0:443".parse().unwrap()),
+      bind_retry: BindRetry::default(),
+      admin_address: None,
+      admin_dashboard: false,
+      admin_tokens: Vec::new(),
+      replacement: Replacement::default(),
+      health_endpoint: None,
+      certificates: ArcSwap::from_pointee(HashMap::new()),
+      acme_orders: Vec::new(),
+      max_concurrent_acme_orders: 1,
+      certificate_expiry: CertificateExpiryConfig::default(),
+      health_interval: HealthInterval {
+        check_every: Duration::from_secs(60),
+        stagger: false,
+        max_concurrent_checks: None,
+        events: None,
+        initial_check: false,
+        coordination: None,
+      },
+      normalize_paths: false,
+      strict_sni: false,
+      internal_endpoints: InternalEndpoints::default(),
+      slow_request_threshold: None,
+      process_stats_interval: None,
+      debug_headers: DebugHeaders::default(),
+      log_redaction: LogRedaction::default(),
+      matchers: MatcherCache::default(),
+      error_pages: None,
+      connection_limit: None,
+      tls_sessions: TlsSessionSettings::default(),
     }
   }
+  fn generate_pool_builder(matcher: BackendPoolMatcher, healthiness: Healthiness) -> BackendPoolBuilder {
+    generate_pool_builder_for(matcher, healthiness, Scheme::HTTP)
+  }
+  fn generate_pool_builder_for(
+    matcher: BackendPoolMatcher,
+    healthiness: Healthiness,
+    scheme: Scheme,
+  ) -> BackendPoolBuilder {
+    let mut builder = test_pool_builder(
+      "test",
+      matcher,
+      vec![("127.0.0.1:8084".into(), BackendHealth::new(healthiness))],
+      Box::new(Random::new()),
+    );
+    builder.schemes = HashSet::from_iter(vec![scheme]);
+    builder
+  }
+
   fn generate_test_service(host: String, scheme: Scheme) -> MainService {
     MainService {
       scheme,
       client_address: "127.0.0.1:3000".parse().unwrap(),
+      server_name: None,
+      tls_fingerprint: None,
+      routes: Arc::default(),
       config: Arc::new(ArcSwap::from_pointee(generate_config(SharedData {
         backend_pools: vec![Arc::new(
-          BackendPoolBuilder::new(
-            BackendPoolMatcher::Host(host),
-            vec![("127.0.0.1:8084".into(), ArcSwap::from_pointee(Healthiness::Healthy))],
-            HealthConfig {
-              slow_threshold: 200,
-              timeout: 500,
-              path: String::from("/"),
-            },
-            Box::new(Random::new()),
-            MiddlewareChain::Empty,
-            HashSet::from_iter(vec![Scheme::HTTP]),
-          )
-          .build(),
+          generate_pool_builder(BackendPoolMatcher::Host(host), Healthiness::Healthy).build(),
         )],
+        default_pool: None,
+        http10_pools: HashMap::new(),
+        services: Vec::new(),
+        pool_index: PoolIndex::default(),
         acme_handler: Arc::new(AcmeHandler::new()),
       }))),
     }
@@ -324,4 +1067,585 @@ mod tests {
 
     assert_eq!(pool, Some(shared_data.backend_pools[0].clone()));
   }
+
+  #[test]
+  fn pool_by_req_fallback_pool_when_unavailable() {
+    let mut primary = generate_pool_builder(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      Healthiness::Unresponsive(None),
+    );
+    primary.fallback(Fallback {
+      pool: 1,
+      error_budget: None,
+    });
+    let fallback = generate_pool_builder(
+      BackendPoolMatcher::Host("fallback.localhost".into()),
+      Healthiness::Healthy,
+    );
+    let shared_data = SharedData {
+      backend_pools: vec![Arc::new(primary.build()), Arc::new(fallback.build())],
+      default_pool: None,
+      http10_pools: HashMap::new(),
+      services: Vec::new(),
+      pool_index: PoolIndex::default(),
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+    let request = Request::builder()
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    let pool = pool_by_req(&shared_data, &request, &Scheme::HTTP);
+
+    assert_eq!(pool, Some(shared_data.backend_pools[1].clone()));
+    assert_eq!(
+      select_pool(&shared_data, &request, &Scheme::HTTP, true).map(|(_, route)| route),
+      Some(Route::Fallback("test"))
+    );
+  }
+
+  #[test]
+  fn pool_by_req_keeps_pool_if_fallback_pool_is_unavailable() {
+    let mut primary = generate_pool_builder(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      Healthiness::Unresponsive(None),
+    );
+    primary.fallback(Fallback {
+      pool: 1,
+      error_budget: None,
+    });
+    let fallback = generate_pool_builder(
+      BackendPoolMatcher::Host("fallback.localhost".into()),
+      Healthiness::Unresponsive(None),
+    );
+    let shared_data = SharedData {
+      backend_pools: vec![Arc::new(primary.build()), Arc::new(fallback.build())],
+      default_pool: None,
+      http10_pools: HashMap::new(),
+      services: Vec::new(),
+      pool_index: PoolIndex::default(),
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+    let request = Request::builder()
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    let pool = pool_by_req(&shared_data, &request, &Scheme::HTTP);
+
+    assert_eq!(pool, Some(shared_data.backend_pools[0].clone()));
+    assert_eq!(
+      select_pool(&shared_data, &request, &Scheme::HTTP, true).map(|(_, route)| route),
+      Some(Route::Matcher)
+    );
+  }
+
+  #[test]
+  fn pool_by_req_default_pool_when_unmatched() {
+    let pool = generate_pool_builder(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      Healthiness::Healthy,
+    );
+    let default = generate_pool_builder(
+      BackendPoolMatcher::Host("default.localhost".into()),
+      Healthiness::Healthy,
+    );
+    let shared_data = SharedData {
+      backend_pools: vec![Arc::new(pool.build()), Arc::new(default.build())],
+      default_pool: Some(1),
+      http10_pools: HashMap::new(),
+      services: Vec::new(),
+      pool_index: PoolIndex::default(),
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+    let request = Request::builder()
+      .header("host", "unknown.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    let http_pool = pool_by_req(&shared_data, &request, &Scheme::HTTP);
+    let https_pool = pool_by_req(&shared_data, &request, &Scheme::HTTPS);
+
+    assert_eq!(http_pool, Some(shared_data.backend_pools[1].clone()));
+    assert_eq!(https_pool, None);
+    assert_eq!(
+      select_pool(&shared_data, &request, &Scheme::HTTP, true).map(|(_, route)| route),
+      Some(Route::DefaultPool)
+    );
+  }
+
+  #[test]
+  fn pool_by_req_keeps_the_order_of_indexed_pools() {
+    // given:
+    let matchers = [
+      "Host('whoami.localhost') && Path('/api')",
+      "PathRegexp('^/api')",
+      "Host('whoami.localhost')",
+    ];
+    let backend_pools = matchers
+      .iter()
+      .map(|matcher| Arc::new(generate_pool_builder(matcher.parse().unwrap(), Healthiness::Healthy).build()))
+      .collect::<Vec<_>>();
+    let shared_data = SharedData {
+      pool_index: PoolIndex::new(&backend_pools),
+      backend_pools,
+      default_pool: None,
+      http10_pools: HashMap::new(),
+      services: Vec::new(),
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+    let request = |host: &str, path: &str| Request::get(path).header(HOST, host).body(Body::empty()).unwrap();
+
+    // when:
+    let api = pool_by_req(&shared_data, &request("whoami.localhost", "/api"), &Scheme::HTTP);
+    let other_api = pool_by_req(&shared_data, &request("other.localhost", "/api/v2"), &Scheme::HTTP);
+    let page = pool_by_req(&shared_data, &request("whoami.localhost", "/"), &Scheme::HTTP);
+    let other_page = pool_by_req(&shared_data, &request("other.localhost", "/"), &Scheme::HTTP);
+
+    // then:
+    assert_eq!(api, Some(shared_data.backend_pools[0].clone()));
+    assert_eq!(other_api, Some(shared_data.backend_pools[1].clone()));
+    assert_eq!(page, Some(shared_data.backend_pools[2].clone()));
+    assert_eq!(other_page, None);
+  }
+
+  #[test]
+  fn working_addresses_prefer_healthy_backend_servers() {
+    let pool = |second: Healthiness, use_slow_backends: bool, slow_weights: Option<SlowWeights>| {
+      let mut builder = generate_pool_builder(
+        BackendPoolMatcher::Host("whoami.localhost".into()),
+        Healthiness::Healthy,
+      );
+      builder.addresses = vec![
+        ("127.0.0.1:1".into(), BackendHealth::new(Healthiness::Slow(500))),
+        ("127.0.0.1:2".into(), BackendHealth::new(second)),
+        (
+          "127.0.0.1:3".into(),
+          BackendHealth::new(Healthiness::Unresponsive(None)),
+        ),
+      ];
+      builder.no_healthy_backends.use_slow_backends = use_slow_backends;
+      builder.slow_weights = slow_weights;
+      builder.build()
+    };
+    let slow_weights = || Some(SlowWeights::new(vec![(200, 0.5)]));
+    let unresponsive = || Healthiness::Unresponsive(None);
+
+    assert_eq!(
+      pool(Healthiness::Healthy, true, None).working_addresses(),
+      vec!["127.0.0.1:2"]
+    );
+    assert_eq!(
+      pool(Healthiness::Healthy, true, slow_weights()).working_addresses(),
+      vec!["127.0.0.1:1", "127.0.0.1:2"]
+    );
+    assert_eq!(
+      pool(unresponsive(), true, None).working_addresses(),
+      vec!["127.0.0.1:1"]
+    );
+    assert!(pool(unresponsive(), false, None).working_addresses().is_empty());
+  }
+
+  #[test]
+  fn backend_weights_follow_the_addresses() {
+    // given:
+    let mut builder = generate_pool_builder(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      Healthiness::Healthy,
+    );
+    builder.addresses = vec![
+      ("127.0.0.1:1".into(), BackendHealth::new(Healthiness::Slow(500))),
+      ("127.0.0.1:2".into(), BackendHealth::new(Healthiness::Healthy)),
+    ];
+    builder.slow_weights = Some(SlowWeights::new(vec![(200, 0.5)]));
+    let pool = builder.build();
+
+    // when:
+    let weights = pool.backend_weights(&["127.0.0.1:2", "127.0.0.1:1"]);
+
+    // then:
+    let weights = weights.iter().map(|it| it.weight).collect::<Vec<_>>();
+    assert_eq!(weights, vec![1.0, 0.5]);
+  }
+
+  #[test]
+  fn pool_by_req_http10_pool_without_host() {
+    let pool = generate_pool_builder(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      Healthiness::Healthy,
+    );
+    let legacy = generate_pool_builder(
+      BackendPoolMatcher::Host("legacy.localhost".into()),
+      Healthiness::Healthy,
+    );
+    let shared_data = SharedData {
+      backend_pools: vec![Arc::new(pool.build()), Arc::new(legacy.build())],
+      default_pool: Some(0),
+      http10_pools: HashMap::from_iter(vec![(Scheme::HTTP, 1)]),
+      services: Vec::new(),
+      pool_index: PoolIndex::default(),
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+    let request = Request::builder()
+      .version(Version::HTTP_10)
+      .body(Body::empty())
+      .unwrap();
+
+    let http_pool = pool_by_req(&shared_data, &request, &Scheme::HTTP);
+    let https_pool = pool_by_req(&shared_data, &request, &Scheme::HTTPS);
+
+    assert_eq!(http_pool, Some(shared_data.backend_pools[1].clone()));
+    assert_eq!(https_pool, None);
+    assert_eq!(
+      select_pool(&shared_data, &request, &Scheme::HTTP, true).map(|(_, route)| route),
+      Some(Route::Http10)
+    );
+  }
+
+  #[test]
+  fn pool_by_req_ignores_http10_pool_of_other_scheme() {
+    let pool = generate_pool_builder(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      Healthiness::Healthy,
+    );
+    let legacy = generate_pool_builder(
+      BackendPoolMatcher::Host("legacy.localhost".into()),
+      Healthiness::Healthy,
+    );
+    let shared_data = SharedData {
+      backend_pools: vec![Arc::new(pool.build()), Arc::new(legacy.build())],
+      default_pool: None,
+      http10_pools: HashMap::from_iter(vec![(Scheme::HTTPS, 1)]),
+      services: Vec::new(),
+      pool_index: PoolIndex::default(),
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+    let request = Request::builder()
+      .version(Version::HTTP_10)
+      .body(Body::empty())
+      .unwrap();
+
+    let pool = pool_by_req(&shared_data, &request, &Scheme::HTTPS);
+
+    assert_eq!(pool, None);
+  }
+
+  #[test]
+  fn pool_by_req_service_pool_before_pools() {
+    let pool = generate_pool_builder(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      Healthiness::Healthy,
+    );
+    let canary = generate_pool_builder(
+      BackendPoolMatcher::Host("canary.whoami.localhost".into()),
+      Healthiness::Healthy,
+    );
+    let shared_data = SharedData {
+      backend_pools: vec![Arc::new(pool.build()), Arc::new(canary.build())],
+      default_pool: None,
+      http10_pools: HashMap::new(),
+      services: vec![Service {
+        name: "whoami".into(),
+        matcher: BackendPoolMatcher::Host("whoami.localhost".into()),
+        pools: vec![WeightedPool { pool: 1, weight: 1.0 }],
+        failover: vec![0],
+      }],
+      pool_index: PoolIndex::default(),
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+    let request = Request::builder()
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    let pool = pool_by_req(&shared_data, &request, &Scheme::HTTP);
+
+    assert_eq!(pool, Some(shared_data.backend_pools[1].clone()));
+    assert_eq!(
+      select_pool(&shared_data, &request, &Scheme::HTTP, true).map(|(_, route)| route),
+      Some(Route::Service("whoami"))
+    );
+  }
+
+  #[tokio::test]
+  async fn handle_answers_with_configured_response_if_no_backend_is_healthy() {
+    // given:
+    let mut pool = generate_pool_builder(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      Healthiness::Unresponsive(None),
+    );
+    pool.no_healthy_backends(NoHealthyBackends {
+      use_slow_backends: true,
+      response: NoHealthyBackendsResponse::Redirect {
+        location: HeaderValue::from_static("https://status.example.org"),
+      },
+    });
+    let service = MainService {
+      scheme: Scheme::HTTP,
+      client_address: "127.0.0.1:3000".parse().unwrap(),
+      server_name: None,
+      tls_fingerprint: None,
+      routes: Arc::default(),
+      config: Arc::new(ArcSwap::from_pointee(generate_config(SharedData {
+        backend_pools: vec![Arc::new(pool.build())],
+        default_pool: None,
+        http10_pools: HashMap::new(),
+        services: Vec::new(),
+        pool_index: PoolIndex::default(),
+        acme_handler: Arc::new(AcmeHandler::new()),
+      }))),
+    };
+    let request = Request::builder()
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = service.handle(request).await.unwrap();
+
+    // then:
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(response.headers()[LOCATION], "https://status.example.org");
+  }
+
+  #[tokio::test]
+  async fn handle_serves_status_of_paused_pool() {
+    // given:
+    let mut pool = generate_pool_builder(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      Healthiness::Unresponsive(None),
+    );
+    pool.status(PoolStatus {
+      path: "/_arlb/status".into(),
+      allowed_ips: vec!["127.0.0.1".parse().unwrap()],
+    });
+    let pool = pool.build();
+    pool.pause();
+    let service = MainService {
+      scheme: Scheme::HTTP,
+      client_address: "127.0.0.1:3000".parse().unwrap(),
+      server_name: None,
+      tls_fingerprint: None,
+      routes: Arc::default(),
+      config: Arc::new(ArcSwap::from_pointee(generate_config(SharedData {
+        backend_pools: vec![Arc::new(pool)],
+        default_pool: None,
+        http10_pools: HashMap::new(),
+        services: Vec::new(),
+        pool_index: PoolIndex::default(),
+        acme_handler: Arc::new(AcmeHandler::new()),
+      }))),
+    };
+    let status_request = Request::get("/_arlb/status")
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+    let other_request = Request::get("/")
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let status_response = service.handle(status_request).await.unwrap();
+    let other_response = service.handle(other_request).await.unwrap();
+
+    // then:
+    assert_eq!(status_response.status(), StatusCode::OK);
+    assert_eq!(other_response.status(), StatusCode::SERVICE_UNAVAILABLE);
+  }
+
+  #[tokio::test]
+  async fn handle_answers_reserved_paths_before_matching_pools() {
+    // given:
+    let mut pool = generate_pool_builder(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      Healthiness::Healthy,
+    );
+    pool.status(PoolStatus {
+      path: "/_arlb/status".into(),
+      allowed_ips: vec!["127.0.0.1".parse().unwrap()],
+    });
+    let service = MainService {
+      scheme: Scheme::HTTP,
+      client_address: "127.0.0.1:3000".parse().unwrap(),
+      server_name: None,
+      tls_fingerprint: None,
+      routes: Arc::default(),
+      config: Arc::new(ArcSwap::from_pointee(generate_config(SharedData {
+        backend_pools: vec![Arc::new(pool.build())],
+        default_pool: None,
+        http10_pools: HashMap::new(),
+        services: Vec::new(),
+        pool_index: PoolIndex::default(),
+        acme_handler: Arc::new(AcmeHandler::new()),
+      }))),
+    };
+    let status_request = Request::get("/.well-known/arlb/status/test")
+      .header("host", "unknown.localhost")
+      .body(Body::empty())
+      .unwrap();
+    let unknown_request = Request::get("/.well-known/arlb/unknown")
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let status_response = service.handle(status_request).await.unwrap();
+    let unknown_response = service.handle(unknown_request).await.unwrap();
+
+    // then:
+    assert_eq!(status_response.status(), StatusCode::OK);
+    assert_eq!(unknown_response.status(), StatusCode::NOT_FOUND);
+  }
+
+  #[tokio::test]
+  async fn handle_rejects_chunked_bodies_exceeding_max_body_size() {
+    // given: a backend server answering with the size of the received body
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(
+      Server::builder(plain_acceptor(listener)).serve(make_service_fn(|_: &TcpStream| async {
+        Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+          let body = hyper::body::to_bytes(request.into_body()).await?;
+          Ok::<_, hyper::Error>(Response::new(Body::from(body.len().to_string())))
+        }))
+      })),
+    );
+    let mut pool = generate_pool_builder(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      Healthiness::Healthy,
+    );
+    pool.addresses = vec![(address.to_string(), BackendHealth::new(Healthiness::Healthy))];
+    pool.chain = MiddlewareChain::Entry {
+      middleware: Box::new(MaxBodySize {
+        limit: 1024,
+        max_decompression_ratio: None,
+      }),
+      matcher: None,
+      chain: Box::new(MiddlewareChain::Empty),
+    };
+    let service = MainService {
+      scheme: Scheme::HTTP,
+      client_address: "127.0.0.1:3000".parse().unwrap(),
+      server_name: None,
+      tls_fingerprint: None,
+      routes: Arc::default(),
+      config: Arc::new(ArcSwap::from_pointee(generate_config(SharedData {
+        backend_pools: vec![Arc::new(pool.build())],
+        default_pool: None,
+        http10_pools: HashMap::new(),
+        services: Vec::new(),
+        pool_index: PoolIndex::default(),
+        acme_handler: Arc::new(AcmeHandler::new()),
+      }))),
+    };
+    let chunked_request = |chunks: Vec<usize>| {
+      let chunks: Vec<Result<_, io::Error>> = chunks.into_iter().map(|size| Ok(vec![0u8; size])).collect();
+      Request::post("/")
+        .header("host", "whoami.localhost")
+        .body(Body::wrap_stream(stream::iter(chunks)))
+        .unwrap()
+    };
+
+    // when:
+    let small = service.handle(chunked_request(vec![500, 500])).await.unwrap();
+    let large = service.handle(chunked_request(vec![500, 500, 500])).await.unwrap();
+
+    // then:
+    assert_eq!(small.status(), StatusCode::OK);
+    assert_eq!(hyper::body::to_bytes(small.into_body()).await.unwrap(), "1000");
+    assert_eq!(large.status(), StatusCode::PAYLOAD_TOO_LARGE);
+  }
+
+  fn generate_strict_sni_service(server_name: &str) -> MainService {
+    let mut config = generate_config(SharedData {
+      backend_pools: vec![Arc::new(
+        generate_pool_builder_for(
+          BackendPoolMatcher::Host("whoami.localhost".into()),
+          Healthiness::Healthy,
+          Scheme::HTTPS,
+        )
+        .build(),
+      )],
+      default_pool: Some(0),
+      http10_pools: HashMap::new(),
+      services: Vec::new(),
+      pool_index: PoolIndex::default(),
+      acme_handler: Arc::new(AcmeHandler::new()),
+    });
+    config.strict_sni = true;
+    MainService {
+      scheme: Scheme::HTTPS,
+      client_address: "127.0.0.1:3000".parse().unwrap(),
+      server_name: Some(server_name.into()),
+      tls_fingerprint: None,
+      config: Arc::new(ArcSwap::from_pointee(config)),
+      routes: Arc::default(),
+    }
+  }
+
+  #[test]
+  fn connection_routes_are_stale_once_a_used_backend_is_removed() {
+    // given:
+    let routes = ConnectionRoutes::default();
+    let shared_data = |name: &str, address: &str| {
+      let mut pool = generate_pool_builder(
+        BackendPoolMatcher::Host("whoami.localhost".into()),
+        Healthiness::Healthy,
+      )
+      .build();
+      pool.name = name.into();
+      pool.addresses = vec![(address.into(), BackendHealth::new(Healthiness::Healthy))];
+      SharedData {
+        backend_pools: vec![Arc::new(pool)],
+        default_pool: None,
+        http10_pools: HashMap::new(),
+        services: Vec::new(),
+        pool_index: PoolIndex::default(),
+        acme_handler: Arc::new(AcmeHandler::new()),
+      }
+    };
+
+    // when:
+    let unused = routes.is_stale(&shared_data("whoami", "127.0.0.1:2"));
+    routes.record("whoami", "127.0.0.1:1");
+
+    // then:
+    assert!(!unused);
+    assert!(!routes.is_stale(&shared_data("whoami", "127.0.0.1:1")));
+    assert!(routes.is_stale(&shared_data("whoami", "127.0.0.1:2")));
+    assert!(routes.is_stale(&shared_data("whoami-v2", "127.0.0.1:1")));
+  }
+
+  #[tokio::test]
+  async fn handle_rejects_host_not_matching_server_name_with_strict_sni() {
+    // given:
+    let service = generate_strict_sni_service("other.localhost");
+    let request = Request::builder()
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = service.handle(request).await.unwrap();
+
+    // then:
+    assert_eq!(response.status(), StatusCode::MISDIRECTED_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn handle_rejects_unknown_host_instead_of_default_pool_with_strict_sni() {
+    // given:
+    let service = generate_strict_sni_service("unknown.localhost");
+    let request = Request::builder()
+      .header("host", "unknown.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = service.handle(request).await.unwrap();
+
+    // then:
+    assert_eq!(response.status(), StatusCode::MISDIRECTED_REQUEST);
+  }
 }
@@ -0,0 +1,116 @@
+use crate::backend_pool_matcher::BackendPoolMatcher;
+use rand::{thread_rng, Rng};
+
+/// A backend pool of a [`Service`] receiving a share of its requests relative to `weight`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeightedPool {
+  pub pool: usize,
+  pub weight: f64,
+}
+
+/// Routes requests matching `matcher` to several backend pools, e.g. clusters in different regions or a canary
+/// release. Requests are spread over the available `pools` by weight. If none of them is available, the `failover`
+/// pools are tried in order.
+#[derive(Debug)]
+pub struct Service {
+  pub name: String,
+  pub matcher: BackendPoolMatcher,
+  pub pools: Vec<WeightedPool>,
+  pub failover: Vec<usize>,
+}
+
+impl Service {
+  /// Returns the index of the backend pool handling the next request. Only pools for which `supports` holds are
+  /// considered, `is_available` decides whether a pool can take requests.
+  pub fn select<S, A>(&self, supports: S, is_available: A) -> Option<usize>
+  where
+    S: Fn(usize) -> bool,
+    A: Fn(usize) -> bool,
+  {
+    let available = self
+      .pools
+      .iter()
+      .filter(|it| it.weight > 0.0 && supports(it.pool) && is_available(it.pool))
+      .collect::<Vec<_>>();
+    let total_weight = available.iter().map(|it| it.weight).sum::<f64>();
+    if total_weight > 0.0 {
+      let mut point = thread_rng().gen_range(0.0..total_weight);
+      for weighted in &available {
+        if point < weighted.weight {
+          return Some(weighted.pool);
+        }
+        point -= weighted.weight;
+      }
+      return available.last().map(|it| it.pool);
+    }
+
+    let failover = || self.failover.iter().copied().filter(|pool| supports(*pool));
+    failover()
+      .find(|pool| is_available(*pool))
+      // nothing is available, the first pool answers the request as usual, e.g. with its `no_healthy_backends` response
+      .or_else(|| self.pools.iter().map(|it| it.pool).find(|pool| supports(*pool)))
+      .or_else(|| failover().next())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn service(weights: &[f64], failover: Vec<usize>) -> Service {
+    Service {
+      name: "whoami".into(),
+      matcher: BackendPoolMatcher::Host("whoami.localhost".into()),
+      pools: weights
+        .iter()
+        .enumerate()
+        .map(|(pool, weight)| WeightedPool { pool, weight: *weight })
+        .collect(),
+      failover,
+    }
+  }
+
+  #[test]
+  fn selects_pools_by_weight() {
+    // given:
+    let service = service(&[3.0, 1.0, 0.0], Vec::new());
+
+    // when:
+    let mut counts = [0; 3];
+    for _ in 0..4000 {
+      counts[service.select(|_| true, |_| true).unwrap()] += 1;
+    }
+
+    // then:
+    assert!(counts[0] > 2700 && counts[0] < 3300, "{:?}", counts);
+    assert_eq!(counts[2], 0);
+  }
+
+  #[test]
+  fn skips_unavailable_and_unsupported_pools() {
+    // given:
+    let service = service(&[1.0, 1.0, 1.0], Vec::new());
+
+    // when:
+    let pools = (0..100)
+      .map(|_| service.select(|pool| pool != 0, |pool| pool != 1).unwrap())
+      .collect::<Vec<_>>();
+
+    // then:
+    assert!(pools.iter().all(|pool| *pool == 2));
+  }
+
+  #[test]
+  fn fails_over_in_order() {
+    // given:
+    let service = service(&[1.0, 1.0], vec![3, 2]);
+
+    // when:
+    let failover = service.select(|_| true, |pool| pool == 2);
+    let nothing_available = service.select(|pool| pool != 0, |_| false);
+
+    // then:
+    assert_eq!(failover, Some(2));
+    assert_eq!(nothing_available, Some(1));
+  }
+}
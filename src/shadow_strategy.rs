@@ -0,0 +1,120 @@
+use crate::{
+  debug_headers::type_name,
+  load_balancing::{Context, LoadBalancingStrategy},
+  log_redaction::LogRedaction,
+};
+use hyper::{Body, Request};
+use log::debug;
+use std::{
+  collections::{BTreeMap, HashMap},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+  },
+};
+
+/// A second load balancing strategy of a backend pool, which selects a backend server for every forwarded request
+/// without influencing where the request is forwarded to. Counts how often it agrees with the active strategy, so a
+/// new strategy can be evaluated with real traffic before switching to it.
+///
+/// The backend server selected by the shadow strategy is never contacted: strategies modifying the response, like
+/// `StickyCookie`, don't get to do so, and strategies counting connections, like `LeastConnection`, see idle backend
+/// servers only.
+#[derive(Debug)]
+pub struct ShadowStrategy {
+  strategy: Box<dyn LoadBalancingStrategy>,
+  agreements: AtomicU64,
+  divergences: AtomicU64,
+  /* The number of requests per backend server the shadow strategy selected */
+  selections: Mutex<HashMap<String, u64>>,
+}
+
+impl ShadowStrategy {
+  pub fn new(strategy: Box<dyn LoadBalancingStrategy>) -> ShadowStrategy {
+    ShadowStrategy {
+      strategy,
+      agreements: AtomicU64::new(0),
+      divergences: AtomicU64::new(0),
+      selections: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Lets the shadow strategy select a backend server for the `request`, which the active strategy forwards to
+  /// `selected`. The request is logged masked by `log_redaction`.
+  pub fn evaluate(&self, request: &Request<Body>, context: &Context, selected: &str, log_redaction: &LogRedaction) {
+    let shadow = self.strategy.select_backend(request, context);
+    let address = shadow.backend_address();
+    if address == selected {
+      self.agreements.fetch_add(1, Ordering::Relaxed);
+    } else {
+      self.divergences.fetch_add(1, Ordering::Relaxed);
+      debug!(
+        "Shadow strategy selected {} instead of {} for {}",
+        address,
+        selected,
+        log_redaction.request_line(request)
+      );
+    }
+    *self.selections.lock().unwrap().entry(address.to_string()).or_insert(0) += 1;
+  }
+
+  pub fn name(&self) -> String {
+    type_name(&self.strategy)
+  }
+
+  /// Requests for which the shadow strategy selected the same backend server as the active strategy.
+  pub fn agreements(&self) -> u64 {
+    self.agreements.load(Ordering::Relaxed)
+  }
+
+  /// Requests for which the shadow strategy selected another backend server than the active strategy.
+  pub fn divergences(&self) -> u64 {
+    self.divergences.load(Ordering::Relaxed)
+  }
+
+  /// The number of requests per backend server the shadow strategy selected.
+  pub fn selections(&self) -> BTreeMap<String, u64> {
+    self
+      .selections
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(address, count)| (address.clone(), *count))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::load_balancing::{ip_hash::IPHash, round_robin::RoundRobin};
+
+  #[test]
+  fn counts_agreements_and_divergences() {
+    // given:
+    let shadow = ShadowStrategy::new(Box::new(RoundRobin::new()));
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      pool_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+      backend_weights: &[],
+    };
+
+    // when:
+    for _ in 0..4 {
+      shadow.evaluate(&request, &context, "127.0.0.1:1", &LogRedaction::default());
+    }
+
+    // then:
+    assert_eq!(shadow.agreements(), 2);
+    assert_eq!(shadow.divergences(), 2);
+    assert_eq!(shadow.selections()["127.0.0.1:1"], 2);
+    assert_eq!(shadow.selections()["127.0.0.1:2"], 2);
+  }
+
+  #[test]
+  fn names_the_shadow_strategy() {
+    assert_eq!(ShadowStrategy::new(Box::new(IPHash::new())).name(), "IPHash");
+  }
+}
@@ -0,0 +1,301 @@
+use crate::{
+  configuration::read_initial_config,
+  debug_headers::type_name,
+  health::Healthiness,
+  load_balancing::{BackendWeight, Context},
+  server::BackendPool,
+  utils::split_once,
+};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use hyper::{Body, Request};
+use std::{
+  collections::{HashMap, HashSet},
+  io,
+  net::{IpAddr, Ipv4Addr, SocketAddr},
+  path::PathBuf,
+};
+
+pub const NAME: &str = "simulate-strategy";
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name(NAME)
+    .about("Prints how the load balancing strategy of a backend pool spreads requests over its backend servers, without starting the load balancer.")
+    .arg(
+      Arg::with_name("config")
+        .short("c")
+        .long("config")
+        .value_name("TOML FILE")
+        .help("The path to the configuration in TOML format.")
+        .required(true)
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("config-dir")
+        .long("config-dir")
+        .value_name("DIRECTORY")
+        .help("The directory relative paths in the configuration are resolved against. Defaults to the directory of the configuration file.")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("pool")
+        .long("pool")
+        .value_name("NAME")
+        .help("The name of the backend pool.")
+        .required(true)
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("requests")
+        .long("requests")
+        .value_name("COUNT")
+        .help("The number of simulated requests.")
+        .default_value("10000")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("clients")
+        .long("clients")
+        .value_name("COUNT")
+        .help("The number of distinct client IP addresses the requests are sent from.")
+        .default_value("1000")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("slow")
+        .long("slow")
+        .value_name("ADDRESS=MILLISECONDS")
+        .help("Treats a backend server as slow with the given response time, can be used multiple times. Applies the slow_weights of the pool.")
+        .multiple(true)
+        .number_of_values(1)
+        .takes_value(true),
+    )
+}
+
+pub async fn run(matches: &ArgMatches<'_>) -> Result<(), io::Error> {
+  let config = read_initial_config(
+    matches.value_of("config").unwrap(),
+    matches.value_of("config-dir").map(PathBuf::from),
+  )
+  .await?;
+  let config = config.load();
+  let pool_name = matches.value_of("pool").unwrap();
+  let requests = parse_count(matches.value_of("requests").unwrap())?;
+  let clients = parse_count(matches.value_of("clients").unwrap())?;
+  let latencies = parse_latencies(matches.values_of("slow").into_iter().flatten())?;
+
+  let pool = match config
+    .shared_data
+    .backend_pools
+    .iter()
+    .find(|pool| pool.name == pool_name)
+  {
+    Some(pool) => pool,
+    None => {
+      println!("No backend pool is named '{}'", pool_name);
+      return Err(io::Error::new(io::ErrorKind::NotFound, "Unknown backend pool"));
+    }
+  };
+  if let Some(address) = latencies
+    .keys()
+    .find(|address| !pool.addresses.iter().any(|(it, _)| it == *address))
+  {
+    return Err(invalid_input(format!(
+      "{} is no backend server of backend pool '{}'",
+      address, pool_name
+    )));
+  }
+
+  println!("Backend pool:  {}", pool.name);
+  println!("Strategy:      {}", type_name(&pool.strategy));
+  println!("Requests:      {} from {} clients", requests, clients);
+  println!();
+  println!(
+    "{:<30} {:>10} {:>8} {:>8}",
+    "Backend server", "Requests", "Share", "Clients"
+  );
+  for share in simulate(pool, requests, clients, &latencies) {
+    println!(
+      "{:<30} {:>10} {:>7.1}% {:>8}",
+      share.address,
+      share.requests,
+      100.0 * share.requests as f64 / requests as f64,
+      share.clients
+    );
+  }
+  Ok(())
+}
+
+/// The requests a backend server received in a simulation, and from how many distinct clients.
+#[derive(Debug, PartialEq)]
+pub struct BackendShare {
+  pub address: String,
+  pub requests: usize,
+  pub clients: usize,
+}
+
+/// Lets the strategy of `pool` select a backend server for `requests` requests, sent round-robin from `clients`
+/// distinct client IP addresses. All backend servers are considered healthy, except those in `latencies`, which are
+/// slow with the given response time in milliseconds.
+///
+/// The requests are never forwarded, so strategies depending on the traffic of open connections, e.g.
+/// `LeastConnection`, see idle backend servers only.
+pub fn simulate(
+  pool: &BackendPool,
+  requests: usize,
+  clients: usize,
+  latencies: &HashMap<String, i64>,
+) -> Vec<BackendShare> {
+  let addresses = pool
+    .addresses
+    .iter()
+    .map(|(address, _)| address.as_str())
+    .collect::<Vec<_>>();
+  let backend_weights = match &pool.slow_weights {
+    Some(slow_weights) => addresses
+      .iter()
+      .map(|address| match latencies.get(*address) {
+        Some(latency) => slow_weights.weight(&Healthiness::Slow(*latency)),
+        None => slow_weights.weight(&Healthiness::Healthy),
+      })
+      .collect(),
+    None => Vec::<BackendWeight>::new(),
+  };
+
+  let mut requests_per_backend = vec![0; addresses.len()];
+  let mut clients_per_backend = vec![HashSet::new(); addresses.len()];
+  for index in 0..requests {
+    let client_address = client_address(index % clients.max(1));
+    let request = Request::get("/").body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &client_address,
+      backend_addresses: &addresses,
+      pool_addresses: &addresses,
+      backend_weights: &backend_weights,
+    };
+    let backend = pool.strategy.select_backend(&request, &context);
+    if let Some(position) = addresses.iter().position(|it| *it == backend.backend_address()) {
+      requests_per_backend[position] += 1;
+      clients_per_backend[position].insert(client_address.ip());
+    }
+  }
+
+  addresses
+    .iter()
+    .zip(requests_per_backend)
+    .zip(clients_per_backend)
+    .map(|((address, requests), clients)| BackendShare {
+      address: address.to_string(),
+      requests,
+      clients: clients.len(),
+    })
+    .collect()
+}
+
+/* The n-th simulated client, counting up from 10.0.0.0 */
+fn client_address(n: usize) -> SocketAddr {
+  let ip = Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 0)).wrapping_add(n as u32));
+  SocketAddr::new(IpAddr::V4(ip), 40000)
+}
+
+fn parse_count(value: &str) -> Result<usize, io::Error> {
+  match value.parse() {
+    Ok(count) if count > 0 => Ok(count),
+    _ => Err(invalid_input(format!(
+      "Invalid count {}, expected a positive number",
+      value
+    ))),
+  }
+}
+
+fn parse_latencies<'a, I>(values: I) -> Result<HashMap<String, i64>, io::Error>
+where
+  I: IntoIterator<Item = &'a str>,
+{
+  values
+    .into_iter()
+    .map(|value| match split_once(value, '=') {
+      Some((address, latency)) => match latency.trim().parse() {
+        Ok(latency) => Ok((address.trim().to_string(), latency)),
+        Err(_) => Err(invalid_input(format!("Invalid response time in {}", value))),
+      },
+      None => Err(invalid_input(format!("Invalid slow backend server {}", value))),
+    })
+    .collect()
+}
+
+fn invalid_input<E>(error: E) -> io::Error
+where
+  E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+  io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    backend_pool_matcher::BackendPoolMatcher,
+    health::BackendHealth,
+    load_balancing::{ip_hash::IPHash, random::Random, round_robin::RoundRobin, LoadBalancingStrategy, SlowWeights},
+    server::{test_pool_builder, BackendPoolBuilder},
+  };
+
+  fn pool_builder(strategy: Box<dyn LoadBalancingStrategy>) -> BackendPoolBuilder {
+    test_pool_builder(
+      "whoami",
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      vec![
+        ("127.0.0.1:8080".into(), BackendHealth::new(Healthiness::Healthy)),
+        ("127.0.0.1:8081".into(), BackendHealth::new(Healthiness::Healthy)),
+        ("127.0.0.1:8082".into(), BackendHealth::new(Healthiness::Healthy)),
+      ],
+      strategy,
+    )
+  }
+
+  #[test]
+  fn round_robin_spreads_requests_evenly() {
+    let pool = pool_builder(Box::new(RoundRobin::new())).build();
+
+    let shares = simulate(&pool, 300, 10, &HashMap::new());
+
+    assert_eq!(
+      shares.iter().map(|share| share.requests).collect::<Vec<_>>(),
+      vec![100, 100, 100]
+    );
+  }
+
+  #[test]
+  fn ip_hash_sends_every_client_to_one_backend_server() {
+    let pool = pool_builder(Box::new(IPHash::new())).build();
+
+    let shares = simulate(&pool, 1000, 100, &HashMap::new());
+
+    assert_eq!(shares.iter().map(|share| share.requests).sum::<usize>(), 1000);
+    assert_eq!(shares.iter().map(|share| share.clients).sum::<usize>(), 100);
+  }
+
+  #[test]
+  fn applies_slow_weights_of_the_pool() {
+    // given:
+    let mut builder = pool_builder(Box::new(Random::new()));
+    builder.slow_weights(SlowWeights::new(vec![(200, 0.0)]));
+    let pool = builder.build();
+    let latencies = parse_latencies(vec!["127.0.0.1:8081=250"]).unwrap();
+
+    // when:
+    let shares = simulate(&pool, 300, 10, &latencies);
+
+    // then:
+    assert_eq!(shares[1].requests, 0);
+    assert_eq!(shares[0].requests + shares[2].requests, 300);
+  }
+
+  #[test]
+  fn parse_invalid_arguments() {
+    assert!(parse_count("0").is_err());
+    assert!(parse_count("many").is_err());
+    assert!(parse_latencies(vec!["127.0.0.1:8081"]).is_err());
+    assert!(parse_latencies(vec!["127.0.0.1:8081=slow"]).is_err());
+  }
+}
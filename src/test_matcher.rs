@@ -0,0 +1,165 @@
+use crate::{
+  configuration::read_initial_config,
+  debug_headers::{middleware_names, type_name},
+  normalization::normalize_request,
+  server::{pool_by_req, Scheme},
+  utils::split_once,
+};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use hyper::{header::HOST, Body, Request, Uri};
+use std::{io, path::PathBuf};
+
+pub const NAME: &str = "test-matcher";
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name(NAME)
+    .about("Prints which backend pool would handle a request, without starting the load balancer.")
+    .arg(
+      Arg::with_name("config")
+        .short("c")
+        .long("config")
+        .value_name("TOML FILE")
+        .help("The path to the configuration in TOML format.")
+        .required(true)
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("config-dir")
+        .long("config-dir")
+        .value_name("DIRECTORY")
+        .help("The directory relative paths in the configuration are resolved against. Defaults to the directory of the configuration file.")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("url")
+        .long("url")
+        .value_name("URL")
+        .help("The URL of the request, e.g. https://whoami.localhost/admin")
+        .required(true)
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("method")
+        .long("method")
+        .value_name("METHOD")
+        .help("The method of the request.")
+        .default_value("GET")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("header")
+        .long("header")
+        .value_name("NAME:VALUE")
+        .help("A header of the request, can be used multiple times. Defaults the host header to the host of the URL.")
+        .multiple(true)
+        .number_of_values(1)
+        .takes_value(true),
+    )
+}
+
+pub async fn run(matches: &ArgMatches<'_>) -> Result<(), io::Error> {
+  let config = read_initial_config(
+    matches.value_of("config").unwrap(),
+    matches.value_of("config-dir").map(PathBuf::from),
+  )
+  .await?;
+  let config = config.load();
+  let (mut request, scheme) = build_request(
+    matches.value_of("url").unwrap(),
+    matches.value_of("method").unwrap(),
+    matches.values_of("header").into_iter().flatten(),
+  )?;
+
+  if config.normalize_paths && normalize_request(&mut request).is_err() {
+    println!("The request would be rejected with 400 Bad Request, because its path is invalid");
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid request path"));
+  }
+
+  match pool_by_req(&config.shared_data, &request, &scheme) {
+    Some(pool) => {
+      let addresses = pool
+        .addresses
+        .iter()
+        .map(|(address, _)| address.as_str())
+        .collect::<Vec<_>>();
+      println!("Backend pool:  {}", pool.name);
+      println!("Matcher:       {:?}", pool.matcher);
+      println!("Addresses:     {}", addresses.join(", "));
+      println!("Strategy:      {}", type_name(&pool.strategy));
+      let middlewares = middleware_names(&pool.chain);
+      if middlewares.is_empty() {
+        println!("Middlewares:   none");
+      } else {
+        println!("Middlewares:   {}", middlewares.join(", "));
+      }
+      Ok(())
+    }
+    None => {
+      println!("No backend pool matches, the request would be answered with 404 Not Found");
+      Err(io::Error::new(io::ErrorKind::NotFound, "No backend pool matches"))
+    }
+  }
+}
+
+fn build_request<'a, I>(url: &str, method: &str, headers: I) -> Result<(Request<Body>, Scheme), io::Error>
+where
+  I: IntoIterator<Item = &'a str>,
+{
+  let uri: Uri = url.parse().map_err(invalid_input)?;
+  let scheme = match uri.scheme_str() {
+    Some("https") => Scheme::HTTPS,
+    Some("http") | None => Scheme::HTTP,
+    Some(other) => return Err(invalid_input(format!("Unsupported scheme {}", other))),
+  };
+
+  let mut builder = Request::builder().method(method).uri(&uri);
+  let mut has_host = false;
+  for header in headers {
+    let (name, value) = split_once(header, ':').ok_or_else(|| invalid_input(format!("Invalid header {}", header)))?;
+    has_host |= name.trim().eq_ignore_ascii_case(HOST.as_str());
+    builder = builder.header(name.trim(), value.trim());
+  }
+  if let (false, Some(authority)) = (has_host, uri.authority()) {
+    builder = builder.header(HOST, authority.as_str());
+  }
+  let request = builder.body(Body::empty()).map_err(invalid_input)?;
+  Ok((request, scheme))
+}
+
+fn invalid_input<E>(error: E) -> io::Error
+where
+  E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+  io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn build_request_defaults_host_header() {
+    let (request, scheme) = build_request("https://whoami.localhost/admin?x=1", "POST", vec![]).unwrap();
+
+    assert_eq!(scheme, Scheme::HTTPS);
+    assert_eq!(request.method(), "POST");
+    assert_eq!(request.uri().path(), "/admin");
+    assert_eq!(request.headers().get(HOST).unwrap(), "whoami.localhost");
+  }
+
+  #[test]
+  fn build_request_with_headers() {
+    let headers = vec!["host: other.localhost", "cookie:ab_variant=b"];
+
+    let (request, scheme) = build_request("http://whoami.localhost/", "GET", headers).unwrap();
+
+    assert_eq!(scheme, Scheme::HTTP);
+    assert_eq!(request.headers().get(HOST).unwrap(), "other.localhost");
+    assert_eq!(request.headers().get("cookie").unwrap(), "ab_variant=b");
+  }
+
+  #[test]
+  fn build_request_invalid_header() {
+    assert!(build_request("http://whoami.localhost/", "GET", vec!["no-colon"]).is_err());
+  }
+}
@@ -0,0 +1,150 @@
+use hyper::{Body, Response};
+use std::{
+  fmt::{self, Display},
+  time::Duration,
+};
+use tokio::time::Instant;
+
+/// Timing of the TCP connection to a backend server. It is attached to the extensions of every response received over
+/// this connection.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectTiming {
+  pub started_at: Instant,
+  pub duration: Duration,
+}
+
+/// Timing of the request to a backend server, attached to the extensions of the backend's response.
+#[derive(Clone, Copy, Debug)]
+pub struct UpstreamTiming {
+  pub sent_at: Instant,
+  pub time_to_first_byte: Duration,
+}
+
+/// The phases of a request passing through the load balancer.
+#[derive(Debug, PartialEq)]
+pub struct RequestTiming {
+  /// Time spent matching the backend pool and selecting a backend server
+  pub select: Duration,
+  /// Time spent connecting to the backend server, zero if an idle connection was reused
+  pub connect: Option<Duration>,
+  /// Time from sending the request over the established connection until the response headers are received
+  pub time_to_first_byte: Option<Duration>,
+  /// Time from receiving the request until the response headers are sent to the client
+  pub total: Duration,
+}
+
+impl RequestTiming {
+  /// Collects the timing of the `response`. Connect and time to first byte are only known if the request was forwarded
+  /// to a backend server and not answered early by a middleware.
+  pub fn new(received_at: Instant, selected_at: Instant, response: &Response<Body>, now: Instant) -> RequestTiming {
+    let upstream = response.extensions().get::<UpstreamTiming>();
+    let connect = upstream.map(|upstream| match response.extensions().get::<ConnectTiming>() {
+      Some(connect) if connect.started_at >= upstream.sent_at => connect.duration,
+      _ => Duration::from_secs(0),
+    });
+    /* a new connection is established while the request is sent, which is already counted by `connect` */
+    let time_to_first_byte = upstream.map(|upstream| {
+      let connect = connect.unwrap_or_default();
+      upstream.time_to_first_byte.saturating_sub(connect)
+    });
+    RequestTiming {
+      select: selected_at.duration_since(received_at),
+      connect,
+      time_to_first_byte,
+      total: now.duration_since(received_at),
+    }
+  }
+}
+
+impl Display for RequestTiming {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fn millis(duration: Option<Duration>) -> String {
+      duration.map_or("-".to_string(), |it| format!("{}ms", it.as_millis()))
+    }
+    write!(
+      f,
+      "select={} connect={} ttfb={} total={}",
+      millis(Some(self.select)),
+      millis(self.connect),
+      millis(self.time_to_first_byte),
+      millis(Some(self.total))
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn request_timing_with_new_connection() {
+    // given:
+    let received_at = Instant::now();
+    let sent_at = received_at + Duration::from_millis(2);
+    let mut response = Response::new(Body::empty());
+    response.extensions_mut().insert(UpstreamTiming {
+      sent_at,
+      time_to_first_byte: Duration::from_millis(105),
+    });
+    response.extensions_mut().insert(ConnectTiming {
+      started_at: sent_at + Duration::from_millis(1),
+      duration: Duration::from_millis(5),
+    });
+
+    // when:
+    let actual = RequestTiming::new(
+      received_at,
+      received_at + Duration::from_millis(1),
+      &response,
+      received_at + Duration::from_millis(110),
+    );
+
+    // then:
+    assert_eq!(actual.to_string(), "select=1ms connect=5ms ttfb=100ms total=110ms");
+  }
+
+  #[test]
+  fn request_timing_with_reused_connection() {
+    // given:
+    let received_at = Instant::now();
+    let mut response = Response::new(Body::empty());
+    response.extensions_mut().insert(ConnectTiming {
+      started_at: received_at,
+      duration: Duration::from_millis(5),
+    });
+    response.extensions_mut().insert(UpstreamTiming {
+      sent_at: received_at + Duration::from_millis(1),
+      time_to_first_byte: Duration::from_millis(10),
+    });
+
+    // when:
+    let actual = RequestTiming::new(
+      received_at,
+      received_at,
+      &response,
+      received_at + Duration::from_millis(12),
+    );
+
+    // then:
+    assert_eq!(actual.connect, Some(Duration::from_secs(0)));
+    assert_eq!(actual.time_to_first_byte, Some(Duration::from_millis(10)));
+  }
+
+  #[test]
+  fn request_timing_without_backend_response() {
+    // given:
+    let received_at = Instant::now();
+    let response = Response::new(Body::empty());
+
+    // when:
+    let actual = RequestTiming::new(
+      received_at,
+      received_at,
+      &response,
+      received_at + Duration::from_millis(3),
+    );
+
+    // then:
+    assert_eq!(actual.to_string(), "select=0ms connect=- ttfb=- total=3ms");
+  }
+}
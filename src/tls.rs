@@ -1,20 +1,33 @@
-use arc_swap::access::Access;
+use arc_swap::{access::Access, ArcSwap};
+use log::trace;
+use openssl::x509::X509;
+use ring::{
+  aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+  hkdf::{Salt, HKDF_SHA256},
+  rand::{SecureRandom, SystemRandom},
+};
 use std::{
   collections::HashMap,
+  convert::TryInto,
   fs::File,
   io::{self, BufReader, ErrorKind::InvalidData},
   path::Path,
   sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio_rustls::{
   rustls::{
     internal::pemfile::{certs, rsa_private_keys},
     sign::{CertifiedKey, RSASigningKey},
-    Certificate, ClientHello, PrivateKey, ResolvesServerCert,
+    Certificate, ClientHello, NoServerSessionStorage, PrivateKey, ProducesTickets, ResolvesServerCert, ServerConfig,
+    ServerSessionMemoryCache,
   },
-  webpki::DNSName,
+  webpki::{DNSName, DNSNameRef},
 };
 
+/// The certificates by SNI name. The map is swapped on its own whenever an ACME certificate is issued in the background.
+pub type Certificates = ArcSwap<HashMap<DNSName, CertifiedKey>>;
+
 pub fn certified_key_from_acme_certificate(certificate: acme_lib::Certificate) -> Result<CertifiedKey, io::Error> {
   let certificates =
     certs(&mut certificate.certificate().as_bytes()).map_err(|_| io::Error::new(InvalidData, "Invalid certificate"))?;
@@ -76,16 +89,43 @@ where
     .map_err(|_| io::Error::new(InvalidData, format!("Invalid RSA key in '{}'", path.as_ref().display())))
 }
 
+/// Returns the DNS names in the subject alternative names of the end entity certificate. Wildcard names are skipped,
+/// since certificates are resolved by exact SNI names.
+pub fn certificate_dns_names(certified_key: &CertifiedKey) -> Vec<DNSName> {
+  match certified_key.end_entity_cert() {
+    Ok(certificate) => dns_names_of_der(&certificate.0),
+    Err(_) => Vec::new(),
+  }
+}
+
+fn dns_names_of_der(der: &[u8]) -> Vec<DNSName> {
+  let subject_alt_names = match X509::from_der(der).ok().and_then(|it| it.subject_alt_names()) {
+    Some(subject_alt_names) => subject_alt_names,
+    None => return Vec::new(),
+  };
+  subject_alt_names
+    .iter()
+    .filter_map(|it| it.dnsname())
+    .filter_map(|name| match DNSNameRef::try_from_ascii_str(name) {
+      Ok(name) => Some(name.to_owned()),
+      Err(_) => {
+        trace!("Skipping subject alternative name '{}'", name);
+        None
+      }
+    })
+    .collect()
+}
+
 pub struct ReconfigurableCertificateResolver<A>
 where
-  A: Access<HashMap<DNSName, CertifiedKey>>,
+  A: Access<Certificates>,
 {
   certificates: A,
 }
 
 impl<A> ReconfigurableCertificateResolver<A>
 where
-  A: Access<HashMap<DNSName, CertifiedKey>>,
+  A: Access<Certificates>,
 {
   pub fn new(certificates: A) -> ReconfigurableCertificateResolver<A> {
     ReconfigurableCertificateResolver { certificates }
@@ -94,11 +134,11 @@ where
 
 impl<A> ResolvesServerCert for ReconfigurableCertificateResolver<A>
 where
-  A: Access<HashMap<DNSName, CertifiedKey>> + Send + Sync,
+  A: Access<Certificates> + Send + Sync,
 {
   fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
     if let Some(name) = client_hello.server_name() {
-      let certificates = self.certificates.load();
+      let certificates = ArcSwap::load(&*self.certificates.load());
       certificates.get(&name.to_owned()).cloned()
     } else {
       // This kind of resolver requires SNI
@@ -106,3 +146,279 @@ where
     }
   }
 }
+
+/// The minimum length of a shared ticket key file.
+pub const MIN_TICKET_SECRET_LEN: usize = 32;
+
+const TICKET_KEY_SALT: &[u8] = b"arlb ticket keys";
+const EPOCH_LEN: usize = 8;
+
+/// Settings for resuming TLS sessions of returning clients without a full handshake.
+#[derive(Clone, PartialEq)]
+pub struct TlsSessionSettings {
+  /// The number of sessions kept in memory for session id based resumption, `0` disables it.
+  pub cache_size: usize,
+  pub tickets: bool,
+  pub ticket_rotation: Duration,
+  /// A secret shared by all instances, so tickets issued by one instance can be resumed by another one.
+  pub ticket_secret: Option<Vec<u8>>,
+}
+
+impl Default for TlsSessionSettings {
+  fn default() -> Self {
+    TlsSessionSettings {
+      cache_size: 256,
+      tickets: false,
+      ticket_rotation: Duration::from_secs(6 * 60 * 60),
+      ticket_secret: None,
+    }
+  }
+}
+
+impl TlsSessionSettings {
+  pub fn apply(&self, tls_config: &mut ServerConfig) -> io::Result<()> {
+    if self.cache_size == 0 {
+      tls_config.session_storage = Arc::new(NoServerSessionStorage {});
+    } else {
+      tls_config.session_storage = ServerSessionMemoryCache::new(self.cache_size);
+    }
+    if self.tickets {
+      let ticketer = match &self.ticket_secret {
+        Some(secret) => RotatingTicketer::new(secret.clone(), self.ticket_rotation),
+        None => RotatingTicketer::random(self.ticket_rotation)?,
+      };
+      tls_config.ticketer = Arc::new(ticketer);
+    }
+    Ok(())
+  }
+}
+
+/// Encrypts session tickets with a ChaCha20-Poly1305 key derived from a secret for every rotation period.
+///
+/// A ticket starts with the number of the period it was issued in, so tickets of the current and the previous period
+/// can be decrypted. Instances sharing the secret and the rotation period derive the same keys.
+struct RotatingTicketer {
+  secret: Vec<u8>,
+  rotation_secs: u64,
+  random: SystemRandom,
+}
+
+impl RotatingTicketer {
+  fn new(secret: Vec<u8>, rotation: Duration) -> RotatingTicketer {
+    RotatingTicketer {
+      secret,
+      rotation_secs: rotation.as_secs().max(1),
+      random: SystemRandom::new(),
+    }
+  }
+
+  fn random(rotation: Duration) -> io::Result<RotatingTicketer> {
+    let mut secret = vec![0; MIN_TICKET_SECRET_LEN];
+    SystemRandom::new()
+      .fill(&mut secret)
+      .map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not generate a ticket secret"))?;
+    Ok(RotatingTicketer::new(secret, rotation))
+  }
+
+  fn current_epoch(&self) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() / self.rotation_secs
+  }
+
+  fn key(&self, epoch: u64) -> LessSafeKey {
+    let prk = Salt::new(HKDF_SHA256, TICKET_KEY_SALT).extract(&self.secret);
+    let epoch = epoch.to_be_bytes();
+    let info = [&epoch[..]];
+    let okm = prk
+      .expand(&info, &CHACHA20_POLY1305)
+      .expect("the key length is valid for HKDF-SHA256");
+    LessSafeKey::new(UnboundKey::from(okm))
+  }
+
+  fn encrypt_in(&self, epoch: u64, plain: &[u8]) -> Option<Vec<u8>> {
+    let mut nonce = [0; NONCE_LEN];
+    self.random.fill(&mut nonce).ok()?;
+    let mut in_out = plain.to_vec();
+    self
+      .key(epoch)
+      .seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(epoch.to_be_bytes()),
+        &mut in_out,
+      )
+      .ok()?;
+
+    let mut ticket = Vec::with_capacity(EPOCH_LEN + NONCE_LEN + in_out.len());
+    ticket.extend_from_slice(&epoch.to_be_bytes());
+    ticket.extend_from_slice(&nonce);
+    ticket.extend_from_slice(&in_out);
+    Some(ticket)
+  }
+
+  fn decrypt_in(&self, current_epoch: u64, ticket: &[u8]) -> Option<Vec<u8>> {
+    if ticket.len() < EPOCH_LEN + NONCE_LEN {
+      return None;
+    }
+    let (epoch, rest) = ticket.split_at(EPOCH_LEN);
+    let epoch = u64::from_be_bytes(epoch.try_into().ok()?);
+    if epoch != current_epoch && epoch.checked_add(1) != Some(current_epoch) {
+      return None;
+    }
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+    let mut in_out = ciphertext.to_vec();
+    let plain_len = self
+      .key(epoch)
+      .open_in_place(nonce, Aad::from(epoch.to_be_bytes()), &mut in_out)
+      .ok()?
+      .len();
+    in_out.truncate(plain_len);
+    Some(in_out)
+  }
+}
+
+impl ProducesTickets for RotatingTicketer {
+  fn enabled(&self) -> bool {
+    true
+  }
+
+  fn get_lifetime(&self) -> u32 {
+    self.rotation_secs.try_into().unwrap_or(u32::MAX)
+  }
+
+  fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+    self.encrypt_in(self.current_epoch(), plain)
+  }
+
+  fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+    self.decrypt_in(self.current_epoch(), cipher)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use openssl::{
+    asn1::Asn1Time,
+    hash::MessageDigest,
+    pkey::PKey,
+    rsa::Rsa,
+    x509::{extension::SubjectAlternativeName, X509Builder, X509NameBuilder},
+  };
+
+  fn ticketer(secret: &[u8]) -> RotatingTicketer {
+    RotatingTicketer::new(secret.to_vec(), Duration::from_secs(3600))
+  }
+
+  #[test]
+  fn ticket_round_trip() {
+    // given:
+    let ticketer = ticketer(&[1; 32]);
+
+    // when:
+    let ticket = ticketer.encrypt(b"session state").unwrap();
+
+    // then:
+    assert_ne!(&ticket[EPOCH_LEN + NONCE_LEN..], b"session state");
+    assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session state");
+  }
+
+  #[test]
+  fn tickets_of_the_previous_period_are_accepted() {
+    // given:
+    let ticketer = ticketer(&[1; 32]);
+    let ticket = ticketer.encrypt_in(41, b"session state").unwrap();
+
+    // then:
+    assert_eq!(ticketer.decrypt_in(41, &ticket).unwrap(), b"session state");
+    assert_eq!(ticketer.decrypt_in(42, &ticket).unwrap(), b"session state");
+    assert_eq!(ticketer.decrypt_in(43, &ticket), None);
+    assert_eq!(ticketer.decrypt_in(40, &ticket), None);
+  }
+
+  #[test]
+  fn tickets_are_shared_by_instances_with_the_same_secret() {
+    // given:
+    let first = ticketer(&[1; 32]);
+    let second = ticketer(&[1; 32]);
+    let other = ticketer(&[2; 32]);
+
+    // when:
+    let ticket = first.encrypt(b"session state").unwrap();
+
+    // then:
+    assert_eq!(second.decrypt(&ticket).unwrap(), b"session state");
+    assert_eq!(other.decrypt(&ticket), None);
+  }
+
+  #[test]
+  fn tampered_tickets_are_rejected() {
+    // given:
+    let ticketer = ticketer(&[1; 32]);
+    let mut ticket = ticketer.encrypt_in(7, b"session state").unwrap();
+
+    // when:
+    let last = ticket.len() - 1;
+    ticket[last] ^= 1;
+
+    // then:
+    assert_eq!(ticketer.decrypt_in(7, &ticket), None);
+    assert_eq!(ticketer.decrypt_in(7, &[0; 4]), None);
+  }
+
+  #[test]
+  fn dns_names_of_certificate() {
+    // given:
+    let der = certificate_der(&["whoami.localhost", "www.whoami.localhost", "*.whoami.localhost"]);
+
+    // when:
+    let names = dns_names_of_der(&der);
+
+    // then:
+    let names = names
+      .iter()
+      .map(|it| Into::<&str>::into(it.as_ref()))
+      .collect::<Vec<_>>();
+    assert_eq!(names, vec!["whoami.localhost", "www.whoami.localhost"]);
+  }
+
+  #[test]
+  fn dns_names_of_invalid_certificate() {
+    assert!(dns_names_of_der(b"not a certificate").is_empty());
+  }
+
+  fn certificate_der(names: &[&str]) -> Vec<u8> {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", names[0]).unwrap();
+    let name = name.build();
+
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(30).unwrap()).unwrap();
+    let mut subject_alt_name = SubjectAlternativeName::new();
+    for name in names {
+      subject_alt_name.dns(name);
+    }
+    let subject_alt_name = subject_alt_name.build(&builder.x509v3_context(None, None)).unwrap();
+    builder.append_extension(subject_alt_name).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    builder.build().to_der().unwrap()
+  }
+
+  #[test]
+  fn settings_disable_tickets_by_default() {
+    // given:
+    let mut tls_config = ServerConfig::new(tokio_rustls::rustls::NoClientAuth::new());
+
+    // when:
+    TlsSessionSettings::default().apply(&mut tls_config).unwrap();
+
+    // then:
+    assert!(!tls_config.ticketer.enabled());
+  }
+}
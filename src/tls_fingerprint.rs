@@ -0,0 +1,272 @@
+use openssl::hash::{hash, MessageDigest};
+use std::{fmt::Display, time::Duration};
+use tokio::net::TcpStream;
+
+const HANDSHAKE_RECORD: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+const RECORD_HEADER_LEN: usize = 5;
+/* The maximum length of a TLS record, see RFC 8446, section 5.1 */
+const MAX_RECORD_LEN: usize = RECORD_HEADER_LEN + (1 << 14);
+const SUPPORTED_GROUPS: u16 = 10;
+const EC_POINT_FORMATS: u16 = 11;
+/* ClientHellos with large key shares may take more than one TCP segment, which are waited for a bit */
+const PEEK_ATTEMPTS: usize = 10;
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The JA3 fingerprint of the ClientHello of a TLS connection, see https://github.com/salesforce/ja3. Clients built
+/// with the same TLS library and settings share a fingerprint, regardless of their `User-Agent`, so it helps to tell
+/// bots from browsers and to correlate abusive clients across IP addresses.
+///
+/// It is attached to the extensions of every request received over the connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsFingerprint(pub String);
+
+impl TlsFingerprint {
+  /// Computes the fingerprint of a TLS record containing a ClientHello, `None` if the record is no complete ClientHello.
+  pub fn of_client_hello(record: &[u8]) -> Option<TlsFingerprint> {
+    let ja3 = ja3_string(record)?;
+    let digest = hash(MessageDigest::md5(), ja3.as_bytes()).ok()?;
+    Some(TlsFingerprint(
+      digest.iter().map(|byte| format!("{:02x}", byte)).collect(),
+    ))
+  }
+
+  /// Reads the fingerprint from the ClientHello the client sent on the `stream`, without consuming it, so the TLS
+  /// handshake can still be done afterwards.
+  pub async fn peek(stream: &TcpStream) -> Option<TlsFingerprint> {
+    let mut buffer = vec![0; MAX_RECORD_LEN];
+    for _ in 0..PEEK_ATTEMPTS {
+      let peeked = stream.peek(&mut buffer).await.ok()?;
+      if peeked == 0 {
+        return None;
+      }
+      if peeked >= RECORD_HEADER_LEN {
+        if buffer[0] != HANDSHAKE_RECORD {
+          return None;
+        }
+        let record_len = RECORD_HEADER_LEN + u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+        if peeked >= record_len {
+          return TlsFingerprint::of_client_hello(&buffer[..record_len]);
+        }
+      }
+      tokio::time::sleep(PEEK_INTERVAL).await;
+    }
+    None
+  }
+
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+}
+
+impl Display for TlsFingerprint {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+/// Returns `SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats` of the ClientHello, each list
+/// joined by `-`. GREASE values (RFC 8701) are skipped, since clients choose them randomly.
+fn ja3_string(record: &[u8]) -> Option<String> {
+  let mut record = Reader(record);
+  if record.u8()? != HANDSHAKE_RECORD {
+    return None;
+  }
+  record.u16()?;
+  let mut handshake = record.vec_u16()?;
+  if handshake.u8()? != CLIENT_HELLO {
+    return None;
+  }
+  let handshake_len = handshake.u24()?;
+  let mut client_hello = Reader(handshake.bytes(handshake_len)?);
+
+  let version = client_hello.u16()?;
+  client_hello.bytes(32)?;
+  client_hello.vec_u8()?;
+  let mut cipher_suites = client_hello.vec_u16()?;
+  let mut ciphers = Vec::new();
+  while !cipher_suites.is_empty() {
+    ciphers.push(cipher_suites.u16()?);
+  }
+  client_hello.vec_u8()?;
+
+  let mut extension_types = Vec::new();
+  let mut groups = Vec::new();
+  let mut point_formats = Vec::new();
+  // ClientHellos without extensions end after the compression methods
+  if !client_hello.is_empty() {
+    let mut extensions = client_hello.vec_u16()?;
+    while !extensions.is_empty() {
+      let extension_type = extensions.u16()?;
+      let mut data = extensions.vec_u16()?;
+      extension_types.push(extension_type);
+      match extension_type {
+        SUPPORTED_GROUPS => {
+          let mut list = data.vec_u16()?;
+          while !list.is_empty() {
+            groups.push(list.u16()?);
+          }
+        }
+        EC_POINT_FORMATS => {
+          let mut list = data.vec_u8()?;
+          while !list.is_empty() {
+            point_formats.push(u16::from(list.u8()?));
+          }
+        }
+        _ => {}
+      }
+    }
+  }
+
+  Some(format!(
+    "{},{},{},{},{}",
+    version,
+    join(&ciphers),
+    join(&extension_types),
+    join(&groups),
+    join(&point_formats)
+  ))
+}
+
+fn join(values: &[u16]) -> String {
+  values
+    .iter()
+    .filter(|value| !is_grease(**value))
+    .map(u16::to_string)
+    .collect::<Vec<_>>()
+    .join("-")
+}
+
+fn is_grease(value: u16) -> bool {
+  value & 0x0f0f == 0x0a0a && (value >> 8) == (value & 0xff)
+}
+
+/* Reads the big-endian integers and length-prefixed vectors of TLS messages */
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+  fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+    if self.0.len() < len {
+      return None;
+    }
+    let (bytes, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Some(bytes)
+  }
+
+  fn u8(&mut self) -> Option<u8> {
+    Some(self.bytes(1)?[0])
+  }
+
+  fn u16(&mut self) -> Option<u16> {
+    let bytes = self.bytes(2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+  }
+
+  fn u24(&mut self) -> Option<usize> {
+    let bytes = self.bytes(3)?;
+    Some((usize::from(bytes[0]) << 16) | (usize::from(bytes[1]) << 8) | usize::from(bytes[2]))
+  }
+
+  fn vec_u8(&mut self) -> Option<Reader<'a>> {
+    let len = self.u8()?;
+    Some(Reader(self.bytes(usize::from(len))?))
+  }
+
+  fn vec_u16(&mut self) -> Option<Reader<'a>> {
+    let len = self.u16()?;
+    Some(Reader(self.bytes(usize::from(len))?))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+  fn with_u16_len(mut data: Vec<u8>) -> Vec<u8> {
+    let mut prefixed = (data.len() as u16).to_be_bytes().to_vec();
+    prefixed.append(&mut data);
+    prefixed
+  }
+
+  /* A ClientHello offering two cipher suites and a GREASE value, with SNI, supported groups and point formats */
+  fn client_hello() -> Vec<u8> {
+    let mut extensions: Vec<u8> = Vec::new();
+    extensions.extend(&[0x00, 0x00]);
+    extensions.extend(with_u16_len(with_u16_len(vec![
+      0x00, 0x00, 0x09, b'l', b'o', b'c', b'a', b'l', b'h', b'o', b's', b't',
+    ])));
+    extensions.extend(&[0x00, 0x0a]);
+    extensions.extend(with_u16_len(with_u16_len(vec![0x3a, 0x3a, 0x00, 0x1d, 0x00, 0x17])));
+    extensions.extend(&[0x00, 0x0b]);
+    extensions.extend(with_u16_len(vec![0x01, 0x00]));
+
+    let mut hello = vec![0x03, 0x03];
+    hello.extend(&[0; 32]);
+    hello.push(0);
+    hello.extend(with_u16_len(vec![0x0a, 0x0a, 0x13, 0x01, 0xc0, 0x2f]));
+    hello.extend(&[0x01, 0x00]);
+    hello.extend(with_u16_len(extensions));
+
+    let mut handshake = vec![CLIENT_HELLO, 0, 0, hello.len() as u8];
+    handshake.append(&mut hello);
+    let mut record = vec![HANDSHAKE_RECORD, 0x03, 0x01];
+    record.extend(with_u16_len(handshake));
+    record
+  }
+
+  #[test]
+  fn ja3_string_skips_grease_values() {
+    assert_eq!(
+      ja3_string(&client_hello()),
+      Some("771,4865-49199,0-10-11,29-23,0".to_string())
+    );
+  }
+
+  #[test]
+  fn fingerprint_is_md5_of_ja3_string() {
+    let fingerprint = TlsFingerprint::of_client_hello(&client_hello()).unwrap();
+
+    assert_eq!(fingerprint.as_str().len(), 32);
+    assert!(fingerprint.as_str().chars().all(|it| it.is_ascii_hexdigit()));
+  }
+
+  #[test]
+  fn rejects_truncated_and_other_records() {
+    let client_hello = client_hello();
+
+    assert_eq!(
+      TlsFingerprint::of_client_hello(&client_hello[..client_hello.len() - 1]),
+      None
+    );
+    assert_eq!(TlsFingerprint::of_client_hello(b"GET / HTTP/1.1\r\n\r\n"), None);
+  }
+
+  #[tokio::test]
+  async fn peeks_client_hello_without_consuming_it() {
+    // given:
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    let client_hello = client_hello();
+    let (first, second) = client_hello.split_at(20);
+    client.write_all(first).await.unwrap();
+    let write_rest = async {
+      tokio::time::sleep(PEEK_INTERVAL).await;
+      client.write_all(second).await.unwrap();
+    };
+
+    // when:
+    let (fingerprint, _) = tokio::join!(TlsFingerprint::peek(&server), write_rest);
+
+    // then:
+    assert_eq!(fingerprint, TlsFingerprint::of_client_hello(&client_hello));
+    let mut buffer = vec![0; client_hello.len()];
+    assert_eq!(server.peek(&mut buffer).await.unwrap(), client_hello.len());
+  }
+}
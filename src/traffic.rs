@@ -0,0 +1,432 @@
+use crate::{backend_limit::BackendConnection, error_response::UpstreamFailure};
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use hyper::{
+  body::{Bytes, HttpBody},
+  header::CONTENT_LENGTH,
+  Body, Request, Response, StatusCode,
+};
+use std::{
+  collections::{BTreeMap, HashMap, VecDeque},
+  pin::Pin,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  task::{Context, Poll},
+};
+
+/* Number of server errors kept per backend pool */
+const RECENT_ERRORS_LENGTH: usize = 20;
+
+/// Counts the responses and the bytes of the request and response bodies a
+/// [`BackendPool`](crate::server::BackendPool) exchanges with its clients, per backend server. The counters start at
+/// zero whenever the configuration is (re)loaded.
+#[derive(Debug, Default)]
+pub struct Traffic {
+  backends: HashMap<String, BackendTraffic>,
+  recent_errors: Mutex<VecDeque<RecentError>>,
+}
+
+#[derive(Debug, Default)]
+pub struct BackendTraffic {
+  request_bytes: Arc<AtomicU64>,
+  response_bytes: Arc<AtomicU64>,
+  responses: AtomicU64,
+  server_errors: AtomicU64,
+  upstream_failures: [AtomicU64; 5],
+  client_aborts: Arc<AtomicU64>,
+}
+
+impl BackendTraffic {
+  pub fn request_bytes(&self) -> u64 {
+    self.request_bytes.load(Ordering::Relaxed)
+  }
+
+  pub fn response_bytes(&self) -> u64 {
+    self.response_bytes.load(Ordering::Relaxed)
+  }
+
+  pub fn responses(&self) -> u64 {
+    self.responses.load(Ordering::Relaxed)
+  }
+
+  /* Responses with a 5xx status code */
+  pub fn server_errors(&self) -> u64 {
+    self.server_errors.load(Ordering::Relaxed)
+  }
+
+  /// The requests which failed without a response of the backend server, per kind of [`UpstreamFailure`].
+  pub fn upstream_failures(&self) -> BTreeMap<&'static str, u64> {
+    UpstreamFailure::ALL
+      .iter()
+      .map(|failure| {
+        (
+          failure.code(),
+          self.upstream_failures[*failure as usize].load(Ordering::Relaxed),
+        )
+      })
+      .collect()
+  }
+
+  /// The requests whose client went away before the whole response was sent.
+  pub fn client_aborts(&self) -> u64 {
+    self.client_aborts.load(Ordering::Relaxed)
+  }
+}
+
+/// Counts a request as aborted by its client, unless it is [finished](ClientAbort::finish) before being dropped.
+/// Hyper drops the future answering a request once the client closes the connection or resets the stream, which in
+/// turn drops the request to the backend server and closes the connection to it.
+#[derive(Debug)]
+pub struct ClientAbort {
+  aborts: Option<Arc<AtomicU64>>,
+}
+
+impl ClientAbort {
+  pub fn finish(mut self) {
+    self.aborts = None;
+  }
+}
+
+impl Drop for ClientAbort {
+  fn drop(&mut self) {
+    if let Some(aborts) = &self.aborts {
+      aborts.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+}
+
+/// A response of a backend server with a 5xx status code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecentError {
+  pub at: DateTime<Utc>,
+  pub address: String,
+  pub status: StatusCode,
+  /// Set if the error response was created by the load balancer, because the backend server failed to respond.
+  pub upstream_failure: Option<UpstreamFailure>,
+}
+
+impl Traffic {
+  pub fn new<'a, I>(addresses: I) -> Traffic
+  where
+    I: IntoIterator<Item = &'a str>,
+  {
+    let backends = addresses
+      .into_iter()
+      .map(|address| (address.to_string(), BackendTraffic::default()))
+      .collect();
+    Traffic {
+      backends,
+      recent_errors: Mutex::new(VecDeque::new()),
+    }
+  }
+
+  pub fn backend(&self, address: &str) -> Option<&BackendTraffic> {
+    self.backends.get(address)
+  }
+
+  /// Returns the request and response bytes of all backend servers.
+  pub fn total(&self) -> (u64, u64) {
+    self
+      .backends
+      .values()
+      .fold((0, 0), |(request_bytes, response_bytes), backend| {
+        (
+          request_bytes + backend.request_bytes(),
+          response_bytes + backend.response_bytes(),
+        )
+      })
+  }
+
+  /// Returns the number of responses and server errors of all backend servers.
+  pub fn total_responses(&self) -> (u64, u64) {
+    self
+      .backends
+      .values()
+      .fold((0, 0), |(responses, server_errors), backend| {
+        (responses + backend.responses(), server_errors + backend.server_errors())
+      })
+  }
+
+  /// Returns the most recent server errors, newest first.
+  pub fn recent_errors(&self) -> Vec<RecentError> {
+    self.recent_errors.lock().unwrap().iter().rev().cloned().collect()
+  }
+
+  /// Returns the guard counting the request forwarded to the backend server at `address` as aborted, if the client
+  /// goes away before the response headers are received.
+  pub fn abort_guard(&self, address: &str) -> ClientAbort {
+    ClientAbort {
+      aborts: self.backends.get(address).map(|backend| backend.client_aborts.clone()),
+    }
+  }
+
+  /// Counts the body of a request forwarded to the backend server at `address` while it is streamed.
+  pub fn count_request(&self, address: &str, request: Request<Body>) -> Request<Body> {
+    match self.backends.get(address) {
+      Some(backend) => request.map(|body| counted(body, backend.request_bytes.clone(), None, None)),
+      None => request,
+    }
+  }
+
+  /// Counts the response of the backend server at `address` and its body while it is streamed. The `connection`
+  /// to the backend server is held until the body has been sent.
+  pub fn count_response(
+    &self,
+    address: &str,
+    response: Response<Body>,
+    connection: Option<BackendConnection>,
+  ) -> Response<Body> {
+    let backend = match self.backends.get(address) {
+      Some(backend) => backend,
+      None => return response,
+    };
+    backend.responses.fetch_add(1, Ordering::Relaxed);
+    let upstream_failure = response.extensions().get::<UpstreamFailure>().copied();
+    if let Some(failure) = upstream_failure {
+      backend.upstream_failures[failure as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    if response.status().is_server_error() {
+      backend.server_errors.fetch_add(1, Ordering::Relaxed);
+      let mut recent_errors = self.recent_errors.lock().unwrap();
+      recent_errors.push_back(RecentError {
+        at: Utc::now(),
+        address: address.to_string(),
+        status: response.status(),
+        upstream_failure,
+      });
+      while recent_errors.len() > RECENT_ERRORS_LENGTH {
+        recent_errors.pop_front();
+      }
+    }
+    let remaining = response
+      .headers()
+      .get(CONTENT_LENGTH)
+      .and_then(|length| length.to_str().ok()?.parse().ok());
+    let abort = (self.abort_guard(address), remaining);
+    response.map(|body| counted(body, backend.response_bytes.clone(), Some(abort), connection))
+  }
+}
+
+/// Wraps the `body` to count its bytes. The `abort` guard of a response is finished together with the body, or
+/// once its remaining `Content-Length` has been sent, since hyper stops polling the body then. The `connection` is
+/// released at the same point.
+fn counted(
+  body: Body,
+  bytes: Arc<AtomicU64>,
+  abort: Option<(ClientAbort, Option<u64>)>,
+  connection: Option<BackendConnection>,
+) -> Body {
+  // keep empty bodies as they are, a wrapped body would be sent with chunked transfer encoding
+  if body.is_end_stream() {
+    if let Some((abort, _)) = abort {
+      abort.finish();
+    }
+    return body;
+  }
+  let (abort, remaining) = match abort {
+    Some((abort, remaining)) => (Some(abort), remaining),
+    None => (None, None),
+  };
+  Body::wrap_stream(Counted {
+    body,
+    bytes,
+    abort,
+    remaining,
+    connection,
+  })
+}
+
+struct Counted {
+  body: Body,
+  bytes: Arc<AtomicU64>,
+  abort: Option<ClientAbort>,
+  remaining: Option<u64>,
+  connection: Option<BackendConnection>,
+}
+
+impl Stream for Counted {
+  type Item = Result<Bytes, hyper::Error>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    let counted = &mut *self;
+    let polled = Pin::new(&mut counted.body).poll_data(cx);
+    let is_finished = match &polled {
+      Poll::Ready(Some(Ok(chunk))) => {
+        let length = chunk.len() as u64;
+        counted.bytes.fetch_add(length, Ordering::Relaxed);
+        counted.remaining = counted.remaining.map(|remaining| remaining.saturating_sub(length));
+        counted.body.is_end_stream() || counted.remaining == Some(0)
+      }
+      /* failures of the backend server are no client aborts */
+      Poll::Ready(_) => true,
+      Poll::Pending => false,
+    };
+    if is_finished {
+      if let Some(abort) = counted.abort.take() {
+        abort.finish();
+      }
+      counted.connection = None;
+    }
+    polled
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backend_limit::BackendLimit;
+  use hyper::body;
+  use std::time::Duration;
+
+  #[tokio::test]
+  async fn counts_streamed_bodies_per_backend() {
+    // given:
+    let traffic = Traffic::new(vec!["127.0.0.1:1", "127.0.0.1:2"]);
+    let request = Request::new(Body::from("hello"));
+    let response = Response::new(Body::from("hello world"));
+
+    // when:
+    let request = traffic.count_request("127.0.0.1:1", request);
+    let response = traffic.count_response("127.0.0.1:1", response, None);
+    body::to_bytes(request.into_body()).await.unwrap();
+    body::to_bytes(response.into_body()).await.unwrap();
+
+    // then:
+    let backend = traffic.backend("127.0.0.1:1").unwrap();
+    assert_eq!(backend.request_bytes(), 5);
+    assert_eq!(backend.response_bytes(), 11);
+    assert_eq!(traffic.backend("127.0.0.1:2").unwrap().response_bytes(), 0);
+    assert_eq!(traffic.total(), (5, 11));
+  }
+
+  #[test]
+  fn keeps_recent_server_errors() {
+    // given:
+    let traffic = Traffic::new(vec!["127.0.0.1:1", "127.0.0.1:2"]);
+    let response = |status: StatusCode| Response::builder().status(status).body(Body::empty()).unwrap();
+
+    // when:
+    for _ in 0..RECENT_ERRORS_LENGTH {
+      traffic.count_response("127.0.0.1:1", response(StatusCode::BAD_GATEWAY), None);
+    }
+    traffic.count_response("127.0.0.1:2", response(StatusCode::OK), None);
+    traffic.count_response("127.0.0.1:2", response(StatusCode::NOT_FOUND), None);
+    traffic.count_response("127.0.0.1:2", response(StatusCode::SERVICE_UNAVAILABLE), None);
+
+    // then:
+    let recent_errors = traffic.recent_errors();
+    assert_eq!(recent_errors.len(), RECENT_ERRORS_LENGTH);
+    assert_eq!(recent_errors[0].address, "127.0.0.1:2");
+    assert_eq!(recent_errors[0].status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(traffic.backend("127.0.0.1:2").unwrap().responses(), 3);
+    assert_eq!(traffic.backend("127.0.0.1:2").unwrap().server_errors(), 1);
+    assert_eq!(
+      traffic.total_responses(),
+      (RECENT_ERRORS_LENGTH as u64 + 3, RECENT_ERRORS_LENGTH as u64 + 1)
+    );
+  }
+
+  #[test]
+  fn counts_upstream_failures() {
+    // given:
+    let traffic = Traffic::new(vec!["127.0.0.1:1"]);
+    let mut response = Response::builder()
+      .status(StatusCode::GATEWAY_TIMEOUT)
+      .body(Body::empty())
+      .unwrap();
+    response.extensions_mut().insert(UpstreamFailure::Timeout);
+
+    // when:
+    traffic.count_response("127.0.0.1:1", response, None);
+
+    // then:
+    let backend = traffic.backend("127.0.0.1:1").unwrap();
+    assert_eq!(backend.upstream_failures()["timeout"], 1);
+    assert_eq!(backend.upstream_failures()["connection_refused"], 0);
+    assert_eq!(
+      traffic.recent_errors()[0].upstream_failure,
+      Some(UpstreamFailure::Timeout)
+    );
+  }
+
+  #[test]
+  fn counts_requests_dropped_before_the_response_as_client_aborts() {
+    // given:
+    let traffic = Traffic::new(vec!["127.0.0.1:1"]);
+
+    // when:
+    drop(traffic.abort_guard("127.0.0.1:1"));
+    traffic.abort_guard("127.0.0.1:1").finish();
+    drop(traffic.abort_guard("127.0.0.1:2"));
+
+    // then:
+    assert_eq!(traffic.backend("127.0.0.1:1").unwrap().client_aborts(), 1);
+  }
+
+  #[tokio::test]
+  async fn counts_responses_dropped_before_their_end_as_client_aborts() {
+    // given:
+    let traffic = Traffic::new(vec!["127.0.0.1:1"]);
+    let chunks = || {
+      Body::wrap_stream(futures::stream::iter(vec![
+        Ok::<_, hyper::Error>("hello"),
+        Ok(" world"),
+      ]))
+    };
+    let (mut sender, endless) = Body::channel();
+    sender.send_data(Bytes::from("hello")).await.unwrap();
+    let with_length = Response::builder().header(CONTENT_LENGTH, "5").body(endless).unwrap();
+
+    // when:
+    let mut aborted = traffic
+      .count_response("127.0.0.1:1", Response::new(chunks()), None)
+      .into_body();
+    aborted.data().await.unwrap().unwrap();
+    drop(aborted);
+    let completed = traffic.count_response("127.0.0.1:1", Response::new(chunks()), None);
+    body::to_bytes(completed.into_body()).await.unwrap();
+    let mut sent = traffic.count_response("127.0.0.1:1", with_length, None).into_body();
+    sent.data().await.unwrap().unwrap();
+    drop(sent);
+    traffic.count_response("127.0.0.1:1", Response::new(Body::empty()), None);
+
+    // then:
+    let backend = traffic.backend("127.0.0.1:1").unwrap();
+    assert_eq!(backend.responses(), 4);
+    assert_eq!(backend.client_aborts(), 1);
+  }
+
+  #[tokio::test]
+  async fn holds_the_connection_until_the_response_body_is_sent() {
+    // given:
+    let traffic = Traffic::new(vec!["127.0.0.1:1"]);
+    let limit = BackendLimit::new(1, Duration::from_secs(0));
+    let (mut sender, body) = Body::channel();
+
+    // when:
+    let connection = limit.try_acquire("127.0.0.1:1");
+    let response = traffic.count_response("127.0.0.1:1", Response::new(body), connection);
+    let in_use_while_streamed = limit.connections_in_use();
+    sender.send_data(Bytes::from("hello")).await.unwrap();
+    drop(sender);
+    body::to_bytes(response.into_body()).await.unwrap();
+
+    // then:
+    assert_eq!(in_use_while_streamed["127.0.0.1:1"], 1);
+    assert!(limit.connections_in_use().is_empty());
+  }
+
+  #[test]
+  fn keeps_empty_bodies() {
+    // given:
+    let traffic = Traffic::new(vec!["127.0.0.1:1"]);
+
+    // when:
+    let request = traffic.count_request("127.0.0.1:1", Request::new(Body::empty()));
+
+    // then:
+    assert!(request.body().is_end_stream());
+  }
+}
@@ -1,3 +1,10 @@
+use cookie::Cookie;
+use hyper::{
+  header::{HeaderName, HeaderValue, COOKIE, VARY},
+  HeaderMap,
+};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
 /// This is a stable alternative to rust's unstable feature [str_split_once](https://github.com/rust-lang/rust/issues/74773).
 pub fn split_once(string: &str, pattern: char) -> Option<(&str, &str)> {
   let mut splitter = string.splitn(2, pattern);
@@ -12,3 +19,88 @@ pub fn unwrap_result<T>(result: Result<T, T>) -> T {
     Err(t) => t,
   }
 }
+
+/// Returns the IPv4 address of IPv4-mapped IPv6 addresses (`::ffff:1.2.3.4`), which dual-stack listeners report for
+/// IPv4 clients, so a client has the same address regardless of the listener. Other addresses are returned unchanged.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+  match ip {
+    IpAddr::V6(v6) => match v6.octets() {
+      [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+      _ => ip,
+    },
+    IpAddr::V4(_) => ip,
+  }
+}
+
+/// Applies [`canonical_ip`] to the IP of the socket address.
+pub fn canonical_address(address: SocketAddr) -> SocketAddr {
+  SocketAddr::new(canonical_ip(address.ip()), address.port())
+}
+
+/// Returns the first cookie with the given `name` sent in the `Cookie` headers.
+pub fn find_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<Cookie<'a>> {
+  headers
+    .get_all(COOKIE)
+    .iter()
+    .filter_map(|header| header.to_str().ok())
+    .flat_map(|header| header.split(';'))
+    .filter_map(|cookie| Cookie::parse(cookie.trim()).ok())
+    .find(|cookie| cookie.name() == name)
+}
+
+/// Adds `name` to the `Vary` header of a response, unless it is listed already or the response varies by everything
+/// (`*`). Existing values are kept, since the response may depend on other headers of the request as well.
+pub fn append_vary(headers: &mut HeaderMap, name: &HeaderName) {
+  let listed = headers
+    .get_all(VARY)
+    .iter()
+    .filter_map(|it| it.to_str().ok())
+    .flat_map(|it| it.split(','))
+    .map(str::trim)
+    .any(|it| it == "*" || it.eq_ignore_ascii_case(name.as_str()));
+  if !listed {
+    headers.append(VARY, HeaderValue::from(name.clone()));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::header::{ACCEPT, ACCEPT_ENCODING, ORIGIN};
+
+  #[test]
+  fn append_vary_keeps_existing_values() {
+    let vary = |existing: &[&'static str]| {
+      let mut headers = HeaderMap::new();
+      for value in existing {
+        headers.append(VARY, HeaderValue::from_static(value));
+      }
+      append_vary(&mut headers, &ACCEPT_ENCODING);
+      headers.get_all(VARY).iter().cloned().collect::<Vec<_>>()
+    };
+
+    assert_eq!(vary(&[]), vec!["accept-encoding"]);
+    assert_eq!(vary(&["Origin"]), vec!["Origin", "accept-encoding"]);
+    assert_eq!(vary(&["Origin, Accept-Encoding"]), vec!["Origin, Accept-Encoding"]);
+    assert_eq!(vary(&["*"]), vec!["*"]);
+
+    let mut headers = HeaderMap::new();
+    append_vary(&mut headers, &ACCEPT);
+    append_vary(&mut headers, &ORIGIN);
+    append_vary(&mut headers, &ACCEPT);
+    assert_eq!(headers.get_all(VARY).iter().count(), 2);
+  }
+
+  #[test]
+  fn canonical_ip_unmaps_ipv4_mapped_ipv6_addresses() {
+    let mapped: IpAddr = "::ffff:1.2.3.4".parse().unwrap();
+    let ipv6: IpAddr = "2001:db8::1".parse().unwrap();
+
+    assert_eq!(canonical_ip(mapped), "1.2.3.4".parse::<IpAddr>().unwrap());
+    assert_eq!(canonical_ip(ipv6), ipv6);
+    assert_eq!(
+      canonical_address("[::ffff:1.2.3.4]:3000".parse().unwrap()),
+      "1.2.3.4:3000".parse().unwrap()
+    );
+  }
+}